@@ -0,0 +1,383 @@
+use crate::{
+    diagnostics::{Diagnostic, Severity},
+    lex::{Span, Token},
+};
+use clap::ValueEnum;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Lint {
+    /// Every lint
+    All,
+    /// Loops that can never run because the current cell is zero
+    DeadLoop,
+    /// Code after a loop that never terminates
+    UnreachableCode,
+    /// Pointer movement beyond either end of the tape
+    TapeOverflow,
+    /// Loops without I/O whose body leaves the loop cell unchanged
+    InfiniteLoop,
+}
+
+impl Lint {
+    const ALL: [Lint; 4] = [
+        Lint::DeadLoop,
+        Lint::UnreachableCode,
+        Lint::TapeOverflow,
+        Lint::InfiniteLoop,
+    ];
+
+    pub fn name(self) -> &'static str {
+        self.to_possible_value()
+            .unwrap()
+            .get_name()
+            .to_string()
+            .leak()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LintLevels {
+    levels: HashMap<Lint, Level>,
+}
+
+impl LintLevels {
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        if lint == Lint::All {
+            for lint in Lint::ALL {
+                self.levels.insert(lint, level);
+            }
+        } else {
+            self.levels.insert(lint, level);
+        }
+    }
+
+    pub fn level(&self, lint: Lint) -> Level {
+        self.levels.get(&lint).copied().unwrap_or(Level::Warn)
+    }
+
+    /// Turns a lint hit into a diagnostic of the configured severity, or
+    /// `None` if the lint is allowed.
+    fn diagnose(&self, lint: Lint, message: String, span: Span) -> Option<Diagnostic> {
+        let severity = match self.level(lint) {
+            Level::Allow => return None,
+            Level::Warn => Severity::Warning,
+            Level::Deny => Severity::Error,
+        };
+        Some(Diagnostic {
+            severity,
+            code: lint.name(),
+            message,
+            span: Some(span),
+            related: vec![],
+        })
+    }
+}
+
+/// What is known about the tape at some point in the program. Cells are
+/// keyed relative to the pointer position where the knowledge was gained.
+#[derive(Debug, Clone)]
+struct State {
+    cells: HashMap<isize, Option<u8>>,
+    rest_zero: bool,
+    offset: isize,
+    position: Option<isize>,
+}
+
+impl State {
+    fn value_at(&self, offset: isize) -> Option<u8> {
+        match self.cells.get(&(self.offset + offset)) {
+            Some(value) => *value,
+            None if self.rest_zero => Some(0),
+            None => None,
+        }
+    }
+
+    fn value(&self) -> Option<u8> {
+        self.value_at(0)
+    }
+
+    fn set_at(&mut self, offset: isize, value: Option<u8>) {
+        self.cells.insert(self.offset + offset, value);
+    }
+
+    fn set(&mut self, value: Option<u8>) {
+        self.set_at(0, value);
+    }
+
+    fn forget(&mut self) {
+        self.cells.clear();
+        self.rest_zero = false;
+        self.offset = 0;
+    }
+}
+
+/// Returns the index of the `LoopEnd` matching the `LoopStart` at `start`.
+fn loop_end(tokens: &[(Token, Span)], start: usize) -> usize {
+    let Token::LoopStart(distance) = tokens[start].0 else {
+        unreachable!()
+    };
+    start + distance as usize
+}
+
+/// Whether the loop body between `start` and `end` is a simple loop that
+/// leaves the loop cell unchanged, so it never exits once entered.
+fn never_exits(tokens: &[(Token, Span)], start: usize, end: usize) -> bool {
+    let mut offset = 0isize;
+    let mut delta = 0isize;
+    for (token, _) in &tokens[start + 1..end] {
+        match *token {
+            Token::PtrAdd(n) => offset += n as isize,
+            Token::PtrSub(n) => offset -= n as isize,
+            Token::Add(n) if offset == 0 => delta += n as isize,
+            Token::Sub(n) if offset == 0 => delta -= n as isize,
+            Token::GetChar | Token::SetZero if offset == 0 => return false,
+            Token::MulAdd { offset: to, .. } | Token::AddAt { offset: to, .. }
+                if offset + to as isize == 0 =>
+            {
+                return false
+            }
+            Token::LoopStart(_)
+            | Token::Call
+            | Token::Extern(_)
+            | Token::SwitchTape
+            | Token::ExchangeCell
+            | Token::ScanRight { .. }
+            | Token::ScanLeft { .. } => return false,
+            _ => {}
+        }
+    }
+    offset == 0 && delta % 256 == 0
+}
+
+/// Runs every lint over `tokens`, returning the resulting diagnostics.
+pub fn check(tokens: &[(Token, Span)], tape_size: usize, levels: &LintLevels) -> Vec<Diagnostic> {
+    walk(tokens, tape_size, levels, &mut vec![])
+}
+
+/// Finds the index of every loop start that can never run because the
+/// current cell is always zero there.
+pub fn dead_loops(tokens: &[(Token, Span)]) -> Vec<usize> {
+    let mut levels = LintLevels::default();
+    levels.set(Lint::All, Level::Allow);
+
+    let mut dead = vec![];
+    walk(tokens, usize::MAX, &levels, &mut dead);
+    dead
+}
+
+fn walk(
+    tokens: &[(Token, Span)],
+    tape_size: usize,
+    levels: &LintLevels,
+    dead: &mut Vec<usize>,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut state = State {
+        cells: HashMap::new(),
+        rest_zero: true,
+        offset: 0,
+        position: Some(0),
+    };
+    let mut loop_positions = vec![];
+    let mut overflowed = false;
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let (token, span) = tokens[i];
+        match token {
+            Token::PtrAdd(_) | Token::PtrSub(_) => {
+                let delta = match token {
+                    Token::PtrAdd(n) => n as isize,
+                    Token::PtrSub(n) => -(n as isize),
+                    _ => unreachable!(),
+                };
+                state.offset += delta;
+                state.position = state.position.map(|p| p + delta);
+
+                if let Some(position) = state.position {
+                    if !overflowed && (position < 0 || position >= tape_size as isize) {
+                        overflowed = true;
+                        diagnostics.extend(levels.diagnose(
+                            Lint::TapeOverflow,
+                            format!("pointer moves to cell {position}, outside the tape of {tape_size} cells"),
+                            span,
+                        ));
+                    }
+                }
+            }
+            Token::Add(n) => state.set(state.value().map(|v| v.wrapping_add(n as u8))),
+            Token::Sub(n) => state.set(state.value().map(|v| v.wrapping_sub(n as u8))),
+            Token::GetChar | Token::ExchangeCell => state.set(None),
+            Token::PutChar | Token::Debug => {}
+            Token::LoopStart(_) => {
+                let end = loop_end(tokens, i);
+                let value = state.value();
+
+                if value == Some(0) {
+                    dead.push(i);
+                    diagnostics.extend(levels.diagnose(
+                        Lint::DeadLoop,
+                        "loop never runs because the current cell is always zero".to_string(),
+                        span,
+                    ));
+                    i = end + 1;
+                    continue;
+                }
+
+                let body = &tokens[i + 1..end];
+                let io = body.iter().any(|(t, _)| {
+                    matches!(
+                        t,
+                        Token::PutChar
+                            | Token::GetChar
+                            | Token::Call
+                            | Token::Extern(_)
+                            | Token::Debug
+                    )
+                });
+                if !io && never_exits(tokens, i, end) {
+                    diagnostics.extend(levels.diagnose(
+                        Lint::InfiniteLoop,
+                        "loop never terminates once entered because its body leaves the current cell unchanged".to_string(),
+                        span,
+                    ));
+                }
+
+                if value.is_some() && never_exits(tokens, i, end) {
+                    if let Some((_, next)) = tokens.get(end + 1) {
+                        diagnostics.extend(levels.diagnose(
+                            Lint::UnreachableCode,
+                            format!("unreachable code after the infinite loop at {span}"),
+                            *next,
+                        ));
+                    }
+                    break;
+                }
+
+                loop_positions.push(state.position);
+                state.forget();
+            }
+            Token::LoopEnd(_) => {
+                let start_position = loop_positions.pop().flatten();
+                if start_position != state.position {
+                    state.position = None;
+                }
+                state.forget();
+                state.set(Some(0));
+            }
+            Token::ProcStart(n) => {
+                // The body only runs when called, from an unknown state
+                i += tokens[i..]
+                    .iter()
+                    .position(|(t, _)| *t == Token::ProcEnd(n))
+                    .unwrap();
+            }
+            Token::ProcEnd(_) => {}
+            // The other tape is unknown, as is where its pointer is
+            Token::Call | Token::SwitchTape => {
+                state.forget();
+                state.position = None;
+            }
+            // The function can change any cell, but not the pointer
+            Token::Extern(_) => state.forget(),
+            Token::SetZero => state.set(Some(0)),
+            Token::MulAdd { offset, factor } => {
+                let offset = offset.into();
+                let product = state.value().map(|v| v.wrapping_mul(factor as u8));
+                let sum = state
+                    .value_at(offset)
+                    .zip(product)
+                    .map(|(a, b)| a.wrapping_add(b));
+                state.set_at(offset, sum);
+            }
+            Token::AddAt { offset, amount } => {
+                let offset = offset.into();
+                let sum = state.value_at(offset).map(|v| v.wrapping_add(amount as u8));
+                state.set_at(offset, sum);
+            }
+            Token::ScanRight { .. } | Token::ScanLeft { .. } => {
+                state.forget();
+                state.position = None;
+                state.set(Some(0));
+            }
+        }
+        i += 1;
+    }
+
+    diagnostics
+}
+
+/// The result of tracking the range of cells the pointer can reach.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bounds {
+    /// The pointer provably stays within these cells
+    Within { lo: isize, hi: isize },
+    /// The pointer may leave the tape at this point
+    MayEscape(Span),
+}
+
+/// Computes the range of cells the pointer can reach. Loops whose body moves
+/// the pointer by a non-zero amount could run any number of times, so they
+/// make the range unbounded in that direction, as do procedures since they
+/// can run with the pointer anywhere.
+pub fn tape_bounds(tokens: &[(Token, Span)], tape_size: usize) -> Bounds {
+    let tape = 0..tape_size as isize;
+    let (mut lo, mut hi) = (0isize, 0isize);
+    let mut reached = (0isize, 0isize);
+    let mut loops = vec![];
+
+    for &(token, span) in tokens {
+        match token {
+            Token::PtrAdd(n) => {
+                lo += n as isize;
+                hi += n as isize;
+            }
+            Token::PtrSub(n) => {
+                lo -= n as isize;
+                hi -= n as isize;
+            }
+            Token::LoopStart(_) => loops.push((lo, hi)),
+            Token::LoopEnd(_) => {
+                let (start_lo, start_hi) = loops.pop().unwrap();
+                if (lo, hi) != (start_lo, start_hi) {
+                    return Bounds::MayEscape(span);
+                }
+            }
+            Token::MulAdd { offset, .. } | Token::AddAt { offset, .. } => {
+                let offset = offset as isize;
+                if !tape.contains(&(lo + offset)) || !tape.contains(&(hi + offset)) {
+                    return Bounds::MayEscape(span);
+                }
+                reached = (reached.0.min(lo + offset), reached.1.max(hi + offset));
+                continue;
+            }
+            Token::ProcStart(_)
+            | Token::ProcEnd(_)
+            | Token::Call
+            | Token::SwitchTape
+            | Token::ScanRight { .. }
+            | Token::ScanLeft { .. } => {
+                return Bounds::MayEscape(span);
+            }
+            _ => continue,
+        }
+
+        if !tape.contains(&lo) || !tape.contains(&hi) {
+            return Bounds::MayEscape(span);
+        }
+        reached = (reached.0.min(lo), reached.1.max(hi));
+    }
+
+    Bounds::Within {
+        lo: reached.0,
+        hi: reached.1,
+    }
+}
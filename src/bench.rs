@@ -0,0 +1,46 @@
+use serde::Serialize;
+use std::time::Duration;
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub name: String,
+    pub runs: usize,
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+    pub stddev_ms: f64,
+}
+
+impl Stats {
+    pub fn new(name: String, samples: &[Duration]) -> Self {
+        let ms: Vec<f64> = samples.iter().map(|d| d.as_secs_f64() * 1000.0).collect();
+        let mean = ms.iter().sum::<f64>() / ms.len() as f64;
+        let variance = ms.iter().map(|m| (m - mean).powi(2)).sum::<f64>() / ms.len() as f64;
+
+        Self {
+            name,
+            runs: ms.len(),
+            min_ms: ms.iter().copied().fold(f64::INFINITY, f64::min),
+            mean_ms: mean,
+            max_ms: ms.iter().copied().fold(0.0, f64::max),
+            stddev_ms: variance.sqrt(),
+        }
+    }
+}
+
+pub fn print_table(stats: &[Stats]) {
+    println!(
+        "{:<16} {:>6} {:>12} {:>12} {:>12} {:>12}",
+        "build", "runs", "min ms", "mean ms", "max ms", "stddev ms"
+    );
+    for s in stats {
+        println!(
+            "{:<16} {:>6} {:>12.3} {:>12.3} {:>12.3} {:>12.3}",
+            s.name, s.runs, s.min_ms, s.mean_ms, s.max_ms, s.stddev_ms
+        );
+    }
+}
+
+pub fn print_json(stats: &[Stats]) {
+    println!("{}", serde_json::to_string_pretty(stats).unwrap());
+}
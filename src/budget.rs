@@ -0,0 +1,34 @@
+use std::time::{Duration, Instant};
+
+/// Limits on how much work the optimiser may do. Passes that run out stop
+/// where they are and keep the program they have so far, which is always
+/// correct, just less optimised.
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    /// Most times a pass that repeats until nothing changes may run
+    pub max_passes: usize,
+    deadline: Option<Instant>,
+}
+
+impl Budget {
+    /// A budget of `max_passes` repeats of each pass, and of `max_time` from
+    /// now for all of them together. Without the `native` feature there's no
+    /// clock to check, so `max_time` is ignored.
+    pub fn new(max_passes: Option<usize>, max_time: Option<Duration>) -> Budget {
+        Budget {
+            max_passes: max_passes.unwrap_or(usize::MAX),
+            deadline: max_time
+                .filter(|_| cfg!(feature = "native"))
+                .map(|t| Instant::now() + t),
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    /// Whether pass number `pass`, counting from zero, may run.
+    pub fn allows(&self, pass: usize) -> bool {
+        pass < self.max_passes && !self.expired()
+    }
+}
@@ -0,0 +1,328 @@
+//! Compiles brainfuck programs from another crate's build script. Each
+//! program becomes a function named after its file, which runs the program
+//! and returns, assembled into an object in the given directory (usually
+//! `OUT_DIR`). The objects are archived into a static library and the cargo
+//! directives to link it, and to rebuild when a program changes, are printed.
+//!
+//! The crate then declares the functions in an `extern "C"` block, so a
+//! `programs/hello.bf` is called as `hello()`. There's no C backend, so
+//! only objects can be made.
+use crate::{
+    budget::Budget,
+    codegen::{self, Codegen},
+    diagnostics::Diagnostic,
+    dialect::Dialect,
+    lex::{self, Token, Tokens},
+    passes, peephole,
+    profile::{self, Profile, Variables},
+    timings::Timings,
+    toolchain::Toolchain,
+};
+use std::{
+    borrow::Cow,
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The profile to build with, rather than the one that builds for
+    /// cargo's `TARGET`
+    pub profile: Option<String>,
+    /// Optimise the programs, as `-O1` does
+    pub optimise: bool,
+    /// The static library to archive the objects into and link, as
+    /// `lib<name>.a`. Without one the objects are left for the caller.
+    pub library: Option<String>,
+    /// The archiver that makes the library
+    pub archiver: String,
+    /// The assembler, rather than the profile's
+    pub nasm_path: Option<String>,
+    /// Keep each program's assembly next to its object
+    pub save_temps: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            profile: None,
+            optimise: true,
+            library: Some("bfprograms".to_string()),
+            archiver: "ar".to_string(),
+            nasm_path: None,
+            save_temps: false,
+        }
+    }
+}
+
+/// A program compiled by [`compile`].
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub source: PathBuf,
+    /// The function that runs the program, as the crate declares it
+    pub name: String,
+    pub object: PathBuf,
+}
+
+/// Compiles the programs matching `pattern` into objects in `out_dir`, and
+/// prints the cargo directives to link them. `*` and `?` in the file name
+/// part of `pattern` match any run of characters and any one character.
+pub fn compile(
+    pattern: &str,
+    out_dir: impl AsRef<Path>,
+    options: &Options,
+) -> Result<Vec<Program>, Diagnostic> {
+    let out_dir = out_dir.as_ref();
+    fs::create_dir_all(out_dir).map_err(|e| {
+        Diagnostic::error(
+            "E0005",
+            format!("could not create {}: {e}", out_dir.display()),
+        )
+    })?;
+
+    let (dir, sources) = expand(pattern)?;
+    // Watching the directory picks up programs added to it later
+    println!("cargo:rerun-if-changed={}", dir.display());
+
+    let profile = select_profile(options)?;
+    // The profile's own entry shows whether symbols take a leading underscore
+    let vars = profile
+        .variables(&Variables::new())
+        .map_err(|e| Diagnostic::error("E0003", e))?;
+    let prefix = if vars["entry"].starts_with('_') {
+        "_"
+    } else {
+        ""
+    };
+
+    let mut programs = vec![];
+    for source in sources {
+        println!("cargo:rerun-if-changed={}", source.display());
+        let name = function_name(&source)?;
+        let object = out_dir.join(&name).with_extension("o");
+        build_program(
+            &profile,
+            &source,
+            &format!("{prefix}{name}"),
+            &object,
+            options,
+        )?;
+        programs.push(Program {
+            source,
+            name,
+            object,
+        });
+    }
+
+    if let Some(library) = &options.library {
+        let objects: Vec<PathBuf> = programs.iter().map(|p| p.object.clone()).collect();
+        let path = out_dir.join(format!("lib{library}.a"));
+        Toolchain::archive(&options.archiver, &objects, &path)?;
+        println!("cargo:rustc-link-search=native={}", out_dir.display());
+        println!("cargo:rustc-link-lib=static={library}");
+    }
+
+    Ok(programs)
+}
+
+/// The directory `pattern` looks in, and the files in it that match, in
+/// order.
+fn expand(pattern: &str) -> Result<(PathBuf, Vec<PathBuf>), Diagnostic> {
+    let path = Path::new(pattern);
+    let file_pattern = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let entries = fs::read_dir(&dir).map_err(|e| {
+        Diagnostic::error("E0005", format!("could not read {}: {e}", dir.display()))
+    })?;
+
+    let mut sources = vec![];
+    for entry in entries {
+        let entry = entry.map_err(|e| {
+            Diagnostic::error("E0005", format!("could not read {}: {e}", dir.display()))
+        })?;
+        let name = entry.file_name();
+        if entry.path().is_file() && matches(&file_pattern, &name.to_string_lossy()) {
+            sources.push(entry.path());
+        }
+    }
+    if sources.is_empty() {
+        return Err(Diagnostic::error(
+            "E0042",
+            format!("no programs match {pattern}"),
+        ));
+    }
+    sources.sort();
+
+    Ok((dir, sources))
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters
+/// and `?` any one character.
+fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where to carry on from if the last `*` should have matched more
+    let mut backtrack = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    backtrack = Some((star, start + 1));
+                    p = star + 1;
+                    n = start + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The function `source` is called as: its file name without the extension,
+/// with `-` and `.` as `_`.
+fn function_name(source: &Path) -> Result<String, Diagnostic> {
+    let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+    let name = stem.replace(['-', '.'], "_");
+    if name.is_empty()
+        || name.starts_with(|c: char| c.is_ascii_digit())
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(Diagnostic::error(
+            "E0026",
+            format!("`{stem}` can't be the name of a function"),
+        ));
+    }
+    Ok(name)
+}
+
+/// The profile given in `options`, or the one for cargo's `TARGET`, which
+/// returns rather than exits.
+fn select_profile(options: &Options) -> Result<Profile, Diagnostic> {
+    let target = env::var("TARGET").ok();
+    let mut profile = match (&options.profile, &target) {
+        (Some(name), _) => {
+            Cow::Borrowed(Profile::select(name).map_err(|e| Diagnostic::error("E0003", e))?)
+        }
+        (None, Some(target)) => Cow::Borrowed(Profile::get_by_target(target).ok_or_else(|| {
+            Diagnostic::error("E0003", format!("no profile builds for target {target}"))
+        })?),
+        (None, None) => {
+            Cow::Borrowed(Profile::get_default().map_err(|e| Diagnostic::error("E0003", e))?)
+        }
+    };
+    if target.as_deref().is_some_and(profile::is_cross) && profile.supports_cross() {
+        profile = Cow::Owned(profile.cross().map_err(|e| {
+            Diagnostic::error(
+                "E0003",
+                format!("invalid cross section in profile {}: {e}", profile.name()),
+            )
+        })?);
+    }
+
+    if !profile.supports_no_exit() {
+        return Err(Diagnostic::error(
+            "E0022",
+            format!("profile {} cannot return rather than exit", profile.name()),
+        ));
+    }
+    profile.no_exit().map_err(|e| {
+        Diagnostic::error(
+            "E0022",
+            format!("invalid no_exit section in profile {}: {e}", profile.name()),
+        )
+    })
+}
+
+/// Compiles `source` into `object`, as a function called `symbol`.
+fn build_program(
+    profile: &Profile,
+    source: &Path,
+    symbol: &str,
+    object: &Path,
+    options: &Options,
+) -> Result<(), Diagnostic> {
+    let infile = source.display().to_string();
+    let contents = fs::read_to_string(source)
+        .map_err(|e| Diagnostic::error("E0005", format!("could not read {infile}: {e}")))?;
+    let tokens = lex_program(&infile, &contents, options.optimise)?;
+
+    let procedures = tokens
+        .iter()
+        .any(|(tok, _)| matches!(tok, Token::ProcStart(_) | Token::Call));
+    if procedures && !profile.supports_procedures() {
+        return Err(Diagnostic::error(
+            "E0011",
+            format!(
+                "profile {} does not support pbrain procedures",
+                profile.name()
+            ),
+        ));
+    }
+
+    let mut vars = Variables::new();
+    vars.insert("file".to_string(), infile.clone());
+    vars.insert("entry".to_string(), symbol.to_string());
+    let vars = profile
+        .variables(&vars)
+        .map_err(|e| Diagnostic::error("E0003", e))?;
+    let profile = &profile.with_variables(&vars);
+    let codegen_options = codegen::Options {
+        coalesce_changes: options.optimise,
+        ..Default::default()
+    };
+    let codegen = Codegen::new(profile, tokens, &vars, &codegen_options);
+
+    let mut toolchain = profile.toolchain();
+    if let Some(nasm) = &options.nasm_path {
+        toolchain.nasm = nasm.clone();
+    }
+    toolchain.save_temps = options.save_temps;
+    let write = |path: &Path, _: &mut Timings| {
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        for i in 0..codegen.chunk_count() {
+            let chunk = codegen.chunk(i);
+            let chunk = if options.optimise {
+                peephole::optimise(chunk, &Budget::new(None, None))
+            } else {
+                chunk
+            };
+            for line in chunk {
+                writeln!(out, "{line}")?;
+            }
+        }
+        out.flush()
+    };
+    toolchain.generate_obj(write, object, &mut Timings::default())?;
+
+    Ok(())
+}
+
+/// Lexes `contents`, the source of `infile`, in the dialect its extension
+/// names, and optimises it if asked.
+fn lex_program(infile: &str, contents: &str, optimise: bool) -> Result<Tokens, Diagnostic> {
+    // Spans name their file, so that diagnostics can say which program
+    // they're about
+    let file: &'static String = Box::leak(Box::new(infile.to_string()));
+    let source = lex::spanned_chars(contents, Some(file));
+    let tokens = lex::lex(Dialect::from_path(infile).commands(source)?).tokens()?;
+    if !optimise {
+        return Ok(tokens);
+    }
+    Ok(passes::optimise_locally(tokens, &mut Timings::default()))
+}
@@ -0,0 +1,324 @@
+//! A compact binary form of an optimised program, for running with `bfc vm`
+//! where there's no assembler or the program should ship pre-optimised.
+//!
+//! A file starts with the magic bytes `BFB`, the format version, the tape
+//! size and the cell width in bits, then the names of the files the program
+//! was read from and the tokens. Each token is an opcode byte followed by its
+//! operands, then its position in the source. Numbers are LEB128, with signed
+//! ones zigzag-encoded first, and lines are stored as the difference from the
+//! previous token's, so most tokens take four or five bytes.
+use crate::lex::{Span, Token, Tokens};
+use std::fmt;
+
+const MAGIC: &[u8; 3] = b"BFB";
+
+/// The version of the format this writes and reads. Files of another version
+/// are rejected rather than guessed at. Version 2 gives each loop bracket the
+/// distance to its match rather than a loop number.
+pub const VERSION: u8 = 2;
+
+/// A program along with the machine it was optimised for.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub tape_size: usize,
+    pub cell_bits: u32,
+    pub tokens: Tokens,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NotBytecode,
+    Version(u8),
+    Truncated,
+    /// An opcode no version of the format has
+    Opcode(u8),
+    /// Unmatched loop or procedure brackets, or a number out of range
+    Malformed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotBytecode => write!(f, "not a bytecode file"),
+            Error::Version(version) => write!(
+                f,
+                "bytecode version {version} is not supported, only version {VERSION}"
+            ),
+            Error::Truncated => write!(f, "bytecode file ends early"),
+            Error::Opcode(op) => write!(f, "unknown opcode {op:#04x}"),
+            Error::Malformed => write!(f, "malformed program"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Writes `program` out as bytecode.
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    write_uint(&mut out, program.tape_size as u64);
+    write_uint(&mut out, program.cell_bits.into());
+
+    // Index 0 is the program's own file, which spans don't name
+    let mut files: Vec<&'static String> = vec![];
+    for (_, span) in &program.tokens {
+        if let Some(file) = span.file {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    write_uint(&mut out, files.len() as u64);
+    for file in &files {
+        write_uint(&mut out, file.len() as u64);
+        out.extend(file.as_bytes());
+    }
+
+    write_uint(&mut out, program.tokens.len() as u64);
+    let mut line = 0;
+    for &(token, span) in &program.tokens {
+        let (op, operands) = opcode(token);
+        out.push(op);
+        for operand in operands {
+            write_uint(&mut out, operand);
+        }
+        write_int(&mut out, i64::from(span.line) - i64::from(line));
+        write_uint(&mut out, span.col.into());
+        let file = span
+            .file
+            .map_or(0, |file| files.iter().position(|&f| f == file).unwrap() + 1);
+        write_uint(&mut out, file as u64);
+        line = span.line;
+    }
+    out
+}
+
+/// Reads a program written by `encode`.
+pub fn decode(bytes: &[u8]) -> Result<Program, Error> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(Error::NotBytecode);
+    }
+    let mut reader = Reader {
+        bytes,
+        pos: MAGIC.len(),
+    };
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(Error::Version(version));
+    }
+    let tape_size = reader.uint()?.try_into().map_err(|_| Error::Malformed)?;
+    let cell_bits = reader.uint()?.try_into().map_err(|_| Error::Malformed)?;
+    if ![8, 16, 32, 64].contains(&cell_bits) {
+        return Err(Error::Malformed);
+    }
+
+    let mut files = vec![];
+    for _ in 0..reader.uint()? {
+        let len = reader.uint()? as usize;
+        let name = reader.take(len)?;
+        let name = String::from_utf8(name.to_vec()).map_err(|_| Error::Malformed)?;
+        // Spans hold their file for the life of the program, as with
+        // `@include`d files
+        let file: &'static String = Box::leak(Box::new(name));
+        files.push(file);
+    }
+
+    let count = reader.uint()?;
+    // Every token takes at least four bytes, so a huge count in a small file
+    // is found out before it's allocated for
+    let mut tokens = Vec::with_capacity(count.min(bytes.len() as u64 / 4) as usize);
+    let mut line: u32 = 0;
+    for _ in 0..count {
+        let token = reader.token()?;
+        let delta = reader.int()?;
+        line = (i64::from(line) + delta)
+            .try_into()
+            .map_err(|_| Error::Malformed)?;
+        let col = reader.uint()?.try_into().map_err(|_| Error::Malformed)?;
+        let file = match reader.uint()? {
+            0 => None,
+            i => Some(*files.get(i as usize - 1).ok_or(Error::Malformed)?),
+        };
+        tokens.push((token, Span { line, col, file }));
+    }
+    if reader.pos != bytes.len() || !balanced(&tokens) {
+        return Err(Error::Malformed);
+    }
+
+    Ok(Program {
+        tape_size,
+        cell_bits,
+        tokens,
+    })
+}
+
+/// The opcode of `token` and its operands.
+fn opcode(token: Token) -> (u8, Vec<u64>) {
+    let zigzag = |n: i16| zigzag(n.into());
+    match token {
+        Token::PtrAdd(n) => (0x00, vec![n.into()]),
+        Token::PtrSub(n) => (0x01, vec![n.into()]),
+        Token::Add(n) => (0x02, vec![n.into()]),
+        Token::Sub(n) => (0x03, vec![n.into()]),
+        Token::LoopStart(distance) => (0x04, vec![distance.into()]),
+        Token::LoopEnd(distance) => (0x05, vec![distance.into()]),
+        Token::PutChar => (0x06, vec![]),
+        Token::GetChar => (0x07, vec![]),
+        Token::ProcStart(id) => (0x08, vec![id.into()]),
+        Token::ProcEnd(id) => (0x09, vec![id.into()]),
+        Token::Call => (0x0a, vec![]),
+        Token::Extern(id) => (0x0b, vec![id.into()]),
+        Token::Debug => (0x0c, vec![]),
+        Token::SwitchTape => (0x0d, vec![]),
+        Token::ExchangeCell => (0x0e, vec![]),
+        Token::SetZero => (0x0f, vec![]),
+        Token::MulAdd { offset, factor } => (0x10, vec![zigzag(offset), zigzag(factor)]),
+        Token::ScanRight { stride, id } => (0x11, vec![stride.into(), id.into()]),
+        Token::ScanLeft { stride, id } => (0x12, vec![stride.into(), id.into()]),
+        Token::AddAt { offset, amount } => (0x13, vec![zigzag(offset), zigzag(amount)]),
+    }
+}
+
+/// Whether every loop and procedure in `tokens` is closed, in order, by the
+/// end that matches it, as the interpreter relies on. Loop brackets must
+/// also point at each other.
+fn balanced(tokens: &Tokens) -> bool {
+    let mut open = vec![];
+    for (i, &(token, _)) in tokens.iter().enumerate() {
+        match token {
+            Token::LoopStart(_) => open.push((true, i as u32)),
+            Token::ProcStart(id) => open.push((false, id)),
+            Token::LoopEnd(distance) => match open.pop() {
+                Some((true, start))
+                    if start + distance == i as u32
+                        && tokens[start as usize].0 == Token::LoopStart(distance) => {}
+                _ => return false,
+            },
+            Token::ProcEnd(id) if open.pop() != Some((false, id)) => return false,
+            _ => {}
+        }
+    }
+    open.is_empty()
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    (n >> 1) as i64 ^ -((n & 1) as i64)
+}
+
+pub(crate) fn write_uint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn write_int(out: &mut Vec<u8>, n: i64) {
+    write_uint(out, zigzag(n));
+}
+
+/// Reads the numbers and bytes files in this format are made of.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Reads `bytes` from `pos` on.
+    pub(crate) fn new(bytes: &'a [u8], pos: usize) -> Self {
+        Self { bytes, pos }
+    }
+
+    /// Whether every byte has been read.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    pub(crate) fn byte(&mut self) -> Result<u8, Error> {
+        let byte = *self.bytes.get(self.pos).ok_or(Error::Truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&[u8], Error> {
+        let end = self.pos.checked_add(len).ok_or(Error::Truncated)?;
+        let bytes = self.bytes.get(self.pos..end).ok_or(Error::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub(crate) fn uint(&mut self) -> Result<u64, Error> {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                return Ok(n);
+            }
+        }
+        Err(Error::Malformed)
+    }
+
+    fn int(&mut self) -> Result<i64, Error> {
+        Ok(unzigzag(self.uint()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, Error> {
+        self.uint()?.try_into().map_err(|_| Error::Malformed)
+    }
+
+    pub(crate) fn usize(&mut self) -> Result<usize, Error> {
+        self.uint()?.try_into().map_err(|_| Error::Malformed)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        self.uint()?.try_into().map_err(|_| Error::Malformed)
+    }
+
+    fn i16(&mut self) -> Result<i16, Error> {
+        self.int()?.try_into().map_err(|_| Error::Malformed)
+    }
+
+    fn token(&mut self) -> Result<Token, Error> {
+        Ok(match self.byte()? {
+            0x00 => Token::PtrAdd(self.u32()?),
+            0x01 => Token::PtrSub(self.u32()?),
+            0x02 => Token::Add(self.u32()?),
+            0x03 => Token::Sub(self.u32()?),
+            0x04 => Token::LoopStart(self.u32()?),
+            0x05 => Token::LoopEnd(self.u32()?),
+            0x06 => Token::PutChar,
+            0x07 => Token::GetChar,
+            0x08 => Token::ProcStart(self.u32()?),
+            0x09 => Token::ProcEnd(self.u32()?),
+            0x0a => Token::Call,
+            0x0b => Token::Extern(self.u32()?),
+            0x0c => Token::Debug,
+            0x0d => Token::SwitchTape,
+            0x0e => Token::ExchangeCell,
+            0x0f => Token::SetZero,
+            0x10 => Token::MulAdd {
+                offset: self.i16()?,
+                factor: self.i16()?,
+            },
+            0x11 => Token::ScanRight {
+                stride: self.u16()?,
+                id: self.u32()?,
+            },
+            0x12 => Token::ScanLeft {
+                stride: self.u16()?,
+                id: self.u32()?,
+            },
+            0x13 => Token::AddAt {
+                offset: self.i16()?,
+                amount: self.i16()?,
+            },
+            op => return Err(Error::Opcode(op)),
+        })
+    }
+}
@@ -0,0 +1,146 @@
+//! The objects and binaries the assembler and linker made, kept under the
+//! cache dir in `cache/<key>/`, where the key is a digest of everything that
+//! went into making them. Building an unchanged program again copies them
+//! rather than running the tools.
+use crate::{
+    digest,
+    toolchain::{self, Toolchain},
+};
+use serde_json::Value;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The name of the file in each entry.
+const ARTIFACT: &str = "artifact";
+
+/// How much is cached.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub entries: usize,
+    pub bytes: u64,
+    /// Scratch dirs left behind by builds that were killed, which were
+    /// removed on the way
+    pub swept: usize,
+}
+
+/// The directory the entries are kept in.
+pub fn dir() -> PathBuf {
+    Toolchain::cache_path().join("cache")
+}
+
+/// The key for the artifact made from `inputs`, which should hold the
+/// contents, or digests of the contents, of every input along with the
+/// commands and arguments that make it.
+pub fn key(inputs: &Value) -> String {
+    digest::sha256_hex(inputs.to_string().as_bytes())
+}
+
+/// The cached artifact with `key`, if there is one.
+pub fn get(key: &str) -> Option<PathBuf> {
+    let path = dir().join(key).join(ARTIFACT);
+    path.is_file().then_some(path)
+}
+
+/// Caches `file` as the artifact with `key`. The entry is put together in a
+/// scratch dir and renamed into place, so that other runs never see half an
+/// entry, and if another run cached the same artifact first, its entry is
+/// kept.
+pub fn put(key: &str, file: &Path) -> Result<(), io::Error> {
+    let entry = dir().join(key);
+    fs::create_dir_all(dir())?;
+    let scratch = toolchain::temp_dir()?;
+    fs::copy(file, scratch.path().join(ARTIFACT))?;
+    match fs::rename(scratch.path(), &entry) {
+        Ok(()) => {
+            // It's the entry now, so it mustn't be removed
+            let _ = scratch.into_path();
+            Ok(())
+        }
+        Err(_) if entry.join(ARTIFACT).is_file() => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The id of the process that made the scratch dir `name`, if it's one.
+/// Scratch dirs are `bfc-<pid>-*`, or `tmp-<pid>-*` from older versions.
+fn scratch_pid(name: &str) -> Option<u32> {
+    let rest = name
+        .strip_prefix("bfc-")
+        .or_else(|| name.strip_prefix("tmp-"))?;
+    rest.split('-').next()?.parse().ok()
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return true;
+    };
+    // SAFETY: signal 0 only checks that the process exists
+    let sent = unsafe { libc::kill(pid, 0) } == 0;
+    sent || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a way to tell, every process might still be running.
+#[cfg(not(unix))]
+fn is_running(_: u32) -> bool {
+    true
+}
+
+/// Removes the scratch dirs of processes that have gone, giving how many
+/// there were.
+fn sweep() -> Result<usize, io::Error> {
+    let entries = match fs::read_dir(Toolchain::cache_path()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut swept = 0;
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(pid) = name.to_str().and_then(scratch_pid) else {
+            continue;
+        };
+        if entry.file_type()?.is_dir() && pid != std::process::id() && !is_running(pid) {
+            fs::remove_dir_all(entry.path())?;
+            swept += 1;
+        }
+    }
+    Ok(swept)
+}
+
+/// Counts the entries and the bytes they take up, first sweeping up scratch
+/// dirs left behind.
+pub fn stats() -> Result<Stats, io::Error> {
+    let mut stats = Stats {
+        swept: sweep()?,
+        ..Stats::default()
+    };
+    let entries = match fs::read_dir(dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(stats),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        stats.entries += 1;
+        for file in fs::read_dir(entry.path())? {
+            stats.bytes += file?.metadata()?.len();
+        }
+    }
+    Ok(stats)
+}
+
+/// Removes every entry, giving how much was removed.
+pub fn clean() -> Result<Stats, io::Error> {
+    let stats = stats()?;
+    match fs::remove_dir_all(dir()) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(stats),
+    }
+}
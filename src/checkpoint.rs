@@ -0,0 +1,187 @@
+//! The state of a program part way through running in the interpreter, saved
+//! so that a long computation can be carried on later with `bfc vm --resume`.
+//!
+//! A file starts with the magic bytes `BFS`, the format version and the
+//! SHA-256 digest of the program it was taken of, then the cell width in bits,
+//! the length of the tape, the tape and its pointer, the other tape if the
+//! program has used it, the program counter, the procedures defined and the
+//! calls being made, and the bytes written so far. Tapes stop at their last
+//! cell that isn't zero. Numbers are LEB128, as in bytecode.
+use crate::bytecode::{self, Reader};
+use std::fmt;
+
+const MAGIC: &[u8; 3] = b"BFS";
+
+/// The version of the format this writes and reads.
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// The digest of the program the state belongs to
+    pub program: [u8; 32],
+    pub cell_bits: u32,
+    /// The number of cells on each tape
+    pub tape_len: usize,
+    /// The cells up to the last that isn't zero
+    pub tape: Vec<u64>,
+    pub ptr: usize,
+    /// The other tape and its pointer, once the program has used it
+    pub other: Option<(Vec<u64>, usize)>,
+    /// The index of the token that runs next
+    pub pc: usize,
+    /// Each procedure defined, by number, and where it starts
+    pub procedures: Vec<(u8, usize)>,
+    /// Where each procedure running was called from
+    pub calls: Vec<usize>,
+    /// Bytes written so far, counted towards `max_output`
+    pub written: u64,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NotCheckpoint,
+    Version(u8),
+    Truncated,
+    Malformed,
+    /// The checkpoint was taken of another program
+    Program,
+    /// The checkpoint was taken on a tape of this many cells of this many
+    /// bits, unlike the one it's being restored to
+    Tape {
+        len: usize,
+        bits: u32,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotCheckpoint => write!(f, "not a checkpoint file"),
+            Error::Version(version) => write!(
+                f,
+                "checkpoint version {version} is not supported, only version {VERSION}"
+            ),
+            Error::Truncated => write!(f, "checkpoint file ends early"),
+            Error::Malformed => write!(f, "malformed checkpoint"),
+            Error::Program => write!(f, "checkpoint is of a different program"),
+            Error::Tape { len, bits } => {
+                write!(f, "checkpoint is of a tape of {len} {bits}-bit cells")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<bytecode::Error> for Error {
+    fn from(e: bytecode::Error) -> Self {
+        match e {
+            bytecode::Error::Truncated => Error::Truncated,
+            _ => Error::Malformed,
+        }
+    }
+}
+
+/// Writes `checkpoint` out.
+pub fn encode(checkpoint: &Checkpoint) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.extend(checkpoint.program);
+    let uint = |out: &mut Vec<u8>, n: usize| bytecode::write_uint(out, n as u64);
+    uint(&mut out, checkpoint.cell_bits as usize);
+    uint(&mut out, checkpoint.tape_len);
+
+    let tape = |out: &mut Vec<u8>, cells: &[u64], ptr: usize| {
+        uint(out, cells.len());
+        for &cell in cells {
+            bytecode::write_uint(out, cell);
+        }
+        uint(out, ptr);
+    };
+    tape(&mut out, &checkpoint.tape, checkpoint.ptr);
+    match &checkpoint.other {
+        None => out.push(0),
+        Some((cells, ptr)) => {
+            out.push(1);
+            tape(&mut out, cells, *ptr);
+        }
+    }
+
+    uint(&mut out, checkpoint.pc);
+    uint(&mut out, checkpoint.procedures.len());
+    for &(number, start) in &checkpoint.procedures {
+        out.push(number);
+        uint(&mut out, start);
+    }
+    uint(&mut out, checkpoint.calls.len());
+    for &call in &checkpoint.calls {
+        uint(&mut out, call);
+    }
+    bytecode::write_uint(&mut out, checkpoint.written);
+    out
+}
+
+/// Reads a checkpoint written by `encode`. Whether it fits the program it's
+/// restored to is checked when it's restored.
+pub fn decode(bytes: &[u8]) -> Result<Checkpoint, Error> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(Error::NotCheckpoint);
+    }
+    let mut reader = Reader::new(bytes, MAGIC.len());
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(Error::Version(version));
+    }
+    let program = reader.take(32)?.try_into().unwrap();
+    let cell_bits = reader.u32()?;
+    let tape_len = reader.usize()?;
+
+    // A tape is no longer than the file, whatever its length says, so a
+    // huge length in a small file is found out before it's allocated for
+    let tape = |reader: &mut Reader| -> Result<(Vec<u64>, usize), Error> {
+        let len = reader.usize()?;
+        if len > tape_len || len > bytes.len() {
+            return Err(Error::Malformed);
+        }
+        let cells = (0..len).map(|_| reader.uint()).collect::<Result<_, _>>()?;
+        Ok((cells, reader.usize()?))
+    };
+    let (tape_cells, ptr) = tape(&mut reader)?;
+    let other = match reader.byte()? {
+        0 => None,
+        1 => Some(tape(&mut reader)?),
+        _ => return Err(Error::Malformed),
+    };
+
+    let pc = reader.usize()?;
+    let count = reader.uint()?;
+    if count > 256 {
+        return Err(Error::Malformed);
+    }
+    let mut procedures = vec![];
+    for _ in 0..count {
+        procedures.push((reader.byte()?, reader.usize()?));
+    }
+    let count = reader.uint()?;
+    let mut calls = Vec::with_capacity(count.min(bytes.len() as u64) as usize);
+    for _ in 0..count {
+        calls.push(reader.usize()?);
+    }
+    let written = reader.uint()?;
+    if !reader.is_empty() {
+        return Err(Error::Malformed);
+    }
+
+    Ok(Checkpoint {
+        program,
+        cell_bits,
+        tape_len,
+        tape: tape_cells,
+        ptr,
+        other,
+        pc,
+        procedures,
+        calls,
+        written,
+    })
+}
@@ -0,0 +1,817 @@
+use crate::{
+    analysis::Lint,
+    coverage,
+    diagnostics::{ColorChoice, MessageFormat},
+    dialect::Dialect,
+    heatmap,
+    interpret::{Eof, TraceKind},
+    logging::LogFormat,
+    passes,
+    profile::AsmSyntax,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::time::Duration;
+
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about=None, subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+
+    /// Filenames of the brainfuck programs, each built on its own unless
+    /// --multicall is given
+    #[arg(required = true)]
+    pub infiles: Vec<String>,
+
+    #[command(flatten)]
+    pub build: BuildArgs,
+
+    /// Run the program in the interpreter in a terminal view of the tape,
+    /// rather than compiling it. Piped input is fed to the program, otherwise
+    /// it waits for input to be typed.
+    #[arg(long = "visualize")]
+    pub visualize: bool,
+
+    /// Run the program in the interpreter on stdin, rather than compiling
+    /// it, and write how often each loop ran to FILE for --pgo-use
+    #[arg(long = "pgo-gen", value_name = "FILE", conflicts_with = "visualize")]
+    pub pgo_gen: Option<String>,
+
+    /// Run the program in the interpreter on stdin, rather than compiling
+    /// it, and write which of its instructions ran to FILE
+    #[arg(
+        long = "coverage",
+        value_name = "FILE",
+        conflicts_with_all = ["visualize", "pgo_gen"]
+    )]
+    pub coverage: Option<String>,
+
+    /// How --coverage writes its report
+    #[arg(long = "coverage-format", value_enum, default_value_t = coverage::Format::Lcov)]
+    pub coverage_format: coverage::Format,
+
+    /// Build all the programs into one executable, which runs the one named
+    /// by what it's called or, failing that, its first argument. Each program
+    /// is named after its file, without the extension.
+    #[arg(long = "multicall", conflicts_with_all = ["visualize", "pgo_gen", "coverage", "instrument_coverage", "pie", "input_source", "output_data", "source_map", "no_exit", "bake_input"])]
+    pub multicall: bool,
+
+    /// Format of log messages, which RUST_LOG turns on
+    #[arg(long = "log-format", global = true, value_enum, default_value_t = LogFormat::Pretty)]
+    pub log_format: LogFormat,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct BuildArgs {
+    /// Name of the output file
+    #[arg(short = 'o', long = "out")]
+    pub outfile: Option<String>,
+
+    /// Directory to write the output to, made if it doesn't exist
+    #[arg(long = "out-dir")]
+    pub out_dir: Option<String>,
+
+    /// Output an assembly file, same as --emit asm
+    #[arg(short = 'a', long = "asm")]
+    pub output_assembly: bool,
+
+    /// Output an object file without linking it, same as --emit obj
+    #[arg(short = 'c', conflicts_with = "output_assembly")]
+    pub output_object: bool,
+
+    /// Kind of output to produce
+    #[arg(long = "emit", value_enum, default_value_t = Emit::Bin)]
+    pub emit: Emit,
+
+    /// Language of the source file, guessed from its extension by default
+    #[arg(long = "dialect", value_enum)]
+    pub dialect: Option<Dialect>,
+
+    /// JSON file mapping each command to the string that stands for it
+    #[arg(long = "dialect-map", conflicts_with = "dialect")]
+    pub dialect_map: Option<String>,
+
+    /// Language extensions to enable, separated by commas
+    #[arg(long = "extensions", value_enum, value_delimiter = ',')]
+    pub extensions: Vec<Extension>,
+
+    /// Treat `#` as a command that writes the pointer and the cells around
+    /// it to stderr, in the interpreter and in compiled programs
+    #[arg(long = "debug-hash")]
+    pub debug_hash: bool,
+
+    /// Optimisation level
+    #[arg(short = 'O', long = "opt-level", value_enum, default_value_t = OptLevel::O1)]
+    pub opt_level: OptLevel,
+
+    /// Optimisation passes to run, in order, instead of those the
+    /// optimisation level picks
+    #[arg(long = "passes", value_name = "PASS", value_delimiter = ',', value_parser = passes::value_parser())]
+    pub passes: Option<Vec<String>>,
+
+    /// Don't run this optimisation pass
+    #[arg(long = "disable-pass", value_name = "PASS", value_parser = passes::value_parser())]
+    pub disable_pass: Vec<String>,
+
+    /// Print the program to stderr after this optimisation pass, or after
+    /// every one with `all`
+    #[arg(long = "dump-after", value_name = "PASS")]
+    pub dump_after: Vec<String>,
+
+    /// Most instructions a loop may unroll into at -O2
+    #[arg(long = "unroll-limit", default_value_t = 64)]
+    pub unroll_limit: usize,
+
+    /// Most times to repeat an optimisation that runs until nothing changes
+    #[arg(long = "max-passes")]
+    pub max_passes: Option<usize>,
+
+    /// Most milliseconds to spend in each optimisation stage, after which the
+    /// program optimised so far is used
+    #[arg(long = "max-time", value_name = "MS")]
+    pub max_time: Option<u64>,
+
+    /// Keep the intermediate assembly and object files next to the output
+    #[arg(long = "save-temps")]
+    pub save_temps: bool,
+
+    /// Emit debug info mapping the binary back to the brainfuck source
+    #[arg(short = 'g')]
+    pub debug: bool,
+
+    /// Also write a source map next to the output, with a .map.json
+    /// extension, relating each instruction to the assembly and machine code
+    /// generated for it
+    #[arg(long = "source-map")]
+    pub source_map: bool,
+
+    /// Stop with an error if the pointer leaves the tape
+    #[arg(long = "checked")]
+    pub checked: bool,
+
+    /// Stop with an error if the pointer runs into the inaccessible pages
+    /// put either side of the tape, which costs nothing per instruction but
+    /// only catches the pointer leaving the tape by up to a page
+    #[arg(long = "guard-pages", conflicts_with = "checked")]
+    pub guard_pages: bool,
+
+    /// Catch the program crashing, as it may when the pointer runs off the
+    /// tape, and report the line it crashed near rather than a bare
+    /// segmentation fault
+    #[arg(long = "fault-handler")]
+    pub fault_handler: bool,
+
+    /// Give `,` each key as it's pressed, without waiting for Enter or
+    /// echoing it, when input is a terminal. Compiled programs put the
+    /// terminal back as they exit.
+    #[arg(long = "raw-tty")]
+    pub raw_tty: bool,
+
+    /// Build a program that counts how often each instruction runs, writing
+    /// the counts next to it as it ends for `bfc coverage` to report on
+    #[arg(long = "instrument-coverage")]
+    pub instrument_coverage: bool,
+
+    /// Build a position-independent executable
+    #[arg(long = "pie")]
+    pub pie: bool,
+
+    /// Drop the symbol table from the binary
+    #[arg(long = "strip", conflicts_with = "debug")]
+    pub strip: bool,
+
+    /// Make the binary as small as possible, stripping it and dropping
+    /// unneeded headers and alignment padding
+    #[arg(long = "small", conflicts_with = "debug")]
+    pub small: bool,
+
+    /// Where the compiled program's `,` reads its input from
+    #[arg(long = "input-source", value_enum, default_value_t = InputSource::Stdin)]
+    pub input_source: InputSource,
+
+    /// Where the compiled program's `.` writes its output to
+    #[arg(long = "output-data", value_enum, default_value_t = OutputData::Stdout)]
+    pub output_data: OutputData,
+
+    /// The file written to with --output-data file, created or emptied when
+    /// the program starts
+    #[arg(
+        long = "output-file",
+        value_name = "PATH",
+        required_if_eq("output_data", "file")
+    )]
+    pub output_file: Option<String>,
+
+    /// Size of the buffer written to with --output-data buffer, beyond which
+    /// output is dropped
+    #[arg(long = "output-buffer-size", value_name = "BYTES")]
+    pub output_buffer_size: Option<usize>,
+
+    /// Exit with the value of the current cell when the program ends,
+    /// rather than 0
+    #[arg(long = "exit-cell")]
+    pub exit_cell: bool,
+
+    /// Produce byte-identical output for identical input
+    #[arg(long = "reproducible")]
+    pub reproducible: bool,
+
+    /// Format of errors and warnings
+    #[arg(long = "message-format", value_enum, default_value_t = MessageFormat::Human)]
+    pub message_format: MessageFormat,
+
+    /// When to colour errors and warnings
+    #[arg(long = "color", value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Silence a warning
+    #[arg(short = 'A', long = "allow", value_enum)]
+    pub allow: Vec<Lint>,
+
+    /// Report a warning, overriding --allow
+    #[arg(short = 'W', long = "warn", value_enum)]
+    pub warn: Vec<Lint>,
+
+    /// Turn a warning into an error, overriding --allow and --warn
+    #[arg(short = 'D', long = "deny", value_enum)]
+    pub deny: Vec<Lint>,
+
+    /// Print how long each compilation phase took
+    #[arg(long = "timings")]
+    pub timings: bool,
+
+    /// Print how many instructions of each kind there were before and after
+    /// optimisation, how deeply loops nest, how much of the tape is used, and
+    /// how big the assembly and binary came out
+    #[arg(long = "stats")]
+    pub stats: bool,
+
+    /// Write a JSON record of the build to this file: the inputs and outputs
+    /// with their SHA-256 digests, the profile, command line, optimisation
+    /// passes, tool versions and timings
+    #[arg(long = "build-report", value_name = "FILE")]
+    pub build_report: Option<String>,
+
+    /// Select which profile to assemble with
+    #[arg(short = 'p', long = "profile")]
+    pub profile: Option<String>,
+
+    /// Build for a target triple, picking the profile for it and its cross
+    /// toolchain when the host is a different kind of machine
+    #[arg(long = "target", value_name = "TRIPLE", conflicts_with = "profile")]
+    pub target: Option<String>,
+
+    /// Assemble with the profile in this file, which needn't be in the config
+    /// directory
+    #[arg(
+        long = "profile-path",
+        value_name = "FILE",
+        conflicts_with_all = ["profile", "target"]
+    )]
+    pub profile_path: Option<String>,
+
+    /// Emit assembly in this syntax, using the profile's templates for it
+    #[arg(long = "syntax", value_enum)]
+    pub syntax: Option<AsmSyntax>,
+
+    /// Number of cells on the tape
+    #[arg(long = "tape-size")]
+    pub tape_size: Option<usize>,
+
+    /// Size of each cell in bits
+    #[arg(long = "cell-size", value_enum)]
+    pub cell_size: Option<CellSize>,
+
+    /// Name of the entry symbol
+    #[arg(long = "entry", visible_alias = "entry-name", value_name = "SYM")]
+    pub entry: Option<String>,
+
+    /// Return from the entry symbol rather than exiting, so that a host
+    /// program can call the brainfuck as a function
+    #[arg(long = "no-exit")]
+    pub no_exit: bool,
+
+    /// Loop counts from --pgo-gen, letting hot loops unroll further and
+    /// aligning them, and skipping the loops that never ran when unrolling
+    #[arg(long = "pgo-use", value_name = "FILE")]
+    pub pgo_use: Option<String>,
+
+    /// When interpreting, log each instruction run to stderr with the
+    /// pointer and the value of the current cell
+    #[arg(long = "trace")]
+    pub trace: bool,
+
+    /// Kinds of instruction to trace, separated by commas
+    #[arg(
+        long = "trace-only",
+        value_enum,
+        value_delimiter = ',',
+        requires = "trace"
+    )]
+    pub trace_only: Vec<TraceKind>,
+
+    /// Most instructions to trace
+    #[arg(long = "trace-limit", value_name = "N", requires = "trace")]
+    pub trace_limit: Option<u64>,
+
+    /// Input known when compiling. The program is run on it as far as it
+    /// can be, and what it did built in, so that the compiled program starts
+    /// from there and reads whatever input follows
+    #[arg(long = "bake-input", value_name = "FILE")]
+    pub bake_input: Option<String>,
+
+    /// Treat the input from --bake-input as all there is, so that reading
+    /// past it gets the end of input rather than stopping the run
+    #[arg(long = "bake-eof", requires = "bake_input")]
+    pub bake_eof: bool,
+
+    /// Most instructions to run ahead of time for --bake-input
+    #[arg(long = "bake-steps", value_name = "N", default_value_t = 10_000_000)]
+    pub bake_steps: u64,
+
+    /// When interpreting, write how often each cell was read and written to
+    /// stderr once the program stops, to show its memory layout
+    #[arg(long = "heatmap", value_name = "FORMAT")]
+    pub heatmap: Option<heatmap::Format>,
+
+    /// When interpreting, stop the program after it runs this many
+    /// instructions, reporting where it got to
+    #[arg(long = "max-steps", value_name = "N")]
+    pub max_steps: Option<u64>,
+
+    /// When running a program, in the interpreter or compiled, stop it after
+    /// this long, such as `5s` or `500ms`. A bare number is in seconds.
+    #[arg(long = "timeout", value_name = "DURATION", value_parser = parse_duration)]
+    pub timeout: Option<Duration>,
+
+    /// When interpreting, allocate at most this many cells of the tape,
+    /// stopping the program if it moves past them
+    #[arg(long = "max-tape", value_name = "CELLS")]
+    pub max_tape: Option<usize>,
+
+    /// When interpreting, stop the program once it has written this many
+    /// bytes
+    #[arg(long = "max-output", value_name = "BYTES")]
+    pub max_output: Option<u64>,
+
+    #[command(flatten)]
+    pub toolchain: ToolchainArgs,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ToolchainArgs {
+    /// Path to the assembler, overriding the profile
+    #[arg(long = "nasm-path", env = "BFC_NASM")]
+    pub nasm_path: Option<String>,
+
+    /// Path to the linker, overriding the profile
+    #[arg(long = "linker-path", env = "BFC_LINKER")]
+    pub linker_path: Option<String>,
+
+    /// Extra argument to pass to the assembler
+    #[arg(
+        long = "nasm-arg",
+        env = "BFC_NASM_ARGS",
+        value_delimiter = ' ',
+        allow_hyphen_values = true
+    )]
+    pub nasm_args: Vec<String>,
+
+    /// Extra argument to pass to the linker
+    #[arg(
+        long = "linker-arg",
+        env = "BFC_LINKER_ARGS",
+        value_delimiter = ' ',
+        allow_hyphen_values = true
+    )]
+    pub linker_args: Vec<String>,
+
+    /// Object file or library to link the program with, such as one defining
+    /// the functions that external calls go to
+    #[arg(long = "link", value_name = "FILE")]
+    pub objects: Vec<String>,
+
+    /// Run the assembler and linker even when the cache has what they would
+    /// make
+    #[arg(long = "no-cache")]
+    pub no_cache: bool,
+}
+
+impl BuildArgs {
+    pub fn emit(&self) -> Emit {
+        if self.output_assembly {
+            Emit::Asm
+        } else if self.output_object {
+            Emit::Obj
+        } else {
+            self.emit
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Emit {
+    /// An executable
+    Bin,
+    /// An object file to link into another program
+    Obj,
+    /// Assembly annotated with the originating brainfuck
+    Asm,
+    /// The source program side by side with its assembly
+    Listing,
+    /// A Graphviz graph of the program's loop nesting
+    Dot,
+    /// Readable pseudocode describing what the optimised program does
+    Pseudo,
+    /// The optimised program written back out as brainfuck
+    Bf,
+    /// Portable bytecode of the optimised program, for `bfc vm`
+    Bytecode,
+    /// The optimised program's instructions as text, which bfc reads back
+    /// from `.bfir` files
+    Ir,
+}
+
+impl Emit {
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Emit::Bin => None,
+            Emit::Obj => Some("o"),
+            Emit::Asm => Some("s"),
+            Emit::Listing => Some("lst"),
+            Emit::Dot => Some("dot"),
+            Emit::Pseudo => Some("txt"),
+            Emit::Bf => Some("opt.bf"),
+            Emit::Bytecode => Some("bfb"),
+            Emit::Ir => Some("opt.bfir"),
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum InputSource {
+    /// Standard input
+    Stdin,
+    /// The bytes of the first argument, reading the end of input after them
+    Arg,
+    /// The file named by the first argument
+    File,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OutputData {
+    /// Standard output
+    Stdout,
+    /// The file named by --output-file
+    File,
+    /// A buffer in memory, for a caller of a --no-exit program to read
+    Buffer,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum CellSize {
+    #[value(name = "8")]
+    Bits8,
+    #[value(name = "16")]
+    Bits16,
+    #[value(name = "32")]
+    Bits32,
+    #[value(name = "64")]
+    Bits64,
+}
+
+impl CellSize {
+    /// The `cell_type` variable for cells of this size.
+    pub fn cell_type(self) -> &'static str {
+        match self {
+            CellSize::Bits8 => "byte",
+            CellSize::Bits16 => "word",
+            CellSize::Bits32 => "dword",
+            CellSize::Bits64 => "qword",
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OptLevel {
+    /// No optimisation
+    #[value(name = "0")]
+    O0,
+    /// Combine instructions and replace common loops with faster code
+    #[value(name = "1")]
+    O1,
+    /// Also unroll loops
+    #[value(name = "2")]
+    O2,
+    /// Like 1, but prefer smaller code: call shared I/O routines instead of
+    /// inlining them and never unroll
+    #[value(name = "s")]
+    Os,
+}
+
+impl OptLevel {
+    pub fn optimises(self) -> bool {
+        self != OptLevel::O0
+    }
+}
+
+impl std::fmt::Display for OptLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Extension {
+    /// `@def NAME ... @end` macros, `@NAME` invocations and `@include "file"`
+    Macros,
+    /// `"text"` string literals that print their text from a zero cell and
+    /// `{N}` number literals that set the current cell, using the cell to the
+    /// right as scratch space
+    Literals,
+    /// `%name%` calls to the external function `name`, which is passed a
+    /// pointer to the current cell and linked in with --link
+    Externs,
+    /// A second tape, with its own pointer: `~` moves to the other tape and
+    /// `$` swaps the current cell with the one the other tape's pointer is on
+    Tapes,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+    /// Manage assembly profiles
+    #[command(subcommand)]
+    Profile(ProfileCommands),
+
+    /// Manage the cache of assembled objects and linked binaries
+    #[command(subcommand)]
+    Cache(CacheCommands),
+
+    /// Check that the toolchain and config directories are usable
+    Doctor {
+        /// Select which profile to check
+        #[arg(short = 'p', long = "profile")]
+        profile: Option<String>,
+
+        #[command(flatten)]
+        toolchain: ToolchainArgs,
+    },
+
+    /// Check the compiled program behaves the same as the interpreter
+    Selftest {
+        /// Filename of the brainfuck program
+        infile: String,
+
+        /// File to feed to the program's input
+        #[arg(long = "input")]
+        input: Option<String>,
+
+        #[command(flatten)]
+        build: Box<BuildArgs>,
+    },
+
+    /// Time a program compiled at each optimisation level
+    Bench {
+        /// Filename of the brainfuck program
+        infile: String,
+
+        /// Optimisation levels to compare
+        #[arg(long = "levels", value_enum, value_delimiter = ',', default_values_t = [OptLevel::O0, OptLevel::O1, OptLevel::O2])]
+        levels: Vec<OptLevel>,
+
+        /// Number of times to run each build
+        #[arg(long = "runs", default_value_t = 5)]
+        runs: usize,
+
+        /// File to feed to the program's input
+        #[arg(long = "input")]
+        input: Option<String>,
+
+        /// Also time the interpreter
+        #[arg(long = "interpret")]
+        interpret: bool,
+
+        /// Print the results as JSON
+        #[arg(long = "json")]
+        json: bool,
+
+        #[command(flatten)]
+        build: Box<BuildArgs>,
+    },
+
+    /// Run every program in a directory and compare it to its expected output
+    ///
+    /// Each `name.bf` is run with `name.in` as its input, if present, and its
+    /// output is compared against `name.expected`.
+    Test {
+        /// Directory containing the programs
+        dir: String,
+
+        /// Use the interpreter instead of compiling each program
+        #[arg(long = "interpret")]
+        interpret: bool,
+
+        #[command(flatten)]
+        build: Box<BuildArgs>,
+    },
+
+    /// Check that two programs behave the same in the interpreter
+    ///
+    /// Both programs are run on each input and their output compared,
+    /// reporting the first input and output byte where they differ.
+    Equiv {
+        /// Filename of the first program
+        first: String,
+
+        /// Filename of the program to compare it with
+        second: String,
+
+        /// Directory whose files are each fed to both programs as input. With
+        /// none given, they run once with no input.
+        #[arg(long = "inputs", value_name = "DIR")]
+        inputs: Option<String>,
+
+        /// What `,` leaves in the cell at the end of input
+        #[arg(long = "eof", value_enum, default_value_t = Eof::Zero)]
+        eof: Eof,
+
+        /// Most instructions either program may run on each input
+        #[arg(long = "fuel", default_value_t = 100_000_000)]
+        fuel: u64,
+
+        #[command(flatten)]
+        build: Box<BuildArgs>,
+    },
+
+    /// Run a program compiled with `--emit bytecode` in the interpreter
+    ///
+    /// The program reads stdin and writes stdout, on the tape it was compiled
+    /// for.
+    Vm {
+        /// Filename of the bytecode
+        infile: String,
+
+        /// Save the program's state to FILE at each `#`, in place of showing
+        /// the tape, and whenever bfc gets SIGUSR1, without stopping it
+        #[arg(long = "checkpoint", value_name = "FILE")]
+        checkpoint: Option<String>,
+
+        /// Carry on from the state saved in FILE by --checkpoint, rather than
+        /// starting afresh. Output already written isn't written again.
+        #[arg(long = "resume", value_name = "FILE")]
+        resume: Option<String>,
+
+        #[command(flatten)]
+        build: Box<BuildArgs>,
+    },
+
+    /// Report which instructions ran in a program built with
+    /// --instrument-coverage, from the counts it wrote when it last ran
+    Coverage {
+        /// Filename of the instrumented program
+        binary: String,
+
+        /// How to write the report
+        #[arg(long = "format", value_enum, default_value_t = coverage::Format::Lcov)]
+        format: coverage::Format,
+
+        /// Filename for the report, stdout by default
+        #[arg(short = 'o', long = "out")]
+        outfile: Option<String>,
+    },
+
+    /// Run brainfuck interactively on a tape that persists between lines
+    ///
+    /// Lines starting with `/` are commands to show or change the tape; enter
+    /// /help to list them.
+    Repl {
+        #[command(flatten)]
+        build: Box<BuildArgs>,
+    },
+
+    /// Reflow a brainfuck program, indenting loops by their nesting depth
+    Fmt {
+        /// Filename of the brainfuck program
+        infile: String,
+
+        /// Filename for the formatted program, stdout by default
+        #[arg(short = 'o', long = "out")]
+        outfile: Option<String>,
+
+        /// Longest line to fill up to
+        #[arg(long = "width", default_value_t = 80)]
+        width: usize,
+
+        /// Spaces to indent each level of loop nesting by
+        #[arg(long = "indent", default_value_t = 2)]
+        indent: usize,
+    },
+
+    /// Rewrite a program as the shortest equivalent brainfuck, without comments
+    ///
+    /// The result is written to the file given with -o, or stdout.
+    Minify {
+        /// Filename of the brainfuck program
+        infile: String,
+
+        #[command(flatten)]
+        build: Box<BuildArgs>,
+    },
+
+    /// Generate a brainfuck program that prints the contents of a file
+    Text2bf {
+        /// File to print
+        infile: String,
+
+        /// Filename for the generated program, stdout by default
+        #[arg(short = 'o', long = "out")]
+        outfile: Option<String>,
+    },
+
+    /// Generate a random program with balanced brackets, for fuzzing the
+    /// optimiser or as an exercise
+    ///
+    /// The pointer never leaves the tape, since every loop body moves it back
+    /// to where it started.
+    Gen {
+        /// Number of commands in the program
+        #[arg(long = "size", default_value_t = 100)]
+        size: usize,
+
+        /// Seed for the random generator, the same seed giving the same
+        /// program. A random one is used and printed if it isn't given.
+        #[arg(long = "seed")]
+        seed: Option<u64>,
+
+        /// Only generate a program that the interpreter finishes within
+        /// --fuel instructions, given no input
+        #[arg(long = "terminating")]
+        terminating: bool,
+
+        /// Most instructions a --terminating program may run
+        #[arg(long = "fuel", default_value_t = 1_000_000, requires = "terminating")]
+        fuel: u64,
+
+        /// Filename for the generated program, stdout by default
+        #[arg(short = 'o', long = "out")]
+        outfile: Option<String>,
+    },
+
+    /// Print a completion script for a shell
+    Completions {
+        /// Shell to complete in
+        shell: clap_complete::Shell,
+    },
+
+    /// Print the man page
+    #[command(hide = true)]
+    Mangen,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ProfileCommands {
+    /// List all available profiles
+    List {
+        /// Also list what each profile can do
+        #[arg(long = "capabilities")]
+        capabilities: bool,
+    },
+
+    /// Create a new profile in the user config directory
+    New {
+        /// Name of the new profile
+        name: String,
+
+        /// Existing profile to base the new one on
+        #[arg(long = "from")]
+        from: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CacheCommands {
+    /// Remove everything from the cache
+    Clean,
+
+    /// Show how much is cached and where
+    Stats,
+}
+
+/// Reads a duration as a number of `ms`, `s` or `m`, or of seconds if it has
+/// no unit.
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("expected a number of ms, s or m, not {text:?}"))?;
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        unit => return Err(format!("unknown unit {unit:?}, expected ms, s or m")),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string())
+}
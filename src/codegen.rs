@@ -0,0 +1,755 @@
+use crate::{
+    gdb,
+    lex::{Span, Token},
+    pgo::{Counts, Heat},
+    profile::{Profile, Variables},
+    sourcemap, superinstructions,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    mem,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Emit debug line info before each instruction
+    pub debug: bool,
+    /// Emit a comment naming the brainfuck behind each instruction
+    pub annotate: bool,
+    /// Check the pointer stays on the tape after every move
+    pub checked: bool,
+    /// Call shared I/O routines rather than inlining them, if the profile has
+    /// them
+    pub helpers: bool,
+    /// Exit with the current cell as the status rather than 0
+    pub exit_cell: bool,
+    /// Counts from a training run, which pick the loops to align
+    pub pgo: Option<Counts>,
+    /// Mark where each token's assembly starts with a comment, for a source
+    /// map
+    pub source_map: bool,
+    /// Catch crashes and report the line they happened near
+    pub fault_handler: bool,
+    /// Read input a key at a time without echo, if it's a terminal
+    pub raw_tty: bool,
+    /// Count how many times each instruction runs, writing the counts out
+    /// when the program ends
+    pub coverage: bool,
+    /// Coalesce the changes each run of arithmetic makes to a cell into one,
+    /// made at an offset from the pointer, and move the pointer once at the
+    /// end of the run, if the profile can
+    pub coalesce_changes: bool,
+}
+
+/// Names for the labels of each loop and procedure, saying where they nest.
+/// Blocks are numbered from 1 among the blocks directly inside the same
+/// block, so `loop_3_2` is the second block inside the third at the top
+/// level. Scans count as loops. Loops are found by the index of their start,
+/// and procedures by number.
+#[derive(Debug, Clone, Default)]
+pub struct Labels {
+    loops: HashMap<usize, String>,
+    procedures: HashMap<u32, String>,
+}
+
+impl Labels {
+    pub fn new(tokens: &[(Token, Span)]) -> Self {
+        let mut labels = Labels::default();
+        labels.add(tokens, 0, "");
+        labels
+    }
+
+    /// Names the blocks in `tokens`, which start at index `base`, starting
+    /// each name with `prefix`.
+    fn add(&mut self, tokens: &[(Token, Span)], base: usize, prefix: &str) {
+        // The path to each open block, and how many blocks are inside it so far
+        let mut open = vec![(String::new(), 0)];
+
+        for (i, &(token, _)) in tokens.iter().enumerate() {
+            let (kind, opens) = match token {
+                Token::LoopStart(_) => ("loop", true),
+                Token::ScanRight { .. } | Token::ScanLeft { .. } => ("loop", false),
+                Token::ProcStart(_) => ("proc", true),
+                Token::LoopEnd(_) | Token::ProcEnd(_) => {
+                    open.pop();
+                    continue;
+                }
+                _ => continue,
+            };
+
+            let (parent, children) = open.last_mut().unwrap();
+            *children += 1;
+            let path = format!("{parent}_{children}");
+            let name = format!("{prefix}{kind}{path}");
+            match token {
+                Token::ProcStart(id) => self.procedures.insert(id, name),
+                _ => self.loops.insert(base + i, name),
+            };
+            if opens {
+                open.push((path, 0));
+            }
+        }
+    }
+
+    /// The label of the block `token`, at `index`, starts, ends or scans in,
+    /// if it's one of those.
+    pub fn get(&self, index: usize, token: Token) -> Option<&str> {
+        match token {
+            Token::LoopStart(_) | Token::ScanRight { .. } | Token::ScanLeft { .. } => {
+                self.loops.get(&index)
+            }
+            Token::LoopEnd(distance) => self.loops.get(&(index - distance as usize)),
+            Token::ProcStart(id) | Token::ProcEnd(id) => self.procedures.get(&id),
+            _ => None,
+        }
+        .map(String::as_str)
+    }
+}
+
+/// Offsets the scan and procedure numbers in `tokens` by `scans` and
+/// `procedures`, moving those past the numbers used so that the next program
+/// can follow on.
+fn renumber(tokens: &mut [(Token, Span)], scans: &mut u32, procedures: &mut u32) {
+    let (scan_base, procedure_base) = (*scans, *procedures);
+    for (token, _) in tokens {
+        let (id, base, next) = match token {
+            Token::ScanRight { id, .. } | Token::ScanLeft { id, .. } => {
+                (id, scan_base, &mut *scans)
+            }
+            Token::ProcStart(id) | Token::ProcEnd(id) => (id, procedure_base, &mut *procedures),
+            _ => continue,
+        };
+        *id += base;
+        *next = (*next).max(*id + 1);
+    }
+}
+
+/// One of the programs in a multicall binary.
+#[derive(Debug, Clone)]
+struct Entry {
+    /// What the binary is called, or the first argument it's given, to run
+    /// this program
+    name: String,
+    label: String,
+    /// The index of the program's first token
+    start: usize,
+}
+
+/// How many tokens' assembly goes in each chunk, which bounds how much of a
+/// huge program's assembly is held in memory at once.
+const CHUNK_TOKENS: usize = 1 << 16;
+
+/// Generates the assembly for a program in chunks, which can be generated on
+/// separate threads and written out in order. Each chunk holds whole lines,
+/// so chunks can also be cleaned up separately. The profile's variables are
+/// filled in already, by `Profile::with_variables`.
+pub struct Codegen<'a> {
+    profile: &'a Profile,
+    tokens: Vec<(Token, Span)>,
+    vars: &'a Variables,
+    options: &'a Options,
+    procedures: bool,
+    helpers: bool,
+    /// Ids of the external functions called, each declared once
+    externs: Vec<u32>,
+    /// Names of the profile's runtime routines that the program calls
+    runtime: Vec<&'a str>,
+    labels: Labels,
+    /// The programs of a multicall binary, in order, or none for a binary
+    /// holding just one
+    programs: Vec<Entry>,
+}
+
+impl<'a> Codegen<'a> {
+    pub fn new(
+        profile: &'a Profile,
+        tokens: Vec<(Token, Span)>,
+        vars: &'a Variables,
+        options: &'a Options,
+    ) -> Self {
+        let tokens = Self::expand(profile, tokens, options);
+        let labels = Labels::new(&tokens);
+        Self::with_programs(profile, tokens, labels, vec![], vars, options)
+    }
+
+    /// Generates a binary holding each of `programs`, which runs the one
+    /// named by what it's called, or failing that, by its first argument.
+    /// Each program's labels start with a label of its own.
+    pub fn multicall(
+        profile: &'a Profile,
+        programs: Vec<(String, Vec<(Token, Span)>)>,
+        vars: &'a Variables,
+        options: &'a Options,
+    ) -> Self {
+        let mut tokens = vec![];
+        let mut labels = Labels::default();
+        let mut entries: Vec<Entry> = vec![];
+        let (mut scans, mut procedures) = (0, 0);
+
+        for (name, program) in programs {
+            let mut program = Self::expand(profile, program, options);
+            renumber(&mut program, &mut scans, &mut procedures);
+
+            let mut label: String = name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            label = format!("prog_{label}");
+            if entries.iter().any(|entry| entry.label == label) {
+                label = format!("{label}_{}", entries.len() + 1);
+            }
+
+            labels.add(&program, tokens.len(), &format!("{label}_"));
+            entries.push(Entry {
+                name,
+                label,
+                start: tokens.len(),
+            });
+            tokens.extend(program);
+        }
+
+        Self::with_programs(profile, tokens, labels, entries, vars, options)
+    }
+
+    /// Rewrites the superinstructions in `tokens` that won't be emitted.
+    fn expand(
+        profile: &Profile,
+        tokens: Vec<(Token, Span)>,
+        options: &Options,
+    ) -> Vec<(Token, Span)> {
+        // Bounds checks follow pointer moves, so with them on,
+        // superinstructions that touch other cells are written out as moves
+        superinstructions::expand(tokens, |tok| {
+            profile.supports(tok) && (!options.checked || tok == Token::SetZero)
+        })
+    }
+
+    fn with_programs(
+        profile: &'a Profile,
+        tokens: Vec<(Token, Span)>,
+        labels: Labels,
+        programs: Vec<Entry>,
+        vars: &'a Variables,
+        options: &'a Options,
+    ) -> Self {
+        let procedures = tokens
+            .iter()
+            .any(|(tok, _)| matches!(tok, Token::ProcStart(_) | Token::Call));
+        let helpers = options.helpers
+            && profile.supports_helpers()
+            && tokens
+                .iter()
+                .any(|(tok, _)| matches!(tok, Token::PutChar | Token::GetChar));
+        let mut externs: Vec<u32> = tokens
+            .iter()
+            .filter_map(|(tok, _)| match tok {
+                Token::Extern(id) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        externs.sort_unstable();
+        externs.dedup();
+
+        let mut codegen = Self {
+            profile,
+            tokens,
+            vars,
+            options,
+            procedures,
+            helpers,
+            externs,
+            runtime: vec![],
+            labels,
+            programs,
+        };
+
+        // Each kind of instruction renders to the same calls whatever its
+        // operands, so one of each shows every routine the program needs
+        let mut kinds = HashSet::new();
+        let mut asm = codegen.prologue();
+        for (i, (tok, _)) in codegen.tokens.iter().enumerate() {
+            if kinds.insert(mem::discriminant(tok)) {
+                asm.extend(codegen.instruction(i));
+            }
+        }
+        asm.extend(codegen.epilogue());
+        codegen.runtime = profile.runtime_routines(&asm.join("\n"));
+
+        codegen
+    }
+
+    fn prologue(&self) -> Vec<String> {
+        let mut lines = vec![self.profile.get_setup_asm()];
+        let input_setup = self.profile.get_input_setup_asm();
+        if !input_setup.is_empty() {
+            lines.push(input_setup);
+        }
+        let output_setup = self.profile.get_output_setup_asm();
+        if !output_setup.is_empty() {
+            lines.push(output_setup);
+        }
+        if self.options.fault_handler {
+            lines.push(self.profile.get_fault_setup_asm());
+        }
+        if self.options.raw_tty {
+            lines.push(self.profile.get_raw_tty_asm());
+        }
+        for &id in &self.externs {
+            lines.push(self.profile.get_extern_decl_asm(id));
+        }
+        if !self.programs.is_empty() {
+            let checks: Vec<String> = self
+                .programs
+                .iter()
+                .map(|entry| self.profile.get_dispatch_check_asm(&entry.label))
+                .collect();
+            lines.push(self.profile.get_dispatch_asm(&checks.join("\n")));
+        }
+        lines
+    }
+
+    fn teardown(&self) -> String {
+        if self.options.exit_cell {
+            self.profile.get_exit_cell_teardown_asm()
+        } else {
+            self.profile.get_teardown_asm()
+        }
+    }
+
+    /// Ends the program before the token at `index` and starts the next,
+    /// for each multicall program starting there.
+    fn boundary(&self, index: usize) -> Vec<String> {
+        let mut lines = vec![];
+        for (i, entry) in self.programs.iter().enumerate() {
+            if entry.start == index {
+                if i > 0 {
+                    lines.push(self.teardown());
+                }
+                lines.push(self.profile.get_program_start_asm(&entry.label));
+            }
+        }
+        lines
+    }
+
+    fn epilogue(&self) -> Vec<String> {
+        let (profile, options) = (self.profile, self.options);
+        let mut lines = self.boundary(self.tokens.len());
+        if options.raw_tty {
+            lines.push(profile.get_raw_tty_restore_asm());
+        }
+        if options.coverage {
+            lines.push(profile.get_coverage_dump_asm(self.tokens.len()));
+        }
+        lines.push(self.teardown());
+        if self.helpers {
+            lines.push(profile.get_helpers_asm());
+        }
+        if options.checked {
+            lines.push(profile.get_bounds_error_asm());
+        }
+        if self.procedures {
+            lines.push(profile.get_proc_error_asm());
+        }
+        if options.fault_handler {
+            let entries: Vec<String> = self
+                .tokens
+                .iter()
+                .enumerate()
+                .map(|(i, (_, span))| profile.get_fault_entry_asm(&gdb::label(i), span.line))
+                .collect();
+            lines.push(profile.get_fault_table_asm(&entries.join("\n")));
+        }
+        for entry in &self.programs {
+            lines.push(profile.get_dispatch_name_asm(&entry.name, &entry.label));
+        }
+        lines
+    }
+
+    /// Whether the start of every token's code is labelled, for the gdb
+    /// script or the fault handler to find.
+    fn is_labelled(&self) -> bool {
+        let options = self.options;
+        (options.debug && self.profile.supports_gdb()) || options.fault_handler
+    }
+
+    /// The source map marker for the token at `index`, or for the end of
+    /// the last token, if making a source map.
+    fn marker(&self, index: Option<usize>) -> Vec<String> {
+        if self.options.source_map {
+            vec![self.profile.get_comment(&sourcemap::marker(index))]
+        } else {
+            vec![]
+        }
+    }
+
+    fn instruction(&self, index: usize) -> Vec<String> {
+        let (profile, vars, options) = (self.profile, self.vars, self.options);
+        let (tok, span) = self.tokens[index];
+        let mut lines = vec![];
+        if let (Token::LoopStart(_), Some(counts)) = (tok, &options.pgo) {
+            if counts.heat(span) == Heat::Hot {
+                lines.push(profile.get_hot_loop_align_asm());
+            }
+        }
+        if options.annotate {
+            lines.push(self.annotation(tok, span));
+        }
+        if options.debug {
+            lines.push(profile.get_debug_line(span, vars));
+        }
+        if self.helpers && matches!(tok, Token::PutChar | Token::GetChar) {
+            lines.push(profile.get_helper_call_asm(tok));
+        } else {
+            lines.push(profile.get_asm(tok, self.labels.get(index, tok)));
+        }
+        if options.checked && matches!(tok, Token::PtrAdd(_) | Token::PtrSub(_)) {
+            lines.push(profile.get_bounds_check_asm());
+        }
+        lines
+    }
+
+    /// A comment naming the brainfuck behind `tok`.
+    fn annotation(&self, tok: Token, span: Span) -> String {
+        let file = span
+            .file
+            .map(|file| format!("{file}, "))
+            .unwrap_or_default();
+        self.profile.get_comment(&format!(
+            "{file}line {}, col {}: {} ({})",
+            span.line,
+            span.col,
+            tok.to_bf(),
+            tok.kind()
+        ))
+    }
+
+    /// A comment naming the brainfuck behind a coalesced run, which has no
+    /// code of its own for each token.
+    fn run_annotation(&self, run: &[(Token, Span)]) -> String {
+        let span = run[0].1;
+        let file = span
+            .file
+            .map(|file| format!("{file}, "))
+            .unwrap_or_default();
+        let bf: String = run.iter().map(|(tok, _)| tok.to_bf()).collect();
+        self.profile.get_comment(&format!(
+            "{file}line {}, col {}: {bf} (coalesced run of {} tokens)",
+            span.line,
+            span.col,
+            run.len()
+        ))
+    }
+
+    /// Whether runs of arithmetic are coalesced. Everything that needs each
+    /// token's code to stand on its own, from bounds checks after moves to
+    /// labels and counters before each token, turns it off.
+    fn is_coalescing(&self) -> bool {
+        let options = self.options;
+        options.coalesce_changes
+            && self.profile.supports_offsets()
+            && !(options.checked
+                || options.debug
+                || options.fault_handler
+                || options.coverage
+                || options.source_map)
+    }
+
+    /// The number of tokens from `index` up to `end` that change cells or
+    /// move the pointer and so can be coalesced together, stopping before the
+    /// start of a multicall program.
+    fn arithmetic_run(&self, index: usize, end: usize) -> usize {
+        (index..end)
+            .take_while(|&i| {
+                let starts_program = i > index && self.programs.iter().any(|e| e.start == i);
+                !starts_program
+                    && matches!(
+                        self.tokens[i].0,
+                        Token::PtrAdd(_)
+                            | Token::PtrSub(_)
+                            | Token::Add(_)
+                            | Token::Sub(_)
+                            | Token::SetZero
+                            | Token::AddAt { .. }
+                    )
+            })
+            .count()
+    }
+
+    /// The code for a run of arithmetic with its changes coalesced. The
+    /// changes to each cell are summed first, so each cell the run changes is
+    /// set to zero at most once and added to or subtracted from at most once,
+    /// at its offset from where the run starts. The pointer then moves once,
+    /// by however far the run moved it.
+    fn coalesced(&self, run: &[(Token, Span)]) -> Vec<String> {
+        let (profile, vars) = (self.profile, self.vars);
+        let bits = vars["cell_bytes"].parse::<u32>().unwrap_or(1) * 8;
+        let mask = u64::MAX >> (64 - bits);
+
+        // For each cell, by offset, whether it's set to zero and what's added
+        // to it after that, modulo the cell size
+        let mut ptr: isize = 0;
+        let mut cells: BTreeMap<isize, (bool, u64)> = BTreeMap::new();
+        for &(tok, _) in run {
+            let (cell, zeroes, amount) = match tok {
+                Token::PtrAdd(n) => {
+                    ptr += n as isize;
+                    continue;
+                }
+                Token::PtrSub(n) => {
+                    ptr -= n as isize;
+                    continue;
+                }
+                Token::Add(n) => (ptr, false, n as u64),
+                Token::Sub(n) => (ptr, false, (n as u64).wrapping_neg()),
+                Token::SetZero => (ptr, true, 0),
+                Token::AddAt { offset, amount } => {
+                    (ptr + offset as isize, false, amount as i64 as u64)
+                }
+                _ => unreachable!("only arithmetic is coalesced"),
+            };
+            let (zeroed, total) = cells.entry(cell).or_default();
+            if zeroes {
+                (*zeroed, *total) = (true, 0);
+            }
+            *total = total.wrapping_add(amount) & mask;
+        }
+
+        let mut lines = vec![];
+        let at = |tok: Token, cell: isize| match cell {
+            0 => profile.get_asm(tok, None),
+            _ => profile.get_offset_asm(tok, cell),
+        };
+        for (cell, (zeroed, total)) in cells {
+            if zeroed {
+                lines.push(at(Token::SetZero, cell));
+            }
+            // Whichever way is shorter, in steps that fit any immediate
+            let (mut left, add) = match total <= mask / 2 {
+                true => (total, true),
+                false => ((mask - total).wrapping_add(1) & mask, false),
+            };
+            while left > 0 {
+                let n = left.min(i32::MAX as u64) as u32;
+                let tok = match add {
+                    true => Token::Add(n),
+                    false => Token::Sub(n),
+                };
+                lines.push(at(tok, cell));
+                left -= n as u64;
+            }
+        }
+
+        let mut distance = ptr.unsigned_abs();
+        while distance > 0 {
+            let n = distance.min(u32::MAX as usize) as u32;
+            let tok = match ptr {
+                0.. => Token::PtrAdd(n),
+                _ => Token::PtrSub(n),
+            };
+            lines.push(profile.get_asm(tok, None));
+            distance -= n as usize;
+        }
+        lines
+    }
+
+    /// The tokens code is generated for, once the superinstructions the
+    /// profile can't emit have been rewritten.
+    pub fn tokens(&self) -> &[(Token, Span)] {
+        &self.tokens
+    }
+
+    /// The number of chunks, counting the setup and the teardown.
+    pub fn chunk_count(&self) -> usize {
+        self.tokens.len().div_ceil(CHUNK_TOKENS) + 2
+    }
+
+    pub fn chunk(&self, index: usize) -> Vec<String> {
+        if index == 0 {
+            return self.prologue();
+        }
+        if index == self.chunk_count() - 1 {
+            let mut lines = self.marker(None);
+            lines.extend(self.epilogue());
+            for name in &self.runtime {
+                lines.push(self.profile.get_runtime_asm(name));
+            }
+            return lines;
+        }
+
+        let start = (index - 1) * CHUNK_TOKENS;
+        let end = self.tokens.len().min(start + CHUNK_TOKENS);
+        let coalescing = self.is_coalescing();
+        let mut lines = vec![];
+        let mut i = start;
+        while i < end {
+            lines.extend(self.boundary(i));
+            let run = match coalescing {
+                true => self.arithmetic_run(i, end),
+                false => 0,
+            };
+            if run > 1 {
+                let run = &self.tokens[i..i + run];
+                if self.options.annotate {
+                    lines.push(self.run_annotation(run));
+                }
+                lines.extend(self.coalesced(run));
+                i += run.len();
+                continue;
+            }
+
+            lines.extend(self.marker(Some(i)));
+            if self.is_labelled() {
+                let label = gdb::label(i);
+                lines.push(self.profile.get_debug_label(&label));
+            }
+            if self.options.coverage {
+                lines.push(self.profile.get_coverage_count_asm(i));
+            }
+            lines.extend(self.instruction(i));
+            i += 1;
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// elf_32 with templates that name what they do, so code can be
+    /// compared without depending on its assembly.
+    fn profile() -> Profile {
+        let templates = [
+            ("ptradd", "ptradd {}"),
+            ("ptrsub", "ptrsub {}"),
+            ("add", "add {}"),
+            ("sub", "sub {}"),
+            ("setzero", "setzero"),
+            ("addat", "addat {offset} {}"),
+            ("subat", "subat {offset} {}"),
+            ("setzeroat", "setzeroat {offset}"),
+        ];
+        let elf_32: Profile =
+            serde_json::from_str(include_str!("../profiles/elf_32.json")).unwrap();
+        templates
+            .into_iter()
+            .fold(elf_32.to_builder(), |builder, (field, line)| {
+                builder.add_template(field, [line])
+            })
+            .build()
+            .unwrap()
+    }
+
+    /// The code for `tokens` with coalescing on, at cells of `cell_size`.
+    fn coalesced(tokens: &[Token], cell_size: &str) -> Vec<String> {
+        let profile = profile();
+        let overrides = Variables::from([("cell_type".to_string(), cell_size.to_string())]);
+        let vars = profile.variables(&overrides).unwrap();
+        let profile = profile.with_variables(&vars);
+        let options = Options {
+            coalesce_changes: true,
+            ..Default::default()
+        };
+        let span = Span {
+            line: 1,
+            col: 1,
+            file: None,
+        };
+        let tokens = tokens.iter().map(|&tok| (tok, span)).collect();
+        Codegen::new(&profile, tokens, &vars, &options).chunk(1)
+    }
+
+    #[test]
+    fn coalesced_sums_each_cell() {
+        let tokens = [
+            Token::Add(2),
+            Token::PtrAdd(1),
+            Token::Sub(1),
+            Token::PtrSub(1),
+            Token::Add(3),
+            Token::PtrAdd(2),
+        ];
+        assert_eq!(
+            coalesced(&tokens, "byte"),
+            ["add 5", "subat 1 1", "ptradd 2"]
+        );
+    }
+
+    #[test]
+    fn coalesced_drops_changes_that_cancel() {
+        let tokens = [
+            Token::Add(3),
+            Token::PtrAdd(1),
+            Token::PtrSub(1),
+            Token::Sub(3),
+        ];
+        assert!(coalesced(&tokens, "byte").is_empty());
+    }
+
+    #[test]
+    fn coalesced_zeroes_once_and_keeps_later_changes() {
+        let tokens = [
+            Token::Add(7),
+            Token::SetZero,
+            Token::PtrSub(2),
+            Token::SetZero,
+            Token::PtrAdd(2),
+            Token::Add(1),
+        ];
+        assert_eq!(
+            coalesced(&tokens, "byte"),
+            ["setzeroat -2", "setzero", "add 1"]
+        );
+    }
+
+    #[test]
+    fn coalesced_counts_add_at_from_the_moved_pointer() {
+        let tokens = [
+            Token::PtrAdd(1),
+            Token::AddAt {
+                offset: 2,
+                amount: -4,
+            },
+            Token::Add(1),
+        ];
+        assert_eq!(
+            coalesced(&tokens, "byte"),
+            ["addat 1 1", "subat 3 4", "ptradd 1"]
+        );
+    }
+
+    #[test]
+    fn coalesced_wraps_at_the_cell_size() {
+        let tokens = [
+            Token::Add(200),
+            Token::PtrAdd(1),
+            Token::PtrSub(1),
+            Token::Add(100),
+        ];
+        assert_eq!(coalesced(&tokens, "byte"), ["add 44"]);
+        assert_eq!(coalesced(&tokens, "word"), ["add 300"]);
+        let tokens = [Token::Add(250), Token::PtrAdd(1), Token::PtrSub(1)];
+        assert_eq!(coalesced(&tokens, "byte"), ["sub 6"]);
+    }
+
+    #[test]
+    fn coalesced_splits_changes_too_big_for_an_immediate() {
+        let tokens = [
+            Token::Add(u32::MAX),
+            Token::PtrAdd(1),
+            Token::PtrSub(1),
+            Token::Add(u32::MAX),
+        ];
+        let big = i32::MAX.to_string();
+        assert_eq!(
+            coalesced(&tokens, "qword"),
+            [
+                format!("add {big}"),
+                format!("add {big}"),
+                format!("add {big}"),
+                format!("add {big}"),
+                "add 2".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use crate::profile::Profile;
+use std::{collections::BTreeMap, fs, io, path::PathBuf};
+
+/// Defaults for command-line flags from `config.toml` in the config
+/// directory, keyed by their long names, such as `opt-level = "2"` or
+/// `allow = ["tape-overflow"]`. Flags given on the command line or through
+/// the environment take precedence.
+#[derive(Debug, Default)]
+pub struct Config {
+    values: BTreeMap<String, Vec<&'static str>>,
+}
+
+impl Config {
+    pub fn path() -> PathBuf {
+        Profile::config_path().join("config.toml")
+    }
+
+    /// Reads the config file, which is empty if there isn't one.
+    pub fn load() -> Result<Config, String> {
+        let path = Config::path();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(format!("could not read {}: {e}", path.display())),
+        };
+        let table: toml::Table = contents
+            .parse()
+            .map_err(|e| format!("invalid config file {}: {e}", path.display()))?;
+
+        let mut values = BTreeMap::new();
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::Array(items) => items.into_iter().map(to_arg).collect(),
+                value => vec![to_arg(value)],
+            };
+            values.insert(key, value);
+        }
+        Ok(Config { values })
+    }
+
+    /// Sets the defaults in this config on every flag of `cmd` and its
+    /// subcommands, warning about keys that aren't flags.
+    pub fn apply(&self, cmd: clap::Command) -> clap::Command {
+        let mut used = vec![];
+        let cmd = self.apply_to(cmd, &mut used);
+        for key in self.values.keys().filter(|key| !used.contains(key)) {
+            eprintln!(
+                "warning: unknown option `{key}` in {}",
+                Config::path().display()
+            );
+        }
+        cmd
+    }
+
+    fn apply_to(&self, mut cmd: clap::Command, used: &mut Vec<String>) -> clap::Command {
+        let args: Vec<(String, String)> = cmd
+            .get_arguments()
+            .filter_map(|arg| Some((arg.get_id().to_string(), arg.get_long()?.to_string())))
+            .filter(|(_, long)| self.values.contains_key(long))
+            .collect();
+        for (id, long) in args {
+            let values = self.values[&long].clone();
+            cmd = cmd.mut_arg(id, |arg| arg.default_values(values));
+            used.push(long);
+        }
+
+        let subcommands: Vec<_> = cmd
+            .get_subcommands()
+            .map(|sub| sub.get_name().to_string())
+            .collect();
+        for sub in subcommands {
+            cmd = cmd.mut_subcommand(sub, |sub| self.apply_to(sub, used));
+        }
+        cmd
+    }
+}
+
+/// Writes a TOML value the way it would be given on the command line. Clap
+/// needs defaults to live forever, and the config is only read once.
+fn to_arg(value: toml::Value) -> &'static str {
+    let arg = match value {
+        toml::Value::String(s) => s,
+        value => value.to_string(),
+    };
+    Box::leak(arg.into_boxed_str())
+}
@@ -0,0 +1,169 @@
+//! Which instructions of a program ran, and how often, from the counts the
+//! interpreter keeps or those an instrumented binary writes out. Reports are
+//! either an lcov tracefile, for tools that already read those, or the source
+//! annotated line by line in the style of gcov.
+use crate::lex::{Span, Token};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt::Write};
+
+/// How a coverage report is written out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// An lcov tracefile, with a record for each source file
+    #[default]
+    Lcov,
+    /// Each source line with how often it ran, marking the instructions on
+    /// it that never did
+    Annotated,
+}
+
+/// A program's instructions and how many times each ran. Written alongside an
+/// instrumented binary with every count zero, since the counts come later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Coverage {
+    /// The brainfuck file, which instructions without a file of their own
+    /// are in
+    pub source: String,
+    pub instructions: Vec<Instruction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Instruction {
+    /// The file the instruction came from, if not the source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    pub line: u32,
+    pub col: u32,
+    pub count: u64,
+}
+
+impl Coverage {
+    /// The coverage of `tokens`, from `source`, where `runs` is how many
+    /// times each ran.
+    pub fn new(source: &str, tokens: &[(Token, Span)], runs: &[u64]) -> Self {
+        let instructions = tokens
+            .iter()
+            .zip(runs.iter().copied().chain(std::iter::repeat(0)))
+            .map(|(&(_, span), count)| Instruction {
+                file: span.file.cloned(),
+                line: span.line,
+                col: span.col,
+                count,
+            })
+            .collect();
+        Self {
+            source: source.to_string(),
+            instructions,
+        }
+    }
+
+    /// Sets the counts from `runs`, one for each instruction, as read from an
+    /// instrumented binary's counts file. Gives `false` if there are more or
+    /// fewer counts than instructions, so they're for another build.
+    pub fn set_counts(&mut self, runs: &[u64]) -> bool {
+        if runs.len() != self.instructions.len() {
+            return false;
+        }
+        for (instruction, &count) in self.instructions.iter_mut().zip(runs) {
+            instruction.count = count;
+        }
+        true
+    }
+
+    /// The files the instructions came from, the source first.
+    pub fn files(&self) -> Vec<&str> {
+        let mut files = vec![self.source.as_str()];
+        for instruction in &self.instructions {
+            let file = self.file(instruction);
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+        files
+    }
+
+    fn file<'a>(&'a self, instruction: &'a Instruction) -> &'a str {
+        instruction.file.as_deref().unwrap_or(&self.source)
+    }
+
+    /// The instructions in `file` by line, in column order.
+    fn lines(&self, file: &str) -> BTreeMap<u32, Vec<&Instruction>> {
+        let mut lines: BTreeMap<u32, Vec<&Instruction>> = BTreeMap::new();
+        for instruction in &self.instructions {
+            if self.file(instruction) == file {
+                lines.entry(instruction.line).or_default().push(instruction);
+            }
+        }
+        for instructions in lines.values_mut() {
+            instructions.sort_by_key(|instruction| instruction.col);
+        }
+        lines
+    }
+
+    /// An lcov tracefile, where a line's count is that of the instruction on
+    /// it that ran most.
+    pub fn lcov(&self) -> String {
+        let mut out = String::new();
+        for file in self.files() {
+            let lines = self.lines(file);
+            writeln!(out, "TN:").unwrap();
+            writeln!(out, "SF:{file}").unwrap();
+            let mut hit = 0;
+            for (line, instructions) in &lines {
+                let count = line_count(instructions);
+                if count > 0 {
+                    hit += 1;
+                }
+                writeln!(out, "DA:{line},{count}").unwrap();
+            }
+            writeln!(out, "LF:{}", lines.len()).unwrap();
+            writeln!(out, "LH:{hit}").unwrap();
+            writeln!(out, "end_of_record").unwrap();
+        }
+        out
+    }
+
+    /// `source`, the contents of `file`, with each line prefixed by how often
+    /// it ran: `#####` if none of its instructions did, or `-` if it has
+    /// none. Lines that only partly ran are followed by a line with a `^`
+    /// under each instruction that didn't.
+    pub fn annotate(&self, file: &str, source: &str) -> String {
+        let lines = self.lines(file);
+        let mut out = format!("{:>9}:{:>5}:Source:{file}\n", "-", 0);
+        for (number, text) in (1..).zip(source.lines()) {
+            let instructions = lines.get(&number);
+            let count = match instructions {
+                None => "-".to_string(),
+                Some(instructions) => match line_count(instructions) {
+                    0 => "#####".to_string(),
+                    count => count.to_string(),
+                },
+            };
+            writeln!(out, "{count:>9}:{number:>5}:{text}").unwrap();
+
+            let missed: Vec<u32> = instructions
+                .filter(|instructions| line_count(instructions) > 0)
+                .into_iter()
+                .flatten()
+                .filter(|instruction| instruction.count == 0)
+                .map(|instruction| instruction.col)
+                .collect();
+            if let Some(&last) = missed.last() {
+                let marks: String = (1..=last)
+                    .map(|col| if missed.contains(&col) { '^' } else { ' ' })
+                    .collect();
+                writeln!(out, "{:>9}:{:>5}:{marks}", "", "").unwrap();
+            }
+        }
+        out
+    }
+}
+
+/// The count of the instruction in `instructions` that ran most.
+fn line_count(instructions: &[&Instruction]) -> u64 {
+    instructions
+        .iter()
+        .map(|instruction| instruction.count)
+        .max()
+        .unwrap_or(0)
+}
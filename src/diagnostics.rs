@@ -0,0 +1,231 @@
+use crate::lex::Span;
+use serde::Serialize;
+use std::{
+    fmt, fs,
+    io::{self, IsTerminal},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    /// ANSI escape for the colour this severity is shown in.
+    fn color(self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+            Severity::Note => "\x1b[1;36m",
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub span: Option<Span>,
+    /// More errors found along with this one, reported after it
+    #[serde(skip)]
+    pub related: Vec<Diagnostic>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            code,
+            message: message.into(),
+            span: None,
+            related: vec![],
+        }
+    }
+
+    pub fn note(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Note,
+            code,
+            message: message.into(),
+            span: None,
+            related: vec![],
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// The first of `diagnostics`, with the rest related to it, or `None` if
+    /// there are none.
+    pub fn combine(diagnostics: Vec<Diagnostic>) -> Option<Self> {
+        let mut diagnostics = diagnostics.into_iter();
+        let mut first = diagnostics.next()?;
+        first.related.extend(diagnostics);
+        Some(first)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum MessageFormat {
+    /// Plain text for people
+    Human,
+    /// One JSON object per line for tools
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Colour when writing to a terminal and NO_COLOR isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// Most characters of a source line shown either side of a diagnostic's
+/// column.
+const SNIPPET_CONTEXT: usize = 40;
+
+const BOLD: &str = "\x1b[1m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Serialize)]
+struct JsonDiagnostic<'a> {
+    file: &'a str,
+    #[serde(flatten)]
+    diagnostic: &'a Diagnostic,
+}
+
+/// Writes diagnostics about `file` to stderr in the chosen format.
+pub struct Emitter {
+    pub format: MessageFormat,
+    pub file: String,
+    pub color: bool,
+}
+
+impl Emitter {
+    fn file(&self, span: Span) -> &str {
+        span.file.unwrap_or(&self.file)
+    }
+
+    /// Wraps `text` in `style` if colour is on.
+    fn paint(&self, style: &str, text: impl fmt::Display) -> String {
+        if self.color {
+            format!("{style}{text}{RESET}")
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// The source line `span` points into, followed by a caret under its
+    /// column. Gives nothing if the file can't be read or is too short to
+    /// have the position, as when a span came from elsewhere.
+    fn snippet(&self, span: Span, severity: Severity) -> Option<String> {
+        let contents = fs::read_to_string(self.file(span)).ok()?;
+        let line: Vec<char> = contents
+            .lines()
+            .nth(span.line.checked_sub(1)? as usize)?
+            .chars()
+            .collect();
+        let col = span.col.saturating_sub(1) as usize;
+        if col > line.len() {
+            return None;
+        }
+
+        // Show only the part of a long line around the column
+        let start = col.saturating_sub(SNIPPET_CONTEXT);
+        let end = line.len().min(col + SNIPPET_CONTEXT);
+        let mut text: String = line[start..end].iter().collect();
+        let mut indent = String::new();
+        if start > 0 {
+            text.insert_str(0, "...");
+            indent.push_str("   ");
+        }
+        if end < line.len() {
+            text.push_str("...");
+        }
+        // Keep tabs so the caret lines up however wide they're shown
+        indent.extend(line[start..col.min(line.len())].iter().map(|&c| {
+            if c == '\t' {
+                '\t'
+            } else {
+                ' '
+            }
+        }));
+
+        let number = span.line.to_string();
+        let margin = " ".repeat(number.len());
+        let bar = self.paint(BLUE, "|");
+        Some(format!(
+            "{margin} {bar}\n{} {bar} {text}\n{margin} {bar} {indent}{}",
+            self.paint(BLUE, number),
+            self.paint(severity.color(), "^"),
+        ))
+    }
+
+    pub fn emit(&self, diagnostic: &Diagnostic) {
+        match self.format {
+            MessageFormat::Human => {
+                let severity = diagnostic.severity;
+                eprintln!(
+                    "{}{}",
+                    self.paint(severity.color(), format!("{severity}[{}]", diagnostic.code)),
+                    self.paint(BOLD, format!(": {}", diagnostic.message)),
+                );
+                if let Some(span) = diagnostic.span {
+                    // Lined up with the margin of the snippet, like rustc
+                    eprintln!(
+                        "{}{} {}:{}:{}",
+                        " ".repeat(span.line.to_string().len()),
+                        self.paint(BLUE, "-->"),
+                        self.file(span),
+                        span.line,
+                        span.col
+                    );
+                    if let Some(snippet) = self.snippet(span, severity) {
+                        eprintln!("{snippet}");
+                    }
+                }
+            }
+            MessageFormat::Json => {
+                let json = JsonDiagnostic {
+                    file: diagnostic.span.map_or(&self.file, |span| self.file(span)),
+                    diagnostic,
+                };
+                eprintln!("{}", serde_json::to_string(&json).unwrap());
+            }
+        }
+
+        for related in &diagnostic.related {
+            self.emit(related);
+        }
+    }
+}
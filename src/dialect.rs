@@ -0,0 +1,238 @@
+use crate::{
+    diagnostics::Diagnostic,
+    externs,
+    lex::{self, Span},
+};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Dialect {
+    /// Plain brainfuck
+    Brainfuck,
+    /// Ook!, where pairs of `Ook.`, `Ook?` and `Ook!` stand for commands
+    Ook,
+    /// Brainfuck with pbrain procedures: `(` defines procedure number
+    /// <current cell>, `)` ends it and `:` calls it
+    Pbrain,
+}
+
+impl Dialect {
+    /// Guesses the dialect from the file extension, defaulting to brainfuck.
+    pub fn from_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("ook") => Dialect::Ook,
+            Some("pb") => Dialect::Pbrain,
+            _ => Dialect::Brainfuck,
+        }
+    }
+
+    /// Translates `source` into the equivalent brainfuck commands, each
+    /// with the position of the source text it came from. External calls and
+    /// the commands of options and extensions are kept as they are. Brainfuck and pbrain are
+    /// translated as `source` is read, without collecting it.
+    pub fn commands<'a>(
+        self,
+        source: impl Iterator<Item = (char, Span)> + 'a,
+    ) -> Result<Box<dyn Iterator<Item = (char, Span)> + 'a>, Diagnostic> {
+        let keep = |commands: &'static str| {
+            move |&(c, _): &(char, Span)| commands.contains(c) || passes_through(c)
+        };
+        match self {
+            Dialect::Brainfuck => Ok(Box::new(source.filter(keep("><+-[].,")))),
+            Dialect::Ook => {
+                let source: Vec<_> = source.collect();
+                Ok(Box::new(ook_commands(&source)?.into_iter()))
+            }
+            Dialect::Pbrain => Ok(Box::new(source.filter(keep("><+-[].,():")))),
+        }
+    }
+}
+
+/// Whether `c` stands for an external call or another command that's only
+/// a command with an option or extension, which every dialect keeps as it is.
+fn passes_through(c: char) -> bool {
+    externs::call_id(c).is_some() || lex::is_marker(c)
+}
+
+fn ook_words(source: &[(char, Span)]) -> Vec<(String, Span)> {
+    let mut words = vec![];
+    let mut current: Option<(String, Span)> = None;
+    for &(c, span) in source {
+        // An external call or other marked command is a word on its own
+        if passes_through(c) {
+            words.extend(current.take());
+            words.push((c.to_string(), span));
+        } else if c.is_whitespace() {
+            words.extend(current.take());
+        } else {
+            current
+                .get_or_insert_with(|| (String::new(), span))
+                .0
+                .push(c);
+        }
+    }
+    words.extend(current);
+    words
+}
+
+fn ook_commands(source: &[(char, Span)]) -> Result<Vec<(char, Span)>, Diagnostic> {
+    let mut words = ook_words(source).into_iter();
+    let mut commands = vec![];
+
+    while let Some((first, span)) = words.next() {
+        if let Some(call) = first.chars().find(|&c| passes_through(c)) {
+            commands.push((call, span));
+            continue;
+        }
+        let Some((second, _)) = words.next() else {
+            return Err(Diagnostic::error("E0009", "Ook! word without a partner").with_span(span));
+        };
+        let command = match (first.as_str(), second.as_str()) {
+            ("Ook.", "Ook?") => '>',
+            ("Ook?", "Ook.") => '<',
+            ("Ook.", "Ook.") => '+',
+            ("Ook!", "Ook!") => '-',
+            ("Ook!", "Ook.") => '.',
+            ("Ook.", "Ook!") => ',',
+            ("Ook!", "Ook?") => '[',
+            ("Ook?", "Ook!") => ']',
+            _ => {
+                return Err(Diagnostic::error(
+                    "E0009",
+                    format!("`{first} {second}` is not an Ook! command"),
+                )
+                .with_span(span))
+            }
+        };
+        commands.push((command, span));
+    }
+
+    Ok(commands)
+}
+
+/// The strings that stand for each command in a trivial brainfuck
+/// substitution, as read from a `--dialect-map` file.
+#[derive(Debug, Clone, Deserialize)]
+struct MapFile {
+    ptradd: String,
+    ptrsub: String,
+    add: String,
+    sub: String,
+    loopstart: String,
+    loopend: String,
+    putchar: String,
+    getchar: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct DialectMap {
+    /// Command strings, longest first so that the longest match wins
+    tokens: Vec<(Vec<char>, char)>,
+}
+
+impl DialectMap {
+    pub fn load(path: &str) -> Result<Self, Diagnostic> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Diagnostic::error("E0005", format!("could not read {path}: {e}")))?;
+        let map: MapFile = serde_json::from_str(&contents)
+            .map_err(|e| Diagnostic::error("E0010", format!("invalid dialect map {path}: {e}")))?;
+
+        let mut tokens: Vec<(Vec<char>, char)> = [
+            (map.ptradd, '>'),
+            (map.ptrsub, '<'),
+            (map.add, '+'),
+            (map.sub, '-'),
+            (map.loopstart, '['),
+            (map.loopend, ']'),
+            (map.putchar, '.'),
+            (map.getchar, ','),
+        ]
+        .into_iter()
+        .map(|(text, command)| (text.chars().collect(), command))
+        .collect();
+
+        if let Some((text, _)) = tokens.iter().find(|(text, _)| text.is_empty()) {
+            return Err(Diagnostic::error(
+                "E0010",
+                format!(
+                    "invalid dialect map {path}: empty command {:?}",
+                    String::from_iter(text)
+                ),
+            ));
+        }
+
+        tokens.sort_by_key(|(text, _)| std::cmp::Reverse(text.len()));
+        Ok(Self { tokens })
+    }
+
+    /// Finds the commands in `source`, skipping anything that doesn't
+    /// match. Whitespace in a command matches any run of whitespace.
+    pub fn commands(&self, chars: &[(char, Span)]) -> Vec<(char, Span)> {
+        let mut commands = vec![];
+
+        let mut i = 0;
+        while i < chars.len() {
+            match self
+                .tokens
+                .iter()
+                .find_map(|(text, command)| match_len(text, &chars[i..]).map(|n| (n, *command)))
+            {
+                Some((len, command)) => {
+                    commands.push((command, chars[i].1));
+                    i += len;
+                }
+                None if passes_through(chars[i].0) => {
+                    commands.push(chars[i]);
+                    i += 1;
+                }
+                None => i += 1,
+            }
+        }
+
+        commands
+    }
+}
+
+/// Returns how many characters of `input` `pattern` matches, if it does.
+fn match_len(pattern: &[char], input: &[(char, Span)]) -> Option<usize> {
+    let mut i = 0;
+    for &p in pattern {
+        if p.is_whitespace() {
+            if !input.get(i)?.0.is_whitespace() {
+                return None;
+            }
+            while input.get(i).is_some_and(|(c, _)| c.is_whitespace()) {
+                i += 1;
+            }
+        } else if input.get(i)?.0 == p {
+            i += 1;
+        } else {
+            return None;
+        }
+    }
+    Some(i)
+}
+
+/// How the source file is turned into brainfuck commands.
+#[derive(Debug, Clone)]
+pub enum Syntax {
+    Dialect(Dialect),
+    Map(DialectMap),
+}
+
+impl Syntax {
+    pub fn commands<'a>(
+        &self,
+        source: impl Iterator<Item = (char, Span)> + 'a,
+    ) -> Result<Box<dyn Iterator<Item = (char, Span)> + 'a>, Diagnostic> {
+        match self {
+            Syntax::Dialect(dialect) => dialect.commands(source),
+            Syntax::Map(map) => {
+                let source: Vec<_> = source.collect();
+                Ok(Box::new(map.commands(&source).into_iter()))
+            }
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use crate::{toolchain::Toolchain, Profile};
+use std::{fs, io, path::Path};
+
+fn report<T: std::fmt::Display>(what: &str, result: Result<T, io::Error>) -> bool {
+    match result {
+        Ok(detail) => {
+            println!("[ok]   {what}: {detail}");
+            true
+        }
+        Err(e) => {
+            println!("[fail] {what}: {e}");
+            false
+        }
+    }
+}
+
+fn check_writable(dir: &Path) -> Result<String, io::Error> {
+    fs::create_dir_all(dir)?;
+
+    let mut probe = dir.to_path_buf();
+    probe.push(".bfc_doctor");
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)?;
+
+    Ok(dir.display().to_string())
+}
+
+/// Checks everything needed to build with `profile`, printing a line per
+/// check. Returns whether every check passed.
+pub fn run(profile: &Profile, toolchain: &Toolchain) -> bool {
+    println!("Default profile: {}", Profile::default_name());
+    println!(
+        "Checking profile: {} ({})",
+        profile.name(),
+        profile.source()
+    );
+
+    let checks = [
+        report("assembler", toolchain.nasm_version()),
+        report("linker", toolchain.linker_version()),
+        report("config dir", check_writable(Profile::config_path())),
+        report("cache dir", check_writable(Toolchain::cache_path())),
+    ];
+
+    checks.iter().all(|&ok| ok)
+}
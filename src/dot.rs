@@ -0,0 +1,68 @@
+use crate::lex::{Span, Token};
+
+struct Node {
+    label: String,
+    direct: usize,
+    total: usize,
+    children: Vec<usize>,
+}
+
+/// Renders the nesting of loops in `tokens` as a Graphviz digraph, with one
+/// node per loop labelled with the number of instructions it contains.
+pub fn generate(tokens: &[(Token, Span)]) -> Vec<String> {
+    let mut nodes = vec![Node {
+        label: "program".to_string(),
+        direct: 0,
+        total: 0,
+        children: vec![],
+    }];
+    let mut stack = vec![0];
+
+    for &(token, span) in tokens {
+        if !matches!(token, Token::LoopEnd(_)) {
+            for &open in &stack {
+                nodes[open].total += 1;
+            }
+        }
+
+        match token {
+            Token::LoopStart(_) => {
+                // Loops are numbered from 0 in the order they start
+                let id = nodes.len();
+                nodes.push(Node {
+                    label: format!("loop {}\\n{span}", id - 1),
+                    direct: 0,
+                    total: 0,
+                    children: vec![],
+                });
+                let parent = *stack.last().unwrap();
+                nodes[parent].direct += 1;
+                nodes[parent].children.push(id);
+                stack.push(id);
+            }
+            Token::LoopEnd(_) => {
+                stack.pop();
+            }
+            _ => nodes[*stack.last().unwrap()].direct += 1,
+        }
+    }
+
+    let mut lines = vec![
+        "digraph program {".to_string(),
+        "    node [shape=box];".to_string(),
+    ];
+    for (id, node) in nodes.iter().enumerate() {
+        lines.push(format!(
+            "    n{id} [label=\"{}\\n{} direct, {} total\"];",
+            node.label, node.direct, node.total
+        ));
+    }
+    for (id, node) in nodes.iter().enumerate() {
+        for child in &node.children {
+            lines.push(format!("    n{id} -> n{child};"));
+        }
+    }
+    lines.push("}".to_string());
+
+    lines
+}
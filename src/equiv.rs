@@ -0,0 +1,112 @@
+use crate::{
+    interpret::{Config, Machine, Program},
+    lex::Tokens,
+};
+use std::{fs, io, path::PathBuf};
+
+/// How a run of a program on one input ended.
+#[derive(Debug, PartialEq)]
+enum Ending {
+    Finished,
+    Failed(String),
+    OutOfFuel,
+}
+
+impl Ending {
+    fn describe(&self) -> String {
+        match self {
+            Ending::Finished => "finished".to_string(),
+            Ending::Failed(e) => format!("failed: {e}"),
+            Ending::OutOfFuel => "ran out of fuel, so may not finish".to_string(),
+        }
+    }
+}
+
+pub struct Run {
+    output: Vec<u8>,
+    ending: Ending,
+}
+
+/// Runs `tokens` on `input` for at most `fuel` instructions.
+pub fn run(tokens: &Tokens, config: &Config, input: &[u8], fuel: u64) -> Run {
+    let mut program = Program::new(tokens);
+    let mut output = vec![];
+    let result = Machine::new(config).resume(&mut program, fuel, &mut &input[..], &mut output);
+    let ending = match result {
+        Err(e) => Ending::Failed(e.to_string()),
+        Ok(_) if program.is_finished() => Ending::Finished,
+        Ok(_) => Ending::OutOfFuel,
+    };
+    Run { output, ending }
+}
+
+/// Shows an output byte as a character if it's printable.
+fn show(byte: u8) -> String {
+    if byte.is_ascii_graphic() || byte == b' ' {
+        format!("{:?}", byte as char)
+    } else {
+        format!("byte {byte}")
+    }
+}
+
+/// Describes the first difference between the runs of the programs `names`,
+/// or gives `None` if they behaved the same. Two runs that fail, or that
+/// both run out of fuel, count as the same.
+pub fn compare(names: [&str; 2], runs: [&Run; 2]) -> Option<String> {
+    let [a, b] = runs;
+    let common = a
+        .output
+        .iter()
+        .zip(&b.output)
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    match (a.output.get(common), b.output.get(common)) {
+        (Some(&x), Some(&y)) => Some(format!(
+            "at output byte {common}, {} wrote {} but {} wrote {}",
+            names[0],
+            show(x),
+            names[1],
+            show(y)
+        )),
+        // The shorter output may only be short because it ran out of fuel
+        (Some(_), None) if b.ending == Ending::OutOfFuel => None,
+        (None, Some(_)) if a.ending == Ending::OutOfFuel => None,
+        (Some(&x), None) => Some(format!(
+            "at output byte {common}, {} wrote {} but {} {}",
+            names[0],
+            show(x),
+            names[1],
+            b.ending.describe()
+        )),
+        (None, Some(&y)) => Some(format!(
+            "at output byte {common}, {} {} but {} wrote {}",
+            names[0],
+            a.ending.describe(),
+            names[1],
+            show(y)
+        )),
+        (None, None) => match (&a.ending, &b.ending) {
+            (Ending::Failed(_), Ending::Failed(_)) => None,
+            (x, y) if x == y => None,
+            (x, y) => Some(format!(
+                "after {common} bytes of output, {} {} but {} {}",
+                names[0],
+                x.describe(),
+                names[1],
+                y.describe()
+            )),
+        },
+    }
+}
+
+/// The files in `dir` to use as inputs, sorted by name.
+pub fn discover(dir: &str) -> Result<Vec<PathBuf>, io::Error> {
+    let mut inputs: Vec<PathBuf> = fs::read_dir(dir)?
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+        .map(|entry| entry.path())
+        .collect();
+    inputs.sort();
+    Ok(inputs)
+}
@@ -0,0 +1,84 @@
+use crate::{diagnostics::Diagnostic, lex::Span};
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+/// Each external function gets one of these private-use characters, so that
+/// its calls pass through dialect translation to the lexer as one character.
+const FIRST_CALL: u32 = 0xF0000;
+const LAST_CALL: u32 = 0xFFFFD;
+
+/// Names of the external functions called so far, indexed by their ids
+static NAMES: Lazy<Mutex<Vec<&'static str>>> = Lazy::new(Mutex::default);
+
+fn error(message: impl Into<String>, span: Span) -> Diagnostic {
+    Diagnostic::error("E0016", message).with_span(span)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// The id of the external function `name`, giving it one if it's new.
+fn intern(name: &str) -> Option<u32> {
+    let mut names = NAMES.lock().unwrap();
+    let id = match names.iter().position(|&n| n == name) {
+        Some(id) => id,
+        None => {
+            names.push(Box::leak(name.to_string().into_boxed_str()));
+            names.len() - 1
+        }
+    };
+    u32::try_from(id)
+        .ok()
+        .filter(|&id| id <= LAST_CALL - FIRST_CALL)
+}
+
+/// The id of the external function `name`, called at `span`, giving it one
+/// if it's new.
+pub(crate) fn id(name: &str, span: Span) -> Result<u32, Diagnostic> {
+    if name.is_empty() || !name.chars().all(is_name_char) || name.starts_with(char::is_numeric) {
+        return Err(error(
+            format!("`{name}` is not a valid external function name"),
+            span,
+        ));
+    }
+    intern(name).ok_or_else(|| error("too many external functions", span))
+}
+
+/// The name of the external function with the id `id`.
+pub fn name(id: u32) -> &'static str {
+    NAMES.lock().unwrap()[id as usize]
+}
+
+/// The id of the external function that `c` calls, if it stands for a call.
+pub fn call_id(c: char) -> Option<u32> {
+    (FIRST_CALL..=LAST_CALL)
+        .contains(&(c as u32))
+        .then(|| c as u32 - FIRST_CALL)
+}
+
+/// Replaces each `%name%` call in `source` with the character standing for
+/// it, keeping the position of its first `%`.
+pub fn extract(source: &[(char, Span)]) -> Result<Vec<(char, Span)>, Diagnostic> {
+    let mut output = vec![];
+
+    let mut i = 0;
+    while i < source.len() {
+        let (c, span) = source[i];
+        if c != '%' {
+            output.push((c, span));
+            i += 1;
+            continue;
+        }
+
+        let Some(len) = source[i + 1..].iter().position(|(c, _)| *c == '%') else {
+            return Err(error("unterminated external call", span));
+        };
+        let name: String = source[i + 1..i + 1 + len].iter().map(|(c, _)| c).collect();
+        let id = id(&name, span)?;
+        output.push((char::from_u32(FIRST_CALL + id).unwrap(), span));
+        i += len + 2;
+    }
+
+    Ok(output)
+}
@@ -0,0 +1,205 @@
+#[derive(Debug, Clone, Copy)]
+pub struct Style {
+    /// Longest line to fill up to, though long comment words may overflow it
+    pub width: usize,
+    /// Spaces per level of loop nesting
+    pub indent: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Atom {
+    Open,
+    Close,
+    /// A run of non-bracket characters without whitespace
+    Text(String),
+    /// A blank line, kept to separate paragraphs
+    Break,
+}
+
+fn is_command(c: char) -> bool {
+    "><+-.,".contains(c)
+}
+
+/// Splits `contents` into atoms, each paired with whether whitespace came
+/// before it.
+fn atoms(contents: &str) -> Vec<(Atom, bool)> {
+    let mut atoms = vec![];
+    let mut text = String::new();
+    let mut space = false;
+    let mut newlines = 0;
+
+    for c in contents.chars() {
+        if (c.is_whitespace() || c == '[' || c == ']') && !text.is_empty() {
+            atoms.push((Atom::Text(std::mem::take(&mut text)), space));
+            space = false;
+        }
+        if c.is_whitespace() {
+            space = true;
+            if c == '\n' {
+                newlines += 1;
+            }
+            continue;
+        }
+        if newlines >= 2 && !atoms.is_empty() {
+            atoms.push((Atom::Break, true));
+        }
+        newlines = 0;
+
+        match c {
+            '[' => atoms.push((Atom::Open, std::mem::take(&mut space))),
+            ']' => atoms.push((Atom::Close, std::mem::take(&mut space))),
+            _ => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        atoms.push((Atom::Text(text), space));
+    }
+
+    atoms
+}
+
+/// Returns the index of the `Close` matching the `Open` at `start`.
+fn matching_close(atoms: &[(Atom, bool)], start: usize) -> usize {
+    let mut depth = 0;
+    for (i, (atom, _)) in atoms.iter().enumerate().skip(start) {
+        match atom {
+            Atom::Open => depth += 1,
+            Atom::Close if depth == 1 => return i,
+            Atom::Close => depth -= 1,
+            _ => {}
+        }
+    }
+    unreachable!("brackets are checked before formatting")
+}
+
+/// Renders a loop with no nested loops or paragraph breaks on one line.
+fn inline_loop(inner: &[(Atom, bool)]) -> Option<String> {
+    let mut text = "[".to_string();
+    for (i, (atom, space)) in inner.iter().enumerate() {
+        let Atom::Text(word) = atom else {
+            return None;
+        };
+        if *space && i > 0 {
+            text.push(' ');
+        }
+        text.push_str(word);
+    }
+    text.push(']');
+    Some(text)
+}
+
+struct Writer {
+    style: Style,
+    lines: Vec<String>,
+    line: String,
+    depth: usize,
+}
+
+impl Writer {
+    fn margin(&self) -> String {
+        " ".repeat(self.style.indent * self.depth)
+    }
+
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            self.lines.push(std::mem::take(&mut self.line));
+        }
+    }
+
+    fn push_line(&mut self, text: &str) {
+        self.flush();
+        self.lines.push(format!("{}{text}", self.margin()));
+    }
+
+    fn fits(&self, space: bool, len: usize) -> bool {
+        self.line.len() + space as usize + len <= self.style.width
+    }
+
+    fn push_word(&mut self, mut word: &str, space: bool) {
+        let commands = word.chars().all(is_command);
+        let space = space && !self.line.is_empty();
+
+        if !self.line.is_empty() && !self.fits(space, word.len()) {
+            // Runs of commands can be split anywhere, so fill the line first
+            let room = self
+                .style
+                .width
+                .saturating_sub(self.line.len() + space as usize);
+            if commands && room > 0 {
+                if space {
+                    self.line.push(' ');
+                }
+                self.line.push_str(&word[..room]);
+                word = &word[room..];
+            }
+            self.flush();
+        }
+
+        if self.line.is_empty() {
+            let margin = self.margin();
+            if commands {
+                let room = self.style.width.saturating_sub(margin.len()).max(1);
+                while word.len() > room {
+                    self.lines.push(format!("{margin}{}", &word[..room]));
+                    word = &word[room..];
+                }
+            }
+            self.line = format!("{margin}{word}");
+        } else {
+            if space {
+                self.line.push(' ');
+            }
+            self.line.push_str(word);
+        }
+    }
+
+    fn push_break(&mut self) {
+        self.flush();
+        if self.lines.last().is_some_and(|line| !line.is_empty()) {
+            self.lines.push(String::new());
+        }
+    }
+}
+
+/// Reflows brainfuck source to fit `style`, giving loops that don't fit on a
+/// line their own indented block. Comments are kept, but whitespace between
+/// words is not, apart from blank lines. The brackets in `contents` must match.
+pub fn format(contents: &str, style: Style) -> String {
+    let atoms = atoms(contents);
+    let mut writer = Writer {
+        style,
+        lines: vec![],
+        line: String::new(),
+        depth: 0,
+    };
+
+    let mut i = 0;
+    while i < atoms.len() {
+        let (atom, space) = &atoms[i];
+        match atom {
+            Atom::Text(word) => writer.push_word(word, *space),
+            Atom::Break => writer.push_break(),
+            Atom::Open => {
+                let end = matching_close(&atoms, i);
+                let room = style.width.saturating_sub(writer.margin().len());
+                if let Some(text) = inline_loop(&atoms[i + 1..end]).filter(|t| t.len() <= room) {
+                    writer.push_word(&text, *space);
+                    i = end;
+                } else {
+                    writer.push_line("[");
+                    writer.depth += 1;
+                }
+            }
+            Atom::Close => {
+                writer.depth -= 1;
+                writer.push_line("]");
+            }
+        }
+        i += 1;
+    }
+    writer.flush();
+
+    let mut output = writer.lines.join("\n");
+    output.push('\n');
+    output
+}
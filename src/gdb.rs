@@ -0,0 +1,173 @@
+use crate::{
+    lex::{Span, Token},
+    profile::{Profile, Variables},
+};
+
+/// The gdb commands, with the details of the program filled in where the
+/// placeholders are
+const SCRIPT: &str = r#"# gdb commands for debugging {binary}, written by bfc. Load them with
+#   source {script}
+# then use bf-tape to see the tape, bf-step to run to the next brainfuck
+# instruction and bf-where to see which one is running.
+import bisect
+
+import gdb
+
+POINTER = "{pointer}"
+TAPE = "{tape}"
+CELL_BYTES = {cell_bytes}
+TAPE_SIZE = {tape_size}
+TAPE_WINDOW = 16
+
+# Where each instruction came from and what it is, by the number in its label
+INSTRUCTIONS = [
+{instructions}
+]
+
+_starts = None
+
+
+def starts():
+    """Where the code of each instruction starts, in order, with its number."""
+    global _starts
+    if _starts is None:
+        _starts = []
+        for i in range(len(INSTRUCTIONS)):
+            try:
+                address = int(gdb.parse_and_eval("(long)&{prefix}%d" % i))
+            except gdb.error:
+                continue
+            _starts.append((address, i))
+        _starts.sort()
+    return _starts
+
+
+def pc():
+    return int(gdb.parse_and_eval("(long)$pc"))
+
+
+def current():
+    """The number of the instruction running, if any has started."""
+    found = starts()
+    i = bisect.bisect_right(found, (pc(), len(INSTRUCTIONS))) - 1
+    return found[i][1] if i >= 0 else None
+
+
+def show_current():
+    i = current()
+    if i is None:
+        print("not yet at a brainfuck instruction")
+        return
+    position, text = INSTRUCTIONS[i]
+    exact = "" if (pc(), i) in starts() else ", part way through"
+    print("instruction %d at %s: %s%s" % (i, position, text, exact))
+
+
+class Tape(gdb.Command):
+    """Show the cells around the pointer, marking the one it's on.
+Usage: bf-tape [START [COUNT]]"""
+
+    def __init__(self):
+        super().__init__("bf-tape", gdb.COMMAND_DATA)
+
+    def invoke(self, arg, from_tty):
+        words = [int(word) for word in arg.split()]
+        tape = int(gdb.parse_and_eval("(long)&" + TAPE))
+        pointer = (int(gdb.parse_and_eval("(long)$" + POINTER)) - tape) // CELL_BYTES
+        count = min(words[1] if len(words) > 1 else TAPE_WINDOW, TAPE_SIZE)
+        start = words[0] if words else max(pointer - count // 2, 0)
+        start = min(start, TAPE_SIZE - count)
+
+        memory = bytes(
+            gdb.selected_inferior().read_memory(
+                tape + start * CELL_BYTES, count * CELL_BYTES
+            )
+        )
+        cells = []
+        for i in range(count):
+            value = int.from_bytes(
+                memory[i * CELL_BYTES : (i + 1) * CELL_BYTES], "little"
+            )
+            cells.append("[%d]" % value if start + i == pointer else str(value))
+        print("%d: %s" % (start, " ".join(cells)))
+        print("pointer at cell %d" % pointer)
+
+
+class Step(gdb.Command):
+    """Run to the start of the next brainfuck instruction, stepping over
+calls. Usage: bf-step [COUNT]"""
+
+    def __init__(self):
+        super().__init__("bf-step", gdb.COMMAND_RUNNING)
+
+    def invoke(self, arg, from_tty):
+        addresses = {address for address, _ in starts()}
+        for _ in range(int(arg) if arg.strip() else 1):
+            try:
+                gdb.execute("nexti", to_string=True)
+                while pc() not in addresses:
+                    gdb.execute("nexti", to_string=True)
+            except gdb.error as e:
+                print(e)
+                return
+        show_current()
+
+
+class Where(gdb.Command):
+    """Show which brainfuck instruction is running. Usage: bf-where"""
+
+    def __init__(self):
+        super().__init__("bf-where", gdb.COMMAND_STACK)
+
+    def invoke(self, arg, from_tty):
+        show_current()
+
+
+Tape()
+Step()
+Where()
+"#;
+
+/// Starts the label marking where each instruction's code starts
+const LABEL_PREFIX: &str = "bf_";
+
+/// The label marking where the code for the instruction at `index` starts.
+pub fn label(index: usize) -> String {
+    format!("{LABEL_PREFIX}{index}")
+}
+
+/// Quotes `text` as a Python string, which JSON strings also are.
+fn quote(text: &str) -> String {
+    serde_json::to_string(text).unwrap()
+}
+
+/// A gdb script for `binary`, saved as `script`, defining commands to show
+/// the tape and step through `tokens`, which are what codegen labelled.
+pub fn script(
+    profile: &Profile,
+    vars: &Variables,
+    tokens: &[(Token, Span)],
+    binary: &str,
+    script: &str,
+) -> String {
+    let instructions: Vec<String> = tokens
+        .iter()
+        .map(|(token, span)| {
+            format!(
+                "    ({}, {}),",
+                quote(&span.to_string()),
+                quote(&token.to_bf())
+            )
+        })
+        .collect();
+
+    SCRIPT
+        .replace("{binary}", binary)
+        .replace("{script}", script)
+        .replace("{pointer}", profile.gdb_pointer())
+        .replace("{tape}", profile.gdb_tape())
+        .replace("{cell_bytes}", &vars["cell_bytes"])
+        .replace("{tape_size}", &vars["tape_size"])
+        .replace("{prefix}", LABEL_PREFIX)
+        .replace("{instructions}", &instructions.join("\n"))
+}
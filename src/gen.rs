@@ -0,0 +1,159 @@
+use crate::{
+    interpret::{Config, Eof, Machine, Program},
+    lex,
+};
+use std::io;
+
+/// Deepest loop nesting a generated program has
+const MAX_DEPTH: usize = 8;
+
+/// Most programs tried when looking for one that terminates
+pub const MAX_ATTEMPTS: usize = 1000;
+
+/// Commands to pick from, each with how often it's picked relative to the
+/// others
+const WEIGHTS: &[(char, u32)] = &[
+    ('+', 4),
+    ('-', 3),
+    ('>', 3),
+    ('<', 3),
+    ('[', 2),
+    (']', 2),
+    ('.', 2),
+    (',', 1),
+];
+
+/// A splitmix64 generator, which is all a reproducible stream of programs
+/// needs.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u32) -> u32 {
+        (self.next() % u64::from(n)) as u32
+    }
+}
+
+/// Generates a program of exactly `size` commands with balanced brackets.
+/// Every loop body moves the pointer back to where it started, so the
+/// pointer never leaves the tape to the left and never goes past cell
+/// `size`.
+pub fn generate(size: usize, rng: &mut Rng) -> String {
+    let mut program = String::with_capacity(size);
+    // How far the body of each open loop has moved the pointer so far
+    let mut open: Vec<i64> = vec![];
+    let mut pos: i64 = 0;
+
+    while program.len() < size {
+        let left = size - program.len();
+        // Closing every loop means moving back to where its body started
+        let needed: usize = open
+            .iter()
+            .map(|moved| moved.unsigned_abs() as usize + 1)
+            .sum();
+
+        let moved = open.last().copied();
+        // A move inside a loop takes it further from or closer to where its
+        // body started, changing what's needed to close it
+        let step_allowed = |step: i64| match moved {
+            Some(moved) if moved * step < 0 => true,
+            Some(_) => left >= needed + 2,
+            None => left > needed,
+        };
+        let allowed = |c: char| match c {
+            '[' => open.len() < MAX_DEPTH && left >= needed + 2,
+            ']' => moved == Some(0),
+            '<' => pos > 0 && step_allowed(-1),
+            '>' => step_allowed(1),
+            _ => left > needed,
+        };
+        let choices: Vec<(char, u32)> = WEIGHTS
+            .iter()
+            .copied()
+            .filter(|&(c, _)| allowed(c))
+            .collect();
+
+        let c = if left == needed || choices.is_empty() {
+            // Only room left to close the innermost loop
+            match moved {
+                Some(moved) if moved > 0 => '<',
+                Some(moved) if moved < 0 => '>',
+                _ => ']',
+            }
+        } else {
+            let total = choices.iter().map(|&(_, weight)| weight).sum();
+            let mut pick = rng.below(total);
+            choices
+                .iter()
+                .find(|&&(_, weight)| {
+                    let found = pick < weight;
+                    pick = pick.saturating_sub(weight);
+                    found
+                })
+                .unwrap()
+                .0
+        };
+
+        match c {
+            '[' => open.push(0),
+            ']' => {
+                open.pop();
+            }
+            '>' | '<' => {
+                let step = if c == '>' { 1 } else { -1 };
+                pos += step;
+                if let Some(moved) = open.last_mut() {
+                    *moved += step;
+                }
+            }
+            _ => {}
+        }
+        program.push(c);
+    }
+
+    program
+}
+
+/// Whether the interpreter finishes `program` within `fuel` instructions,
+/// with no input.
+pub fn terminates(program: &str, fuel: u64) -> bool {
+    let Ok(tokens) = lex::lex(lex::spanned_chars(program, None)).tokens() else {
+        return false;
+    };
+    let config = Config {
+        tape_size: program.len() + 1,
+        cell_bits: 8,
+        eof: Eof::Zero,
+        trace: None,
+        heatmap: None,
+        max_steps: None,
+        timeout: None,
+        max_tape: None,
+        max_output: None,
+        pause_at_debug: false,
+        pause: None,
+    };
+    let mut program = Program::new(&tokens);
+    let result =
+        Machine::new(&config).resume(&mut program, fuel, &mut io::empty(), &mut io::sink());
+    result.is_ok() && program.is_finished()
+}
+
+/// Generates programs of `size` commands until one terminates within `fuel`
+/// instructions, giving up after `MAX_ATTEMPTS`.
+pub fn generate_terminating(size: usize, fuel: u64, rng: &mut Rng) -> Option<String> {
+    (0..MAX_ATTEMPTS)
+        .map(|_| generate(size, rng))
+        .find(|program| terminates(program, fuel))
+}
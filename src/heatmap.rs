@@ -0,0 +1,122 @@
+use std::fmt::Write;
+
+/// Widest a bar of the text histogram gets
+const BAR_WIDTH: u64 = 40;
+
+/// How a heatmap is written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// A histogram of the cells used, with gaps between them skipped
+    Text,
+    /// A `cell,reads,writes` row for each cell used
+    Csv,
+}
+
+/// How often each cell of the tape has been read and written. Only grows as
+/// far as the cells used, so a program that stays near the start of a large
+/// tape doesn't pay for all of it.
+#[derive(Debug, Clone, Default)]
+pub struct Heatmap {
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+}
+
+/// Adds one to the count for `cell`, growing `counts` to reach it.
+fn bump(counts: &mut Vec<u64>, cell: usize) {
+    if cell >= counts.len() {
+        counts.resize(cell + 1, 0);
+    }
+    counts[cell] += 1;
+}
+
+impl Heatmap {
+    pub fn read(&mut self, cell: usize) {
+        bump(&mut self.reads, cell);
+    }
+
+    pub fn write(&mut self, cell: usize) {
+        bump(&mut self.writes, cell);
+    }
+
+    /// Each cell that's been read or written, with how many times.
+    pub fn cells(&self) -> impl Iterator<Item = (usize, u64, u64)> + '_ {
+        (0..self.reads.len().max(self.writes.len()))
+            .map(|cell| {
+                let reads = self.reads.get(cell).copied().unwrap_or(0);
+                let writes = self.writes.get(cell).copied().unwrap_or(0);
+                (cell, reads, writes)
+            })
+            .filter(|&(_, reads, writes)| reads + writes > 0)
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Text => self.histogram(),
+            Format::Csv => self.csv(),
+        }
+    }
+
+    fn csv(&self) -> String {
+        let mut csv = "cell,reads,writes\n".to_string();
+        for (cell, reads, writes) in self.cells() {
+            writeln!(csv, "{cell},{reads},{writes}").unwrap();
+        }
+        csv
+    }
+
+    /// A line for each cell used, with a bar as long as its share of the
+    /// busiest cell's uses. Runs of unused cells between them get a line
+    /// saying how many were skipped.
+    fn histogram(&self) -> String {
+        let cells: Vec<_> = self.cells().collect();
+        let (Some(first), Some(last)) = (cells.first(), cells.last()) else {
+            return "heatmap: no cells used\n".to_string();
+        };
+        let busiest = cells
+            .iter()
+            .map(|&(_, reads, writes)| reads + writes)
+            .max()
+            .unwrap();
+
+        let mut text = String::new();
+        writeln!(
+            text,
+            "heatmap: {} cells used, from cell {} to {}",
+            cells.len(),
+            first.0,
+            last.0
+        )
+        .unwrap();
+
+        let cell_width = last.0.to_string().len().max("cell".len());
+        let count_width = busiest.to_string().len().max("writes".len());
+        writeln!(
+            text,
+            "{:>cell_width$} {:>count_width$} {:>count_width$}",
+            "cell", "reads", "writes"
+        )
+        .unwrap();
+
+        let mut next = first.0;
+        for &(cell, reads, writes) in &cells {
+            if cell > next {
+                writeln!(
+                    text,
+                    "{:>cell_width$} ({} unused cells)",
+                    "...",
+                    cell - next
+                )
+                .unwrap();
+            }
+            let bar = ((reads + writes) * BAR_WIDTH).div_ceil(busiest);
+            writeln!(
+                text,
+                "{cell:>cell_width$} {reads:>count_width$} {writes:>count_width$} {}",
+                "#".repeat(bar as usize)
+            )
+            .unwrap();
+            next = cell + 1;
+        }
+        text
+    }
+}
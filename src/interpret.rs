@@ -0,0 +1,1088 @@
+use crate::{
+    checkpoint::{self, Checkpoint},
+    heatmap::{self, Heatmap},
+    lex::{Span, Token},
+    profile::Variables,
+};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub tape_size: usize,
+    pub cell_bits: u32,
+    /// What `,` leaves in the cell at the end of input
+    pub eof: Eof,
+    /// Logs the instructions run to stderr, if set
+    pub trace: Option<Trace>,
+    /// Counts how often each cell is read and written, and has `run` write
+    /// the counts to stderr in this format, if set
+    pub heatmap: Option<heatmap::Format>,
+    /// Most ops a program may run before it's stopped
+    pub max_steps: Option<u64>,
+    /// Longest a program may run before it's stopped. Without the `native`
+    /// feature there's no clock to check, so this is ignored.
+    pub timeout: Option<Duration>,
+    /// Most cells of the tape to allocate, stopping the program if it goes
+    /// further
+    pub max_tape: Option<usize>,
+    /// Most bytes a program may write before it's stopped
+    pub max_output: Option<u64>,
+    /// Pauses the program with `Error::Paused` just after each `#`, rather
+    /// than showing the tape
+    pub pause_at_debug: bool,
+    /// Pauses the program with `Error::Paused` soon after this is set, and
+    /// clears it. Without the `native` feature this is ignored, like the
+    /// timeout.
+    pub pause: Option<&'static AtomicBool>,
+}
+
+/// What `,` does at the end of input. Implementations disagree, so programs
+/// written for one may need another.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Eof {
+    /// Set the cell to zero, as compiled programs do
+    #[default]
+    Zero,
+    /// Set the cell to its largest value, -1 when read as signed
+    MinusOne,
+    /// Leave the cell as it was
+    Unchanged,
+}
+
+/// Kinds of instruction a trace can be limited to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TraceKind {
+    /// Loop starts and ends, whether they jump or not, and scans
+    Loops,
+    /// `.`, `,` and `#`
+    Io,
+    /// Procedure definitions, returns and calls, and external calls
+    Calls,
+    /// Pointer moves, including to the other tape
+    Moves,
+    /// Changes to cells other than by input
+    Cells,
+}
+
+impl TraceKind {
+    fn of(token: Token) -> TraceKind {
+        match token {
+            Token::LoopStart(_)
+            | Token::LoopEnd(_)
+            | Token::ScanRight { .. }
+            | Token::ScanLeft { .. } => TraceKind::Loops,
+            Token::PutChar | Token::GetChar | Token::Debug => TraceKind::Io,
+            Token::ProcStart(_) | Token::ProcEnd(_) | Token::Call | Token::Extern(_) => {
+                TraceKind::Calls
+            }
+            Token::PtrAdd(_) | Token::PtrSub(_) | Token::SwitchTape => TraceKind::Moves,
+            Token::Add(_)
+            | Token::Sub(_)
+            | Token::SetZero
+            | Token::MulAdd { .. }
+            | Token::AddAt { .. }
+            | Token::ExchangeCell => TraceKind::Cells,
+        }
+    }
+}
+
+/// What to log of a program as it runs.
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    /// Kinds of instruction to log, or every kind if empty
+    pub kinds: Vec<TraceKind>,
+    /// Most instructions to log before the trace stops
+    pub limit: Option<u64>,
+}
+
+/// A trace in progress, logging each instruction with where the pointer is
+/// and what the current cell holds before it runs.
+#[derive(Debug, Clone)]
+struct Tracer {
+    trace: Trace,
+    logged: u64,
+}
+
+impl Tracer {
+    fn log(&mut self, (token, span): (Token, Span), ptr: usize, cell: u64) -> io::Result<()> {
+        if self.trace.limit.is_some_and(|limit| self.logged >= limit)
+            || !(self.trace.kinds.is_empty() || self.trace.kinds.contains(&TraceKind::of(token)))
+        {
+            return Ok(());
+        }
+
+        let mut stderr = io::stderr().lock();
+        writeln!(stderr, "trace: {span} {token:?} ptr={ptr} cell={cell}")?;
+        self.logged += 1;
+        if self.trace.limit == Some(self.logged) {
+            writeln!(stderr, "trace: stopped after {} instructions", self.logged)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<&Variables> for Config {
+    fn from(vars: &Variables) -> Self {
+        Self {
+            tape_size: vars["tape_size"].parse().unwrap_or(30000),
+            cell_bits: vars["cell_bytes"].parse::<u32>().unwrap_or(1) * 8,
+            eof: Eof::Zero,
+            trace: None,
+            heatmap: None,
+            max_steps: None,
+            timeout: None,
+            max_tape: None,
+            max_output: None,
+            pause_at_debug: false,
+            pause: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    OutOfBounds(Span),
+    UndefinedProcedure(Span),
+    /// External functions only exist in compiled programs
+    Extern(Span),
+    /// The program went past a limit. Boxed, as it's rare and the error is
+    /// returned through the interpreter's inner loop
+    Stopped(Box<Stopped>),
+    /// The program was paused at `#` or when asked to, so that its state
+    /// can be saved. It carries on from where it stopped when it's resumed.
+    Paused,
+    Io(io::Error),
+}
+
+/// Where a program was stopped for going past a limit.
+#[derive(Debug)]
+pub struct Stopped {
+    pub limit: Limit,
+    /// Ops run before it was stopped, when they're counted
+    pub steps: Option<u64>,
+    /// The token that would have run next
+    pub at: (Token, Span),
+}
+
+/// A limit on how long a program may run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Steps(u64),
+    Time(Duration),
+    /// Cells of tape
+    Tape(usize),
+    /// Bytes of output
+    Output(u64),
+}
+
+/// What a writer cut short by `max_output` fails with.
+#[derive(Debug)]
+struct OutputLimit;
+
+impl fmt::Display for OutputLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "output limit reached")
+    }
+}
+
+impl std::error::Error for OutputLimit {}
+
+/// Passes output on to `inner` until `max` bytes have been written in all,
+/// then fails with `OutputLimit`.
+struct LimitedOutput<'a, W> {
+    inner: &'a mut W,
+    written: &'a mut u64,
+    max: u64,
+}
+
+impl<W: Write> Write for LimitedOutput<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if *self.written + buf.len() as u64 > self.max {
+            return Err(io::Error::other(OutputLimit));
+        }
+        let n = self.inner.write(buf)?;
+        *self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::OutOfBounds(span) => {
+                write!(f, "pointer left the tape at {span}")
+            }
+            Error::UndefinedProcedure(span) => {
+                write!(f, "call to undefined procedure at {span}")
+            }
+            Error::Extern(span) => {
+                write!(f, "external call at {span} can't be interpreted")
+            }
+            Error::Stopped(stopped) => {
+                let Stopped {
+                    limit,
+                    steps,
+                    at: (token, span),
+                } = **stopped;
+                match limit {
+                    Limit::Steps(max) => write!(f, "reached the limit of {max} steps")?,
+                    Limit::Time(time) => write!(f, "timed out after {time:?}")?,
+                    Limit::Tape(max) => write!(f, "reached the limit of {max} tape cells")?,
+                    Limit::Output(max) => write!(f, "reached the limit of {max} bytes of output")?,
+                }
+                write!(f, " at {span}, about to run `{}`", token.to_bf())?;
+                match steps {
+                    Some(steps) => write!(f, ", having run {steps} steps"),
+                    None => Ok(()),
+                }
+            }
+            Error::Paused => write!(f, "paused"),
+            Error::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// A token ready to run, with every jump target worked out in advance and
+/// amounts widened to whole cells. Ops line up one to one with the tokens
+/// they come from, so the program counter also finds an op's span.
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Move(isize),
+    /// Adds to the current cell, with subtraction as wrapping addition
+    Add(u64),
+    /// Jumps to the matching loop end if the current cell is zero
+    LoopStart(usize),
+    /// Jumps back to the matching loop start if the current cell isn't zero
+    LoopEnd(usize),
+    PutChar,
+    GetChar,
+    /// Defines the procedure for the current cell and skips to its end
+    ProcStart(usize),
+    ProcEnd,
+    Call,
+    Extern,
+    Debug,
+    /// Left to the machine, as it needs the other tape
+    SwitchTape,
+    /// Left to the machine, as it needs the other tape
+    ExchangeCell,
+    SetZero,
+    MulAdd {
+        offset: isize,
+        factor: u64,
+    },
+    AddAt {
+        offset: isize,
+        amount: u64,
+    },
+    Scan(isize),
+}
+
+/// Translates `tokens` into ops, matching up every loop and procedure.
+fn compile(tokens: &[(Token, Span)]) -> Vec<Op> {
+    let mut ops = Vec::with_capacity(tokens.len());
+    let mut open = vec![];
+    for (i, &(token, _)) in tokens.iter().enumerate() {
+        let op = match token {
+            Token::PtrAdd(n) => Op::Move(n as isize),
+            Token::PtrSub(n) => Op::Move(-(n as isize)),
+            Token::Add(n) => Op::Add(n.into()),
+            Token::Sub(n) => Op::Add(u64::from(n).wrapping_neg()),
+            Token::LoopStart(_) | Token::ProcStart(_) => {
+                open.push(i);
+                Op::LoopStart(0)
+            }
+            Token::LoopEnd(_) => {
+                let start = open.pop().unwrap();
+                ops[start] = Op::LoopStart(i);
+                Op::LoopEnd(start)
+            }
+            Token::ProcEnd(_) => {
+                let start = open.pop().unwrap();
+                ops[start] = Op::ProcStart(i);
+                Op::ProcEnd
+            }
+            Token::PutChar => Op::PutChar,
+            Token::GetChar => Op::GetChar,
+            Token::Call => Op::Call,
+            Token::Extern(_) => Op::Extern,
+            Token::Debug => Op::Debug,
+            Token::SwitchTape => Op::SwitchTape,
+            Token::ExchangeCell => Op::ExchangeCell,
+            Token::SetZero => Op::SetZero,
+            Token::MulAdd { offset, factor } => Op::MulAdd {
+                offset: offset.into(),
+                factor: factor as i64 as u64,
+            },
+            Token::AddAt { offset, amount } => Op::AddAt {
+                offset: offset.into(),
+                amount: amount as i64 as u64,
+            },
+            Token::ScanRight { stride, .. } => Op::Scan(stride as isize),
+            Token::ScanLeft { stride, .. } => Op::Scan(-(stride as isize)),
+        };
+        ops.push(op);
+    }
+    ops
+}
+
+/// A tape cell of one of the sizes brainfuck programs can be built with.
+trait Cell: Copy + Default + PartialEq {
+    /// The low bits of `n`
+    fn truncate(n: u64) -> Self;
+    fn widen(self) -> u64;
+}
+
+macro_rules! impl_cell {
+    ($($t:ty),*) => {$(
+        impl Cell for $t {
+            fn truncate(n: u64) -> Self {
+                n as $t
+            }
+
+            fn widen(self) -> u64 {
+                self as u64
+            }
+        }
+    )*};
+}
+
+impl_cell!(u8, u16, u32, u64);
+
+/// The index of the cell `offset` away from `ptr`, if it's on the tape.
+fn offset_cell(ptr: usize, offset: isize, len: usize) -> Option<usize> {
+    ptr.checked_add_signed(offset).filter(|&cell| cell < len)
+}
+
+/// Runs `$body` with `$tape` bound to the cells of `$self`, whatever their
+/// size.
+macro_rules! with_tape {
+    ($self:expr, $tape:ident => $body:expr) => {
+        match $self {
+            Tape::U8($tape) => $body,
+            Tape::U16($tape) => $body,
+            Tape::U32($tape) => $body,
+            Tape::U64($tape) => $body,
+        }
+    };
+}
+
+/// The cells of a tape, at the size the program is built with.
+#[derive(Debug, Clone)]
+enum Tape {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+    U64(Vec<u64>),
+}
+
+impl Tape {
+    /// A tape of zeros as long as this one, with cells of the same size.
+    fn blank(&self) -> Tape {
+        match self {
+            Tape::U8(tape) => Tape::U8(vec![0; tape.len()]),
+            Tape::U16(tape) => Tape::U16(vec![0; tape.len()]),
+            Tape::U32(tape) => Tape::U32(vec![0; tape.len()]),
+            Tape::U64(tape) => Tape::U64(vec![0; tape.len()]),
+        }
+    }
+
+    /// The size of a cell in bits.
+    fn cell_bits(&self) -> u32 {
+        match self {
+            Tape::U8(_) => 8,
+            Tape::U16(_) => 16,
+            Tape::U32(_) => 32,
+            Tape::U64(_) => 64,
+        }
+    }
+
+    /// The cells up to the last that isn't zero, widened.
+    fn saved(&self) -> Vec<u64> {
+        with_tape!(self, tape => {
+            let len = tape.iter().rposition(|&cell| cell.widen() != 0);
+            tape[..len.map_or(0, |last| last + 1)]
+                .iter()
+                .map(|cell| cell.widen())
+                .collect()
+        })
+    }
+
+    /// Sets the first cells to `cells`, which are no more than there are,
+    /// and the rest to zero.
+    fn restore(&mut self, cells: &[u64]) {
+        with_tape!(self, tape => {
+            tape.fill(Default::default());
+            for (cell, &value) in tape.iter_mut().zip(cells) {
+                *cell = Cell::truncate(value);
+            }
+        })
+    }
+
+    /// Swaps cell `i` with cell `j` of `other`, which has cells of the same
+    /// size.
+    fn exchange(&mut self, i: usize, other: &mut Tape, j: usize) {
+        match (self, other) {
+            (Tape::U8(a), Tape::U8(b)) => std::mem::swap(&mut a[i], &mut b[j]),
+            (Tape::U16(a), Tape::U16(b)) => std::mem::swap(&mut a[i], &mut b[j]),
+            (Tape::U32(a), Tape::U32(b)) => std::mem::swap(&mut a[i], &mut b[j]),
+            (Tape::U64(a), Tape::U64(b)) => std::mem::swap(&mut a[i], &mut b[j]),
+            _ => unreachable!("both tapes have cells of the same size"),
+        }
+    }
+}
+
+/// The tape and pointer of a program, which carry over from one run to the
+/// next so that code can be run a piece at a time.
+#[derive(Debug, Clone)]
+pub struct Machine {
+    tape: Tape,
+    ptr: usize,
+    /// The other tape and its pointer, once the program has used it
+    other: Option<(Tape, usize)>,
+    eof: Eof,
+    /// Carries over between runs, so the limit covers them all
+    tracer: Option<Tracer>,
+    heatmap: Option<Heatmap>,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    /// The tape the program was built with, which may be longer than the
+    /// one allocated
+    tape_size: usize,
+    max_output: Option<u64>,
+    /// Bytes written by all the runs so far, counted to check `max_output`
+    written: u64,
+    pause_at_debug: bool,
+    pause: Option<&'static AtomicBool>,
+}
+
+impl Machine {
+    pub fn new(config: &Config) -> Self {
+        let len = config
+            .tape_size
+            .min(config.max_tape.unwrap_or(usize::MAX))
+            .max(1);
+        let tape = match config.cell_bits {
+            8 => Tape::U8(vec![0; len]),
+            16 => Tape::U16(vec![0; len]),
+            32 => Tape::U32(vec![0; len]),
+            _ => Tape::U64(vec![0; len]),
+        };
+        let tracer = config
+            .trace
+            .clone()
+            .map(|trace| Tracer { trace, logged: 0 });
+        Self {
+            tape,
+            ptr: 0,
+            other: None,
+            eof: config.eof,
+            tracer,
+            heatmap: config.heatmap.map(|_| Heatmap::default()),
+            max_steps: config.max_steps,
+            timeout: config.timeout.filter(|_| cfg!(feature = "native")),
+            tape_size: config.tape_size.max(1),
+            max_output: config.max_output,
+            written: 0,
+            pause_at_debug: config.pause_at_debug,
+            pause: config.pause.filter(|_| cfg!(feature = "native")),
+        }
+    }
+
+    /// Runs `tokens` from where the last run left off, as `run` does.
+    /// Procedures don't carry over.
+    pub fn run(
+        &mut self,
+        tokens: &[(Token, Span)],
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), Error> {
+        self.finish(&mut Program::new(tokens), input, output)
+    }
+
+    /// Runs the rest of `program`, unless it goes past one of the config's
+    /// limits first, in which case it stops with `Error::Stopped` and the op
+    /// it got to left as the next to run, or it's paused, in which case it
+    /// stops with `Error::Paused` and can be finished later.
+    pub fn finish(
+        &mut self,
+        program: &mut Program,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), Error> {
+        let result = match self.max_output {
+            None => self.finish_in_time(program, input, output),
+            Some(max) => {
+                let mut written = self.written;
+                let mut output = LimitedOutput {
+                    inner: output,
+                    written: &mut written,
+                    max,
+                };
+                let result = self.finish_in_time(program, input, &mut output);
+                self.written = written;
+                result
+            }
+        };
+        result.map_err(|e| self.explain(e, program))
+    }
+
+    /// Runs the rest of `program` within the config's step limit and
+    /// timeout, looking out for being asked to pause.
+    fn finish_in_time(
+        &mut self,
+        program: &mut Program,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), Error> {
+        if self.max_steps.is_none() && self.timeout.is_none() && self.pause.is_none() {
+            self.resume(program, u64::MAX, input, output)?;
+            return Ok(());
+        }
+
+        let max_steps = self.max_steps.unwrap_or(u64::MAX);
+        // Only looked at with a timeout, as there may be no clock otherwise
+        let start = self.timeout.map(|_| Instant::now());
+        let mut steps = 0;
+        loop {
+            let slice = match self.timeout.is_some() || self.pause.is_some() {
+                true => TIME_SLICE.min(max_steps - steps),
+                false => max_steps - steps,
+            };
+            self.resume(program, slice, input, output)?;
+            let Some(at) = program.next() else {
+                return Ok(());
+            };
+            // Only a finished program runs fewer ops than it's given
+            steps += slice;
+            if self
+                .pause
+                .is_some_and(|pause| pause.swap(false, Ordering::Relaxed))
+            {
+                return Err(Error::Paused);
+            }
+
+            let limit = if steps == max_steps {
+                Limit::Steps(max_steps)
+            } else if let (Some(timeout), Some(start)) = (self.timeout, start) {
+                if start.elapsed() < timeout {
+                    continue;
+                }
+                Limit::Time(timeout)
+            } else {
+                continue;
+            };
+            return Err(Error::Stopped(Box::new(Stopped {
+                limit,
+                steps: Some(steps),
+                at,
+            })));
+        }
+    }
+
+    /// Turns `e` into an `Error::Stopped` if it came from going past
+    /// `max_tape` or `max_output`, rather than from the program.
+    fn explain(&self, e: Error, program: &Program) -> Error {
+        let limit = match &e {
+            Error::OutOfBounds(_) if self.past_max_tape(program) => Limit::Tape(self.len()),
+            Error::Io(io) if io.get_ref().is_some_and(|e| e.is::<OutputLimit>()) => {
+                Limit::Output(self.written)
+            }
+            _ => return e,
+        };
+        match program.next() {
+            Some(at) => Error::Stopped(Box::new(Stopped {
+                limit,
+                steps: None,
+                at,
+            })),
+            None => e,
+        }
+    }
+
+    /// Whether the op that left the tape would have stayed on the tape the
+    /// program was built with, had `max_tape` not cut it short.
+    fn past_max_tape(&self, program: &Program) -> bool {
+        let offset = match program.ops.get(program.pc) {
+            Some(
+                &Op::Move(offset)
+                | &Op::MulAdd { offset, .. }
+                | &Op::AddAt { offset, .. }
+                | &Op::Scan(offset),
+            ) => offset,
+            _ => return false,
+        };
+        self.ptr
+            .checked_add_signed(offset)
+            .is_some_and(|cell| cell >= self.len() && cell < self.tape_size)
+    }
+
+    /// Runs at most `steps` more ops of `program`, stopping early if it
+    /// finishes, and gives the steps left over. A failed op is left as the
+    /// next to run.
+    pub fn resume(
+        &mut self,
+        program: &mut Program,
+        steps: u64,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<u64, Error> {
+        let mut steps = steps;
+        loop {
+            match self.execute(program, steps, input, output)? {
+                Stop::Done(left) => return Ok(left),
+                Stop::Tape(op, left) => {
+                    self.run_tape_op(op);
+                    steps = left;
+                }
+                Stop::Debug => return Err(Error::Paused),
+            }
+        }
+    }
+
+    /// Runs ops of `program` until it stops, on the current tape.
+    fn execute(
+        &mut self,
+        program: &mut Program,
+        steps: u64,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<Stop, Error> {
+        let ptr = &mut self.ptr;
+        let eof = self.eof;
+        let pause = self.pause_at_debug;
+        let tracer = self.tracer.as_mut();
+        let heatmap = self.heatmap.as_mut();
+        // Instrumenting is worked out at compile time, so runs without it
+        // don't pay for it
+        if tracer.is_some() || heatmap.is_some() || program.counts.is_some() {
+            with_tape!(&mut self.tape, tape => {
+                execute::<_, true>(
+                    program, tape, ptr, steps, eof, pause, tracer, heatmap, input, output,
+                )
+            })
+        } else {
+            with_tape!(&mut self.tape, tape => {
+                execute::<_, false>(program, tape, ptr, steps, eof, pause, None, None, input, output)
+            })
+        }
+    }
+
+    /// Carries out `op`, which needs the other tape, making it the first time
+    /// it's used.
+    fn run_tape_op(&mut self, op: Op) {
+        let (other, other_ptr) = self.other.get_or_insert_with(|| (self.tape.blank(), 0));
+        match op {
+            Op::SwitchTape => {
+                std::mem::swap(&mut self.tape, other);
+                std::mem::swap(&mut self.ptr, other_ptr);
+            }
+            Op::ExchangeCell => self.tape.exchange(self.ptr, other, *other_ptr),
+            _ => unreachable!("only tape ops are left to the machine"),
+        }
+    }
+
+    /// The state of the machine and of `program` on it, as saved to carry
+    /// on later. `digest` identifies the program, so that the state can't be
+    /// restored to another.
+    pub fn checkpoint(&self, program: &Program, digest: [u8; 32]) -> Checkpoint {
+        Checkpoint {
+            program: digest,
+            cell_bits: self.tape.cell_bits(),
+            tape_len: self.len(),
+            tape: self.tape.saved(),
+            ptr: self.ptr,
+            other: self.other.as_ref().map(|(tape, ptr)| (tape.saved(), *ptr)),
+            pc: program.pc,
+            procedures: (0..=255)
+                .filter_map(|number| Some((number, program.procedures[number as usize]?)))
+                .collect(),
+            calls: program.calls.clone(),
+            written: self.written,
+        }
+    }
+
+    /// Puts the machine and `program` back in the state `checkpoint` saved,
+    /// if it was taken of the program with `digest` on a tape like this one.
+    pub fn restore(
+        &mut self,
+        program: &mut Program,
+        checkpoint: &Checkpoint,
+        digest: [u8; 32],
+    ) -> Result<(), checkpoint::Error> {
+        if checkpoint.program != digest {
+            return Err(checkpoint::Error::Program);
+        }
+        if checkpoint.cell_bits != self.tape.cell_bits() || checkpoint.tape_len != self.len() {
+            return Err(checkpoint::Error::Tape {
+                len: checkpoint.tape_len,
+                bits: checkpoint.cell_bits,
+            });
+        }
+        // Jumps aren't checked when they're taken, so anything that leads to
+        // one has to be where the program would have left it
+        let is = |pc: usize, op: fn(Op) -> bool| program.ops.get(pc).is_some_and(|&o| op(o));
+        let valid = checkpoint.ptr < self.len()
+            && checkpoint
+                .other
+                .as_ref()
+                .is_none_or(|(_, ptr)| *ptr < self.len())
+            && checkpoint.pc <= program.ops.len()
+            && (checkpoint.procedures.iter())
+                .all(|&(_, start)| is(start, |op| matches!(op, Op::ProcStart(_))))
+            && (checkpoint.calls.iter()).all(|&call| is(call, |op| matches!(op, Op::Call)));
+        if !valid {
+            return Err(checkpoint::Error::Malformed);
+        }
+
+        self.tape.restore(&checkpoint.tape);
+        self.ptr = checkpoint.ptr;
+        self.other = checkpoint.other.as_ref().map(|(cells, ptr)| {
+            let mut tape = self.tape.blank();
+            tape.restore(cells);
+            (tape, *ptr)
+        });
+        self.written = checkpoint.written;
+        program.pc = checkpoint.pc;
+        program.procedures = [None; 256];
+        for &(number, start) in &checkpoint.procedures {
+            program.procedures[number as usize] = Some(start);
+        }
+        program.calls = checkpoint.calls.clone();
+        Ok(())
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    /// How often each cell has been read and written, if counting.
+    pub fn heatmap(&self) -> Option<&Heatmap> {
+        self.heatmap.as_ref()
+    }
+
+    /// The number of cells on the tape.
+    pub fn len(&self) -> usize {
+        with_tape!(&self.tape, tape => tape.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn cell(&self, index: usize) -> u64 {
+        with_tape!(&self.tape, tape => tape[index].widen())
+    }
+
+    /// Sets the cell at `index` to the low bits of `value` that fit.
+    pub fn set_cell(&mut self, index: usize, value: u64) {
+        with_tape!(&mut self.tape, tape => tape[index] = Cell::truncate(value))
+    }
+}
+
+/// Ops to run between looks at the clock, when there's a timeout
+const TIME_SLICE: u64 = 1 << 20;
+
+/// A program and how far it's got, so that it can be run a few ops at a time.
+#[derive(Debug, Clone)]
+pub struct Program<'a> {
+    tokens: &'a [(Token, Span)],
+    ops: Vec<Op>,
+    pc: usize,
+    procedures: [Option<usize>; 256],
+    /// Where each procedure running was called from
+    calls: Vec<usize>,
+    /// How many times each op has run, when counting
+    counts: Option<Vec<u64>>,
+}
+
+impl<'a> Program<'a> {
+    pub fn new(tokens: &'a [(Token, Span)]) -> Self {
+        Self {
+            tokens,
+            ops: compile(tokens),
+            pc: 0,
+            procedures: [None; 256],
+            calls: vec![],
+            counts: None,
+        }
+    }
+
+    /// Counts how many times each token runs, which `counts` gives.
+    pub fn counting(mut self) -> Self {
+        self.counts = Some(vec![0; self.ops.len()]);
+        self
+    }
+
+    /// How many times each token has run, if counting.
+    pub fn counts(&self) -> Option<&[u64]> {
+        self.counts.as_deref()
+    }
+
+    /// The token that runs next, or `None` once the program has finished.
+    pub fn next(&self) -> Option<(Token, Span)> {
+        self.tokens.get(self.pc).copied()
+    }
+
+    /// The index of the token that runs next.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pc >= self.ops.len()
+    }
+}
+
+/// Runs `tokens`, reading `,` from `input` and writing `.` to `output`. Input
+/// past the end of the stream reads as the config's `eof` says. Procedures are numbered by the
+/// low byte of the cell, matching the compiled code. Returns the low byte of
+/// the cell the program ends on, which is its exit status with --exit-cell.
+/// The config's heatmap is written to stderr once the program stops, whether
+/// it finished, failed or was stopped by the config's limits.
+pub fn run(
+    tokens: &[(Token, Span)],
+    config: &Config,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<u8, Error> {
+    let mut machine = Machine::new(config);
+    let result = machine.run(tokens, input, output);
+    if let (Some(format), Some(heatmap)) = (config.heatmap, machine.heatmap()) {
+        io::stderr().write_all(heatmap.render(format).as_bytes())?;
+    }
+    result?;
+    Ok(machine.cell(machine.ptr()) as u8)
+}
+
+/// Cells either side of the pointer that `#` shows
+const DEBUG_RADIUS: usize = 4;
+
+/// Writes the pointer and the cells around it to stderr for `#`, in the same
+/// form as the runtime routine compiled programs call.
+fn debug_dump<C: Cell>(tape: &[C], ptr: usize) -> io::Result<()> {
+    let start = ptr.saturating_sub(DEBUG_RADIUS);
+    let end = (ptr + DEBUG_RADIUS).min(tape.len() - 1);
+    let cells: Vec<String> = (start..=end)
+        .map(|cell| match tape[cell].widen() {
+            value if cell == ptr => format!("[{value}]"),
+            value => value.to_string(),
+        })
+        .collect();
+    writeln!(
+        io::stderr().lock(),
+        "#: pointer at cell {ptr}, cells {start} to {end}: {}",
+        cells.join(" ")
+    )
+}
+
+/// Counts the cells `op` reads and writes when it runs with the pointer at
+/// `ptr`, other than the cells a scan passes, which it counts as it goes.
+fn record(heatmap: &mut Heatmap, op: Op, ptr: usize, is_zero: bool, len: usize) {
+    match op {
+        // What `#` shows isn't part of the program's own use of the tape
+        Op::Move(_) | Op::ProcEnd | Op::Extern | Op::Debug | Op::SwitchTape => {}
+        Op::Add(_) | Op::GetChar | Op::SetZero | Op::ExchangeCell => heatmap.write(ptr),
+        Op::LoopStart(_)
+        | Op::LoopEnd(_)
+        | Op::PutChar
+        | Op::ProcStart(_)
+        | Op::Call
+        | Op::Scan(_) => heatmap.read(ptr),
+        Op::MulAdd { offset, .. } => {
+            heatmap.read(ptr);
+            // The other cell is only touched if the loop would have run
+            if let Some(target) = offset_cell(ptr, offset, len).filter(|_| !is_zero) {
+                heatmap.write(target);
+            }
+        }
+        Op::AddAt { offset, .. } => {
+            if let Some(target) = offset_cell(ptr, offset, len) {
+                heatmap.write(target);
+            }
+        }
+    }
+}
+
+/// Where `execute` stopped, when it didn't fail.
+enum Stop {
+    /// At the end of the program, or of the steps it was given, with the
+    /// steps left over
+    Done(u64),
+    /// Just after an op that needs the other tape, for the machine to carry
+    /// out, with the steps left
+    Tape(Op, u64),
+    /// Just after `#`, when the machine pauses there
+    Debug,
+}
+
+/// Runs up to `steps` ops of `program` on `tape` starting from the cell
+/// `start`, which is left wherever the pointer ends up, even if the program
+/// fails. Instructions are only traced and counted, and cells only counted
+/// in the heatmap, when `INSTRUMENTED` is set.
+#[allow(clippy::too_many_arguments)]
+fn execute<C: Cell, const INSTRUMENTED: bool>(
+    program: &mut Program,
+    tape: &mut [C],
+    start: &mut usize,
+    mut steps: u64,
+    eof: Eof,
+    pause_at_debug: bool,
+    mut tracer: Option<&mut Tracer>,
+    mut heatmap: Option<&mut Heatmap>,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<Stop, Error> {
+    let Program {
+        tokens,
+        ops,
+        pc: resume_at,
+        procedures,
+        calls,
+        counts,
+    } = program;
+    let mut ptr = *start;
+    let span = |pc: usize| tokens[pc].1;
+
+    // Every move is checked, so `ptr` is always on the tape
+    macro_rules! cell {
+        () => {
+            *{
+                debug_assert!(ptr < tape.len());
+                // SAFETY: `ptr` only changes through checked moves
+                unsafe { tape.get_unchecked_mut(ptr) }
+            }
+        };
+    }
+
+    let mut pc = *resume_at;
+    // Stops the run with the error from `$result`, if it's one
+    macro_rules! check {
+        ($result:expr) => {
+            match $result {
+                Ok(value) => value,
+                Err(e) => {
+                    *start = ptr;
+                    *resume_at = pc;
+                    return Err(Error::from(e));
+                }
+            }
+        };
+    }
+
+    while pc < ops.len() && steps > 0 {
+        steps -= 1;
+        if INSTRUMENTED {
+            if let Some(counts) = counts {
+                counts[pc] += 1;
+            }
+            if let Some(tracer) = tracer.as_deref_mut() {
+                check!(tracer.log(tokens[pc], ptr, cell!().widen()));
+            }
+            if let Some(heatmap) = heatmap.as_deref_mut() {
+                record(heatmap, ops[pc], ptr, cell!() == C::default(), tape.len());
+            }
+        }
+        match ops[pc] {
+            Op::Move(n) => {
+                ptr = check!(
+                    offset_cell(ptr, n, tape.len()).ok_or_else(|| Error::OutOfBounds(span(pc)))
+                );
+            }
+            Op::Add(n) => cell!() = C::truncate(cell!().widen().wrapping_add(n)),
+            Op::LoopStart(end) => {
+                if cell!() == C::default() {
+                    pc = end;
+                }
+            }
+            Op::LoopEnd(start) => {
+                if cell!() != C::default() {
+                    pc = start;
+                }
+            }
+            Op::PutChar => check!(output.write_all(&[cell!().widen() as u8])),
+            Op::GetChar => {
+                let mut byte = [0];
+                cell!() = match check!(input.read(&mut byte)) {
+                    0 => match eof {
+                        Eof::Zero => C::default(),
+                        Eof::MinusOne => C::truncate(u64::MAX),
+                        Eof::Unchanged => cell!(),
+                    },
+                    _ => C::truncate(byte[0].into()),
+                };
+            }
+            Op::ProcStart(end) => {
+                procedures[cell!().widen() as u8 as usize] = Some(pc);
+                pc = end;
+            }
+            Op::ProcEnd => pc = calls.pop().unwrap(),
+            Op::Call => {
+                let start = check!(procedures[cell!().widen() as u8 as usize]
+                    .ok_or_else(|| Error::UndefinedProcedure(span(pc))));
+                calls.push(pc);
+                pc = start;
+            }
+            Op::Extern => check!(Err(Error::Extern(span(pc)))),
+            Op::Debug if pause_at_debug => {
+                *start = ptr;
+                *resume_at = pc + 1;
+                return Ok(Stop::Debug);
+            }
+            Op::Debug => check!(debug_dump(tape, ptr)),
+            Op::SwitchTape | Op::ExchangeCell => {
+                *start = ptr;
+                *resume_at = pc + 1;
+                return Ok(Stop::Tape(ops[pc], steps));
+            }
+            Op::SetZero => cell!() = C::default(),
+            // The loop this came from wouldn't have run, so mustn't touch
+            // the other cell
+            Op::MulAdd { .. } if cell!() == C::default() => {}
+            Op::MulAdd { offset, factor } => {
+                let target = check!(offset_cell(ptr, offset, tape.len())
+                    .ok_or_else(|| Error::OutOfBounds(span(pc))));
+                let product = cell!().widen().wrapping_mul(factor);
+                tape[target] = C::truncate(tape[target].widen().wrapping_add(product));
+            }
+            Op::AddAt { offset, amount } => {
+                let target = check!(offset_cell(ptr, offset, tape.len())
+                    .ok_or_else(|| Error::OutOfBounds(span(pc))));
+                tape[target] = C::truncate(tape[target].widen().wrapping_add(amount));
+            }
+            Op::Scan(stride) => {
+                while cell!() != C::default() {
+                    ptr = check!(offset_cell(ptr, stride, tape.len())
+                        .ok_or_else(|| Error::OutOfBounds(span(pc))));
+                    // Each cell the scan passes is tested
+                    if INSTRUMENTED {
+                        if let Some(heatmap) = heatmap.as_deref_mut() {
+                            heatmap.read(ptr);
+                        }
+                    }
+                }
+            }
+        }
+        pc += 1;
+    }
+
+    *start = ptr;
+    *resume_at = pc;
+    output.flush()?;
+    Ok(Stop::Done(steps))
+}
@@ -0,0 +1,254 @@
+//! The textual form of the tokens, written by `--emit ir` and read back from
+//! `.bfir` files, so that other tools can generate or optimise programs and
+//! use bfc only to compile them.
+//!
+//! Each line holds one instruction: the token's kind, its operands separated
+//! by spaces, and optionally `@` and the position in the source it came from,
+//! as `file:line:col`, or `line:col` for a position in the IR file itself.
+//! Loop offsets and procedure and scan ids are left out and worked out
+//! afresh when the file is read, and external calls are written by name.
+//! Indentation, blank lines and lines starting with `;` are ignored.
+use crate::{
+    diagnostics::Diagnostic,
+    externs,
+    lex::{self, Span, Token, Tokens},
+};
+use std::str::FromStr;
+
+/// The IR for `tokens`, read from `file`, one line per token. Spans name
+/// `file` where they don't name another, so that they still point at the
+/// source when the IR is read back.
+pub fn write(tokens: &[(Token, Span)], file: &str) -> Vec<String> {
+    let mut lines = Vec::with_capacity(tokens.len());
+    let mut depth = 0;
+    for &(token, span) in tokens {
+        if matches!(token, Token::LoopEnd(_) | Token::ProcEnd(_)) {
+            depth -= 1;
+        }
+        let instruction = format!("{}{}", "  ".repeat(depth), instruction(token));
+        match span.file {
+            Some(_) => lines.push(format!("{instruction:<20} @ {span}")),
+            None => lines.push(format!("{instruction:<20} @ {file}:{span}")),
+        }
+        if matches!(token, Token::LoopStart(_) | Token::ProcStart(_)) {
+            depth += 1;
+        }
+    }
+    lines
+}
+
+/// The kind of `token` and its operands, without the ids that are given
+/// when the IR is read.
+fn instruction(token: Token) -> String {
+    let kind = token.kind();
+    match token {
+        Token::PtrAdd(n) | Token::PtrSub(n) | Token::Add(n) | Token::Sub(n) => {
+            format!("{kind} {n}")
+        }
+        Token::Extern(id) => format!("{kind} {}", externs::name(id)),
+        Token::MulAdd { offset, factor } => format!("{kind} {offset} {factor}"),
+        Token::AddAt { offset, amount } => format!("{kind} {offset} {amount}"),
+        Token::ScanRight { stride, .. } | Token::ScanLeft { stride, .. } => {
+            format!("{kind} {stride}")
+        }
+        _ => kind.to_string(),
+    }
+}
+
+fn error(message: impl Into<String>, span: Span) -> Diagnostic {
+    Diagnostic::error("E0036", message).with_span(span)
+}
+
+fn unclosed_mul_run(start: Span) -> Diagnostic {
+    error("a run of MulAdd must be followed by SetZero", start)
+}
+
+/// Reads the IR in `source`. Spans are those the IR gives, or the position
+/// of the instruction in `source` where it gives none.
+pub fn parse(source: &str) -> Result<Tokens, Diagnostic> {
+    let mut tokens = vec![];
+    // Files named in spans, each kept for the life of the program as with
+    // `@include`d files
+    let mut files: Vec<&'static String> = vec![];
+    let mut scan_counter = 0;
+    let mut proc_counter = 0;
+    let mut active_blocks: Vec<(Token, Span)> = vec![];
+    // Where the run of `MulAdd`s being read started, which a `SetZero` must
+    // close, as the loop it came from cleared its cell
+    let mut mul_run: Option<Span> = None;
+
+    for (i, line) in source.lines().enumerate() {
+        let here = Span {
+            line: i as u32 + 1,
+            col: 1,
+            file: None,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let (instruction, span) = match line.split_once('@') {
+            Some((instruction, span)) => (instruction, parse_span(span.trim(), &mut files, here)?),
+            None => (line, here),
+        };
+
+        let mut words = instruction.split_whitespace();
+        let kind = words
+            .next()
+            .ok_or_else(|| error("missing instruction", here))?;
+        let token = match kind {
+            "PtrAdd" => Token::PtrAdd(operand(&mut words, kind, here)?),
+            "PtrSub" => Token::PtrSub(operand(&mut words, kind, here)?),
+            "Add" => Token::Add(operand(&mut words, kind, here)?),
+            "Sub" => Token::Sub(operand(&mut words, kind, here)?),
+            "LoopStart" => {
+                let token = Token::LoopStart(0);
+                active_blocks.push((token, here));
+                token
+            }
+            "LoopEnd" => match active_blocks.last() {
+                Some(&(Token::LoopStart(_), _)) => {
+                    active_blocks.pop();
+                    Token::LoopEnd(0)
+                }
+                _ => return Err(Diagnostic::error("E0001", "unmatched loop end").with_span(here)),
+            },
+            "PutChar" => Token::PutChar,
+            "GetChar" => Token::GetChar,
+            "ProcStart" => {
+                let token = Token::ProcStart(proc_counter);
+                active_blocks.push((token, here));
+                proc_counter += 1;
+                token
+            }
+            "ProcEnd" => match active_blocks.last() {
+                Some(&(Token::ProcStart(id), _)) => {
+                    active_blocks.pop();
+                    Token::ProcEnd(id)
+                }
+                _ => {
+                    return Err(
+                        Diagnostic::error("E0001", "unmatched procedure end").with_span(here)
+                    )
+                }
+            },
+            "Call" => Token::Call,
+            "Extern" => {
+                let name = words
+                    .next()
+                    .ok_or_else(|| error("Extern needs a function name", here))?;
+                Token::Extern(externs::id(name, here)?)
+            }
+            "Debug" => Token::Debug,
+            "SwitchTape" => Token::SwitchTape,
+            "ExchangeCell" => Token::ExchangeCell,
+            "SetZero" => Token::SetZero,
+            "MulAdd" => Token::MulAdd {
+                offset: operand(&mut words, kind, here)?,
+                factor: operand(&mut words, kind, here)?,
+            },
+            "ScanRight" | "ScanLeft" => {
+                let stride = operand(&mut words, kind, here)?;
+                let id = scan_counter;
+                scan_counter += 1;
+                match kind {
+                    "ScanRight" => Token::ScanRight { stride, id },
+                    _ => Token::ScanLeft { stride, id },
+                }
+            }
+            "AddAt" => Token::AddAt {
+                offset: operand(&mut words, kind, here)?,
+                amount: operand(&mut words, kind, here)?,
+            },
+            _ => return Err(error(format!("unknown instruction `{kind}`"), here)),
+        };
+        if words.next().is_some() {
+            return Err(error(format!("too many operands for {kind}"), here));
+        }
+        match (token, mul_run) {
+            (Token::MulAdd { .. }, None) => mul_run = Some(here),
+            (Token::MulAdd { .. }, _) => {}
+            (Token::SetZero, _) => mul_run = None,
+            (_, Some(start)) => return Err(unclosed_mul_run(start)),
+            _ => {}
+        }
+        tokens.push((token, span));
+    }
+
+    if let Some(start) = mul_run {
+        return Err(unclosed_mul_run(start));
+    }
+    // As when lexing, blocks left open are reported in the order they opened
+    match active_blocks.first() {
+        Some(&(Token::ProcStart(_), span)) => {
+            Err(Diagnostic::error("E0002", "unmatched procedure start").with_span(span))
+        }
+        Some(&(_, span)) => Err(Diagnostic::error("E0002", "unmatched loop start").with_span(span)),
+        None => {
+            lex::link_loops(&mut tokens);
+            Ok(tokens)
+        }
+    }
+}
+
+/// Reads the next operand in `words` of the instruction `kind`.
+fn operand<'a, T: FromStr>(
+    words: &mut impl Iterator<Item = &'a str>,
+    kind: &str,
+    here: Span,
+) -> Result<T, Diagnostic> {
+    let word = words
+        .next()
+        .ok_or_else(|| error(format!("too few operands for {kind}"), here))?;
+    word.parse()
+        .map_err(|_| error(format!("`{word}` is not a valid operand for {kind}"), here))
+}
+
+/// Reads a span written as `line:col` or `file:line:col`.
+fn parse_span(
+    text: &str,
+    files: &mut Vec<&'static String>,
+    here: Span,
+) -> Result<Span, Diagnostic> {
+    let invalid = || error(format!("`{text}` is not a valid source position"), here);
+    let mut parts = text.rsplitn(3, ':');
+    let col = parts
+        .next()
+        .and_then(|col| col.parse().ok())
+        .ok_or_else(invalid)?;
+    let line = parts
+        .next()
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(invalid)?;
+    let file = parts.next().map(
+        |name| match files.iter().find(|file| file.as_str() == name) {
+            Some(&file) => file,
+            None => {
+                let file: &'static String = Box::leak(Box::new(name.to_string()));
+                files.push(file);
+                file
+            }
+        },
+    );
+    Ok(Span { line, col, file })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_mul_runs_closed_by_set_zero() {
+        let tokens = parse("MulAdd 1 2\nMulAdd -1 3\nSetZero\nPutChar").unwrap();
+        assert_eq!(tokens.len(), 4);
+    }
+
+    #[test]
+    fn rejects_mul_runs_not_closed_by_set_zero() {
+        for source in ["MulAdd 1 2", "MulAdd 1 2\nMulAdd 2 1\nPutChar\nSetZero"] {
+            let error = parse(source).unwrap_err();
+            assert_eq!(error.code, "E0036");
+            assert_eq!(error.span.map(|span| span.line), Some(1), "{source}");
+        }
+    }
+}
@@ -1,131 +1,423 @@
+use crate::{diagnostics::Diagnostic, externs, timings::Timings};
+use serde::Serialize;
+use std::fmt;
+
+/// One instruction of a program. Programs can run to hundreds of millions of
+/// tokens, so each packs into eight bytes: an opcode and a `u32` operand, or
+/// two `i16`s for the superinstructions that need two operands.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Token {
-    PtrAdd(usize),
-    PtrSub(usize),
-    Add(usize),
-    Sub(usize),
-    LoopStart(usize),
-    LoopEnd(usize),
+    PtrAdd(u32),
+    PtrSub(u32),
+    Add(u32),
+    Sub(u32),
+    /// Starts a loop whose `LoopEnd` is this many tokens later, as set by
+    /// [`link_loops`]
+    LoopStart(u32),
+    /// Ends a loop whose `LoopStart` is this many tokens earlier
+    LoopEnd(u32),
     PutChar,
     GetChar,
+    ProcStart(u32),
+    ProcEnd(u32),
+    Call,
+    /// Calls the external function with the id given by `externs`, passing it
+    /// the tape pointer
+    Extern(u32),
+    /// Writes the pointer and the cells around it to stderr, from `#` with
+    /// --debug-hash
+    Debug,
+    /// Moves the pointer to the other tape, to the cell it was last on there,
+    /// from `~` with the tapes extension
+    SwitchTape,
+    /// Swaps the current cell with the cell the other tape's pointer is on,
+    /// from `$` with the tapes extension
+    ExchangeCell,
+    /// Sets the current cell to zero, from `[-]`
+    SetZero,
+    /// Adds `factor` times the current cell to the cell `offset` away. These
+    /// come from multiplication loops, so a run of them always ends in a
+    /// `SetZero`.
+    MulAdd {
+        offset: i16,
+        factor: i16,
+    },
+    /// Moves right `stride` cells at a time until the current cell is zero,
+    /// from loop number `id`
+    ScanRight {
+        stride: u16,
+        id: u32,
+    },
+    /// Moves left `stride` cells at a time until the current cell is zero,
+    /// from loop number `id`
+    ScanLeft {
+        stride: u16,
+        id: u32,
+    },
+    /// Adds `amount` to the cell `offset` away without moving the pointer
+    AddAt {
+        offset: i16,
+        amount: i16,
+    },
 }
 
-pub fn lex(contents: &str) -> Vec<Token> {
-    let mut tokens = Vec::new();
+impl Token {
+    pub fn kind(self) -> &'static str {
+        match self {
+            Token::PtrAdd(_) => "PtrAdd",
+            Token::PtrSub(_) => "PtrSub",
+            Token::Add(_) => "Add",
+            Token::Sub(_) => "Sub",
+            Token::LoopStart(_) => "LoopStart",
+            Token::LoopEnd(_) => "LoopEnd",
+            Token::PutChar => "PutChar",
+            Token::GetChar => "GetChar",
+            Token::ProcStart(_) => "ProcStart",
+            Token::ProcEnd(_) => "ProcEnd",
+            Token::Call => "Call",
+            Token::Extern(_) => "Extern",
+            Token::Debug => "Debug",
+            Token::SwitchTape => "SwitchTape",
+            Token::ExchangeCell => "ExchangeCell",
+            Token::SetZero => "SetZero",
+            Token::MulAdd { .. } => "MulAdd",
+            Token::ScanRight { .. } => "ScanRight",
+            Token::ScanLeft { .. } => "ScanLeft",
+            Token::AddAt { .. } => "AddAt",
+        }
+    }
 
-    let mut loop_counter = 0;
-    let mut active_loops = Vec::new();
+    /// Whether this is one of the higher-level instructions that profiles
+    /// don't have to support.
+    pub fn is_superinstruction(self) -> bool {
+        matches!(
+            self,
+            Token::SetZero
+                | Token::MulAdd { .. }
+                | Token::ScanRight { .. }
+                | Token::ScanLeft { .. }
+                | Token::AddAt { .. }
+        )
+    }
 
-    for c in contents.chars() {
-        match c {
-            '>' => tokens.push(Token::PtrAdd(1)),
-            '<' => tokens.push(Token::PtrSub(1)),
-            '+' => tokens.push(Token::Add(1)),
-            '-' => tokens.push(Token::Sub(1)),
-            '[' => {
-                tokens.push(Token::LoopStart(loop_counter));
-                active_loops.push(loop_counter);
-                loop_counter += 1;
-            }
-            ']' => {
-                let t = active_loops.pop().expect("Unmapped loop end");
-                tokens.push(Token::LoopEnd(t));
+    /// The brainfuck source this token stands for. A `MulAdd` has none on its
+    /// own, so it gives what one iteration of its loop does.
+    pub fn to_bf(self) -> String {
+        let shift = |offset: i16| match offset {
+            n if n < 0 => "<".repeat(n.unsigned_abs() as usize),
+            n => ">".repeat(n as usize),
+        };
+        let change = |amount: i16| match amount {
+            n if n < 0 => "-".repeat(n.unsigned_abs() as usize),
+            n => "+".repeat(n as usize),
+        };
+
+        match self {
+            Token::PtrAdd(n) => ">".repeat(n as usize),
+            Token::PtrSub(n) => "<".repeat(n as usize),
+            Token::Add(n) => "+".repeat(n as usize),
+            Token::Sub(n) => "-".repeat(n as usize),
+            Token::LoopStart(_) => "[".to_string(),
+            Token::LoopEnd(_) => "]".to_string(),
+            Token::PutChar => ".".to_string(),
+            Token::GetChar => ",".to_string(),
+            Token::ProcStart(_) => "(".to_string(),
+            Token::ProcEnd(_) => ")".to_string(),
+            Token::Call => ":".to_string(),
+            Token::Extern(id) => format!("%{}%", externs::name(id)),
+            Token::Debug => "#".to_string(),
+            Token::SwitchTape => "~".to_string(),
+            Token::ExchangeCell => "$".to_string(),
+            Token::SetZero => "[-]".to_string(),
+            Token::MulAdd { offset, factor }
+            | Token::AddAt {
+                offset,
+                amount: factor,
+            } => {
+                format!("{}{}{}", shift(offset), change(factor), shift(-offset))
             }
-            '.' => tokens.push(Token::PutChar),
-            ',' => tokens.push(Token::GetChar),
-            _ => {}
+            Token::ScanRight { stride, .. } => format!("[{}]", ">".repeat(stride as usize)),
+            Token::ScanLeft { stride, .. } => format!("[{}]", "<".repeat(stride as usize)),
         }
     }
+}
 
-    assert!(active_loops.is_empty(), "Unmatched loop start");
+/// Position in the source file of the first character of a token.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Span {
+    pub line: u32,
+    pub col: u32,
+    /// The file this came from when it isn't the input file, such as an
+    /// `@include`d file. A `&String` rather than a `&str` keeps spans small.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<&'static String>,
+}
 
-    tokens
+/// Shows `file:line:col`, or just `line:col` for the input file.
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = self.file {
+            write!(f, "{file}:")?;
+        }
+        write!(f, "{}:{}", self.line, self.col)
+    }
 }
 
-pub fn optimise_tokens(tokens: Vec<Token>) -> Vec<Token> {
-    let mut tokens = tokens;
+const _: () = assert!(std::mem::size_of::<(Token, Span)>() <= 24);
+
+pub type Tokens = Vec<(Token, Span)>;
 
-    loop {
-        let next = optimise_tokens_inner(&tokens);
-        if next == tokens {
-            return next;
+/// Points the start and end of each loop in `tokens` at each other. Loops
+/// are found through these offsets rather than searched for, so passes that
+/// add or remove tokens build their output and then link it once. The loops
+/// must be balanced.
+pub fn link_loops(tokens: &mut [(Token, Span)]) {
+    let mut open = vec![];
+    for i in 0..tokens.len() {
+        match tokens[i].0 {
+            Token::LoopStart(_) => open.push(i),
+            Token::LoopEnd(_) => {
+                let start = open.pop().expect("loops are balanced");
+                let distance = (i - start) as u32;
+                tokens[start].0 = Token::LoopStart(distance);
+                tokens[i].0 = Token::LoopEnd(distance);
+            }
+            _ => {}
         }
-        tokens = next;
     }
 }
 
-fn optimise_tokens_inner(tokens: &[Token]) -> Vec<Token> {
-    let tokens = group_tokens(tokens);
-    let tokens = cancel_out(&tokens);
+/// Stand for the commands that are only commands with an option or
+/// extension, so that they pass through dialect translation to the lexer.
+/// They come after the characters for external calls.
+pub const DEBUG: char = '\u{FFFFE}';
+pub const SWITCH_TAPE: char = '\u{100000}';
+pub const EXCHANGE_CELL: char = '\u{100001}';
 
-    #[allow(clippy::let_and_return)]
-    tokens
+/// Whether `c` stands for a command marked by `mark_debug` or `mark_tapes`.
+pub fn is_marker(c: char) -> bool {
+    matches!(c, DEBUG | SWITCH_TAPE | EXCHANGE_CELL)
 }
 
-fn group_tokens(tokens: &[Token]) -> Vec<Token> {
-    let mut new_tokens = vec![];
+/// Marks `#` as a debug command, for --debug-hash.
+pub fn mark_debug((c, span): (char, Span)) -> (char, Span) {
+    match c {
+        '#' => (DEBUG, span),
+        c => (c, span),
+    }
+}
 
-    let mut accumulator = None;
-    for token in tokens {
-        accumulator = match (token, accumulator) {
-            (Token::PtrAdd(a), Some(Token::PtrAdd(b))) => Some(Token::PtrAdd(a + b)),
-            (Token::PtrSub(a), Some(Token::PtrSub(b))) => Some(Token::PtrSub(a + b)),
-            (Token::Add(a), Some(Token::Add(b))) => Some(Token::Add(a + b)),
-            (Token::Sub(a), Some(Token::Sub(b))) => Some(Token::Sub(a + b)),
+/// Marks `~` and `$` as the commands of the tapes extension.
+pub fn mark_tapes((c, span): (char, Span)) -> (char, Span) {
+    match c {
+        '~' => (SWITCH_TAPE, span),
+        '$' => (EXCHANGE_CELL, span),
+        c => (c, span),
+    }
+}
+
+/// Pairs every character of `contents` with its position in `file`.
+pub fn spanned_chars<'a>(
+    contents: &'a str,
+    file: Option<&'static String>,
+) -> impl Iterator<Item = (char, Span)> + 'a {
+    let mut span = Span {
+        line: 1,
+        col: 1,
+        file,
+    };
+    contents.chars().map(move |c| {
+        let current = span;
+        if c == '\n' {
+            span.line += 1;
+            span.col = 1;
+        } else {
+            span.col += 1;
+        }
+        (c, current)
+    })
+}
 
-            (tok, Some(acc)) => {
-                new_tokens.push(acc);
-                Some(*tok)
+/// Turns brainfuck commands into tokens as they're read, merging each run of
+/// the same `>`, `<`, `+` or `-` into one token. Characters that aren't
+/// commands are skipped. After an unmatched bracket it carries on lexing, so
+/// that every unmatched bracket gets its own error. Loops come out unlinked,
+/// and [`Lexer::tokens`] links them.
+pub struct Lexer<I: Iterator<Item = (char, Span)>> {
+    commands: std::iter::Peekable<I>,
+    proc_counter: u32,
+    /// Open loops and procedures, which must close in reverse order
+    active_blocks: Vec<(Token, Span)>,
+}
+
+pub fn lex<I: IntoIterator<Item = (char, Span)>>(commands: I) -> Lexer<I::IntoIter> {
+    Lexer {
+        commands: commands.into_iter().peekable(),
+        proc_counter: 0,
+        active_blocks: vec![],
+    }
+}
+
+impl<I: Iterator<Item = (char, Span)>> Lexer<I> {
+    /// Lexes everything, failing with every error found rather than just the
+    /// first.
+    pub fn tokens(self) -> Result<Tokens, Diagnostic> {
+        let mut tokens = vec![];
+        let mut errors = vec![];
+        for result in self {
+            match result {
+                Ok(token) => tokens.push(token),
+                Err(error) => errors.push(error),
             }
-            (tok, None) => Some(*tok),
-        };
+        }
+        match Diagnostic::combine(errors) {
+            Some(error) => Err(error),
+            None => {
+                link_loops(&mut tokens);
+                Ok(tokens)
+            }
+        }
     }
 
-    if let Some(acc) = accumulator {
-        new_tokens.push(acc);
+    /// Counts `c` and the copies of it straight after it, up to as many as
+    /// one token can hold.
+    fn run_length(&mut self, c: char) -> u32 {
+        let mut n = 1;
+        while n < u32::MAX && self.commands.next_if(|&(next, _)| next == c).is_some() {
+            n += 1;
+        }
+        n
     }
+}
 
-    new_tokens
-}
-
-fn cancel_out(tokens: &[Token]) -> Vec<Token> {
-    let mut new_tokens = vec![];
-
-    let mut accumulator = None;
-    for token in tokens {
-        accumulator = match (token, accumulator) {
-            (Token::PtrAdd(a), Some(Token::PtrSub(b))) => match a.cmp(&b) {
-                std::cmp::Ordering::Less => Some(Token::PtrAdd(a - b)),
-                std::cmp::Ordering::Equal => None,
-                std::cmp::Ordering::Greater => Some(Token::PtrSub(b - a)),
-            },
-            (Token::PtrSub(a), Some(Token::PtrAdd(b))) => match a.cmp(&b) {
-                std::cmp::Ordering::Less => Some(Token::PtrAdd(b - a)),
-                std::cmp::Ordering::Equal => None,
-                std::cmp::Ordering::Greater => Some(Token::PtrSub(a - b)),
-            },
-            (Token::Add(a), Some(Token::Sub(b))) => match a.cmp(&b) {
-                std::cmp::Ordering::Less => Some(Token::Add(a - b)),
-                std::cmp::Ordering::Equal => None,
-                std::cmp::Ordering::Greater => Some(Token::Sub(b - a)),
-            },
-            (Token::Sub(a), Some(Token::Add(b))) => match a.cmp(&b) {
-                std::cmp::Ordering::Less => Some(Token::Add(b - a)),
-                std::cmp::Ordering::Equal => None,
-                std::cmp::Ordering::Greater => Some(Token::Sub(a - b)),
-            },
-
-            (tok, Some(acc)) => {
-                new_tokens.push(acc);
-                Some(*tok)
-            }
-            (tok, None) => Some(*tok),
+impl<I: Iterator<Item = (char, Span)>> Iterator for Lexer<I> {
+    type Item = Result<(Token, Span), Diagnostic>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some((c, span)) = self.commands.next() else {
+                // Blocks left open are reported in the order they opened
+                if self.active_blocks.is_empty() {
+                    return None;
+                }
+                let (token, span) = self.active_blocks.remove(0);
+                let message = match token {
+                    Token::ProcStart(_) => "unmatched procedure start",
+                    _ => "unmatched loop start",
+                };
+                return Some(Err(Diagnostic::error("E0002", message).with_span(span)));
+            };
+
+            let token = match c {
+                '>' => Token::PtrAdd(self.run_length(c)),
+                '<' => Token::PtrSub(self.run_length(c)),
+                '+' => Token::Add(self.run_length(c)),
+                '-' => Token::Sub(self.run_length(c)),
+                '[' => {
+                    let token = Token::LoopStart(0);
+                    self.active_blocks.push((token, span));
+                    token
+                }
+                ']' => match self.active_blocks.last() {
+                    Some(&(Token::LoopStart(_), _)) => {
+                        self.active_blocks.pop();
+                        Token::LoopEnd(0)
+                    }
+                    // Whatever is open is left open, to be closed later
+                    _ => {
+                        return Some(Err(
+                            Diagnostic::error("E0001", "unmatched loop end").with_span(span)
+                        ))
+                    }
+                },
+                '.' => Token::PutChar,
+                ',' => Token::GetChar,
+                '(' => {
+                    let token = Token::ProcStart(self.proc_counter);
+                    self.active_blocks.push((token, span));
+                    self.proc_counter += 1;
+                    token
+                }
+                ')' => match self.active_blocks.last() {
+                    Some(&(Token::ProcStart(t), _)) => {
+                        self.active_blocks.pop();
+                        Token::ProcEnd(t)
+                    }
+                    _ => {
+                        return Some(Err(
+                            Diagnostic::error("E0001", "unmatched procedure end").with_span(span)
+                        ))
+                    }
+                },
+                ':' => Token::Call,
+                DEBUG => Token::Debug,
+                SWITCH_TAPE => Token::SwitchTape,
+                EXCHANGE_CELL => Token::ExchangeCell,
+                c => match externs::call_id(c) {
+                    Some(id) => Token::Extern(id),
+                    None => continue,
+                },
+            };
+            return Some(Ok((token, span)));
         }
     }
+}
+
+/// Folds each run of pointer moves and each run of cell changes into a single
+/// instruction, dropping runs that cancel out. This takes one pass: when a run
+/// cancels out, the instruction before it is exposed again and later
+/// instructions can still fold into it, so the result never needs another.
+pub fn optimise_tokens(tokens: Vec<(Token, Span)>, timings: &mut Timings) -> Vec<(Token, Span)> {
+    timings.time("fold", || fold_tokens(tokens))
+}
+
+/// Whether `tok` moves the pointer or changes the cell, and by how much.
+fn net_amount(tok: Token) -> Option<(bool, i64)> {
+    match tok {
+        Token::PtrAdd(n) => Some((true, n.into())),
+        Token::PtrSub(n) => Some((true, -i64::from(n))),
+        Token::Add(n) => Some((false, n.into())),
+        Token::Sub(n) => Some((false, -i64::from(n))),
+        _ => None,
+    }
+}
 
-    if let Some(acc) = accumulator {
-        new_tokens.push(acc);
+/// The token moving or changing by `amount`, if there is one and it fits.
+fn from_net_amount(moves: bool, amount: i64) -> Option<Option<Token>> {
+    let n = u32::try_from(amount.unsigned_abs()).ok()?;
+    Some(match (moves, amount.signum()) {
+        (_, 0) => None,
+        (true, 1) => Some(Token::PtrAdd(n)),
+        (true, _) => Some(Token::PtrSub(n)),
+        (false, 1) => Some(Token::Add(n)),
+        (false, _) => Some(Token::Sub(n)),
+    })
+}
+
+/// Folds `tokens` in place, treating the front of the vector as a stack of
+/// the tokens kept so far.
+pub fn fold_tokens(mut tokens: Vec<(Token, Span)>) -> Vec<(Token, Span)> {
+    let mut len = 0;
+
+    for i in 0..tokens.len() {
+        let (tok, span) = tokens[i];
+        if let (Some((moves, a)), Some(&(last, _))) = (net_amount(tok), tokens[..len].last()) {
+            let folded = net_amount(last)
+                .filter(|&(m, _)| m == moves)
+                .and_then(|(_, b)| from_net_amount(moves, a + b));
+            if let Some(folded) = folded {
+                match folded {
+                    Some(folded) => tokens[len - 1].0 = folded,
+                    None => len -= 1,
+                }
+                continue;
+            }
+        }
+        tokens[len] = (tok, span);
+        len += 1;
     }
 
-    new_tokens
+    tokens.truncate(len);
+    link_loops(&mut tokens);
+    tokens
 }
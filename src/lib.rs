@@ -0,0 +1,45 @@
+//! The compiler's lexer, optimiser, code generator and interpreter. The
+//! command-line tool in `main.rs` drives them, and with the `native` feature
+//! turned off they build for wasm32-unknown-unknown, for a playground in the
+//! browser.
+pub mod analysis;
+pub mod budget;
+#[cfg(feature = "native")]
+pub mod build;
+pub mod bytecode;
+#[cfg(feature = "native")]
+pub mod cache;
+pub mod checkpoint;
+pub mod codegen;
+pub mod coverage;
+pub mod diagnostics;
+pub mod dialect;
+pub mod digest;
+pub mod dot;
+pub mod externs;
+pub mod fmt;
+pub mod gdb;
+pub mod gen;
+pub mod heatmap;
+pub mod interpret;
+pub mod ir;
+pub mod lex;
+pub mod listing;
+pub mod minify;
+pub mod passes;
+pub mod peephole;
+pub mod pgo;
+pub mod preprocess;
+pub mod profile;
+pub mod pseudo;
+pub mod report;
+pub mod sourcemap;
+pub mod specialize;
+pub mod stats;
+pub mod sugar;
+pub mod superinstructions;
+pub mod text2bf;
+pub mod timings;
+#[cfg(feature = "native")]
+pub mod toolchain;
+pub mod unroll;
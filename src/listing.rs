@@ -0,0 +1,69 @@
+use crate::{
+    codegen::Labels,
+    lex::{Span, Token},
+    profile::Profile,
+    superinstructions,
+};
+
+const SOURCE_WIDTH: usize = 24;
+
+/// Describes how the optimiser rewrote `original` into `token`, if at all.
+fn rewrite_note(token: Token, original: &[(Token, Span)]) -> Option<&'static str> {
+    if token.is_superinstruction() {
+        Some("combined")
+    } else if original.len() <= 1 {
+        None
+    } else if original.iter().all(|(t, _)| t.kind() == token.kind()) {
+        Some("grouped")
+    } else {
+        Some("grouped, cancelled")
+    }
+}
+
+/// Lays out each optimised token's original source next to the assembly
+/// generated for it. `original` is the token stream before optimisation.
+pub fn generate(
+    profile: &Profile,
+    original: &[(Token, Span)],
+    optimised: &[(Token, Span)],
+) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{:<9} {:<SOURCE_WIDTH$} | assembly",
+        "line:col", "source"
+    )];
+
+    let optimised = superinstructions::expand(optimised.to_vec(), |tok| profile.supports(tok));
+    let labels = Labels::new(&optimised);
+    let mut rest = original;
+    for (i, &(token, span)) in optimised.iter().enumerate() {
+        // Everything up to the start of the next optimised token was folded
+        // into this one.
+        let covered = match optimised.get(i + 1) {
+            Some((_, next)) => rest.iter().take_while(|(_, s)| s != next).count(),
+            None => rest.len(),
+        };
+        let (region, remaining) = rest.split_at(covered);
+        rest = remaining;
+
+        let mut source: String = region.iter().map(|(t, _)| t.to_bf()).collect();
+        if source.len() > SOURCE_WIDTH {
+            source.truncate(SOURCE_WIDTH - 3);
+            source.push_str("...");
+        }
+
+        let asm = profile.get_asm(token, labels.get(i, token));
+        let mut asm_lines = asm.lines();
+        let mut first = asm_lines.next().unwrap_or_default().to_string();
+        if let Some(note) = rewrite_note(token, region) {
+            first.push_str(&format!("  {}", profile.get_comment(note)));
+        }
+
+        let position = span.to_string();
+        lines.push(format!("{position:<9} {source:<SOURCE_WIDTH$} | {first}"));
+        for line in asm_lines {
+            lines.push(format!("{:<9} {:<SOURCE_WIDTH$} | {line}", "", ""));
+        }
+    }
+
+    lines
+}
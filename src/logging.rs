@@ -0,0 +1,57 @@
+use log::kv::{self, Key, Value, VisitSource};
+use serde_json::{Map, Number};
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Coloured text for people
+    Pretty,
+    /// One JSON object per line with each event's fields, for tools
+    Json,
+}
+
+/// Collects the key-values of a log record as JSON fields.
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(n) = value.to_f64().and_then(Number::from_f64) {
+            n.into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Starts logging at the levels set in RUST_LOG, written in `format`. JSON
+/// logs show `info` and above by default, so that tools get phase timings and
+/// counts without setting RUST_LOG.
+pub fn init(format: LogFormat) {
+    match format {
+        LogFormat::Pretty => pretty_env_logger::init(),
+        LogFormat::Json => {
+            let mut builder = pretty_env_logger::formatted_builder();
+            builder.parse_filters(&std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()));
+            builder.format(|buf, record| {
+                let mut event = Map::new();
+                event.insert(
+                    "level".into(),
+                    record.level().as_str().to_lowercase().into(),
+                );
+                event.insert("target".into(), record.target().into());
+                event.insert("message".into(), record.args().to_string().into());
+                let _ = record.key_values().visit(&mut Fields(&mut event));
+                writeln!(buf, "{}", serde_json::Value::Object(event))
+            });
+            builder.init();
+        }
+    }
+}
@@ -5,105 +5,2225 @@
 // 4. Perform optimisations (++ ++ => +=2)
 // 5. Generate nasm(?) assembly
 // 6. Assembly generated code
-mod lex;
-mod profile;
+mod bench;
+mod cli;
+mod config;
+mod doctor;
+mod equiv;
+mod logging;
+mod man;
+mod repl;
+mod selftest;
+mod signal;
+mod test_runner;
+mod tty;
+mod visualize;
 
-use clap::Parser;
-use lex::Token;
-use profile::Profile;
-use std::{fs, path::Path};
+use analysis::{Bounds, Level, LintLevels};
+use bfc::{
+    analysis, budget, bytecode, cache, checkpoint, codegen, coverage, diagnostics, dialect, digest,
+    dot, externs, fmt, gdb, gen, heatmap, interpret, ir, lex, listing, minify, passes, peephole,
+    pgo, preprocess, profile, pseudo, report, sourcemap, specialize, stats, sugar, text2bf,
+    timings, toolchain,
+};
+use budget::Budget;
+use checkpoint::Checkpoint;
+use clap::{builder::PossibleValuesParser, CommandFactory, FromArgMatches};
+use cli::{
+    Args, BuildArgs, CacheCommands, Commands, Emit, Extension, InputSource, OptLevel, OutputData,
+    ProfileCommands, ToolchainArgs,
+};
+use codegen::Codegen;
+use coverage::Coverage;
+use diagnostics::{ColorChoice, Diagnostic, Emitter, MessageFormat, Severity};
+use dialect::{Dialect, DialectMap, Syntax};
+use lex::{Span, Token, Tokens};
+use passes::Pipeline;
+use preprocess::Preprocessor;
+use profile::{AsmSyntax, Profile, ProfileSource, Variables};
+use report::BuildReport;
+use sourcemap::SourceMap;
+use stats::Stats;
+use std::{
+    borrow::Cow,
+    fs,
+    io::{self, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+use test_runner::Outcome;
+use timings::Timings;
+use toolchain::Toolchain;
 
-#[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about=None)]
-struct Args {
-    /// Filename of the brainfuck program
-    infile: String,
+/// Where to write the output for `infile`: the -o path if there is one,
+/// otherwise the source file's name with the extension for the kind of
+/// output, next to the source. Either goes in --out-dir if it's set.
+fn gen_file_names(infile: &str, args: &BuildArgs) -> String {
+    let outfile = match &args.outfile {
+        Some(outfile) => PathBuf::from(outfile),
+        None => {
+            let infile = Path::new(infile);
+            let stem = infile
+                .file_stem()
+                .unwrap_or(infile.as_os_str())
+                .to_string_lossy();
+            let name = match args.emit().extension() {
+                Some(ext) => format!("{stem}.{ext}"),
+                // Dropping no extension would give the source's own name
+                None if infile.extension().is_none() => format!("{stem}.out"),
+                None => stem.to_string(),
+            };
+            match &args.out_dir {
+                Some(_) => PathBuf::from(name),
+                None => infile.with_file_name(name),
+            }
+        }
+    };
 
-    /// Name of the output file
-    #[arg(short = 'o', long = "out")]
-    outfile: Option<String>,
+    match &args.out_dir {
+        Some(dir) => Path::new(dir).join(outfile),
+        None => outfile,
+    }
+    .display()
+    .to_string()
+}
 
-    /// Output an assembly file
-    #[arg(short = 'a', long = "asm")]
-    output_assembly: bool,
+fn read_bf_file(filename: &str) -> Result<String, Diagnostic> {
+    fs::read_to_string(filename)
+        .map_err(|e| Diagnostic::error("E0005", format!("could not read {filename}: {e}")))
+}
 
-    /// Select which profile to assemble with
-    #[arg(short = 'p', long = "profile")]
-    profile: Option<String>,
+fn write_output(filename: &str, lines: &[String]) -> Result<(), Diagnostic> {
+    Profile::write_asm(lines, Path::new(filename))
+        .map_err(|e| Diagnostic::error("E0005", format!("could not write {filename}: {e}")))
 }
 
-fn gen_file_names(args: &Args) -> (String, String, String) {
-    let infile = args.infile.clone();
-    let base = infile.split('.').collect::<Vec<&str>>()[0];
+/// Runs `f` over each of `items` on a thread of its own, returning the
+/// results in order.
+fn par_map<T: Send, U: Send>(items: Vec<T>, f: impl Fn(T) -> U + Sync) -> Vec<U> {
+    let f = &f;
+    thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .into_iter()
+            .map(|item| scope.spawn(move || f(item)))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
 
-    let asmfile = if args.output_assembly {
-        if let Some(outfile) = &args.outfile {
-            outfile.clone()
-        } else {
-            format!("{base}.s")
+/// Writes the assembly from `codegen` to `path`, generating a chunk on each
+/// core at a time and running the peephole pass over each chunk within the
+/// `peephole` budget, if there is one. Only the chunks being worked on are
+/// ever in memory.
+fn write_asm(
+    codegen: &Codegen,
+    peephole: Option<Budget>,
+    path: &Path,
+    timings: &mut Timings,
+) -> Result<usize, io::Error> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut out = io::BufWriter::new(fs::File::create(path)?);
+    let mut first = true;
+    let mut lines = 0;
+
+    let indices: Vec<usize> = (0..codegen.chunk_count()).collect();
+    for batch in indices.chunks(threads) {
+        let chunks = timings.time("codegen", || par_map(batch.to_vec(), |i| codegen.chunk(i)));
+        let chunks = match &peephole {
+            Some(budget) => timings.time("peephole", || {
+                par_map(chunks, |chunk| peephole::optimise(chunk, budget))
+            }),
+            None => chunks,
+        };
+
+        for line in chunks.iter().flatten() {
+            if !first {
+                out.write_all(b"\n")?;
+            }
+            first = false;
+            lines += line.lines().count();
+            out.write_all(line.as_bytes())?;
         }
-    } else {
-        format!("{base}.s")
-    };
+    }
+    log::info!(lines; "Wrote {lines} lines of assembly");
+    out.flush()?;
+    Ok(lines)
+}
 
-    let outfile = if let Some(outfile) = &args.outfile {
-        outfile.clone()
-    } else {
-        base.to_string()
+fn select_profile(name: &Option<String>) -> Result<&'static Profile, Diagnostic> {
+    let profile_name = name.as_deref().unwrap_or(Profile::default_name());
+    Profile::select(profile_name).map_err(|e| Diagnostic::error("E0003", e))
+}
+
+/// The profile chosen by `--profile-path`, `--target` or `--profile`.
+fn select_build_profile(args: &BuildArgs) -> Result<&'static Profile, Diagnostic> {
+    if let Some(path) = &args.profile_path {
+        return Profile::load_file(Path::new(path)).map_err(|e| Diagnostic::error("E0003", e));
+    }
+    match &args.target {
+        Some(target) => Profile::get_by_target(target).ok_or_else(|| {
+            Diagnostic::error("E0003", format!("no profile builds for target {target}"))
+        }),
+        None => select_profile(&args.profile),
+    }
+}
+
+/// A fresh optimisation budget from the limits in `args`, for one stage.
+fn gen_budget(args: &BuildArgs) -> Budget {
+    Budget::new(args.max_passes, args.max_time.map(Duration::from_millis))
+}
+
+fn gen_variables(args: &BuildArgs, infile: &str) -> Variables {
+    let mut vars = Variables::new();
+    let file = fs::canonicalize(infile).unwrap_or_else(|_| infile.into());
+    vars.insert("file".to_string(), file.display().to_string());
+    if let Some(tape_size) = args.tape_size {
+        vars.insert("tape_size".to_string(), tape_size.to_string());
+    }
+    if let Some(cell_size) = args.cell_size {
+        vars.insert("cell_type".to_string(), cell_size.cell_type().to_string());
+    }
+    if let Some(entry) = &args.entry {
+        vars.insert("entry".to_string(), entry.clone());
+    }
+    if let Some(output_file) = &args.output_file {
+        vars.insert("output_file".to_string(), output_file.clone());
+    }
+    if let Some(size) = args.output_buffer_size {
+        vars.insert("output_buffer_size".to_string(), size.to_string());
+    }
+    vars
+}
+
+fn gen_toolchain(args: &ToolchainArgs, profile: &Profile) -> Toolchain {
+    let mut toolchain = profile.toolchain();
+    if let Some(nasm) = &args.nasm_path {
+        toolchain.nasm = nasm.clone();
+    }
+    if let Some(linker) = &args.linker_path {
+        toolchain.linker = linker.clone();
+    }
+    toolchain.nasm_args.extend(args.nasm_args.iter().cloned());
+    toolchain
+        .linker_args
+        .extend(args.linker_args.iter().cloned());
+    toolchain.objects.extend(args.objects.iter().cloned());
+    toolchain.cache = !args.no_cache;
+    toolchain
+}
+
+fn list_profiles(capabilities: bool) {
+    for profile in Profile::load_all() {
+        let profile = match profile {
+            Ok(profile) => profile,
+            Err(e) => {
+                eprintln!("warning: {e}");
+                continue;
+            }
+        };
+        let marker = if profile.is_default() { "*" } else { " " };
+        let linking = if profile.is_static() { " [static]" } else { "" };
+        println!(
+            "{marker} {:<16} {:<40} {}{linking}",
+            profile.name(),
+            profile.source().to_string(),
+            profile.description()
+        );
+        if capabilities {
+            println!("{:<18} {}", "", profile.features().join(", "));
+        }
+    }
+}
+
+/// Prints a completion script for `shell`, completing profile names with the
+/// profiles available now.
+fn print_completions(shell: clap_complete::Shell) {
+    let names: Vec<&str> = Profile::get_all_profiles()
+        .iter()
+        .map(|p| p.name())
+        .collect();
+    let mut cmd = complete_profiles(Args::command(), &names);
+    let name = cmd.get_name().to_string();
+    clap_complete::generate(shell, &mut cmd, name, &mut io::stdout());
+}
+
+/// Offers `names` as the values of every argument taking a profile name in
+/// `cmd` and its subcommands.
+fn complete_profiles(mut cmd: clap::Command, names: &[&'static str]) -> clap::Command {
+    let args: Vec<_> = cmd
+        .get_arguments()
+        .map(|arg| arg.get_id().to_string())
+        .filter(|id| matches!(id.as_str(), "profile" | "from"))
+        .collect();
+    for arg in args {
+        cmd = cmd.mut_arg(arg, |arg| {
+            arg.value_parser(PossibleValuesParser::new(names.to_vec()))
+        });
+    }
+
+    let subcommands: Vec<_> = cmd
+        .get_subcommands()
+        .map(|sub| sub.get_name().to_string())
+        .collect();
+    for sub in subcommands {
+        cmd = cmd.mut_subcommand(sub, |sub| complete_profiles(sub, names));
+    }
+    cmd
+}
+
+fn new_profile(name: &str, from: &Option<String>) -> Result<(), Diagnostic> {
+    let base = match from {
+        Some(from) => Profile::select(from).map_err(|e| Diagnostic::error("E0003", e))?,
+        None => Profile::get_default().map_err(|e| Diagnostic::error("E0003", e))?,
     };
 
-    (infile, asmfile, outfile)
+    let dir = Profile::config_path();
+    let path = dir.join(format!("{name}.json"));
+    if path.exists() {
+        return Err(Diagnostic::error(
+            "E0037",
+            format!("profile file {} already exists", path.display()),
+        ));
+    }
+
+    fs::create_dir_all(dir)
+        .and_then(|()| fs::write(&path, base.scaffold(name)))
+        .map_err(|e| {
+            Diagnostic::error("E0037", format!("could not write {}: {e}", path.display()))
+        })?;
+    println!("Created {}", path.display());
+    Ok(())
 }
 
-fn read_bf_file(filename: &String) -> String {
-    fs::read_to_string(filename).expect("Could not read file")
+fn clean_cache() {
+    match cache::clean() {
+        Ok(stats) => {
+            println!(
+                "Removed {} cache entries ({} bytes)",
+                stats.entries, stats.bytes
+            );
+            print_swept(stats.swept);
+        }
+        Err(e) => {
+            eprintln!("error: could not clean {}: {e}", cache::dir().display());
+            std::process::exit(1);
+        }
+    }
 }
 
-fn generate_asm(profile: &Profile, tokens: Vec<Token>) -> Vec<String> {
-    let mut lines = vec![profile.get_setup_asm()];
-    for tok in tokens {
-        lines.push(profile.get_asm(tok));
+fn print_swept(swept: usize) {
+    if swept > 0 {
+        println!("Removed {swept} scratch directories left by builds that were killed");
     }
-    lines.push(profile.get_teardown_asm());
+}
 
-    lines
+fn print_cache_stats() {
+    match cache::stats() {
+        Ok(stats) => {
+            println!("Cache: {}", cache::dir().display());
+            println!("Entries: {}", stats.entries);
+            println!("Size: {} bytes", stats.bytes);
+            print_swept(stats.swept);
+        }
+        Err(e) => {
+            eprintln!("error: could not read {}: {e}", cache::dir().display());
+            std::process::exit(1);
+        }
+    }
 }
 
 fn main() {
-    pretty_env_logger::init();
+    let config = config::Config::load().unwrap_or_else(|e| {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    });
+    let matches = config.apply(Args::command()).get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    logging::init(args.log_format);
     log::info!("Enabled logging");
-
-    let args = Args::parse();
     log::info!("Read args: {:?}", args);
 
-    let (infile, asmfile, execfile) = gen_file_names(&args);
+    if let Some(command) = &args.command {
+        match command {
+            Commands::Profile(ProfileCommands::List { capabilities }) => {
+                list_profiles(*capabilities)
+            }
+            Commands::Profile(ProfileCommands::New { name, from }) => {
+                if let Err(e) = new_profile(name, from) {
+                    exit_with(&e, "");
+                }
+            }
+            Commands::Cache(CacheCommands::Clean) => clean_cache(),
+            Commands::Cache(CacheCommands::Stats) => print_cache_stats(),
+            Commands::Doctor { profile, toolchain } => {
+                let profile = select_profile(profile).unwrap_or_else(|e| exit_with(&e, ""));
+                if !doctor::run(profile, &gen_toolchain(toolchain, profile)) {
+                    std::process::exit(1);
+                }
+            }
+            Commands::Selftest {
+                infile,
+                input,
+                build,
+            } => {
+                let result = run_build(infile, build, |infile, args, emitter, timings| {
+                    selftest(infile, args, input, emitter, timings)
+                });
+                if !result {
+                    std::process::exit(1);
+                }
+            }
+            Commands::Bench {
+                infile,
+                levels,
+                runs,
+                input,
+                interpret,
+                json,
+                build,
+            } => {
+                let result = run_build(infile, build, |infile, args, emitter, timings| {
+                    let input = read_input(input)?;
+                    let stats = bench(
+                        infile, args, levels, *runs, &input, *interpret, emitter, timings,
+                    )?;
+                    if *json {
+                        bench::print_json(&stats);
+                    } else {
+                        bench::print_table(&stats);
+                    }
+                    Ok(true)
+                });
+                if !result {
+                    std::process::exit(1);
+                }
+            }
+            Commands::Test {
+                dir,
+                interpret,
+                build,
+            } => {
+                if !run_tests(dir, *interpret, build) {
+                    std::process::exit(1);
+                }
+            }
+            Commands::Equiv {
+                first,
+                second,
+                inputs,
+                eof,
+                fuel,
+                build,
+            } => {
+                if !check_equiv(first, second, inputs, *eof, *fuel, build) {
+                    std::process::exit(1);
+                }
+            }
+            Commands::Vm {
+                infile,
+                checkpoint,
+                resume,
+                build,
+            } => {
+                let checkpoints = Checkpoints {
+                    save: checkpoint.as_deref(),
+                    resume: resume.as_deref(),
+                };
+                let result = run_build(infile, build, |infile, args, _, timings| {
+                    vm(infile, &checkpoints, args, timings)
+                });
+                if !result {
+                    std::process::exit(1);
+                }
+            }
+            Commands::Coverage {
+                binary,
+                format,
+                outfile,
+            } => {
+                if let Err(e) = coverage_report(binary, *format, outfile.as_deref()) {
+                    exit_with(&e, binary);
+                }
+            }
+            Commands::Repl { build } => {
+                if let Err(e) = repl(build) {
+                    let emitter = Emitter {
+                        format: build.message_format,
+                        file: REPL_FILE.to_string(),
+                        color: build.color.enabled(),
+                    };
+                    emitter.emit(&e);
+                    std::process::exit(1);
+                }
+            }
+            Commands::Fmt {
+                infile,
+                outfile,
+                width,
+                indent,
+            } => {
+                let style = fmt::Style {
+                    width: *width,
+                    indent: *indent,
+                };
+                if let Err(e) = format_file(infile, outfile, style) {
+                    exit_with(&e, infile);
+                }
+            }
+            Commands::Minify { infile, build } => {
+                let result = run_build(infile, build, |infile, args, _, timings| {
+                    let tokens = load(infile, args, load_pgo(args)?.as_ref(), timings)?;
+                    let program = minify::minify(tokens, &gen_budget(args), timings);
+                    match &args.outfile {
+                        Some(outfile) => write_output(outfile, &[program, String::new()])?,
+                        None => println!("{program}"),
+                    }
+                    Ok(true)
+                });
+                if !result {
+                    std::process::exit(1);
+                }
+            }
+            Commands::Text2bf { infile, outfile } => {
+                if let Err(e) = text2bf(infile, outfile) {
+                    exit_with(&e, infile);
+                }
+            }
+            Commands::Gen {
+                size,
+                seed,
+                terminating,
+                fuel,
+                outfile,
+            } => {
+                let fuel = terminating.then_some(*fuel);
+                if let Err(e) = gen(*size, *seed, fuel, outfile) {
+                    exit_with(&e, "");
+                }
+            }
+            Commands::Completions { shell } => print_completions(*shell),
+            Commands::Mangen => {
+                if let Err(e) = man::render(Args::command(), &mut io::stdout()) {
+                    eprintln!("error: could not write the man page: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        return;
+    }
+
+    let infiles = &args.infiles;
+    if infiles.len() > 1 && !args.multicall {
+        let conflict = if args.visualize {
+            Some("--visualize")
+        } else if args.pgo_gen.is_some() {
+            Some("--pgo-gen")
+        } else if args.coverage.is_some() {
+            Some("--coverage")
+        } else if args.build.outfile.is_some() {
+            Some("--out")
+        } else {
+            None
+        };
+        if let Some(flag) = conflict {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    format!("{flag} takes only one program, unless --multicall is given"),
+                )
+                .exit();
+        }
+    }
+
+    let infile = &infiles[0];
+    let result = if args.multicall {
+        run_build(infile, &args.build, |_, args, emitter, timings| {
+            compile_multicall(infiles, args, emitter, timings)
+        })
+    } else if args.visualize {
+        run_build(infile, &args.build, |infile, args, _, timings| {
+            visualize(infile, args, timings)
+        })
+    } else if let Some(outfile) = &args.pgo_gen {
+        run_build(infile, &args.build, |infile, args, _, timings| {
+            pgo_gen(infile, outfile, args, timings)
+        })
+    } else if let Some(outfile) = &args.coverage {
+        let format = args.coverage_format;
+        run_build(infile, &args.build, |infile, args, _, timings| {
+            coverage_run(infile, outfile, format, args, timings)
+        })
+    } else {
+        // Build every program even if one fails, to report all their errors
+        let failed = infiles
+            .iter()
+            .filter(|infile| !run_build(infile, &args.build, compile))
+            .count();
+        failed == 0
+    };
+    if !result {
+        std::process::exit(1);
+    }
+}
+
+/// Emits `diagnostic` for a command outside a build, about `file`, and
+/// exits.
+fn exit_with(diagnostic: &Diagnostic, file: &str) -> ! {
+    let emitter = Emitter {
+        format: MessageFormat::Human,
+        file: file.to_string(),
+        color: ColorChoice::Auto.enabled(),
+    };
+    emitter.emit(diagnostic);
+    std::process::exit(1);
+}
+
+/// Runs `f` with the diagnostics and timings setup shared by everything that
+/// compiles a program, reporting its error if there is one.
+fn run_build<F>(infile: &str, args: &BuildArgs, f: F) -> bool
+where
+    F: FnOnce(&str, &BuildArgs, &Emitter, &mut Timings) -> Result<bool, Diagnostic>,
+{
+    let emitter = Emitter {
+        format: args.message_format,
+        file: infile.to_string(),
+        color: args.color.enabled(),
+    };
+
+    let mut timings = Timings::default();
+    let result = f(infile, args, &emitter, &mut timings);
+
+    if args.timings {
+        for line in timings.report() {
+            eprintln!("{line}");
+        }
+    }
+
+    match result {
+        Ok(passed) => passed,
+        Err(diagnostic) => {
+            emitter.emit(&diagnostic);
+            false
+        }
+    }
+}
+
+/// Whether `infile` holds IR rather than source, going by its extension.
+fn is_ir(infile: &str) -> bool {
+    Path::new(infile)
+        .extension()
+        .is_some_and(|ext| ext == "bfir")
+}
 
-    let file_contents = read_bf_file(&infile);
+/// Reads and lexes `infile`, without optimising it. IR is read as the tokens
+/// it gives.
+fn lex_file(infile: &str, args: &BuildArgs, timings: &mut Timings) -> Result<Tokens, Diagnostic> {
+    let file_contents = timings.time("read", || read_bf_file(infile))?;
     log::debug!(
         "Read file: {:#?} ({:#?} chars)",
-        &args.infile,
+        infile,
         file_contents.len()
     );
+    if is_ir(infile) {
+        return timings.time("parse", || ir::parse(&file_contents));
+    }
 
-    let tokens = lex::lex(&file_contents);
-    log::debug!("Lexed to {:#?} symbols", tokens.len());
+    lex_source(infile, &file_contents, args, timings)
+}
 
-    let optimised_tokens = lex::optimise_tokens(tokens);
-    log::debug!("Optimised to {:#?} symbols", optimised_tokens.len());
+/// Lexes `file_contents`, the source of `infile`, without optimising it.
+fn lex_source(
+    infile: &str,
+    file_contents: &str,
+    args: &BuildArgs,
+    timings: &mut Timings,
+) -> Result<Tokens, Diagnostic> {
+    let syntax = match &args.dialect_map {
+        Some(path) => Syntax::Map(DialectMap::load(path)?),
+        None => Syntax::Dialect(args.dialect.unwrap_or_else(|| Dialect::from_path(infile))),
+    };
 
-    let profile = if let Some(profile_name) = &args.profile {
-        Profile::get_by_string(profile_name).expect("Profile not found")
+    // Extensions rewrite the source as a whole, but otherwise it's lexed as
+    // it's read so huge programs never exist one token per character
+    let source: Box<dyn Iterator<Item = (char, Span)>> = if args.extensions.is_empty() {
+        Box::new(lex::spanned_chars(file_contents, None))
     } else {
-        Profile::default()
+        let source = if args.extensions.contains(&Extension::Macros) {
+            timings.time("preprocess", || Preprocessor::run(infile, file_contents))?
+        } else {
+            lex::spanned_chars(file_contents, None).collect()
+        };
+        let source = if args.extensions.contains(&Extension::Literals) {
+            timings.time("desugar", || sugar::desugar(&source))?
+        } else {
+            source
+        };
+        let source = if args.extensions.contains(&Extension::Externs) {
+            externs::extract(&source)?
+        } else {
+            source
+        };
+        let source = if args.extensions.contains(&Extension::Tapes) {
+            source.into_iter().map(lex::mark_tapes).collect()
+        } else {
+            source
+        };
+        Box::new(source.into_iter())
     };
+    let source: Box<dyn Iterator<Item = (char, Span)>> = if args.debug_hash {
+        Box::new(source.map(lex::mark_debug))
+    } else {
+        source
+    };
+
+    let tokens: Tokens = timings.time("lex", || lex::lex(syntax.commands(source)?).tokens())?;
+    let count = tokens.len();
+    log::info!(tokens = count; "Lexed to {count} symbols");
+
+    Ok(tokens)
+}
+
+/// Reads, lexes and optimises `infile`, unrolling loops as `counts` from
+/// --pgo-use suggest.
+fn load(
+    infile: &str,
+    args: &BuildArgs,
+    counts: Option<&pgo::Counts>,
+    timings: &mut Timings,
+) -> Result<Tokens, Diagnostic> {
+    let tokens = lex_file(infile, args, timings)?;
+
+    let pipeline = gen_opt_pipeline(args, infile);
+    let context = passes::Context {
+        unroll_limit: args.unroll_limit,
+        budget: gen_budget(args),
+        counts,
+    };
+    let optimised_tokens = pipeline.run(tokens, &context, timings, |name, tokens| {
+        if args
+            .dump_after
+            .iter()
+            .any(|pass| pass == name || pass == "all")
+        {
+            eprintln!("after {name}, {} instructions:", tokens.len());
+            for (token, span) in tokens {
+                eprintln!("{:>10}  {token:?}", span.to_string());
+            }
+        }
+    });
+    let count = optimised_tokens.len();
+    log::info!(tokens = count; "Optimised to {count} symbols");
+
+    Ok(optimised_tokens)
+}
+
+/// The optimisation passes named with --passes, or `default` without them,
+/// less those disabled with --disable-pass.
+fn gen_pipeline(args: &BuildArgs, default: &[&str]) -> Pipeline {
+    let mut pipeline = match &args.passes {
+        Some(names) => Pipeline::new(names),
+        None => Pipeline::new(default),
+    }
+    .expect("pass names are checked when parsing arguments");
+    for name in &args.disable_pass {
+        pipeline.disable(name);
+    }
+    pipeline
+}
+
+/// The optimisation passes a build of `infile` runs, by default those for
+/// its -O level, or none for IR, which is taken to be optimised already.
+fn gen_opt_pipeline(args: &BuildArgs, infile: &str) -> Pipeline {
+    gen_pipeline(
+        args,
+        match args.opt_level {
+            _ if is_ir(infile) => &[],
+            OptLevel::O0 => &[],
+            OptLevel::O2 => passes::UNROLLING,
+            _ => passes::LOCAL,
+        },
+    )
+}
+
+/// Reads the counts from a training run given with --pgo-use.
+fn load_pgo(args: &BuildArgs) -> Result<Option<pgo::Counts>, Diagnostic> {
+    let Some(path) = &args.pgo_use else {
+        return Ok(None);
+    };
+    let contents = fs::read_to_string(path)
+        .map_err(|e| Diagnostic::error("E0005", format!("could not read {path}: {e}")))?;
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|e| Diagnostic::error("E0018", format!("invalid loop counts in {path}: {e}")))
+}
+
+/// Runs `tokens`, read from `infile`, in the interpreter on stdin, counting
+/// how often each instruction runs. The counts come with how the run ended,
+/// so that a run that failed can still be reported on.
+fn run_counted(
+    infile: &str,
+    tokens: &Tokens,
+    args: &BuildArgs,
+    phase: &'static str,
+    timings: &mut Timings,
+) -> Result<(Vec<u64>, Result<(), interpret::Error>), Diagnostic> {
+    let vars = select_build_profile(args)?
+        .variables(&gen_variables(args, infile))
+        .map_err(|e| Diagnostic::error("E0003", e))?;
+
+    let mut machine = interpret::Machine::new(&gen_interpret_config(args, &vars));
+    let mut program = interpret::Program::new(tokens).counting();
+    let _raw_tty = args.raw_tty.then(tty::RawTty::enable);
+    let result = timings.time(phase, || {
+        machine.finish(
+            &mut program,
+            &mut stdin_input(args),
+            &mut io::stdout().lock(),
+        )
+    });
+    Ok((program.counts().unwrap().to_vec(), result))
+}
+
+/// Runs `infile` in the interpreter on stdin, writing how often each loop ran
+/// to `outfile`. Loops are kept as loops, rather than turned into
+/// superinstructions, so that they can all be counted.
+fn pgo_gen(
+    infile: &str,
+    outfile: &str,
+    args: &BuildArgs,
+    timings: &mut Timings,
+) -> Result<bool, Diagnostic> {
+    let tokens = lex::optimise_tokens(lex_file(infile, args, timings)?, timings);
+    let (runs, result) = run_counted(infile, &tokens, args, "train", timings)?;
+    if let Err(e) = result {
+        return Err(Diagnostic::error(
+            "E0040",
+            format!("training run failed: {e}"),
+        ));
+    }
+
+    let counts = pgo::Counts::record(&tokens, &runs);
+    log::info!(
+        "Training ran {} instructions over {} loops",
+        counts.steps,
+        counts.loops.len()
+    );
+    let json = serde_json::to_string_pretty(&counts).unwrap();
+    write_output(outfile, &[json, String::new()])?;
+    Ok(true)
+}
+
+/// Runs `infile` in the interpreter on stdin, writing which of its
+/// instructions ran to `outfile`. The program isn't optimised, so that each
+/// instruction is counted where it's written. The report is written even if
+/// the program fails, showing how far it got.
+fn coverage_run(
+    infile: &str,
+    outfile: &str,
+    format: coverage::Format,
+    args: &BuildArgs,
+    timings: &mut Timings,
+) -> Result<bool, Diagnostic> {
+    let tokens = lex_file(infile, args, timings)?;
+    let (runs, result) = run_counted(infile, &tokens, args, "run", timings)?;
+
+    let coverage = Coverage::new(infile, &tokens, &runs);
+    write_coverage(&coverage, format, Some(outfile))?;
+    if let Err(e) = result {
+        return Err(Diagnostic::error("E0040", format!("program failed: {e}")));
+    }
+    Ok(true)
+}
+
+/// Where a program built with --instrument-coverage has its instructions
+/// described, and where it writes its counts.
+fn coverage_files(binary: &str) -> (PathBuf, PathBuf) {
+    let binary = Path::new(binary);
+    (
+        binary.with_extension("cov.json"),
+        binary.with_extension("counts"),
+    )
+}
+
+/// Reports on the last run of `binary`, built with --instrument-coverage.
+fn coverage_report(
+    binary: &str,
+    format: coverage::Format,
+    outfile: Option<&str>,
+) -> Result<(), Diagnostic> {
+    let (map, counts) = coverage_files(binary);
+    let read = |path: &Path| {
+        fs::read(path).map_err(|e| {
+            Diagnostic::error("E0030", format!("could not read {}: {e}", path.display()))
+        })
+    };
+
+    let mut coverage: Coverage = serde_json::from_slice(&read(&map)?).map_err(|e| {
+        Diagnostic::error(
+            "E0030",
+            format!("invalid coverage map {}: {e}", map.display()),
+        )
+    })?;
+    let bytes = read(&counts)?;
+    let runs: Vec<u64> = bytes
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect();
+    if bytes.len() % 8 != 0 || !coverage.set_counts(&runs) {
+        return Err(Diagnostic::error(
+            "E0030",
+            format!(
+                "{} doesn't hold the counts for {binary}, so it may have been rebuilt since it ran",
+                counts.display()
+            ),
+        ));
+    }
+
+    write_coverage(&coverage, format, outfile)
+}
+
+/// Writes `coverage` to `outfile`, or stdout, reading the sources it covers
+/// to annotate them if asked.
+fn write_coverage(
+    coverage: &Coverage,
+    format: coverage::Format,
+    outfile: Option<&str>,
+) -> Result<(), Diagnostic> {
+    let report = match format {
+        coverage::Format::Lcov => coverage.lcov(),
+        coverage::Format::Annotated => {
+            let mut report = String::new();
+            for file in coverage.files() {
+                let source = fs::read_to_string(file).map_err(|e| {
+                    Diagnostic::error("E0005", format!("could not read {file}: {e}"))
+                })?;
+                report.push_str(&coverage.annotate(file, &source));
+            }
+            report
+        }
+    };
+    match outfile {
+        Some(outfile) => fs::write(outfile, report)
+            .map_err(|e| Diagnostic::error("E0005", format!("could not write {outfile}: {e}"))),
+        None => {
+            print!("{report}");
+            Ok(())
+        }
+    }
+}
+
+/// Stdin, for the interpreter to read the program's input from. With
+/// --raw-tty, stdout is flushed before each read, since a key can be asked
+/// for without a newline.
+fn stdin_input(args: &BuildArgs) -> Box<dyn Read> {
+    if args.raw_tty {
+        Box::new(tty::PromptedStdin)
+    } else {
+        Box::new(io::stdin().lock())
+    }
+}
+
+/// Where `bfc vm` saves the program's state, and where it carries on from.
+struct Checkpoints<'a> {
+    save: Option<&'a str>,
+    resume: Option<&'a str>,
+}
+
+/// Runs the bytecode in `infile` on stdin, on the tape it was compiled for,
+/// saving its state and carrying on from saved state as `checkpoints` says.
+fn vm(
+    infile: &str,
+    checkpoints: &Checkpoints,
+    args: &BuildArgs,
+    timings: &mut Timings,
+) -> Result<bool, Diagnostic> {
+    let bytes = timings
+        .time("read", || fs::read(infile))
+        .map_err(|e| Diagnostic::error("E0005", format!("could not read {infile}: {e}")))?;
+    let program = timings
+        .time("decode", || bytecode::decode(&bytes))
+        .map_err(|e| Diagnostic::error("E0027", format!("could not load {infile}: {e}")))?;
+
+    let vars = select_build_profile(args)?
+        .variables(&gen_variables(args, infile))
+        .map_err(|e| Diagnostic::error("E0003", e))?;
+    let config = interpret::Config {
+        tape_size: program.tape_size,
+        cell_bits: program.cell_bits,
+        pause_at_debug: checkpoints.save.is_some(),
+        pause: checkpoints.save.map(|_| signal::on_sigusr1()),
+        ..gen_interpret_config(args, &vars)
+    };
+    let digest = digest::sha256(&bytes);
+    let mut machine = interpret::Machine::new(&config);
+    let mut state = interpret::Program::new(&program.tokens);
+    if let Some(path) = checkpoints.resume {
+        fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| checkpoint::decode(&bytes).map_err(|e| e.to_string()))
+            .and_then(|checkpoint| {
+                machine
+                    .restore(&mut state, &checkpoint, digest)
+                    .map_err(|e| e.to_string())
+            })
+            .map_err(|e| {
+                Diagnostic::error("E0035", format!("could not resume from {path}: {e}"))
+            })?;
+        log::info!("Resuming from {path} at instruction {}", state.pc());
+    }
+
+    let _raw_tty = args.raw_tty.then(tty::RawTty::enable);
+    let result = timings.time("run", || {
+        let mut input = stdin_input(args);
+        let mut output = io::stdout().lock();
+        loop {
+            match machine.finish(&mut state, &mut input, &mut output) {
+                Err(interpret::Error::Paused) => {
+                    let path = checkpoints.save.unwrap();
+                    // The file has the output so far to follow on from
+                    output.flush()?;
+                    save_checkpoint(path, &machine.checkpoint(&state, digest))?;
+                    log::info!("Saved a checkpoint to {path} at instruction {}", state.pc());
+                }
+                result => return result,
+            }
+        }
+    });
+    if let (Some(format), Some(heatmap)) = (config.heatmap, machine.heatmap()) {
+        eprint!("{}", heatmap.render(format));
+    }
+    if let Err(e) = result {
+        return Err(Diagnostic::error("E0040", format!("program failed: {e}")));
+    }
+    Ok(true)
+}
+
+/// Writes `checkpoint` to a file beside `path` and then moves it over `path`,
+/// so that the last checkpoint is left whole if bfc is stopped part way.
+fn save_checkpoint(path: &str, checkpoint: &Checkpoint) -> Result<(), interpret::Error> {
+    let partial = format!("{path}.partial");
+    fs::write(&partial, checkpoint::encode(checkpoint))?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// Name the REPL gives the code entered into it
+const REPL_FILE: &str = "<repl>";
+
+fn repl(args: &BuildArgs) -> Result<(), Diagnostic> {
+    let vars = select_build_profile(args)?
+        .variables(&gen_variables(args, REPL_FILE))
+        .map_err(|e| Diagnostic::error("E0003", e))?;
+    let emitter = Emitter {
+        format: args.message_format,
+        file: REPL_FILE.to_string(),
+        color: args.color.enabled(),
+    };
+
+    repl::run(
+        &gen_interpret_config(args, &vars),
+        args.opt_level.optimises(),
+        &emitter,
+        |source, optimise| {
+            let mut timings = Timings::default();
+            let tokens = lex_source(REPL_FILE, source, args, &mut timings)?;
+            if !optimise {
+                return Ok(tokens);
+            }
+            // Unrolling assumes the tape starts out zeroed, which it needn't
+            // be by the time a line is entered, so only local rewrites apply
+            let mut pipeline = gen_pipeline(args, passes::LOCAL);
+            pipeline.disable("unroll");
+            Ok(pipeline.run(tokens, &passes::Context::default(), &mut timings, |_, _| {}))
+        },
+    )
+    .map_err(|e| Diagnostic::error("E0041", format!("could not use the terminal: {e}")))
+}
+
+fn visualize(infile: &str, args: &BuildArgs, timings: &mut Timings) -> Result<bool, Diagnostic> {
+    let tokens = load(infile, args, load_pgo(args)?.as_ref(), timings)?;
+    let vars = select_build_profile(args)?
+        .variables(&gen_variables(args, infile))
+        .map_err(|e| Diagnostic::error("E0003", e))?;
+
+    let interactive = io::stdin().is_terminal();
+    let mut input = vec![];
+    if !interactive {
+        io::stdin()
+            .read_to_end(&mut input)
+            .map_err(|e| Diagnostic::error("E0005", format!("could not read stdin: {e}")))?;
+    }
+
+    let output = visualize::run(&tokens, &(&vars).into(), infile, input, interactive)
+        .map_err(|e| Diagnostic::error("E0041", format!("could not use the terminal: {e}")))?;
+    // The view is gone once it's left, so keep what the program wrote
+    io::stdout()
+        .write_all(&output)
+        .map_err(|e| Diagnostic::error("E0005", format!("could not write output: {e}")))?;
+    Ok(true)
+}
+
+fn format_file(
+    infile: &str,
+    outfile: &Option<String>,
+    style: fmt::Style,
+) -> Result<(), Diagnostic> {
+    let contents = read_bf_file(infile)?;
+    let commands =
+        Syntax::Dialect(Dialect::Brainfuck).commands(lex::spanned_chars(&contents, None))?;
+    lex::lex(commands).tokens()?;
+    let formatted = fmt::format(&contents, style);
+
+    match outfile {
+        Some(outfile) => write_output(outfile, &[formatted]),
+        None => {
+            print!("{formatted}");
+            Ok(())
+        }
+    }
+}
+
+fn text2bf(infile: &str, outfile: &Option<String>) -> Result<(), Diagnostic> {
+    let bytes = fs::read(infile)
+        .map_err(|e| Diagnostic::error("E0005", format!("could not read {infile}: {e}")))?;
+    let program = text2bf::generate(&bytes);
+
+    match outfile {
+        Some(outfile) => write_output(outfile, &[program, String::new()]),
+        None => {
+            println!("{program}");
+            Ok(())
+        }
+    }
+}
+
+/// Generates a random program of `size` commands, one that finishes within
+/// `fuel` instructions if that's given.
+fn gen(
+    size: usize,
+    seed: Option<u64>,
+    fuel: Option<u64>,
+    outfile: &Option<String>,
+) -> Result<(), Diagnostic> {
+    let seed = seed.unwrap_or_else(|| {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        eprintln!("seed: {seed}");
+        seed
+    });
+    let mut rng = gen::Rng::new(seed);
+    let program = match fuel {
+        Some(fuel) => gen::generate_terminating(size, fuel, &mut rng).ok_or_else(|| {
+            Diagnostic::error(
+                "E0021",
+                format!(
+                    "none of {} programs finished within {fuel} instructions",
+                    gen::MAX_ATTEMPTS
+                ),
+            )
+        })?,
+        None => gen::generate(size, &mut rng),
+    };
+
+    match outfile {
+        Some(outfile) => write_output(outfile, &[program, String::new()]),
+        None => {
+            println!("{program}");
+            Ok(())
+        }
+    }
+}
+
+fn read_input(input: &Option<String>) -> Result<Vec<u8>, Diagnostic> {
+    match input {
+        Some(path) => fs::read(path)
+            .map_err(|e| Diagnostic::error("E0005", format!("could not read {path}: {e}"))),
+        None => Ok(vec![]),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn bench(
+    infile: &str,
+    args: &BuildArgs,
+    levels: &[OptLevel],
+    runs: usize,
+    input: &[u8],
+    interpret: bool,
+    emitter: &Emitter,
+    timings: &mut Timings,
+) -> Result<Vec<bench::Stats>, Diagnostic> {
+    let temp_dir = toolchain::temp_dir()
+        .map_err(|e| Diagnostic::error("E0038", format!("could not create temp dir: {e}")))?;
+
+    let mut stats = vec![];
+    for &level in levels {
+        let binary = temp_dir.path().join(format!("bench-O{level}"));
+
+        let mut build = args.clone();
+        build.outfile = Some(binary.display().to_string());
+        build.output_assembly = false;
+        build.output_object = false;
+        build.emit = Emit::Bin;
+        build.opt_level = level;
+        compile(infile, &build, emitter, timings)?;
+
+        let mut samples = vec![];
+        for _ in 0..runs {
+            let start = Instant::now();
+            selftest::run_binary(&binary, input, args.timeout).map_err(|e| {
+                Diagnostic::error("E0039", format!("could not run {}: {e}", binary.display()))
+            })?;
+            samples.push(start.elapsed());
+        }
+        stats.push(bench::Stats::new(format!("-O{level}"), &samples));
+    }
+
+    if interpret {
+        let tokens = load(infile, args, load_pgo(args)?.as_ref(), timings)?;
+        let vars = select_build_profile(args)?
+            .variables(&gen_variables(args, infile))
+            .map_err(|e| Diagnostic::error("E0003", e))?;
+        let config = interpret::Config::from(&vars);
+
+        let samples: Vec<Duration> = (0..runs)
+            .map(|_| {
+                let start = Instant::now();
+                let _ = interpret::run(&tokens, &config, &mut &input[..], &mut std::io::sink());
+                start.elapsed()
+            })
+            .collect();
+        stats.push(bench::Stats::new(
+            format!("interpret -O{}", args.opt_level),
+            &samples,
+        ));
+    }
+
+    Ok(stats)
+}
+
+fn selftest(
+    infile: &str,
+    args: &BuildArgs,
+    input: &Option<String>,
+    emitter: &Emitter,
+    timings: &mut Timings,
+) -> Result<bool, Diagnostic> {
+    let temp_dir = toolchain::temp_dir()
+        .map_err(|e| Diagnostic::error("E0038", format!("could not create temp dir: {e}")))?;
+    let binary = temp_dir.path().join("selftest");
+
+    let mut build = args.clone();
+    build.outfile = Some(binary.display().to_string());
+    build.output_assembly = false;
+    build.output_object = false;
+    build.emit = Emit::Bin;
+    compile(infile, &build, emitter, timings)?;
+
+    let input = read_input(input)?;
+
+    let tokens = load(infile, args, load_pgo(args)?.as_ref(), timings)?;
+    let vars = select_build_profile(args)?
+        .variables(&gen_variables(args, infile))
+        .map_err(|e| Diagnostic::error("E0003", e))?;
+
+    let config = gen_interpret_config(args, &vars);
+    selftest::run(&binary, &tokens, &config, &input, args.exit_cell)
+        .map_err(|e| Diagnostic::error("E0039", format!("could not run {}: {e}", binary.display())))
+}
+
+/// How to interpret a program, tracing it and counting its cell use if
+/// asked.
+fn gen_interpret_config(args: &BuildArgs, vars: &Variables) -> interpret::Config {
+    interpret::Config {
+        trace: args.trace.then(|| interpret::Trace {
+            kinds: args.trace_only.clone(),
+            limit: args.trace_limit,
+        }),
+        heatmap: args.heatmap,
+        max_steps: args.max_steps,
+        timeout: args.timeout,
+        max_tape: args.max_tape,
+        max_output: args.max_output,
+        ..vars.into()
+    }
+}
+
+fn gen_lint_levels(args: &BuildArgs) -> LintLevels {
+    let mut levels = LintLevels::default();
+    for (lints, level) in [
+        (&args.allow, Level::Allow),
+        (&args.warn, Level::Warn),
+        (&args.deny, Level::Deny),
+    ] {
+        for &lint in lints {
+            levels.set(lint, level);
+        }
+    }
+    levels
+}
+
+/// Produces the output of `infile` on `input`, either by interpreting it or
+/// by compiling and running it.
+fn run_program(
+    infile: &str,
+    args: &BuildArgs,
+    interpret: bool,
+    input: &[u8],
+    emitter: &Emitter,
+    timings: &mut Timings,
+) -> Result<Result<Vec<u8>, String>, Diagnostic> {
+    if interpret {
+        let tokens = load(infile, args, load_pgo(args)?.as_ref(), timings)?;
+        let vars = select_build_profile(args)?
+            .variables(&gen_variables(args, infile))
+            .map_err(|e| Diagnostic::error("E0003", e))?;
+
+        let mut output = vec![];
+        return Ok(interpret::run(
+            &tokens,
+            &gen_interpret_config(args, &vars),
+            &mut &input[..],
+            &mut output,
+        )
+        .map(|_| output)
+        .map_err(|e| e.to_string()));
+    }
+
+    let temp_dir = toolchain::temp_dir()
+        .map_err(|e| Diagnostic::error("E0038", format!("could not create temp dir: {e}")))?;
+    let binary = temp_dir.path().join("test");
+
+    let mut build = args.clone();
+    build.outfile = Some(binary.display().to_string());
+    build.output_assembly = false;
+    build.output_object = false;
+    build.emit = Emit::Bin;
+    compile(infile, &build, emitter, timings)?;
+
+    let output = match selftest::run_binary(&binary, input, args.timeout) {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => return Ok(Err(e.to_string())),
+        Err(e) => {
+            return Err(Diagnostic::error(
+                "E0039",
+                format!("could not run {}: {e}", binary.display()),
+            ))
+        }
+    };
+    if output.status.success() {
+        Ok(Ok(output.stdout))
+    } else {
+        Ok(Err(format!("program failed ({})", output.status)))
+    }
+}
+
+fn run_tests(dir: &str, interpret: bool, args: &BuildArgs) -> bool {
+    let cases = match test_runner::discover(Path::new(dir)) {
+        Ok(cases) => cases,
+        Err(e) => {
+            eprintln!("error: could not read {dir}: {e}");
+            return false;
+        }
+    };
+
+    let mut outcomes = vec![];
+    for case in cases {
+        let outcome = match &case.expected {
+            None => Outcome::Skipped("no .expected file".to_string()),
+            Some(expected) => {
+                let mut result = None;
+                run_build(
+                    &case.program.display().to_string(),
+                    args,
+                    |infile, args, emitter, timings| {
+                        result = Some(run_program(
+                            infile,
+                            args,
+                            interpret,
+                            &case.input,
+                            emitter,
+                            timings,
+                        )?);
+                        Ok(true)
+                    },
+                );
+                match result {
+                    None => Outcome::Failed("could not build program".to_string()),
+                    Some(Err(e)) => Outcome::Failed(e),
+                    Some(Ok(actual)) => test_runner::compare(expected, &actual),
+                }
+            }
+        };
+        test_runner::report(&case.name, &outcome);
+        outcomes.push(outcome);
+    }
+
+    test_runner::summary(&outcomes)
+}
+
+/// Runs `first` and `second` on each input in `inputs`, printing the first
+/// difference between them. Returns whether they behaved the same.
+fn check_equiv(
+    first: &str,
+    second: &str,
+    inputs: &Option<String>,
+    eof: interpret::Eof,
+    fuel: u64,
+    args: &BuildArgs,
+) -> bool {
+    let mut config = None;
+    let mut programs = vec![];
+    for infile in [first, second] {
+        let mut tokens = None;
+        let built = run_build(infile, args, |infile, args, _, timings| {
+            tokens = Some(load(infile, args, load_pgo(args)?.as_ref(), timings)?);
+            let vars = select_build_profile(args)?
+                .variables(&gen_variables(args, infile))
+                .map_err(|e| Diagnostic::error("E0003", e))?;
+            config = Some(interpret::Config {
+                eof,
+                ..gen_interpret_config(args, &vars)
+            });
+            Ok(true)
+        });
+        match tokens {
+            Some(tokens) if built => programs.push(tokens),
+            _ => return false,
+        }
+    }
+    let config = config.unwrap();
+
+    let inputs = match inputs {
+        Some(dir) => match equiv::discover(dir) {
+            Ok(inputs) => inputs.into_iter().map(Some).collect(),
+            Err(e) => {
+                eprintln!("error: could not read {dir}: {e}");
+                return false;
+            }
+        },
+        None => vec![None],
+    };
+
+    for path in &inputs {
+        let input = match path {
+            Some(path) => match fs::read(path) {
+                Ok(input) => input,
+                Err(e) => {
+                    eprintln!("error: could not read {}: {e}", path.display());
+                    return false;
+                }
+            },
+            None => vec![],
+        };
+        let runs =
+            [&programs[0], &programs[1]].map(|tokens| equiv::run(tokens, &config, &input, fuel));
+        if let Some(difference) = equiv::compare([first, second], [&runs[0], &runs[1]]) {
+            match path {
+                Some(path) => println!("programs differ on input {}", path.display()),
+                None => println!("programs differ with no input"),
+            }
+            println!("    {difference}");
+            return false;
+        }
+    }
+
+    println!("programs behave the same on {} input(s)", inputs.len());
+    true
+}
+
+fn compile(
+    infile: &str,
+    args: &BuildArgs,
+    emitter: &Emitter,
+    timings: &mut Timings,
+) -> Result<bool, Diagnostic> {
+    build(&[infile.to_string()], false, args, emitter, timings)
+}
+
+/// The name a multicall binary runs `infile` by: its file name without the
+/// extension.
+fn program_name(infile: &str) -> Result<String, Diagnostic> {
+    let path = Path::new(infile);
+    let name = path
+        .file_stem()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(Diagnostic::error(
+            "E0019",
+            format!("`{name}` can't be the name of a program in a multicall binary"),
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// Builds `infiles` into one binary, which runs the program named by what
+/// it's called or its first argument.
+fn compile_multicall(
+    infiles: &[String],
+    args: &BuildArgs,
+    emitter: &Emitter,
+    timings: &mut Timings,
+) -> Result<bool, Diagnostic> {
+    build(infiles, true, args, emitter, timings)
+}
+
+/// Builds `infiles`, which is a single program unless `multicall` is set.
+fn build(
+    infiles: &[String],
+    multicall: bool,
+    args: &BuildArgs,
+    emitter: &Emitter,
+    timings: &mut Timings,
+) -> Result<bool, Diagnostic> {
+    let infile = &infiles[0];
+    let outfile = gen_file_names(infile, args);
+    let emit = args.emit();
+    if multicall && !matches!(emit, Emit::Bin | Emit::Asm) {
+        return Err(Diagnostic::error(
+            "E0019",
+            "multicall binaries can only be emitted as binaries or assembly",
+        ));
+    }
+    // Only one program is baked, so every other would be built unbaked
+    if multicall && args.bake_input.is_some() {
+        return Err(Diagnostic::error(
+            "E0019",
+            "--bake-input takes only one program, so can't be used with --multicall",
+        ));
+    }
+    if let Some(dir) = &args.out_dir {
+        fs::create_dir_all(dir)
+            .map_err(|e| Diagnostic::error("E0005", format!("could not create {dir}: {e}")))?;
+    }
+
+    let pgo = load_pgo(args)?;
+    let mut programs = vec![];
+    for infile in infiles {
+        let mut tokens = load(infile, args, pgo.as_ref(), timings)?;
+        if multicall {
+            // Spans name their file, so that diagnostics and debug info can
+            // tell the programs apart
+            let file: &'static String = Box::leak(Box::new(infile.clone()));
+            for (_, span) in &mut tokens {
+                span.file = span.file.or(Some(file));
+            }
+        }
+        programs.push(tokens);
+    }
+
+    let mut profile = Cow::Borrowed(select_build_profile(args)?);
+    if let Some(syntax) = args.syntax {
+        if !profile.supports_syntax(syntax) {
+            return Err(Diagnostic::error(
+                "E0032",
+                format!(
+                    "profile {} has no templates in {} syntax",
+                    profile.name(),
+                    syntax.name()
+                ),
+            ));
+        }
+        profile = Cow::Owned(profile.with_syntax(syntax).map_err(|e| {
+            Diagnostic::error(
+                "E0032",
+                format!(
+                    "invalid syntaxes section in profile {}: {e}",
+                    profile.name()
+                ),
+            )
+        })?);
+    }
+    if args.target.as_deref().is_some_and(profile::is_cross) && profile.supports_cross() {
+        profile = Cow::Owned(profile.cross().map_err(|e| {
+            Diagnostic::error(
+                "E0003",
+                format!("invalid cross section in profile {}: {e}", profile.name()),
+            )
+        })?);
+    }
+    if args.pie {
+        if !profile.supports_pie() {
+            return Err(Diagnostic::error(
+                "E0015",
+                format!(
+                    "profile {} does not support position-independent executables",
+                    profile.name()
+                ),
+            ));
+        }
+        profile = Cow::Owned(profile.pie().map_err(|e| {
+            Diagnostic::error(
+                "E0015",
+                format!("invalid pie section in profile {}: {e}", profile.name()),
+            )
+        })?);
+    }
+    if args.no_exit {
+        if !profile.supports_no_exit() {
+            return Err(Diagnostic::error(
+                "E0022",
+                format!("profile {} cannot return rather than exit", profile.name()),
+            ));
+        }
+        profile = Cow::Owned(profile.no_exit().map_err(|e| {
+            Diagnostic::error(
+                "E0022",
+                format!("invalid no_exit section in profile {}: {e}", profile.name()),
+            )
+        })?);
+    }
+    if args.guard_pages {
+        if !profile.supports_guard_pages() {
+            return Err(Diagnostic::error(
+                "E0033",
+                format!(
+                    "profile {} cannot put guard pages around the tape",
+                    profile.name()
+                ),
+            ));
+        }
+        profile = Cow::Owned(profile.guard_pages().map_err(|e| {
+            Diagnostic::error(
+                "E0033",
+                format!(
+                    "invalid guard_pages section in profile {}: {e}",
+                    profile.name()
+                ),
+            )
+        })?);
+    }
+    if args.input_source != InputSource::Stdin {
+        let (source, supported) = match args.input_source {
+            InputSource::Arg => ("arg", profile.supports_arg_input()),
+            _ => ("file", profile.supports_file_input()),
+        };
+        if !supported {
+            return Err(Diagnostic::error(
+                "E0020",
+                format!(
+                    "profile {} cannot read input from the {source} source",
+                    profile.name()
+                ),
+            ));
+        }
+        let section = match args.input_source {
+            InputSource::Arg => profile.arg_input(),
+            _ => profile.file_input(),
+        };
+        profile = Cow::Owned(section.map_err(|e| {
+            Diagnostic::error(
+                "E0020",
+                format!(
+                    "invalid {source}_input section in profile {}: {e}",
+                    profile.name()
+                ),
+            )
+        })?);
+    }
+    if args.output_data != OutputData::Stdout {
+        let (sink, supported) = match args.output_data {
+            OutputData::File => ("file", profile.supports_file_output()),
+            _ => ("buffer", profile.supports_buffer_output()),
+        };
+        if !supported {
+            return Err(Diagnostic::error(
+                "E0029",
+                format!("profile {} cannot write output to a {sink}", profile.name()),
+            ));
+        }
+        let section = match args.output_data {
+            OutputData::File => profile.file_output(),
+            _ => profile.buffer_output(),
+        };
+        profile = Cow::Owned(section.map_err(|e| {
+            Diagnostic::error(
+                "E0029",
+                format!(
+                    "invalid {sink}_output section in profile {}: {e}",
+                    profile.name()
+                ),
+            )
+        })?);
+    }
+    let profile: &Profile = &profile;
     log::trace!("Using profile: {:#?}", profile);
 
-    let asm = generate_asm(profile, optimised_tokens);
-    log::debug!("Generated assembly");
+    if multicall && !profile.supports_multicall() {
+        return Err(Diagnostic::error(
+            "E0019",
+            format!(
+                "profile {} does not support multicall binaries",
+                profile.name()
+            ),
+        ));
+    }
+
+    if args.debug && !profile.supports_debug() {
+        return Err(Diagnostic::error(
+            "E0006",
+            format!("profile {} does not support debug info", profile.name()),
+        ));
+    }
+
+    if args.exit_cell && !profile.supports_exit_cell() {
+        return Err(Diagnostic::error(
+            "E0014",
+            format!(
+                "profile {} does not support exiting with the current cell",
+                profile.name()
+            ),
+        ));
+    }
+
+    if args.fault_handler && !profile.supports_fault_handler() {
+        return Err(Diagnostic::error(
+            "E0023",
+            format!("profile {} does not support fault handlers", profile.name()),
+        ));
+    }
+
+    if args.raw_tty && !profile.supports_raw_tty() {
+        return Err(Diagnostic::error(
+            "E0028",
+            format!(
+                "profile {} cannot read from the terminal a key at a time",
+                profile.name()
+            ),
+        ));
+    }
+
+    if args.instrument_coverage && !profile.supports_coverage() {
+        return Err(Diagnostic::error(
+            "E0030",
+            format!(
+                "profile {} cannot count the instructions run",
+                profile.name()
+            ),
+        ));
+    }
+
+    if args.checked && !profile.supports_checked() {
+        return Err(Diagnostic::error(
+            "E0008",
+            format!("profile {} does not support bounds checks", profile.name()),
+        ));
+    }
+    let procedures = programs
+        .iter()
+        .flatten()
+        .any(|(tok, _)| matches!(tok, Token::ProcStart(_) | Token::Call));
+    if procedures && !profile.supports_procedures() {
+        return Err(Diagnostic::error(
+            "E0011",
+            format!(
+                "profile {} does not support pbrain procedures",
+                profile.name()
+            ),
+        ));
+    }
+
+    let externs = programs
+        .iter()
+        .flatten()
+        .any(|(tok, _)| matches!(tok, Token::Extern(_)));
+    if externs && !profile.supports_externs() {
+        return Err(Diagnostic::error(
+            "E0017",
+            format!("profile {} does not support external calls", profile.name()),
+        ));
+    }
+
+    let tapes = programs
+        .iter()
+        .flatten()
+        .any(|(tok, _)| matches!(tok, Token::SwitchTape | Token::ExchangeCell));
+    if tapes && !profile.supports_tapes() {
+        return Err(Diagnostic::error(
+            "E0025",
+            format!("profile {} does not support a second tape", profile.name()),
+        ));
+    }
+    if tapes && args.checked {
+        return Err(Diagnostic::error(
+            "E0025",
+            "bounds checks only cover the first tape, so can't be used with a second",
+        ));
+    }
+
+    let debug = programs
+        .iter()
+        .flatten()
+        .any(|(tok, _)| *tok == Token::Debug);
+    if debug && !profile.supports_debug_hash() {
+        return Err(Diagnostic::error(
+            "E0024",
+            format!("profile {} does not support `#` debugging", profile.name()),
+        ));
+    }
+
+    let mut overrides = gen_variables(args, infile);
+    if args.instrument_coverage {
+        // The program may be run from anywhere, so it's given where to write
+        // its counts in full
+        let (_, counts) = coverage_files(&outfile);
+        let counts = std::path::absolute(&counts).unwrap_or(counts);
+        overrides.insert("coverage_file".to_string(), counts.display().to_string());
+    }
+    let vars = profile
+        .variables(&overrides)
+        .map_err(|e| Diagnostic::error("E0003", e))?;
+    log::trace!("Using variables: {:#?}", vars);
+    let profile = &profile.with_variables(&vars);
+
+    let tape_size = vars["tape_size"].parse().unwrap_or(usize::MAX);
+    let cell_bits = vars["cell_bytes"].parse::<usize>().unwrap() * 8;
+    if !profile.supports_cell_size(cell_bits) {
+        let sizes: Vec<String> = profile
+            .capabilities()
+            .cell_sizes
+            .iter()
+            .map(|bits| bits.to_string())
+            .collect();
+        return Err(Diagnostic::error(
+            "E0034",
+            format!(
+                "profile {} has no {cell_bits}-bit cells, only {}-bit ones",
+                profile.name(),
+                sizes.join("/")
+            ),
+        ));
+    }
+    if !profile.supports_tape_size(tape_size) {
+        return Err(Diagnostic::error(
+            "E0034",
+            format!(
+                "profile {}'s tape can have at most {} cells, not {tape_size}",
+                profile.name(),
+                profile.capabilities().max_tape_size.unwrap()
+            ),
+        ));
+    }
+    let first_debug = programs
+        .iter()
+        .flatten()
+        .find(|(tok, _)| *tok == Token::Debug);
+    if let Some(&(_, span)) = first_debug.filter(|_| profile.capabilities().buffered_output) {
+        emitter.emit(
+            &Diagnostic::note(
+                "buffered-output",
+                "`#` writes straight to stderr while output waits in a buffer, so output from before a `#` may come after it",
+            )
+            .with_span(span),
+        );
+    }
+    let diagnostics: Vec<Diagnostic> = timings.time("analysis", || {
+        programs
+            .iter()
+            .flat_map(|tokens| analysis::check(tokens, tape_size, &gen_lint_levels(args)))
+            .collect()
+    });
+    for diagnostic in &diagnostics {
+        emitter.emit(diagnostic);
+    }
+    let denied = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    if denied > 0 {
+        return Err(Diagnostic::error(
+            "E0007",
+            format!("aborting due to {denied} denied warning(s)"),
+        ));
+    }
+
+    if let Some(path) = &args.bake_input {
+        let input = fs::read(path)
+            .map_err(|e| Diagnostic::error("E0005", format!("could not read {path}: {e}")))?;
+        let config = interpret::Config::from(&vars);
+        let tokens = programs.pop().unwrap();
+        let (mut tokens, summary) = timings
+            .time("bake", || {
+                specialize::specialize(&tokens, &config, &input, args.bake_eof, args.bake_steps)
+            })
+            .map_err(|summary| {
+                let reason = summary.stopped.unwrap();
+                Diagnostic::error(
+                    "E0031",
+                    format!(
+                        "could not bake in {path}: only {} of its {} bytes were read before \
+                         stopping, as {reason}",
+                        summary.read,
+                        input.len()
+                    ),
+                )
+            })?;
+        let (read, written) = (summary.read, summary.written);
+        log::info!(read, written; "Ran ahead of time, reading {read} bytes and writing {written}");
+        if let Some(reason) = summary.stopped {
+            emitter.emit(&Diagnostic::note(
+                "bake-input",
+                format!("only part of the program ran ahead of time, as {reason}"),
+            ));
+        }
+        // The move that ends the code setting up the tape may fold into the
+        // first instruction of the rest
+        if args.opt_level.optimises() {
+            tokens = lex::fold_tokens(tokens);
+        }
+        programs.push(tokens);
+    }
+
+    // The programs are optimised by now, so they're lexed again for the
+    // instructions as written
+    let mut stats = None;
+    if args.stats {
+        let mut counted = Stats::default();
+        for (infile, tokens) in infiles.iter().zip(&programs) {
+            counted.source(&lex_file(infile, args, timings)?, tape_size);
+            counted.compiled(tokens);
+        }
+        if !matches!(emit, Emit::Bin | Emit::Obj | Emit::Asm) {
+            print_stats(&counted);
+        }
+        stats = Some(counted);
+    }
+    let report = match args.build_report {
+        Some(_) => Some(gen_build_report(infiles, args, profile, &vars)?),
+        None => None,
+    };
+
+    let mut checked = false;
+    for tokens in programs.iter().filter(|_| args.checked) {
+        match analysis::tape_bounds(tokens, tape_size) {
+            Bounds::Within { lo, hi } => {
+                log::info!("Pointer stays within cells {lo}..={hi}, eliding bounds checks");
+            }
+            Bounds::MayEscape(span) => {
+                emitter.emit(
+                    &Diagnostic::note(
+                        "bounds-check",
+                        "keeping bounds checks because the pointer may leave the tape here",
+                    )
+                    .with_span(span),
+                );
+                checked = true;
+            }
+        }
+    }
 
-    if args.output_assembly {
-        Profile::write_asm(&asm, Path::new(&asmfile)).unwrap();
+    if multicall {
+        let mut named = vec![];
+        for (infile, tokens) in infiles.iter().zip(programs) {
+            let name = program_name(infile)?;
+            if named.iter().any(|(other, _)| *other == name) {
+                return Err(Diagnostic::error(
+                    "E0019",
+                    format!("more than one program is called `{name}`"),
+                ));
+            }
+            named.push((name, tokens));
+        }
+        let options = gen_codegen_options(args, checked, pgo);
+        let codegen = timings.time("codegen", || {
+            Codegen::multicall(profile, named, &vars, &options)
+        });
+        return emit_code(
+            &codegen, profile, &vars, infile, &outfile, args, stats, report, timings,
+        );
+    }
+    let optimised_tokens = programs.pop().unwrap();
+
+    if emit == Emit::Dot {
+        let graph = dot::generate(&optimised_tokens);
+        write_output(&outfile, &graph)?;
+        return finish_build_report(args, report, &[outfile], timings);
+    }
+
+    if emit == Emit::Bf {
+        let program = minify::serialize(&optimised_tokens);
+        write_output(&outfile, &[program, String::new()])?;
+        return finish_build_report(args, report, &[outfile], timings);
+    }
+
+    if emit == Emit::Bytecode {
+        let config = interpret::Config::from(&vars);
+        let program = bytecode::Program {
+            tape_size: config.tape_size,
+            cell_bits: config.cell_bits,
+            tokens: optimised_tokens,
+        };
+        fs::write(&outfile, bytecode::encode(&program))
+            .map_err(|e| Diagnostic::error("E0005", format!("could not write {outfile}: {e}")))?;
+        return finish_build_report(args, report, &[outfile], timings);
+    }
+
+    if emit == Emit::Ir {
+        write_output(
+            &outfile,
+            &[
+                ir::write(&optimised_tokens, infile).join("\n"),
+                String::new(),
+            ],
+        )?;
+        return finish_build_report(args, report, &[outfile], timings);
+    }
+
+    if emit == Emit::Pseudo {
+        write_output(&outfile, &pseudo::generate(&optimised_tokens))?;
+        return finish_build_report(args, report, &[outfile], timings);
+    }
+
+    if emit == Emit::Listing {
+        let tokens = lex_file(infile, args, timings)?;
+        let listing = listing::generate(profile, &tokens, &optimised_tokens);
+        write_output(&outfile, &listing)?;
+        return finish_build_report(args, report, &[outfile], timings);
+    }
+
+    if args.opt_level == OptLevel::Os && !profile.supports_helpers() {
+        log::info!(
+            "Profile {} has no I/O helper routines, inlining I/O",
+            profile.name()
+        );
+    }
+    let options = gen_codegen_options(args, checked, pgo);
+    let codegen = timings.time("codegen", || {
+        Codegen::new(profile, optimised_tokens, &vars, &options)
+    });
+    emit_code(
+        &codegen, profile, &vars, infile, &outfile, args, stats, report, timings,
+    )
+}
+
+fn gen_codegen_options(
+    args: &BuildArgs,
+    checked: bool,
+    pgo: Option<pgo::Counts>,
+) -> codegen::Options {
+    codegen::Options {
+        debug: args.debug,
+        annotate: args.emit() == Emit::Asm,
+        checked,
+        helpers: args.opt_level == OptLevel::Os,
+        exit_cell: args.exit_cell,
+        pgo,
+        source_map: args.source_map,
+        fault_handler: args.fault_handler,
+        raw_tty: args.raw_tty,
+        coverage: args.instrument_coverage,
+        coalesce_changes: args.opt_level.optimises(),
+    }
+}
+
+/// Writes the assembly from `codegen` to `outfile`, or assembles it there and
+/// links it unless only an object is wanted. A source map of `infile` and a
+/// gdb script are written alongside if asked for, and `stats` printed with
+/// the size of what was written.
+#[allow(clippy::too_many_arguments)]
+fn emit_code(
+    codegen: &Codegen,
+    profile: &Profile,
+    vars: &Variables,
+    infile: &str,
+    outfile: &str,
+    args: &BuildArgs,
+    mut stats: Option<Stats>,
+    mut report: Option<BuildReport>,
+    timings: &mut Timings,
+) -> Result<bool, Diagnostic> {
+    let emit = args.emit();
+    let mut outputs = vec![outfile.to_string()];
+    // The peephole optimiser only reads Intel syntax
+    let peephole = (args.opt_level.optimises() && profile.syntax() == AsmSyntax::Intel)
+        .then(|| gen_budget(args));
+    // The source map is read from the assembly as written, after peephole
+    // optimisation
+    let mut asm = None;
+    let mut asm_lines = None;
+    let mut write = |path: &Path, timings: &mut Timings| {
+        asm_lines = Some(write_asm(codegen, peephole, path, timings)?);
+        if args.source_map {
+            asm = Some(fs::read_to_string(path)?);
+        }
+        Ok(())
+    };
+    let mut listing = None;
+    if emit == Emit::Asm {
+        write(Path::new(outfile), timings)
+            .map_err(|e| Diagnostic::error("E0005", format!("could not write {outfile}: {e}")))?;
     } else {
-        profile.generate_bin(&asm, Path::new(&execfile)).unwrap();
+        let mut toolchain = gen_toolchain(&args.toolchain, profile);
+        toolchain.save_temps = args.save_temps;
+        toolchain.reproducible = args.reproducible;
+        toolchain.debug = args.debug;
+        toolchain.strip = args.strip;
+        toolchain.small = args.small;
+        if args.opt_level == OptLevel::Os {
+            // Lets the assembler pick the shortest encoding for every jump
+            let size_args = toolchain.size_nasm_args.clone();
+            toolchain.nasm_args.extend(size_args);
+        }
+        let listing_dir = args
+            .source_map
+            .then(toolchain::temp_dir)
+            .transpose()
+            .map_err(|e| Diagnostic::error("E0038", format!("could not create temp dir: {e}")))?;
+        toolchain.listing = listing_dir.as_ref().map(|dir| dir.path().join("bfc.lst"));
+        log::trace!("Using toolchain: {:#?}", toolchain);
+        if let Some(report) = &mut report {
+            report.tools.push(report::Tool {
+                role: "assembler",
+                command: toolchain.nasm.clone(),
+                args: toolchain.nasm_args.clone(),
+                version: toolchain.nasm_version().ok(),
+            });
+            if emit == Emit::Bin {
+                report.tools.push(report::Tool {
+                    role: "linker",
+                    command: toolchain.linker.clone(),
+                    args: toolchain.linker_args.clone(),
+                    version: toolchain.linker_version().ok(),
+                });
+            }
+        }
+
+        if emit == Emit::Obj {
+            toolchain.generate_obj(write, Path::new(outfile), timings)?;
+        } else {
+            toolchain.generate_bin(write, Path::new(outfile), timings)?;
+        }
+        listing = toolchain
+            .listing
+            .and_then(|path| fs::read_to_string(path).ok());
+
+        if args.debug && profile.supports_gdb() {
+            let binary = Path::new(outfile);
+            let name = binary.file_name().unwrap_or_default().to_string_lossy();
+            let path = binary.with_file_name(format!("{name}-gdb.py"));
+            let path = path.display().to_string();
+            let script = gdb::script(profile, vars, codegen.tokens(), outfile, &path);
+            write_output(&path, &[script])?;
+            log::info!("Wrote gdb script to {path}");
+            outputs.push(path);
+        } else if args.debug {
+            log::info!(
+                "Profile {} has no gdb support, so no gdb script",
+                profile.name()
+            );
+        }
+    }
+
+    if args.instrument_coverage {
+        let (path, _) = coverage_files(outfile);
+        let path = path.display().to_string();
+        let coverage = Coverage::new(&vars["file"], codegen.tokens(), &[]);
+        let json = serde_json::to_string_pretty(&coverage).unwrap();
+        write_output(&path, &[json, String::new()])?;
+        log::info!("Wrote coverage map to {path}");
+        outputs.push(path);
+    }
+
+    if let Some(asm) = asm {
+        let mut map = SourceMap::new(infile, codegen.tokens(), &asm);
+        if let Some(listing) = listing {
+            map.add_listing(&listing);
+        }
+        let path = Path::new(outfile).with_extension("map.json");
+        let path = path.display().to_string();
+        let json = serde_json::to_string_pretty(&map).unwrap();
+        write_output(&path, &[json, String::new()])?;
+        log::info!("Wrote source map to {path}");
+        outputs.push(path);
+    }
+
+    if let Some(stats) = &mut stats {
+        stats.asm_lines = asm_lines;
+        if emit != Emit::Asm {
+            stats.size = fs::metadata(outfile).ok().map(|metadata| metadata.len());
+        }
+        print_stats(stats);
+    }
+
+    finish_build_report(args, report, &outputs, timings)
+}
+
+/// The start of the report of a build from `infiles` with `profile`, with
+/// everything known before any code is generated.
+fn gen_build_report(
+    infiles: &[String],
+    args: &BuildArgs,
+    profile: &Profile,
+    vars: &Variables,
+) -> Result<BuildReport, Diagnostic> {
+    let read = |path: &str| {
+        fs::read(path)
+            .map(|contents| report::File::new(path, &contents))
+            .map_err(|e| Diagnostic::error("E0005", format!("could not read {path}: {e}")))
+    };
+    let inputs = infiles
+        .iter()
+        .chain(&args.bake_input)
+        .chain(&args.pgo_use)
+        .map(|path| read(path))
+        .collect::<Result<_, _>>()?;
+    let sha256 = match profile.source() {
+        ProfileSource::User(path) => Some(read(&path.display().to_string())?.sha256),
+        _ => None,
+    };
+    Ok(BuildReport {
+        bfc_version: env!("CARGO_PKG_VERSION").to_string(),
+        command: std::env::args().collect(),
+        inputs,
+        profile: report::Profile {
+            name: profile.name().to_string(),
+            source: profile.source().to_string(),
+            sha256,
+        },
+        variables: vars.clone(),
+        passes: gen_opt_pipeline(args, &infiles[0]).names(),
+        tools: vec![],
+        timings: vec![],
+        outputs: vec![],
+    })
+}
+
+/// Writes `report`, if there is one, to the file given with --build-report,
+/// with the digests of `outputs` and the timings so far. Outputs that aren't
+/// files, such as standard output, are left out.
+fn finish_build_report(
+    args: &BuildArgs,
+    report: Option<BuildReport>,
+    outputs: &[String],
+    timings: &Timings,
+) -> Result<bool, Diagnostic> {
+    let (Some(mut report), Some(path)) = (report, &args.build_report) else {
+        return Ok(true);
+    };
+    report.outputs = outputs
+        .iter()
+        .filter_map(|output| {
+            fs::read(output)
+                .ok()
+                .map(|contents| report::File::new(output, &contents))
+        })
+        .collect();
+    report.set_timings(timings);
+    let json = serde_json::to_string_pretty(&report).unwrap();
+    write_output(path, &[json, String::new()])?;
+    log::info!("Wrote build report to {path}");
+    Ok(true)
+}
+
+fn print_stats(stats: &Stats) {
+    for line in stats.report() {
+        eprintln!("{line}");
     }
 }
@@ -0,0 +1,62 @@
+use crate::{cli::Extension, profile::Profile};
+use clap::ValueEnum;
+use clap_mangen::{
+    roff::{bold, italic, roman, Roff},
+    Man,
+};
+use std::io::{self, Write};
+
+/// Writes the man page for `cmd`, followed by sections on the profile file
+/// format and the language extensions.
+pub fn render(cmd: clap::Command, out: &mut dyn Write) -> Result<(), io::Error> {
+    let man = Man::new(cmd);
+    man.render_title(out)?;
+    man.render_name_section(out)?;
+    man.render_synopsis_section(out)?;
+    man.render_description_section(out)?;
+    man.render_options_section(out)?;
+    man.render_subcommands_section(out)?;
+
+    let mut roff = Roff::new();
+    roff.control("SH", ["PROFILES"]);
+    roff.text([
+        roman("A profile is a JSON file in the bfc config directory, such as "),
+        italic("~/.config/bfc"),
+        roman(" on Linux, holding the assembly templates and toolchain for a platform. "),
+        roman("Templates are lists of lines, in which {} stands for the instruction's operand "),
+        roman("and {name} for a variable. Its fields are:"),
+    ]);
+    for (field, doc) in Profile::field_docs() {
+        roff.control("TP", []);
+        roff.text([bold(*field)]);
+        roff.text([roman(*doc)]);
+    }
+
+    roff.control("SH", ["CONFIGURATION"]);
+    roff.text([
+        roman("Defaults for flags can be set in "),
+        italic("config.toml"),
+        roman(" in the config directory, keyed by the flag's long name, such as "),
+        bold("opt-level = \"2\""),
+        roman(" or "),
+        bold("allow = [\"tape-overflow\"]"),
+        roman(". Flags given on the command line or through the environment take precedence."),
+    ]);
+
+    roff.control("SH", ["EXTENSIONS"]);
+    roff.text([roman(
+        "Extensions to the language are enabled with --extensions, and are:",
+    )]);
+    for extension in Extension::value_variants() {
+        let value = extension.to_possible_value().unwrap();
+        roff.control("TP", []);
+        roff.text([bold(value.get_name())]);
+        if let Some(help) = value.get_help() {
+            roff.text([roman(help.to_string())]);
+        }
+    }
+    roff.to_writer(out)?;
+
+    man.render_version_section(out)?;
+    man.render_authors_section(out)
+}
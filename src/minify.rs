@@ -0,0 +1,65 @@
+use crate::{
+    analysis,
+    budget::Budget,
+    lex::{self, Span, Token, Tokens},
+    superinstructions,
+    timings::Timings,
+};
+
+/// Writes `tokens` back out as brainfuck.
+pub fn serialize(tokens: &[(Token, Span)]) -> String {
+    superinstructions::expand(tokens.to_vec(), |_| false)
+        .iter()
+        .map(|(token, _)| token.to_bf())
+        .collect()
+}
+
+/// Removes the loop starting at each of `starts` along with its body.
+fn remove_loops(tokens: Tokens, starts: &[usize]) -> Tokens {
+    let mut kept = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i].0 {
+            Token::LoopStart(distance) if starts.contains(&i) => i += distance as usize,
+            _ => kept.push(tokens[i]),
+        }
+        i += 1;
+    }
+    lex::link_loops(&mut kept);
+    kept
+}
+
+/// Rewrites `tokens` as the shortest brainfuck we can find with the same
+/// behaviour, dropping dead loops and trailing code that can't affect the
+/// output. Dead loops are looked for again after each removal, as long as
+/// `budget` allows.
+pub fn minify(tokens: Tokens, budget: &Budget, timings: &mut Timings) -> String {
+    let mut tokens = tokens;
+    let mut pass = 0;
+    while budget.allows(pass) {
+        pass += 1;
+        let dead = timings.time("dead loops", || analysis::dead_loops(&tokens));
+        if dead.is_empty() {
+            break;
+        }
+        tokens = lex::optimise_tokens(remove_loops(tokens, &dead), timings);
+    }
+
+    while matches!(
+        tokens.last(),
+        Some((
+            Token::PtrAdd(_)
+                | Token::PtrSub(_)
+                | Token::Add(_)
+                | Token::Sub(_)
+                | Token::SetZero
+                | Token::MulAdd { .. }
+                | Token::AddAt { .. },
+            _
+        ))
+    ) {
+        tokens.pop();
+    }
+
+    serialize(&tokens)
+}
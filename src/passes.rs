@@ -0,0 +1,160 @@
+//! The optimisation passes, each with a name, and the pipelines that run them
+//! in order. A pass takes the whole program and gives back one that does the
+//! same, so passes can be added, dropped or reordered independently.
+use crate::{
+    budget::Budget,
+    lex::{self, Tokens},
+    pgo::Counts,
+    superinstructions::{self, Shape},
+    timings::Timings,
+    unroll,
+};
+use clap::builder::{PossibleValue, PossibleValuesParser};
+
+/// What passes may need besides the program.
+#[derive(Debug, Clone, Copy)]
+pub struct Context<'a> {
+    /// Most instructions a loop may unroll into
+    pub unroll_limit: usize,
+    pub budget: Budget,
+    /// Loop counts from a training run
+    pub counts: Option<&'a Counts>,
+}
+
+/// Unrolls nothing and has no limit on the time spent.
+impl Default for Context<'_> {
+    fn default() -> Self {
+        Context {
+            unroll_limit: 0,
+            budget: Budget::new(None, None),
+            counts: None,
+        }
+    }
+}
+
+pub struct Pass {
+    pub name: &'static str,
+    pub help: &'static str,
+    run: fn(Tokens, &Context) -> Tokens,
+}
+
+pub const PASSES: &[Pass] = &[
+    Pass {
+        name: "fold",
+        help: "Fold runs of moves and of changes, dropping those that cancel out",
+        run: |tokens, _| lex::fold_tokens(tokens),
+    },
+    Pass {
+        name: "unroll",
+        help: "Unroll loops that run a known number of times",
+        run: |tokens, context| {
+            unroll::unroll_loops(
+                tokens,
+                context.unroll_limit,
+                &context.budget,
+                context.counts,
+            )
+        },
+    },
+    Pass {
+        name: "clear-loops",
+        help: "Turn loops that count their cell down to zero into SetZero",
+        run: |tokens, _| superinstructions::combine(tokens, &[Shape::Clear]),
+    },
+    Pass {
+        name: "scan-loops",
+        help: "Turn loops that move until they find a zero cell into scans",
+        run: |tokens, _| superinstructions::combine(tokens, &[Shape::Scan]),
+    },
+    Pass {
+        name: "mul-loops",
+        help: "Turn loops that add multiples of their cell to others into MulAdds",
+        run: |tokens, _| superinstructions::combine(tokens, &[Shape::Mul]),
+    },
+    Pass {
+        name: "add-at",
+        help: "Turn moves to a cell, a change to it and a move back into AddAt",
+        run: |tokens, _| superinstructions::combine(tokens, &[Shape::AddAt]),
+    },
+];
+
+/// The passes that only rewrite code in place, as -O1 and -Os run them.
+/// Loops are combined before `add-at`, which would otherwise take apart the
+/// bodies of multiplication loops.
+pub const LOCAL: &[&str] = &["fold", "clear-loops", "scan-loops", "mul-loops", "add-at"];
+
+/// The passes -O2 runs, which fold again after unrolling since copies of a
+/// body meet end to start.
+pub const UNROLLING: &[&str] = &[
+    "fold",
+    "unroll",
+    "fold",
+    "clear-loops",
+    "scan-loops",
+    "mul-loops",
+    "add-at",
+];
+
+pub fn get(name: &str) -> Option<&'static Pass> {
+    PASSES.iter().find(|pass| pass.name == name)
+}
+
+/// Parses a pass name, listing them all with what they do in --help.
+pub fn value_parser() -> PossibleValuesParser {
+    PossibleValuesParser::new(
+        PASSES
+            .iter()
+            .map(|pass| PossibleValue::new(pass.name).help(pass.help)),
+    )
+}
+
+/// Passes to run one after another.
+#[derive(Clone)]
+pub struct Pipeline {
+    passes: Vec<&'static Pass>,
+}
+
+impl Pipeline {
+    pub fn new<S: AsRef<str>>(names: &[S]) -> Result<Self, String> {
+        let passes = names
+            .iter()
+            .map(|name| {
+                let name = name.as_ref();
+                get(name).ok_or_else(|| format!("there's no optimisation pass called `{name}`"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Pipeline { passes })
+    }
+
+    /// Drops every run of the pass called `name`.
+    pub fn disable(&mut self, name: &str) {
+        self.passes.retain(|pass| pass.name != name);
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name).collect()
+    }
+
+    /// Runs each pass on `tokens` in turn, timing it under its name, and
+    /// hands the program after it to `dump`.
+    pub fn run(
+        &self,
+        mut tokens: Tokens,
+        context: &Context,
+        timings: &mut Timings,
+        mut dump: impl FnMut(&'static str, &Tokens),
+    ) -> Tokens {
+        for pass in &self.passes {
+            tokens = timings.time(pass.name, || (pass.run)(tokens, context));
+            dump(pass.name, &tokens);
+        }
+        tokens
+    }
+}
+
+/// Runs the [`LOCAL`] passes on `tokens`.
+pub fn optimise_locally(tokens: Tokens, timings: &mut Timings) -> Tokens {
+    Pipeline::new(LOCAL)
+        .unwrap()
+        .run(tokens, &Context::default(), timings, |_, _| {})
+}
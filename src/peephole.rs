@@ -0,0 +1,471 @@
+use crate::budget::Budget;
+
+/// One line of assembly, split into its parts when it's an instruction.
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    Instruction {
+        mnemonic: String,
+        operands: Vec<String>,
+        text: String,
+    },
+    Label(String),
+    /// Comments and blank lines, which don't get in the way of rewrites
+    Comment(String),
+    /// Directives and anything else we don't understand, which rewrites never
+    /// look past
+    Other(String),
+}
+
+impl Line {
+    fn parse(text: &str) -> Line {
+        let code = text.split(';').next().unwrap_or("").trim();
+        if code.is_empty() {
+            return Line::Comment(text.to_string());
+        }
+        if code.starts_with('%') {
+            return Line::Other(text.to_string());
+        }
+        if let Some(label) = code.strip_suffix(':') {
+            if !label.contains(char::is_whitespace) {
+                return Line::Label(label.to_string());
+            }
+        }
+        if code.len() != text.trim().len() {
+            // Keep trailing comments by leaving the line alone
+            return Line::Other(text.to_string());
+        }
+
+        let (mnemonic, operands) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+        let mnemonic = mnemonic.to_ascii_lowercase();
+        if matches!(
+            mnemonic.as_str(),
+            "section" | "global" | "extern" | "default" | "bits" | "align"
+        ) {
+            return Line::Other(text.to_string());
+        }
+        let operands = operands
+            .split(',')
+            .map(|op| op.trim().to_string())
+            .filter(|op| !op.is_empty())
+            .collect();
+        Line::Instruction {
+            mnemonic,
+            operands,
+            text: text.to_string(),
+        }
+    }
+
+    fn render(&self) -> String {
+        match self {
+            Line::Label(label) => format!("{label}:"),
+            Line::Instruction { text, .. } | Line::Comment(text) | Line::Other(text) => {
+                text.clone()
+            }
+        }
+    }
+
+    fn instruction(&self) -> Option<(&str, &[String])> {
+        match self {
+            Line::Instruction {
+                mnemonic, operands, ..
+            } => Some((mnemonic, operands)),
+            _ => None,
+        }
+    }
+}
+
+fn is_register(operand: &str) -> bool {
+    !operand.is_empty() && operand.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Jumps that only read the zero flag, which `add` and `sub` set the same way
+/// whichever order they're done in.
+fn reads_zero_flag_only(mnemonic: &str) -> bool {
+    matches!(mnemonic, "jz" | "jnz" | "je" | "jne")
+}
+
+fn is_jump(mnemonic: &str) -> bool {
+    mnemonic.starts_with('j')
+}
+
+/// Instructions that set every flag `add` and `sub` set, without reading any.
+fn overwrites_flags(mnemonic: &str) -> bool {
+    matches!(
+        mnemonic,
+        "add" | "sub" | "cmp" | "test" | "and" | "or" | "xor" | "neg"
+    )
+}
+
+/// Instructions that neither read nor set the flags.
+fn keeps_flags(mnemonic: &str) -> bool {
+    matches!(
+        mnemonic,
+        "mov" | "movzx" | "movsx" | "movsxd" | "lea" | "push" | "pop"
+    )
+}
+
+/// What the code from `lines[start]` on reads of the flags set just before
+/// it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlagUse {
+    /// Nothing, as they're set again first
+    Unread,
+    /// Only the zero flag, for a jump
+    ZeroOnly,
+    /// Possibly any of them, including after a label or a jump, or at the end
+    Any,
+}
+
+fn flag_use(lines: &[Line], start: usize) -> FlagUse {
+    for line in &lines[start..] {
+        if let Line::Comment(_) = line {
+            continue;
+        }
+        match line.instruction() {
+            Some((mnemonic, _)) if keeps_flags(mnemonic) => {}
+            Some((mnemonic, _)) if overwrites_flags(mnemonic) => return FlagUse::Unread,
+            Some((mnemonic, _)) if reads_zero_flag_only(mnemonic) => return FlagUse::ZeroOnly,
+            _ => return FlagUse::Any,
+        }
+    }
+    FlagUse::Any
+}
+
+/// Evaluates an operand made of integers, `+`, `-`, `*` and parentheses.
+fn evaluate(expr: &str) -> Option<i64> {
+    fn sum(chars: &[char], i: &mut usize) -> Option<i64> {
+        let mut value = product(chars, i)?;
+        while let Some(&op) = chars.get(*i).filter(|c| matches!(c, '+' | '-')) {
+            *i += 1;
+            let rhs = product(chars, i)?;
+            value = if op == '+' {
+                value.checked_add(rhs)?
+            } else {
+                value.checked_sub(rhs)?
+            };
+        }
+        Some(value)
+    }
+    fn product(chars: &[char], i: &mut usize) -> Option<i64> {
+        let mut value = atom(chars, i)?;
+        while chars.get(*i) == Some(&'*') {
+            *i += 1;
+            value = value.checked_mul(atom(chars, i)?)?;
+        }
+        Some(value)
+    }
+    fn atom(chars: &[char], i: &mut usize) -> Option<i64> {
+        match chars.get(*i)? {
+            '(' => {
+                *i += 1;
+                let value = sum(chars, i)?;
+                (chars.get(*i) == Some(&')')).then_some(())?;
+                *i += 1;
+                Some(value)
+            }
+            '-' => {
+                *i += 1;
+                atom(chars, i)?.checked_neg()
+            }
+            c if c.is_ascii_digit() => {
+                let start = *i;
+                while chars.get(*i).is_some_and(char::is_ascii_digit) {
+                    *i += 1;
+                }
+                chars[start..*i].iter().collect::<String>().parse().ok()
+            }
+            _ => None,
+        }
+    }
+
+    let chars: Vec<char> = expr.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut i = 0;
+    let value = sum(&chars, &mut i)?;
+    (i == chars.len()).then_some(value)
+}
+
+/// The signed amount an `add` or `sub` of a register by `amount` moves it,
+/// written as an expression.
+fn signed(mnemonic: &str, amount: &str) -> Option<String> {
+    match mnemonic {
+        "add" => Some(format!("({amount})")),
+        "sub" => Some(format!("-({amount})")),
+        _ => None,
+    }
+}
+
+/// Index of the last instruction, label or directive in `lines`, skipping
+/// comments.
+fn last_code(lines: &[Line]) -> Option<usize> {
+    lines
+        .iter()
+        .rposition(|line| !matches!(line, Line::Comment(_)))
+}
+
+/// Merges `add`s and `sub`s of the same register by constants that follow
+/// each other, such as pointer moves from neighbouring templates. The merged
+/// instruction only sets the zero flag the same way, so this only happens
+/// where nothing else is read of the flags, and the total must fit in the
+/// signed 32-bit immediate that 64-bit registers take.
+fn fold_register_moves(mut lines: Vec<Line>) -> Vec<Line> {
+    let mut output: Vec<Line> = vec![];
+
+    for i in 0..lines.len() {
+        // Only the lines after this one are looked at from here on
+        let line = std::mem::replace(&mut lines[i], Line::Comment(String::new()));
+        let merged = (|| {
+            let (mnemonic, operands) = line.instruction()?;
+            let [register, amount] = operands else {
+                return None;
+            };
+            let prev = last_code(&output)?;
+            let (prev_mnemonic, prev_operands) = output[prev].instruction()?;
+            let [prev_register, prev_amount] = prev_operands else {
+                return None;
+            };
+            if !is_register(register) || register != prev_register {
+                return None;
+            }
+            let total = format!(
+                "{} + {}",
+                signed(prev_mnemonic, prev_amount)?,
+                signed(mnemonic, amount)?
+            );
+
+            let flags = flag_use(&lines, i + 1);
+            if flags == FlagUse::Any {
+                return None;
+            }
+
+            // Only constants can be merged: an amount may be another
+            // register, or a relocation that can't take part in an expression
+            let total = evaluate(&total)?;
+            if i32::try_from(total.unsigned_abs()).is_err() {
+                return None;
+            }
+            let replacement = match total {
+                0 if flags == FlagUse::Unread => None,
+                n if n < 0 => Some(format!("sub {register}, {}", -n)),
+                n => Some(format!("add {register}, {n}")),
+            };
+            Some((prev, replacement))
+        })();
+
+        match merged {
+            Some((prev, replacement)) => {
+                output.remove(prev);
+                if let Some(replacement) = replacement {
+                    output.push(Line::parse(&replacement));
+                }
+            }
+            None => output.push(line),
+        }
+    }
+
+    output
+}
+
+/// Drops `mov`s that copy a value which is already in place, and comparisons
+/// with zero that only repeat the flags the `add` or `sub` just before them
+/// set for a conditional jump.
+fn drop_reloads(mut lines: Vec<Line>) -> Vec<Line> {
+    let mut output: Vec<Line> = vec![];
+
+    for i in 0..lines.len() {
+        let line = std::mem::replace(&mut lines[i], Line::Comment(String::new()));
+        let redundant = (|| {
+            let (mnemonic, operands) = line.instruction()?;
+            let [dst, src] = operands else {
+                return None;
+            };
+            let prev = last_code(&output)?;
+            let (prev_mnemonic, prev_operands) = output[prev].instruction()?;
+            let [prev_dst, prev_src] = prev_operands else {
+                return None;
+            };
+
+            match (prev_mnemonic, mnemonic) {
+                // A copy whose address depends on the register it writes
+                // isn't the same copy when repeated
+                ("mov", "mov") if !src.contains(dst.as_str()) && !dst.contains(src.as_str()) => {
+                    Some(
+                        (dst == prev_dst && src == prev_src)
+                            || (dst == prev_src && src == prev_dst),
+                    )
+                }
+                ("add" | "sub", "cmp") => Some(
+                    dst == prev_dst
+                        && evaluate(src) == Some(0)
+                        && flag_use(&lines, i + 1) == FlagUse::ZeroOnly,
+                ),
+                _ => None,
+            }
+        })();
+
+        if redundant != Some(true) {
+            output.push(line);
+        }
+    }
+
+    output
+}
+
+/// Drops jumps to a label that comes straight after them.
+fn drop_jumps_to_next(mut lines: Vec<Line>) -> Vec<Line> {
+    let mut output = vec![];
+
+    for i in 0..lines.len() {
+        let line = std::mem::replace(&mut lines[i], Line::Comment(String::new()));
+        if let Some((mnemonic, [target])) = line.instruction() {
+            let to_next = is_jump(mnemonic)
+                && lines[i + 1..]
+                    .iter()
+                    .take_while(|line| matches!(line, Line::Label(_) | Line::Comment(_)))
+                    .any(|line| *line == Line::Label(target.clone()));
+            if to_next {
+                continue;
+            }
+        }
+        output.push(line);
+    }
+
+    output
+}
+
+/// Cleans up the redundancies left where templates meet in generated
+/// assembly, until there are none left or `budget` runs out. Each element of
+/// `asm` may hold several lines, and the result has one line per element.
+pub fn optimise(asm: Vec<String>, budget: &Budget) -> Vec<String> {
+    let mut lines: Vec<Line> = asm
+        .iter()
+        .flat_map(|text| text.lines())
+        .map(Line::parse)
+        .collect();
+
+    // Every rewrite removes a line, so the length shows when they're done
+    let mut pass = 0;
+    while budget.allows(pass) {
+        pass += 1;
+        let len = lines.len();
+        lines = drop_jumps_to_next(drop_reloads(fold_register_moves(lines)));
+        if lines.len() == len {
+            break;
+        }
+    }
+
+    lines.iter().map(Line::render).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimised(asm: &[&str]) -> Vec<String> {
+        let asm = asm.iter().map(|line| line.to_string()).collect();
+        optimise(asm, &Budget::new(None, None))
+    }
+
+    #[test]
+    fn merges_adds() {
+        assert_eq!(
+            optimised(&["add edi, 1", "add edi, 2 * 1", "mov al, [edi]", "cmp al, 0"]),
+            ["add edi, 3", "mov al, [edi]", "cmp al, 0"]
+        );
+        assert_eq!(
+            optimised(&["add edi, 1", "sub edi, 4", "jnz loop_1_start"]),
+            ["sub edi, 3", "jnz loop_1_start"]
+        );
+    }
+
+    #[test]
+    fn drops_adds_that_cancel_out() {
+        assert_eq!(
+            optimised(&["add edi, 2", "sub edi, 2", "cmp byte [edi], 0"]),
+            ["cmp byte [edi], 0"]
+        );
+        // The jump reads the zero flag, which only an instruction can set
+        assert_eq!(
+            optimised(&["add edi, 2", "sub edi, 2", "jz loop_1_end"]),
+            ["add edi, 0", "jz loop_1_end"]
+        );
+    }
+
+    #[test]
+    fn keeps_adds_with_a_label_between() {
+        let asm = [
+            "add edi, 1",
+            "loop_1_start:",
+            "add edi, 2",
+            "cmp byte [edi], 0",
+        ];
+        assert_eq!(optimised(&asm), asm);
+    }
+
+    #[test]
+    fn keeps_adds_whose_flags_are_read() {
+        for asm in [
+            ["add eax, 1", "add eax, 2", "adc ebx, 0"],
+            ["add eax, 1", "add eax, 2", "jc overflow"],
+            ["add eax, 1", "add eax, 2", "mov ecx, eax"],
+            ["add eax, 1", "add eax, 2", "ret"],
+        ] {
+            assert_eq!(optimised(&asm), asm);
+        }
+    }
+
+    #[test]
+    fn keeps_adds_too_big_for_an_immediate() {
+        for asm in [
+            ["add rbx, 2147483647", "add rbx, 1", "cmp byte [rbx], 0"],
+            ["sub rbx, 2147483647", "sub rbx, 2", "cmp byte [rbx], 0"],
+        ] {
+            assert_eq!(optimised(&asm), asm);
+        }
+    }
+
+    #[test]
+    fn drops_cmp_before_zero_jumps() {
+        for jump in ["jz loop_1_end", "jnz loop_1_start"] {
+            assert_eq!(
+                optimised(&["sub byte [edi], 1", "cmp byte [edi], 0", jump]),
+                ["sub byte [edi], 1", jump]
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_cmp_whose_other_flags_are_read() {
+        for asm in [
+            ["sub byte [edi], 1", "cmp byte [edi], 0", "jc underflow"],
+            ["sub byte [edi], 1", "cmp byte [edi], 0", "setz al"],
+            ["sub byte [edi], 1", "cmp byte [esi], 0", "jz loop_1_end"],
+        ] {
+            assert_eq!(optimised(&asm), asm);
+        }
+    }
+
+    #[test]
+    fn drops_repeated_movs() {
+        assert_eq!(
+            optimised(&["mov eax, ebx", "mov eax, ebx"]),
+            ["mov eax, ebx"]
+        );
+        assert_eq!(
+            optimised(&["mov [edi], al", "mov al, [edi]"]),
+            ["mov [edi], al"]
+        );
+        // Each copy reads through a different value of eax
+        let asm = ["mov eax, [eax]", "mov eax, [eax]"];
+        assert_eq!(optimised(&asm), asm);
+    }
+
+    #[test]
+    fn drops_jumps_to_the_next_label() {
+        assert_eq!(
+            optimised(&["jmp loop_1_test", "; the test", "loop_1_test:", "ret"]),
+            ["; the test", "loop_1_test:", "ret"]
+        );
+        let asm = ["jmp loop_1_test", "loop_1_body:", "ret", "loop_1_test:"];
+        assert_eq!(optimised(&asm), asm);
+    }
+}
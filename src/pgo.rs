@@ -0,0 +1,77 @@
+use crate::lex::{Span, Token};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Hot loops may unroll into this many times the usual limit.
+pub const HOT_UNROLL_FACTOR: usize = 4;
+
+/// How hot a loop ran in training.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Heat {
+    /// Its body ran for at least a hundredth of every instruction run
+    Hot,
+    Warm,
+    /// It was never reached
+    Cold,
+    /// Training didn't see it, so the program has changed since
+    Unknown,
+}
+
+/// How often one loop ran in a training run.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LoopCounts {
+    /// Times the loop was reached, whether its body ran or not
+    pub reached: u64,
+    /// Times its body ran
+    pub iterations: u64,
+}
+
+/// What a training run of a program did. Loops are keyed by where they start
+/// in the source, so that the counts still apply whatever the program is
+/// optimised into.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Counts {
+    /// Instructions run in all
+    pub steps: u64,
+    pub loops: BTreeMap<String, LoopCounts>,
+}
+
+impl Counts {
+    /// Collects the loop counts from `runs`, the number of times each of
+    /// `tokens` ran. The tokens mustn't have had loops turned into
+    /// superinstructions, or those loops go uncounted.
+    pub fn record(tokens: &[(Token, Span)], runs: &[u64]) -> Self {
+        let mut counts = Counts {
+            steps: runs.iter().sum(),
+            loops: BTreeMap::new(),
+        };
+
+        for (i, (&(token, span), &ran)) in tokens.iter().zip(runs).enumerate() {
+            match token {
+                Token::LoopStart(_) => {
+                    counts.loops.entry(span.to_string()).or_default().reached += ran;
+                }
+                // The end runs once for each time the body does
+                Token::LoopEnd(distance) => {
+                    let start = tokens[i - distance as usize].1;
+                    counts.loops.get_mut(&start.to_string()).unwrap().iterations += ran;
+                }
+                _ => {}
+            }
+        }
+
+        counts
+    }
+
+    /// How hot the loop starting at `span` ran.
+    pub fn heat(&self, span: Span) -> Heat {
+        match self.loops.get(&span.to_string()) {
+            None => Heat::Unknown,
+            Some(loop_counts) if loop_counts.reached == 0 => Heat::Cold,
+            Some(loop_counts) if loop_counts.iterations.saturating_mul(100) >= self.steps => {
+                Heat::Hot
+            }
+            Some(_) => Heat::Warm,
+        }
+    }
+}
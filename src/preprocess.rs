@@ -0,0 +1,199 @@
+use crate::{
+    diagnostics::Diagnostic,
+    lex::{spanned_chars, Span},
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// How deeply macros may expand inside each other before we assume one is
+/// recursive.
+const MAX_DEPTH: usize = 64;
+
+/// Expands `@def NAME ... @end` definitions, `@NAME` invocations and
+/// `@include "file"` directives. Every character keeps the position it had in
+/// the file it was written in, and included paths are relative to the file
+/// the `@include` is written in.
+#[derive(Debug, Default)]
+pub struct Preprocessor {
+    /// Directory of the input file
+    dir: PathBuf,
+    macros: HashMap<String, Vec<(char, Span)>>,
+    /// Files currently being included, to catch include cycles
+    including: Vec<PathBuf>,
+    depth: usize,
+}
+
+fn error(message: impl Into<String>, span: Span) -> Diagnostic {
+    Diagnostic::error("E0012", message).with_span(span)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Reads the name starting at `i`, returning it and the index after it.
+fn name_at(source: &[(char, Span)], mut i: usize) -> (String, usize) {
+    let mut name = String::new();
+    while let Some(&(c, _)) = source.get(i).filter(|(c, _)| is_name_char(*c)) {
+        name.push(c);
+        i += 1;
+    }
+    (name, i)
+}
+
+fn skip_whitespace(source: &[(char, Span)], mut i: usize) -> usize {
+    while source.get(i).is_some_and(|(c, _)| c.is_whitespace()) {
+        i += 1;
+    }
+    i
+}
+
+impl Preprocessor {
+    /// Expands the input file `path`, whose text is `contents`.
+    pub fn run(path: &str, contents: &str) -> Result<Vec<(char, Span)>, Diagnostic> {
+        let source: Vec<_> = spanned_chars(contents, None).collect();
+
+        let mut preprocessor = Self {
+            dir: Path::new(path)
+                .parent()
+                .unwrap_or(Path::new(""))
+                .to_path_buf(),
+            ..Self::default()
+        };
+        if let Ok(path) = fs::canonicalize(path) {
+            preprocessor.including.push(path);
+        }
+
+        let mut output = vec![];
+        preprocessor.expand(&source, &mut output)?;
+        Ok(output)
+    }
+
+    fn expand(
+        &mut self,
+        source: &[(char, Span)],
+        output: &mut Vec<(char, Span)>,
+    ) -> Result<(), Diagnostic> {
+        let mut i = 0;
+        while i < source.len() {
+            let (c, span) = source[i];
+            if c != '@' {
+                output.push((c, span));
+                i += 1;
+                continue;
+            }
+
+            let (name, next) = name_at(source, i + 1);
+            i = match name.as_str() {
+                // A lone `@` is just a comment character
+                "" => {
+                    output.push((c, span));
+                    next
+                }
+                "def" => self.define(source, next, span)?,
+                "end" => return Err(error("`@end` without a matching `@def`", span)),
+                "include" => self.include(source, next, output, span)?,
+                _ => {
+                    let Some(body) = self.macros.get(&name).cloned() else {
+                        return Err(error(format!("unknown macro `{name}`"), span));
+                    };
+                    if self.depth == MAX_DEPTH {
+                        return Err(error(
+                            format!("macro `{name}` expands too deeply, is it recursive?"),
+                            span,
+                        ));
+                    }
+                    self.depth += 1;
+                    self.expand(&body, output)?;
+                    self.depth -= 1;
+                    next
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Records the macro defined after the `@def` ending just before `i`,
+    /// returning the index after its `@end`.
+    fn define(
+        &mut self,
+        source: &[(char, Span)],
+        i: usize,
+        span: Span,
+    ) -> Result<usize, Diagnostic> {
+        let (name, start) = name_at(source, skip_whitespace(source, i));
+        if name.is_empty() {
+            return Err(error("expected a macro name after `@def`", span));
+        }
+        if matches!(name.as_str(), "def" | "end" | "include") {
+            return Err(error(
+                format!("`{name}` can't be used as a macro name"),
+                span,
+            ));
+        }
+
+        let mut end = start;
+        loop {
+            match source.get(end) {
+                None => {
+                    return Err(error(format!("macro `{name}` is missing its `@end`"), span));
+                }
+                Some(('@', _)) if name_at(source, end + 1).0 == "end" => break,
+                Some(_) => end += 1,
+            }
+        }
+
+        self.macros.insert(name, source[start..end].to_vec());
+        Ok(end + "@end".len())
+    }
+
+    /// Expands the file named after the `@include` ending just before `i`,
+    /// returning the index after the closing quote.
+    fn include(
+        &mut self,
+        source: &[(char, Span)],
+        i: usize,
+        output: &mut Vec<(char, Span)>,
+        span: Span,
+    ) -> Result<usize, Diagnostic> {
+        let start = skip_whitespace(source, i);
+        if !matches!(source.get(start), Some(('"', _))) {
+            return Err(error("expected a quoted file name after `@include`", span));
+        }
+        let Some(len) = source[start + 1..].iter().position(|(c, _)| *c == '"') else {
+            return Err(error("unterminated file name after `@include`", span));
+        };
+        let name: String = source[start + 1..start + 1 + len]
+            .iter()
+            .map(|(c, _)| c)
+            .collect();
+
+        let dir = match span.file {
+            Some(file) => Path::new(file).parent().unwrap_or(Path::new("")),
+            None => &self.dir,
+        };
+        let path = dir.join(&name);
+        let contents = fs::read_to_string(&path).map_err(|e| {
+            Diagnostic::error("E0005", format!("could not read {}: {e}", path.display()))
+                .with_span(span)
+        })?;
+
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if self.including.contains(&canonical) {
+            return Err(error(format!("`{name}` includes itself"), span));
+        }
+
+        let file: &'static String = Box::leak(Box::new(path.display().to_string()));
+        let included: Vec<_> = spanned_chars(&contents, Some(file)).collect();
+
+        self.including.push(canonical);
+        self.expand(&included, output)?;
+        self.including.pop();
+
+        Ok(start + len + 2)
+    }
+}
@@ -1,35 +1,396 @@
-use crate::Token;
-use once_cell::sync::Lazy;
+#[cfg(feature = "native")]
+use crate::toolchain::Toolchain;
+use crate::{
+    externs,
+    lex::{Span, Token},
+};
+use once_cell::sync::{Lazy, OnceCell};
+#[cfg(feature = "native")]
 use platform_dirs::AppDirs;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, path::PathBuf};
+#[cfg(feature = "native")]
 use std::{
     fs,
     io::{self, Write},
-    path::{Path, PathBuf},
-    process::Command,
+    path::Path,
 };
 
+#[cfg(feature = "native")]
 static CONFIG_PATH: Lazy<PathBuf> =
     Lazy::new(|| AppDirs::new(Some("bfc"), true).unwrap().config_dir);
-static CACHE_PATH: Lazy<PathBuf> = Lazy::new(|| AppDirs::new(Some("bfc"), true).unwrap().cache_dir);
 
-static PROFILES: Lazy<Vec<Profile>> = Lazy::new(|| {
-    let mut profiles = vec![];
+/// The built-in profiles, by name.
+static BUILTIN_PROFILES: &[(&str, &str)] = &[
+    ("elf_32", include_str!("../profiles/elf_32.json")),
+    ("elf_64_musl", include_str!("../profiles/elf_64_musl.json")),
+    ("macos_64", include_str!("../profiles/macos_64.json")),
+    ("c64", include_str!("../profiles/c64.json")),
+];
 
-    for entry in fs::read_dir(CONFIG_PATH.as_path()).unwrap().flatten() {
-        if entry.file_type().unwrap().is_file() {
-            if let Ok(s) = fs::read_to_string(entry.path()) {
-                let str = Box::leak(s.into_boxed_str());
+/// Every profile that can be selected, by name, without having read any of
+/// them. Each is parsed the first time it's asked for.
+static PROFILES: Lazy<Vec<ProfileEntry>> = Lazy::new(|| {
+    let users = user_profiles();
+    let mut entries: Vec<ProfileEntry> = BUILTIN_PROFILES
+        .iter()
+        .filter(|(name, _)| !users.iter().any(|(user, _)| user == name))
+        .map(|&(name, json)| ProfileEntry::new(name.to_string(), ProfileOrigin::BuiltIn(json)))
+        .collect();
+    entries.extend(
+        users
+            .into_iter()
+            .map(|(name, path)| ProfileEntry::new(name, ProfileOrigin::User(path))),
+    );
+    entries
+});
 
-                if let Ok(profile) = serde_json::from_str::<Profile>(str) {
-                    profiles.push(profile);
-                }
-            }
+/// A profile that may not have been parsed yet.
+struct ProfileEntry {
+    name: String,
+    origin: ProfileOrigin,
+    profile: OnceCell<Result<Profile, String>>,
+}
+
+enum ProfileOrigin {
+    BuiltIn(&'static str),
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    User(PathBuf),
+}
+
+impl ProfileEntry {
+    fn new(name: String, origin: ProfileOrigin) -> Self {
+        Self {
+            name,
+            origin,
+            profile: OnceCell::new(),
         }
     }
 
+    fn get(&self) -> Result<&Profile, &str> {
+        self.profile
+            .get_or_init(|| match &self.origin {
+                ProfileOrigin::BuiltIn(json) => {
+                    Ok(serde_json::from_str(json).expect("Invalid built-in profile"))
+                }
+                ProfileOrigin::User(path) => load_user_profile(&self.name, path),
+            })
+            .as_ref()
+            .map_err(String::as_str)
+    }
+}
+
+/// The profiles in the config directory, named after their files, which
+/// replace built-in profiles with the same name.
+#[cfg(feature = "native")]
+fn user_profiles() -> Vec<(String, PathBuf)> {
+    let Ok(entries) = fs::read_dir(CONFIG_PATH.as_path()) else {
+        return vec![];
+    };
+    let mut profiles: Vec<(String, PathBuf)> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| Some((path.file_stem()?.to_str()?.to_string(), path)))
+        .collect();
+    profiles.sort();
     profiles
-});
+}
+
+/// Without a filesystem there are only the built-in profiles.
+#[cfg(not(feature = "native"))]
+fn user_profiles() -> Vec<(String, PathBuf)> {
+    vec![]
+}
+
+/// Parses the profile file at `path`, which should define the profile
+/// `name`. User profiles are found by their file names, so a file must define
+/// the profile it's named after, or selecting that name would give another.
+#[cfg(feature = "native")]
+fn load_user_profile(name: &str, path: &Path) -> Result<Profile, String> {
+    let profile = Profile::read_file(path)?;
+    if profile.name != name {
+        return Err(format!(
+            "{} defines profile {}, but is named after profile {name}, so its name should \
+             be {name} or it should be renamed to {}.json",
+            path.display(),
+            profile.name,
+            profile.name
+        ));
+    }
+    Ok(profile)
+}
+
+#[cfg(not(feature = "native"))]
+fn load_user_profile(_: &str, _: &std::path::Path) -> Result<Profile, String> {
+    unreachable!()
+}
+
+static FIELD_DOCS: &[(&str, &str)] = &[
+    ("name", "Name used to select this profile with -p"),
+    (
+        "description",
+        "Short description shown by `bfc profile list`",
+    ),
+    (
+        "static",
+        "Whether binaries run without a dynamic loader or shared libraries",
+    ),
+    (
+        "variables",
+        "Defaults for named template variables like {tape_size}, {cell_type} and {entry}",
+    ),
+    (
+        "capabilities",
+        "What the templates can handle that can't be told from which of them there are: cell_sizes, the cell sizes in bits they work with, any if not given; max_tape_size, the most cells the tape can have; and buffered_output, whether output is held in the C library until it's flushed",
+    ),
+    ("setup", "Lines emitted once before the program"),
+    ("teardown", "Lines emitted once after the program"),
+    (
+        "exit_cell_teardown",
+        "Optional: lines emitted instead of teardown with --exit-cell, exiting with the current cell as the status",
+    ),
+    ("ptradd", "Move the tape pointer right by {}"),
+    ("ptrsub", "Move the tape pointer left by {}"),
+    ("add", "Add {} to the current cell"),
+    ("sub", "Subtract {} from the current cell"),
+    (
+        "loopstart",
+        "Start of a loop, whose name showing where it nests is {label}",
+    ),
+    ("loopend", "End of the loop named {label}"),
+    ("putchar", "Write the current cell to stdout"),
+    ("getchar", "Read a byte from stdin into the current cell"),
+    (
+        "input_setup",
+        "Optional: lines emitted once after the setup, preparing the input that getchar reads",
+    ),
+    (
+        "output_setup",
+        "Optional: lines emitted once after the input setup, preparing the output that putchar writes",
+    ),
+    (
+        "bounds_check",
+        "Emitted after each pointer move with --checked, jumping to the bounds error",
+    ),
+    (
+        "bounds_error",
+        "Emitted once after the teardown with --checked to report the pointer leaving the tape",
+    ),
+    (
+        "setzero",
+        "Optional: set the current cell to zero, replacing [-]",
+    ),
+    (
+        "muladd",
+        "Optional: add {factor} times the current cell to the cell {offset} away",
+    ),
+    (
+        "scanright",
+        "Optional: move right {} cells at a time until the current cell is zero, with {id} and {label} unique to each scan",
+    ),
+    (
+        "scanleft",
+        "Optional: move left {} cells at a time until the current cell is zero, with {id} and {label} unique to each scan",
+    ),
+    (
+        "addat",
+        "Optional: add {} to the cell {offset} away without moving the pointer",
+    ),
+    (
+        "subat",
+        "Optional: subtract {} from the cell {offset} away without moving the pointer",
+    ),
+    (
+        "setzeroat",
+        "Optional: set the cell {offset} away to zero without moving the pointer",
+    ),
+    (
+        "procstart",
+        "Start of pbrain procedure number {}, named {label}, defining it for the current cell",
+    ),
+    ("procend", "End of pbrain procedure number {}, named {label}"),
+    ("call", "Call the pbrain procedure defined for the current cell"),
+    (
+        "proc_error",
+        "Emitted once after the teardown in programs with procedures, holding the procedure table and the undefined procedure error",
+    ),
+    (
+        "extern_call",
+        "Optional: call the external function {name} with the tape pointer as its first argument",
+    ),
+    (
+        "extern_decl",
+        "Emitted once after the setup for each external function {name} the program calls",
+    ),
+    (
+        "switch_tape",
+        "Optional: move the pointer to the other tape of the tapes extension, where it's on the cell it was last on",
+    ),
+    (
+        "exchange_cell",
+        "Optional: swap the current cell with the cell the other tape's pointer is on",
+    ),
+    (
+        "debug_dump",
+        "Optional: write the pointer and the cells around it to stderr for `#` with --debug-hash",
+    ),
+    (
+        "dispatch",
+        "Optional: emitted once after the setup of a --multicall binary to find the program named by argv[0] or argv[1], with {checks} standing for a dispatch_check for every program",
+    ),
+    (
+        "dispatch_check",
+        "Jump to the program labelled {label} if the name being looked for is the string labelled {label}_name",
+    ),
+    (
+        "dispatch_name",
+        "Emitted once after the teardown for each program in a --multicall binary, defining its name {name} as the string labelled {label}_name",
+    ),
+    (
+        "program_start",
+        "Emitted at the start of each program in a --multicall binary, defining the label {label}",
+    ),
+    (
+        "putchar_call",
+        "Optional: write the current cell to stdout by calling a routine in helpers or runtime, used with -Os",
+    ),
+    (
+        "getchar_call",
+        "Optional: read a byte into the current cell by calling a routine in helpers or runtime, used with -Os",
+    ),
+    (
+        "helpers",
+        "Emitted once after the teardown in programs using putchar_call or getchar_call, holding the routines they call",
+    ),
+    (
+        "runtime",
+        "Optional: routines by label, each emitted once after the teardown when the program's other templates mention the label, for templates to call",
+    ),
+    (
+        "hot_loop_align",
+        "Optional: emitted before each loop that --pgo-use finds hot, to align it",
+    ),
+    (
+        "debug_line",
+        "Emitted before each instruction with -g to map it to {file}:{line}:{col}",
+    ),
+    (
+        "debug_label",
+        "Optional: emitted before each instruction with -g or --fault-handler to define the label {label} where its code starts",
+    ),
+    (
+        "gdb_pointer",
+        "Optional: register holding the pointer, which the gdb script reads the tape through",
+    ),
+    ("gdb_tape", "Optional: label of the first cell of the tape, for the gdb script"),
+    (
+        "raw_tty",
+        "Optional: lines emitted once after the setup with --raw-tty, taking input a key at a time without echo if it's a terminal",
+    ),
+    (
+        "raw_tty_restore",
+        "Lines emitted before the teardown with --raw-tty, putting the terminal back as it was",
+    ),
+    (
+        "coverage_count",
+        "Optional: emitted before instruction number {index} with --instrument-coverage, adding one to its 8-byte counter at bf_coverage + {index} * 8",
+    ),
+    (
+        "coverage_dump",
+        "Emitted before the teardown with --instrument-coverage, defining the {counters} counters at bf_coverage and writing them to the file {coverage_file}",
+    ),
+    (
+        "fault_setup",
+        "Optional: emitted after the setup with --fault-handler to catch crashes, reporting the line of the instruction they happened in from the fault_table",
+    ),
+    (
+        "fault_table",
+        "Emitted once after the teardown with --fault-handler, with {entries} standing for a fault_entry for every instruction in the order they're emitted",
+    ),
+    (
+        "fault_entry",
+        "Entry in the fault_table for the instruction whose code starts at {label} and which is on line {line}",
+    ),
+    (
+        "syntax",
+        "Assembly syntax the templates are written in, intel (nasm) or att (gas)",
+    ),
+    ("comment", "Format of a comment line, with the text in {}"),
+    ("nasm", "Assembler executable"),
+    (
+        "nasm_version_args",
+        "Arguments that make the assembler print its version, -v unless given",
+    ),
+    ("nasm_args", "Arguments passed to the assembler"),
+    ("debug_nasm_args", "Extra assembler arguments used with -g"),
+    (
+        "size_nasm_args",
+        "Extra assembler arguments used with -Os to make the code smaller, -Ox unless given",
+    ),
+    ("linker", "Linker executable"),
+    ("linker_args", "Arguments passed to the linker"),
+    (
+        "reproducible_linker_args",
+        "Extra linker arguments needed for byte-identical output with --reproducible",
+    ),
+    (
+        "strip_linker_args",
+        "Extra linker arguments that drop the symbol table with --strip",
+    ),
+    (
+        "small_linker_args",
+        "Extra linker arguments that drop unneeded headers and padding with --small",
+    ),
+    (
+        "arg_input",
+        "Optional: fields replacing the ones above with --input-source arg, to read input from the bytes of argv[1]",
+    ),
+    (
+        "file_input",
+        "Optional: fields replacing the ones above with --input-source file, to read input from the file named by argv[1]",
+    ),
+    (
+        "file_output",
+        "Optional: fields replacing the ones above with --output-data file, to write output to the file {output_file}",
+    ),
+    (
+        "buffer_output",
+        "Optional: fields replacing the ones above with --output-data buffer, to write output to the {output_buffer_size} byte buffer bf_output, counting the bytes written in bf_output_len",
+    ),
+    (
+        "pie",
+        "Optional: fields replacing the ones above with --pie, to build a position-independent executable",
+    ),
+    (
+        "no_exit",
+        "Optional: fields replacing the ones above with --no-exit, to return from {entry} to the caller rather than exit",
+    ),
+    (
+        "guard_pages",
+        "Optional: fields replacing the ones above with --guard-pages, to put inaccessible pages either side of the tape and report the fault from running into one as the pointer leaving the tape",
+    ),
+    (
+        "targets",
+        "Targets, as arch-os pairs like x86_64-linux, that --target picks this profile for",
+    ),
+    (
+        "cross",
+        "Optional: fields replacing the ones above when building with --target on another kind of host, such as a cross linker",
+    ),
+    (
+        "syntaxes",
+        "Optional: fields replacing the ones above with --syntax, keyed by the syntax they're written in, such as templates and an assembler for att",
+    ),
+];
+
+static DEFAULT_VARIABLES: &[(&str, &str)] = &[
+    ("tape_size", "40000000"),
+    ("cell_type", "byte"),
+    ("entry", "_start"),
+    ("output_buffer_size", "65536"),
+];
+
 static DEFAULT_PROFILE: Lazy<&str> = Lazy::new(|| {
     if std::env::consts::OS == "macos" {
         "macos_64"
@@ -38,28 +399,467 @@ static DEFAULT_PROFILE: Lazy<&str> = Lazy::new(|| {
     }
 });
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ProfileSource {
+    #[default]
+    BuiltIn,
+    User(PathBuf),
+    /// Made with a [`ProfileBuilder`]
+    Code,
+}
+
+impl fmt::Display for ProfileSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProfileSource::BuiltIn => write!(f, "built-in"),
+            ProfileSource::User(path) => write!(f, "{}", path.display()),
+            ProfileSource::Code => write!(f, "defined in code"),
+        }
+    }
+}
+
+/// The architecture and operating system of a target triple, skipping the
+/// vendor, so that `x86_64-unknown-linux-musl` and `x86_64-linux` match.
+fn arch_os(triple: &str) -> (&str, &str) {
+    let mut parts = triple
+        .split('-')
+        .filter(|part| !matches!(*part, "unknown" | "pc" | "apple" | "none"));
+    let arch = parts.next().unwrap_or_default();
+    let os = match parts.next().unwrap_or_default() {
+        "darwin" => "macos",
+        os => os,
+    };
+    (arch, os)
+}
+
+/// Whether building for `triple` needs tools for a different kind of machine
+/// than this one.
+pub fn is_cross(triple: &str) -> bool {
+    arch_os(triple) != (std::env::consts::ARCH, std::env::consts::OS)
+}
+
+pub type Variables = BTreeMap<String, String>;
+
+/// Fills in `vars` wherever `lines` name them.
+fn fill(lines: &mut [String], vars: &Variables) {
+    for line in lines {
+        for (name, value) in vars {
+            *line = line.replace(&format!("{{{name}}}"), value);
+        }
+    }
+}
+
+fn default_comment() -> String {
+    "; {}".to_string()
+}
+
+fn default_nasm() -> String {
+    "nasm".to_string()
+}
+
+fn default_nasm_version_args() -> Vec<String> {
+    vec!["-v".to_string()]
+}
+
+fn default_size_nasm_args() -> Vec<String> {
+    vec!["-Ox".to_string()]
+}
+
+fn cell_bytes(cell_type: &str) -> Option<usize> {
+    match cell_type {
+        "byte" => Some(1),
+        "word" => Some(2),
+        "dword" => Some(4),
+        "qword" => Some(8),
+        _ => None,
+    }
+}
+
+/// The syntax a profile's templates are written in, which decides the
+/// assembler that can read them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AsmSyntax {
+    /// Intel syntax, as nasm and ca65 read it
+    #[default]
+    Intel,
+    /// AT&T syntax, as the GNU assembler reads it
+    Att,
+}
+
+impl AsmSyntax {
+    pub fn name(self) -> &'static str {
+        match self {
+            AsmSyntax::Intel => "intel",
+            AsmSyntax::Att => "att",
+        }
+    }
+}
+
+/// What a profile's templates can handle that can't be told from which of them
+/// it has.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Capabilities {
+    /// Cell sizes in bits the templates work with, any if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cell_sizes: Vec<usize>,
+    /// Most cells the tape can have, if it's limited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tape_size: Option<usize>,
+    /// Whether output is held in the C library until it's flushed, rather
+    /// than written as each `.` runs
+    #[serde(default)]
+    pub buffered_output: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Profile {
-    name: &'static str,
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default, rename = "static")]
+    is_static: bool,
+    #[serde(skip)]
+    source: ProfileSource,
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+    #[serde(default)]
+    capabilities: Capabilities,
 
-    setup: Vec<&'static str>,
-    teardown: Vec<&'static str>,
+    setup: Vec<String>,
+    teardown: Vec<String>,
+    #[serde(default)]
+    exit_cell_teardown: Vec<String>,
 
-    ptradd: Vec<&'static str>,
-    ptrsub: Vec<&'static str>,
-    add: Vec<&'static str>,
-    sub: Vec<&'static str>,
-    loopstart: Vec<&'static str>,
-    loopend: Vec<&'static str>,
-    putchar: Vec<&'static str>,
-    getchar: Vec<&'static str>,
+    ptradd: Vec<String>,
+    ptrsub: Vec<String>,
+    add: Vec<String>,
+    sub: Vec<String>,
+    loopstart: Vec<String>,
+    loopend: Vec<String>,
+    putchar: Vec<String>,
+    getchar: Vec<String>,
+    #[serde(default)]
+    input_setup: Vec<String>,
+    #[serde(default)]
+    output_setup: Vec<String>,
+    #[serde(default)]
+    bounds_check: Vec<String>,
+    #[serde(default)]
+    bounds_error: Vec<String>,
+    #[serde(default)]
+    setzero: Vec<String>,
+    #[serde(default)]
+    muladd: Vec<String>,
+    #[serde(default)]
+    scanright: Vec<String>,
+    #[serde(default)]
+    scanleft: Vec<String>,
+    #[serde(default)]
+    addat: Vec<String>,
+    #[serde(default)]
+    subat: Vec<String>,
+    #[serde(default)]
+    setzeroat: Vec<String>,
+    #[serde(default)]
+    procstart: Vec<String>,
+    #[serde(default)]
+    procend: Vec<String>,
+    #[serde(default)]
+    call: Vec<String>,
+    #[serde(default)]
+    proc_error: Vec<String>,
+    #[serde(default)]
+    extern_call: Vec<String>,
+    #[serde(default)]
+    extern_decl: Vec<String>,
+    #[serde(default)]
+    switch_tape: Vec<String>,
+    #[serde(default)]
+    exchange_cell: Vec<String>,
+    #[serde(default)]
+    debug_dump: Vec<String>,
+    #[serde(default)]
+    dispatch: Vec<String>,
+    #[serde(default)]
+    dispatch_check: Vec<String>,
+    #[serde(default)]
+    dispatch_name: Vec<String>,
+    #[serde(default)]
+    program_start: Vec<String>,
+    #[serde(default)]
+    putchar_call: Vec<String>,
+    #[serde(default)]
+    getchar_call: Vec<String>,
+    #[serde(default)]
+    helpers: Vec<String>,
+    #[serde(default)]
+    runtime: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    hot_loop_align: Vec<String>,
+    #[serde(default)]
+    debug_line: Vec<String>,
+    #[serde(default)]
+    debug_label: Vec<String>,
+    #[serde(default)]
+    gdb_pointer: String,
+    #[serde(default)]
+    gdb_tape: String,
+    #[serde(default)]
+    raw_tty: Vec<String>,
+    #[serde(default)]
+    raw_tty_restore: Vec<String>,
+    #[serde(default)]
+    coverage_count: Vec<String>,
+    #[serde(default)]
+    coverage_dump: Vec<String>,
+    #[serde(default)]
+    fault_setup: Vec<String>,
+    #[serde(default)]
+    fault_table: Vec<String>,
+    #[serde(default)]
+    fault_entry: Vec<String>,
+    #[serde(default)]
+    syntax: AsmSyntax,
+    #[serde(default = "default_comment")]
+    comment: String,
 
-    nasm_args: Vec<&'static str>,
-    linker: &'static str,
-    linker_args: Vec<&'static str>,
+    #[serde(default = "default_nasm")]
+    nasm: String,
+    #[serde(default = "default_nasm_version_args")]
+    nasm_version_args: Vec<String>,
+    nasm_args: Vec<String>,
+    #[serde(default)]
+    debug_nasm_args: Vec<String>,
+    #[serde(default = "default_size_nasm_args")]
+    size_nasm_args: Vec<String>,
+    linker: String,
+    linker_args: Vec<String>,
+    #[serde(default)]
+    reproducible_linker_args: Vec<String>,
+    #[serde(default)]
+    strip_linker_args: Vec<String>,
+    #[serde(default)]
+    small_linker_args: Vec<String>,
+    #[serde(default)]
+    arg_input: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    file_input: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    file_output: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    buffer_output: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    pie: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    no_exit: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    guard_pages: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    targets: Vec<String>,
+    #[serde(default)]
+    cross: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    syntaxes: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Profile {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn is_static(&self) -> bool {
+        self.is_static
+    }
+
+    pub fn source(&self) -> &ProfileSource {
+        &self.source
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.name == *DEFAULT_PROFILE
+    }
+
+    /// A builder for a profile defined in code rather than a profile file.
+    pub fn builder() -> ProfileBuilder {
+        ProfileBuilder::default()
+    }
+
+    /// A builder starting from this profile's fields, to make a changed copy
+    /// of it.
+    pub fn to_builder(&self) -> ProfileBuilder {
+        let serde_json::Value::Object(fields) = serde_json::to_value(self).unwrap() else {
+            unreachable!("profiles serialize to objects")
+        };
+        ProfileBuilder { fields }
+    }
+
+    /// Merges the built-in defaults, this profile's defaults and the given
+    /// overrides into the set of variables available to templates. Fails if
+    /// they leave a cell type there's no size for.
+    pub fn variables(&self, overrides: &Variables) -> Result<Variables, String> {
+        let mut vars: Variables = DEFAULT_VARIABLES
+            .iter()
+            .copied()
+            .chain(self.variables.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        vars.extend(overrides.clone());
+
+        let cell_type = &vars["cell_type"];
+        let bytes = cell_bytes(cell_type).ok_or_else(|| {
+            format!(
+                "profile {} has unknown cell_type `{cell_type}`, expected byte, word, dword or qword",
+                self.name
+            )
+        })?;
+        vars.insert("cell_bytes".to_string(), bytes.to_string());
+
+        Ok(vars)
+    }
+
+    /// This profile with `vars` filled in to its templates, leaving only what
+    /// differs from one use of a template to the next, like `{}` and
+    /// `{label}`. Debug lines keep `{file}`, which is the file each
+    /// instruction came from.
+    pub fn with_variables(&self, vars: &Variables) -> Profile {
+        let mut profile = self.clone();
+        for template in [
+            &mut profile.setup,
+            &mut profile.teardown,
+            &mut profile.exit_cell_teardown,
+            &mut profile.ptradd,
+            &mut profile.ptrsub,
+            &mut profile.add,
+            &mut profile.sub,
+            &mut profile.loopstart,
+            &mut profile.loopend,
+            &mut profile.putchar,
+            &mut profile.getchar,
+            &mut profile.input_setup,
+            &mut profile.output_setup,
+            &mut profile.bounds_check,
+            &mut profile.bounds_error,
+            &mut profile.setzero,
+            &mut profile.muladd,
+            &mut profile.scanright,
+            &mut profile.scanleft,
+            &mut profile.addat,
+            &mut profile.subat,
+            &mut profile.setzeroat,
+            &mut profile.procstart,
+            &mut profile.procend,
+            &mut profile.call,
+            &mut profile.proc_error,
+            &mut profile.extern_call,
+            &mut profile.extern_decl,
+            &mut profile.switch_tape,
+            &mut profile.exchange_cell,
+            &mut profile.debug_dump,
+            &mut profile.dispatch,
+            &mut profile.dispatch_check,
+            &mut profile.dispatch_name,
+            &mut profile.program_start,
+            &mut profile.putchar_call,
+            &mut profile.getchar_call,
+            &mut profile.helpers,
+            &mut profile.hot_loop_align,
+            &mut profile.debug_label,
+            &mut profile.raw_tty,
+            &mut profile.raw_tty_restore,
+            &mut profile.coverage_count,
+            &mut profile.coverage_dump,
+            &mut profile.fault_setup,
+            &mut profile.fault_table,
+            &mut profile.fault_entry,
+        ] {
+            fill(template, vars);
+        }
+        for body in profile.runtime.values_mut() {
+            fill(body, vars);
+        }
+        let mut line_vars = vars.clone();
+        line_vars.remove("file");
+        fill(&mut profile.debug_line, &line_vars);
+        profile
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    pub fn supports_cell_size(&self, bits: usize) -> bool {
+        self.capabilities.cell_sizes.is_empty() || self.capabilities.cell_sizes.contains(&bits)
+    }
+
+    pub fn supports_tape_size(&self, cells: usize) -> bool {
+        self.capabilities
+            .max_tape_size
+            .is_none_or(|max| cells <= max)
+    }
+
+    /// The names of the features this profile has, from its capabilities and
+    /// the templates it has for each feature.
+    pub fn features(&self) -> Vec<String> {
+        let mut features = vec![];
+        if !self.capabilities.cell_sizes.is_empty() {
+            let sizes: Vec<String> = self
+                .capabilities
+                .cell_sizes
+                .iter()
+                .map(|bits| bits.to_string())
+                .collect();
+            features.push(format!("{}-bit cells", sizes.join("/")));
+        }
+        if let Some(max) = self.capabilities.max_tape_size {
+            features.push(format!("up to {max} cells"));
+        }
+        if self.capabilities.buffered_output {
+            features.push("buffered output".to_string());
+        }
+        for syntax in [AsmSyntax::Intel, AsmSyntax::Att] {
+            if self.supports_syntax(syntax) {
+                features.push(format!("{} syntax", syntax.name()));
+            }
+        }
+        let templated = [
+            ("exit cell", self.supports_exit_cell()),
+            ("checked", self.supports_checked()),
+            ("guard pages", self.supports_guard_pages()),
+            ("fault handler", self.supports_fault_handler()),
+            ("debug info", self.supports_debug()),
+            ("gdb", self.supports_gdb()),
+            ("# debugging", self.supports_debug_hash()),
+            ("procedures", self.supports_procedures()),
+            ("externs", self.supports_externs()),
+            ("tapes", self.supports_tapes()),
+            ("offsets", self.supports_offsets()),
+            ("multicall", self.supports_multicall()),
+            ("helpers", self.supports_helpers()),
+            ("raw tty", self.supports_raw_tty()),
+            ("coverage", self.supports_coverage()),
+            ("arg input", self.supports_arg_input()),
+            ("file input", self.supports_file_input()),
+            ("file output", self.supports_file_output()),
+            ("buffer output", self.supports_buffer_output()),
+            ("pie", self.supports_pie()),
+            ("no exit", self.supports_no_exit()),
+            ("cross", self.supports_cross()),
+        ];
+        features.extend(
+            templated
+                .into_iter()
+                .filter(|&(_, supported)| supported)
+                .map(|(name, _)| name.to_string()),
+        );
+        features
+    }
+
     pub fn get_setup_asm(&self) -> String {
         self.setup.join("\n")
     }
@@ -68,45 +868,494 @@ impl Profile {
         self.teardown.join("\n")
     }
 
-    pub fn get_asm(&self, tok: Token) -> String {
+    pub fn get_input_setup_asm(&self) -> String {
+        self.input_setup.join("\n")
+    }
+
+    pub fn get_output_setup_asm(&self) -> String {
+        self.output_setup.join("\n")
+    }
+
+    pub fn supports_exit_cell(&self) -> bool {
+        !self.exit_cell_teardown.is_empty()
+    }
+
+    pub fn get_exit_cell_teardown_asm(&self) -> String {
+        self.exit_cell_teardown.join("\n")
+    }
+
+    /// The assembly for `tok`, with `{label}` standing for `label`, the name
+    /// of the loop or procedure it belongs to.
+    pub fn get_asm(&self, tok: Token, label: Option<&str>) -> String {
+        let asm = self.get_token_asm(tok);
+        match label {
+            Some(label) => asm.replace("{label}", label),
+            None => asm,
+        }
+    }
+
+    fn get_token_asm(&self, tok: Token) -> String {
+        let (template, n) = match tok {
+            Token::PtrAdd(n) => (&self.ptradd, n),
+            Token::PtrSub(n) => (&self.ptrsub, n),
+            Token::Add(n) => (&self.add, n),
+            Token::Sub(n) => (&self.sub, n),
+            Token::LoopStart(_) => return self.loopstart.join("\n"),
+            Token::LoopEnd(_) => return self.loopend.join("\n"),
+            Token::PutChar => return self.putchar.join("\n"),
+            Token::GetChar => return self.getchar.join("\n"),
+            Token::ProcStart(n) => (&self.procstart, n),
+            Token::ProcEnd(n) => (&self.procend, n),
+            Token::Call => return self.call.join("\n"),
+            Token::Extern(id) => {
+                return self
+                    .extern_call
+                    .join("\n")
+                    .replace("{name}", externs::name(id))
+            }
+            Token::SwitchTape => return self.switch_tape.join("\n"),
+            Token::ExchangeCell => return self.exchange_cell.join("\n"),
+            Token::Debug => return self.debug_dump.join("\n"),
+            Token::SetZero => return self.setzero.join("\n"),
+            Token::MulAdd { offset, factor } => {
+                return self
+                    .muladd
+                    .join("\n")
+                    .replace("{offset}", &offset.to_string())
+                    .replace("{factor}", &factor.to_string())
+            }
+            Token::ScanRight { stride, id } => {
+                return self
+                    .scanright
+                    .join("\n")
+                    .replace("{id}", &id.to_string())
+                    .replace("{}", &stride.to_string())
+            }
+            Token::ScanLeft { stride, id } => {
+                return self
+                    .scanleft
+                    .join("\n")
+                    .replace("{id}", &id.to_string())
+                    .replace("{}", &stride.to_string())
+            }
+            Token::AddAt { offset, amount } => {
+                return self
+                    .addat
+                    .join("\n")
+                    .replace("{offset}", &offset.to_string())
+                    .replace("{}", &amount.to_string())
+            }
+        };
+        template.join("\n").replace("{}", &n.to_string())
+    }
+
+    /// Whether this profile has a template for `tok`. Every profile supports
+    /// the base instructions, but superinstructions are optional.
+    pub fn supports(&self, tok: Token) -> bool {
+        let template = match tok {
+            Token::SetZero => &self.setzero,
+            Token::MulAdd { .. } => &self.muladd,
+            Token::ScanRight { .. } => &self.scanright,
+            Token::ScanLeft { .. } => &self.scanleft,
+            Token::AddAt { .. } => &self.addat,
+            _ => return true,
+        };
+        !template.is_empty()
+    }
+
+    /// Whether every change to a cell can be made at an offset from the
+    /// pointer.
+    pub fn supports_offsets(&self) -> bool {
+        [&self.addat, &self.subat, &self.setzeroat]
+            .iter()
+            .all(|template| !template.is_empty())
+    }
+
+    /// The code doing `tok`, an `Add`, `Sub` or `SetZero`, to the cell
+    /// `offset` away without moving the pointer.
+    pub fn get_offset_asm(&self, tok: Token, offset: isize) -> String {
+        let (template, n) = match tok {
+            Token::Add(n) => (&self.addat, n),
+            Token::Sub(n) => (&self.subat, n),
+            Token::SetZero => (&self.setzeroat, 0),
+            _ => unreachable!("only changes to a cell are made at an offset"),
+        };
+        template
+            .join("\n")
+            .replace("{offset}", &offset.to_string())
+            .replace("{}", &n.to_string())
+    }
+
+    pub fn supports_checked(&self) -> bool {
+        !self.bounds_check.is_empty()
+    }
+
+    pub fn get_bounds_check_asm(&self) -> String {
+        self.bounds_check.join("\n")
+    }
+
+    pub fn get_hot_loop_align_asm(&self) -> String {
+        self.hot_loop_align.join("\n")
+    }
+
+    pub fn get_bounds_error_asm(&self) -> String {
+        self.bounds_error.join("\n")
+    }
+
+    pub fn supports_procedures(&self) -> bool {
+        !self.procstart.is_empty()
+    }
+
+    pub fn get_proc_error_asm(&self) -> String {
+        self.proc_error.join("\n")
+    }
+
+    pub fn supports_externs(&self) -> bool {
+        !self.extern_call.is_empty()
+    }
+
+    /// The declaration of the external function with the id `id`.
+    pub fn get_extern_decl_asm(&self, id: u32) -> String {
+        self.extern_decl
+            .join("\n")
+            .replace("{name}", externs::name(id))
+    }
+
+    /// Whether this profile can build binaries holding several programs.
+    pub fn supports_multicall(&self) -> bool {
+        !self.dispatch.is_empty()
+    }
+
+    /// The code picking the program to run, given `checks` comparing the
+    /// name to each program's.
+    pub fn get_dispatch_asm(&self, checks: &str) -> String {
+        self.dispatch.join("\n").replace("{checks}", checks)
+    }
+
+    pub fn get_dispatch_check_asm(&self, label: &str) -> String {
+        self.dispatch_check.join("\n").replace("{label}", label)
+    }
+
+    pub fn get_dispatch_name_asm(&self, name: &str, label: &str) -> String {
+        self.dispatch_name
+            .join("\n")
+            .replace("{name}", name)
+            .replace("{label}", label)
+    }
+
+    pub fn get_program_start_asm(&self, label: &str) -> String {
+        self.program_start.join("\n").replace("{label}", label)
+    }
+
+    /// Whether this profile can call shared I/O routines instead of inlining
+    /// them.
+    pub fn supports_helpers(&self) -> bool {
+        !self.putchar_call.is_empty() && !self.getchar_call.is_empty()
+    }
+
+    /// The call to the shared routine for `tok`, which must be a `PutChar` or
+    /// `GetChar`.
+    pub fn get_helper_call_asm(&self, tok: Token) -> String {
         match tok {
-            Token::PtrAdd(n) => self.ptradd.join("\n").replace("{}", &n.to_string()),
-            Token::PtrSub(n) => self.ptrsub.join("\n").replace("{}", &n.to_string()),
-            Token::Add(n) => self.add.join("\n").replace("{}", &n.to_string()),
-            Token::Sub(n) => self.sub.join("\n").replace("{}", &n.to_string()),
-            Token::LoopStart(n) => self.loopstart.join("\n").replace("{}", &n.to_string()),
-            Token::LoopEnd(n) => self.loopend.join("\n").replace("{}", &n.to_string()),
-            Token::PutChar => self.putchar.join("\n"),
-            Token::GetChar => self.getchar.join("\n"),
+            Token::PutChar => self.putchar_call.join("\n"),
+            Token::GetChar => self.getchar_call.join("\n"),
+            _ => unreachable!("only I/O has helper routines"),
         }
     }
 
-    pub fn generate_bin(&self, asm: &[String], outfile: &Path) -> Result<(), io::Error> {
-        let mut asm_path = CACHE_PATH.clone();
-        asm_path.push("temp.s");
-        Self::write_asm(asm, &asm_path)?;
+    pub fn get_helpers_asm(&self) -> String {
+        self.helpers.join("\n")
+    }
 
-        let mut obj_path = CACHE_PATH.clone();
-        obj_path.push("temp.o");
+    /// The runtime routines that `asm` mentions, along with the ones those
+    /// mention in turn, in name order.
+    pub fn runtime_routines(&self, asm: &str) -> Vec<&str> {
+        let mentions = |text: &str, name: &str| {
+            text.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                .any(|word| word == name)
+        };
 
-        let mut cmd = Command::new("nasm");
-        cmd.args(&self.nasm_args)
-            .args(["-o", obj_path.to_str().unwrap()])
-            .arg("temp.s");
-        cmd.spawn()?;
+        let mut used: Vec<&str> = vec![];
+        let mut pending = vec![asm.to_string()];
+        while let Some(text) = pending.pop() {
+            for (name, body) in &self.runtime {
+                if !used.contains(&name.as_str()) && mentions(&text, name) {
+                    used.push(name);
+                    pending.push(body.join("\n"));
+                }
+            }
+        }
 
-        let mut cmd = Command::new(self.linker);
-        cmd.args(&self.linker_args)
-            .args(["-o", outfile.to_str().unwrap()])
-            .arg(obj_path.to_str().unwrap());
-        cmd.spawn()?;
+        used.sort_unstable();
+        used
+    }
 
-        fs::remove_file(asm_path)?;
-        fs::remove_file(obj_path)?;
+    pub fn get_runtime_asm(&self, name: &str) -> String {
+        self.runtime[name].join("\n")
+    }
 
-        Ok(())
+    pub fn supports_debug(&self) -> bool {
+        !self.debug_line.is_empty()
+    }
+
+    /// Whether debug builds can come with a gdb script.
+    pub fn supports_gdb(&self) -> bool {
+        !self.debug_label.is_empty() && !self.gdb_pointer.is_empty() && !self.gdb_tape.is_empty()
+    }
+
+    pub fn get_debug_label(&self, label: &str) -> String {
+        self.debug_label.join("\n").replace("{label}", label)
+    }
+
+    pub fn supports_tapes(&self) -> bool {
+        !self.switch_tape.is_empty() && !self.exchange_cell.is_empty()
+    }
+
+    pub fn supports_debug_hash(&self) -> bool {
+        !self.debug_dump.is_empty()
+    }
+
+    pub fn supports_fault_handler(&self) -> bool {
+        !self.fault_setup.is_empty() && !self.debug_label.is_empty()
+    }
+
+    pub fn get_fault_setup_asm(&self) -> String {
+        self.fault_setup.join("\n")
+    }
+
+    pub fn supports_raw_tty(&self) -> bool {
+        !self.raw_tty.is_empty()
+    }
+
+    pub fn get_raw_tty_asm(&self) -> String {
+        self.raw_tty.join("\n")
+    }
+
+    pub fn get_raw_tty_restore_asm(&self) -> String {
+        self.raw_tty_restore.join("\n")
+    }
+
+    pub fn supports_coverage(&self) -> bool {
+        !self.coverage_count.is_empty()
+    }
+
+    /// The count of instruction number `index` running.
+    pub fn get_coverage_count_asm(&self, index: usize) -> String {
+        self.coverage_count
+            .join("\n")
+            .replace("{index}", &index.to_string())
+    }
+
+    /// The code writing out the `counters` counts when the program ends.
+    pub fn get_coverage_dump_asm(&self, counters: usize) -> String {
+        self.coverage_dump
+            .join("\n")
+            .replace("{counters}", &counters.to_string())
+    }
+
+    /// The table of where each instruction starts, given `entries` with a
+    /// fault entry for each.
+    pub fn get_fault_table_asm(&self, entries: &str) -> String {
+        self.fault_table.join("\n").replace("{entries}", entries)
+    }
+
+    pub fn get_fault_entry_asm(&self, label: &str, line: u32) -> String {
+        self.fault_entry
+            .join("\n")
+            .replace("{label}", label)
+            .replace("{line}", &line.to_string())
+    }
+
+    pub fn gdb_pointer(&self) -> &str {
+        &self.gdb_pointer
+    }
+
+    pub fn gdb_tape(&self) -> &str {
+        &self.gdb_tape
+    }
+
+    /// The debug line for `span`, whose `{file}` is the file `span` is in,
+    /// which may be an included file rather than the input file `vars` name.
+    pub fn get_debug_line(&self, span: Span, vars: &Variables) -> String {
+        let file = match span.file {
+            Some(file) => std::path::absolute(file)
+                .unwrap_or_else(|_| file.into())
+                .display()
+                .to_string(),
+            None => vars
+                .get("file")
+                .cloned()
+                .unwrap_or_else(|| "{file}".to_string()),
+        };
+        self.debug_line
+            .join("\n")
+            .replace("{file}", &file)
+            .replace("{line}", &span.line.to_string())
+            .replace("{col}", &span.col.to_string())
+    }
+
+    pub fn get_comment(&self, text: &str) -> String {
+        self.comment.replace("{}", text)
+    }
+
+    #[cfg(feature = "native")]
+    pub fn toolchain(&self) -> Toolchain {
+        Toolchain {
+            nasm: self.nasm.to_string(),
+            nasm_version_args: self.nasm_version_args.clone(),
+            nasm_args: self.nasm_args.iter().map(|s| s.to_string()).collect(),
+            linker: self.linker.to_string(),
+            linker_args: self.linker_args.iter().map(|s| s.to_string()).collect(),
+            reproducible_linker_args: self
+                .reproducible_linker_args
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            strip_linker_args: self
+                .strip_linker_args
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            small_linker_args: self
+                .small_linker_args
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            debug_nasm_args: self.debug_nasm_args.iter().map(|s| s.to_string()).collect(),
+            size_nasm_args: self.size_nasm_args.iter().map(|s| s.to_string()).collect(),
+            objects: vec![],
+            save_temps: false,
+            reproducible: false,
+            debug: false,
+            strip: false,
+            small: false,
+            listing: None,
+            cache: true,
+        }
+    }
+
+    pub fn supports_arg_input(&self) -> bool {
+        !self.arg_input.is_empty()
+    }
+
+    /// This profile with the fields in its `arg_input` section swapped in.
+    pub fn arg_input(&self) -> Result<Profile, serde_json::Error> {
+        self.with_section("arg_input", &self.arg_input)
+    }
+
+    pub fn supports_file_input(&self) -> bool {
+        !self.file_input.is_empty()
+    }
+
+    /// This profile with the fields in its `file_input` section swapped in.
+    pub fn file_input(&self) -> Result<Profile, serde_json::Error> {
+        self.with_section("file_input", &self.file_input)
+    }
+
+    pub fn supports_file_output(&self) -> bool {
+        !self.file_output.is_empty()
+    }
+
+    /// This profile with the fields in its `file_output` section swapped in.
+    pub fn file_output(&self) -> Result<Profile, serde_json::Error> {
+        self.with_section("file_output", &self.file_output)
+    }
+
+    pub fn supports_buffer_output(&self) -> bool {
+        !self.buffer_output.is_empty()
+    }
+
+    /// This profile with the fields in its `buffer_output` section swapped
+    /// in.
+    pub fn buffer_output(&self) -> Result<Profile, serde_json::Error> {
+        self.with_section("buffer_output", &self.buffer_output)
+    }
+
+    pub fn supports_pie(&self) -> bool {
+        !self.pie.is_empty()
+    }
+
+    /// This profile with the fields in its `pie` section swapped in.
+    pub fn pie(&self) -> Result<Profile, serde_json::Error> {
+        self.with_section("pie", &self.pie)
+    }
+
+    pub fn supports_no_exit(&self) -> bool {
+        !self.no_exit.is_empty()
+    }
+
+    /// This profile with the fields in its `no_exit` section swapped in.
+    pub fn no_exit(&self) -> Result<Profile, serde_json::Error> {
+        self.with_section("no_exit", &self.no_exit)
+    }
+
+    pub fn supports_guard_pages(&self) -> bool {
+        !self.guard_pages.is_empty()
+    }
+
+    /// This profile with the fields in its `guard_pages` section swapped in.
+    pub fn guard_pages(&self) -> Result<Profile, serde_json::Error> {
+        self.with_section("guard_pages", &self.guard_pages)
+    }
+
+    pub fn supports_cross(&self) -> bool {
+        !self.cross.is_empty()
+    }
+
+    /// This profile with the fields in its `cross` section swapped in.
+    pub fn cross(&self) -> Result<Profile, serde_json::Error> {
+        self.with_section("cross", &self.cross)
     }
 
+    pub fn syntax(&self) -> AsmSyntax {
+        self.syntax
+    }
+
+    /// Whether the profile's templates are written in `syntax` or it has a
+    /// section for it.
+    pub fn supports_syntax(&self, syntax: AsmSyntax) -> bool {
+        self.syntax == syntax || self.syntaxes.contains_key(syntax.name())
+    }
+
+    /// This profile with the fields in its section for `syntax` swapped in,
+    /// or as it is if its templates are already written in it.
+    pub fn with_syntax(&self, syntax: AsmSyntax) -> Result<Profile, serde_json::Error> {
+        if self.syntax == syntax {
+            return Ok(self.clone());
+        }
+        let section = match self.syntaxes.get(syntax.name()) {
+            Some(serde_json::Value::Object(section)) => section.clone(),
+            _ => {
+                return Err(serde::de::Error::custom(format!(
+                    "the `syntaxes` section has no object for `{}`",
+                    syntax.name()
+                )))
+            }
+        };
+        let mut profile = self.with_section("syntaxes", &section)?;
+        profile.syntax = syntax;
+        Ok(profile)
+    }
+
+    /// This profile with the fields in `section` swapped in and the section
+    /// itself emptied.
+    fn with_section(
+        &self,
+        name: &str,
+        section: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Profile, serde_json::Error> {
+        let mut value = serde_json::to_value(self)?;
+        for (field, template) in section {
+            value[field] = template.clone();
+        }
+        value[name] = serde_json::Value::Object(Default::default());
+
+        let mut profile: Profile = serde_json::from_value(value)?;
+        profile.source = self.source.clone();
+        Ok(profile)
+    }
+
+    #[cfg(feature = "native")]
     pub fn write_asm(asm: &[String], outfile: &Path) -> Result<(), io::Error> {
         let mut file = fs::File::create(outfile)?;
         file.write_all(asm.join("\n").as_bytes())?;
@@ -114,17 +1363,256 @@ impl Profile {
         Ok(())
     }
 
-    pub fn default() -> &'static Self {
-        Self::get_by_string(&DEFAULT_PROFILE).expect("No default profile found")
+    /// Generates the JSON for a new profile based on this one, with a `_`
+    /// prefixed comment entry before every field.
+    pub fn scaffold(&self, name: &str) -> String {
+        let mut value = serde_json::to_value(self).unwrap();
+        value["name"] = name.into();
+        let serde_json::Value::Object(mut object) = value else {
+            unreachable!("profiles serialize to objects");
+        };
+
+        // Documented fields come in the order they're documented, then any
+        // that aren't, so that every field the profile has is written out
+        let entry = |field: &str, body: &serde_json::Value| {
+            let body = serde_json::to_string_pretty(body)
+                .unwrap()
+                .replace('\n', "\n    ");
+            format!("    \"{field}\": {body}")
+        };
+        let mut fields = vec![];
+        for (field, doc) in FIELD_DOCS {
+            if let Some(body) = object.remove(*field) {
+                fields.push(format!(
+                    "    \"_{field}\": {doc:?},\n{}",
+                    entry(field, &body)
+                ));
+            }
+        }
+        fields.extend(object.iter().map(|(field, body)| entry(field, body)));
+
+        format!("{{\n{}\n}}\n", fields.join(",\n\n"))
+    }
+
+    /// Every field a profile file can have, with what it's for.
+    pub fn field_docs() -> &'static [(&'static str, &'static str)] {
+        FIELD_DOCS
     }
 
-    pub fn get_by_string(profile: &str) -> Option<&Profile> {
+    #[cfg(feature = "native")]
+    pub fn config_path() -> &'static Path {
+        CONFIG_PATH.as_path()
+    }
+
+    /// The default profile, or why it can't be had, as when a user profile
+    /// replacing it is broken.
+    pub fn get_default() -> Result<&'static Self, String> {
+        Self::select(Self::default_name())
+    }
+
+    pub fn default_name() -> &'static str {
+        &DEFAULT_PROFILE
+    }
+
+    /// The profile named `profile`, parsing only that one. Gives `None` if
+    /// there's no such profile, and the reason it couldn't be read if its
+    /// file is broken.
+    pub fn get_by_string(profile: &str) -> Result<Option<&'static Profile>, String> {
+        PROFILES
+            .iter()
+            .find(|entry| entry.name == profile)
+            .map(|entry| entry.get().map_err(str::to_string))
+            .transpose()
+    }
+
+    /// The profile named `profile`, or why it can't be had.
+    pub fn select(profile: &str) -> Result<&'static Profile, String> {
+        Self::get_by_string(profile)?.ok_or_else(|| format!("profile {profile} not found"))
+    }
+
+    /// The first profile that builds for `triple`.
+    pub fn get_by_target(triple: &str) -> Option<&'static Profile> {
+        let target = arch_os(triple);
         Self::get_all_profiles()
+            .into_iter()
+            .find(|prof| prof.targets.iter().any(|t| arch_os(t) == target))
+    }
+
+    /// Every profile that can be read, parsing them all.
+    pub fn get_all_profiles() -> Vec<&'static Profile> {
+        Self::load_all().into_iter().flatten().collect()
+    }
+
+    /// Every profile, parsing them all, with the reasons the ones that can't
+    /// be read couldn't be.
+    pub fn load_all() -> Vec<Result<&'static Profile, String>> {
+        PROFILES
             .iter()
-            .find(|&prof| prof.name == profile)
+            .map(|entry| entry.get().map_err(str::to_string))
+            .collect()
+    }
+
+    /// Reads the profile file at `path`, which needn't be in the config
+    /// directory or named after the profile.
+    #[cfg(feature = "native")]
+    pub fn load_file(path: &Path) -> Result<&'static Profile, String> {
+        Ok(Box::leak(Box::new(Self::read_file(path)?)))
+    }
+
+    #[cfg(feature = "native")]
+    fn read_file(path: &Path) -> Result<Profile, String> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {e}", path.display()))?;
+        let mut profile: Profile = serde_json::from_str(&json)
+            .map_err(|e| format!("invalid profile {}: {e}", path.display()))?;
+        profile.source = ProfileSource::User(path.to_path_buf());
+        Ok(profile)
+    }
+}
+
+/// Defines a [`Profile`] field by field, taking the same fields and templates
+/// as a profile file. `build` checks them the same way loading a file does,
+/// so a profile needs at least a name, the base instruction templates and a
+/// toolchain.
+#[derive(Clone, Debug, Default)]
+pub struct ProfileBuilder {
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ProfileBuilder {
+    fn set(mut self, field: &str, value: impl Into<serde_json::Value>) -> Self {
+        self.fields.insert(field.to_string(), value.into());
+        self
+    }
+
+    fn lines<S: Into<String>>(lines: impl IntoIterator<Item = S>) -> Vec<String> {
+        lines.into_iter().map(Into::into).collect()
+    }
+
+    pub fn name(self, name: impl Into<String>) -> Self {
+        self.set("name", name.into())
+    }
+
+    pub fn description(self, description: impl Into<String>) -> Self {
+        self.set("description", description.into())
+    }
+
+    pub fn is_static(self, is_static: bool) -> Self {
+        self.set("static", is_static)
+    }
+
+    /// Sets the default for the template variable `{name}`.
+    pub fn variable(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let variables = self
+            .fields
+            .entry("variables")
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+        variables[name.into()] = value.into().into();
+        self
+    }
+
+    /// Sets the template `field`, such as `setup` or `ptradd`, to `lines`.
+    pub fn add_template<S: Into<String>>(
+        self,
+        field: &str,
+        lines: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.set(field, Self::lines(lines))
+    }
+
+    /// Adds the runtime routine `name`, emitted once when a template
+    /// mentions it.
+    pub fn add_runtime<S: Into<String>>(
+        mut self,
+        name: impl Into<String>,
+        lines: impl IntoIterator<Item = S>,
+    ) -> Self {
+        let runtime = self
+            .fields
+            .entry("runtime")
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+        runtime[name.into()] = Self::lines(lines).into();
+        self
     }
 
-    pub fn get_all_profiles() -> &'static [Profile] {
-        &PROFILES
+    /// Sets the assembler and the arguments passed to it.
+    pub fn nasm<S: Into<String>>(
+        self,
+        nasm: impl Into<String>,
+        args: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.set("nasm", nasm.into())
+            .set("nasm_args", Self::lines(args))
+    }
+
+    /// Sets the linker and the arguments passed to it.
+    pub fn linker<S: Into<String>>(
+        self,
+        linker: impl Into<String>,
+        args: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.set("linker", linker.into())
+            .set("linker_args", Self::lines(args))
+    }
+
+    /// Adds a target, as an arch-os pair like `x86_64-linux`, that --target
+    /// picks this profile for.
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        let targets = self
+            .fields
+            .entry("targets")
+            .or_insert_with(|| serde_json::Value::Array(vec![]));
+        if let serde_json::Value::Array(targets) = targets {
+            targets.push(target.into().into());
+        }
+        self
+    }
+
+    /// The profile, or an error if a field it needs is missing, a field isn't
+    /// one profiles have or a template isn't a list of lines.
+    pub fn build(self) -> Result<Profile, serde_json::Error> {
+        if let Some(field) = self
+            .fields
+            .keys()
+            .find(|field| !FIELD_DOCS.iter().any(|(name, _)| name == field))
+        {
+            return Err(serde::de::Error::custom(format!(
+                "profiles have no field `{field}`"
+            )));
+        }
+
+        let mut profile: Profile = serde_json::from_value(self.fields.into())?;
+        profile.source = ProfileSource::Code;
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_field_is_documented() {
+        for (name, json) in BUILTIN_PROFILES {
+            let profile: Profile = serde_json::from_str(json).unwrap();
+            let serde_json::Value::Object(fields) = serde_json::to_value(&profile).unwrap() else {
+                panic!("{name} doesn't serialize to an object");
+            };
+            for field in fields.keys() {
+                assert!(
+                    FIELD_DOCS.iter().any(|(documented, _)| documented == field),
+                    "`{field}` of {name} is missing from FIELD_DOCS"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn scaffold_reads_back() {
+        for (name, json) in BUILTIN_PROFILES {
+            let profile: Profile = serde_json::from_str(json).unwrap();
+            let scaffold: Profile = serde_json::from_str(&profile.scaffold("copy")).unwrap();
+            assert_eq!(scaffold.name, "copy", "scaffold of {name}");
+        }
     }
 }
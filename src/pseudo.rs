@@ -0,0 +1,162 @@
+use crate::{
+    externs,
+    lex::{Span, Token},
+};
+use std::collections::BTreeMap;
+
+fn cell(offset: isize) -> String {
+    match offset {
+        0 => "cell[p]".to_string(),
+        n if n > 0 => format!("cell[p+{n}]"),
+        n => format!("cell[p-{}]", -n),
+    }
+}
+
+/// If the loop between `start` and `end` only moves cells around and counts
+/// its own cell down by one, returns how much it adds to each other cell per
+/// iteration, keyed by offset.
+fn multiplication(
+    tokens: &[(Token, Span)],
+    start: usize,
+    end: usize,
+) -> Option<BTreeMap<isize, isize>> {
+    let mut offset = 0isize;
+    let mut deltas = BTreeMap::new();
+    for (token, _) in &tokens[start + 1..end] {
+        match *token {
+            Token::PtrAdd(n) => offset += n as isize,
+            Token::PtrSub(n) => offset -= n as isize,
+            Token::Add(n) => *deltas.entry(offset).or_insert(0) += n as isize,
+            Token::Sub(n) => *deltas.entry(offset).or_insert(0) -= n as isize,
+            _ => return None,
+        }
+    }
+    if offset != 0 || deltas.remove(&0) != Some(-1) {
+        return None;
+    }
+    deltas.retain(|_, delta| *delta != 0);
+    Some(deltas)
+}
+
+struct Writer {
+    lines: Vec<String>,
+    depth: usize,
+    /// Pointer movement not yet written out
+    offset: isize,
+}
+
+impl Writer {
+    fn line(&mut self, text: String) {
+        self.lines
+            .push(format!("{}{text}", "    ".repeat(self.depth)));
+    }
+
+    /// Writes out pending pointer movement, which has to happen before any
+    /// code that depends on where the pointer is.
+    fn settle(&mut self) {
+        match self.offset {
+            0 => {}
+            n if n > 0 => self.line(format!("p += {n}")),
+            n => self.line(format!("p -= {}", -n)),
+        }
+        self.offset = 0;
+    }
+}
+
+/// Describes `tokens` as structured pseudocode, with cells addressed relative
+/// to the pointer `p` and multiplication loops written as the arithmetic they
+/// perform.
+pub fn generate(tokens: &[(Token, Span)]) -> Vec<String> {
+    let mut writer = Writer {
+        lines: vec![],
+        depth: 0,
+        offset: 0,
+    };
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let here = cell(writer.offset);
+        match tokens[i].0 {
+            Token::PtrAdd(n) => writer.offset += n as isize,
+            Token::PtrSub(n) => writer.offset -= n as isize,
+            Token::Add(n) => writer.line(format!("{here} += {n}")),
+            Token::Sub(n) => writer.line(format!("{here} -= {n}")),
+            Token::PutChar => writer.line(format!("output({here})")),
+            Token::GetChar => writer.line(format!("{here} = input()")),
+            Token::LoopStart(distance) => {
+                let end = i + distance as usize;
+                if let Some(deltas) = multiplication(tokens, i, end) {
+                    for (offset, delta) in deltas {
+                        let target = cell(writer.offset + offset);
+                        let op = if delta < 0 { "-=" } else { "+=" };
+                        let factor = match delta.abs() {
+                            1 => String::new(),
+                            n => format!("{n} * "),
+                        };
+                        writer.line(format!("{target} {op} {factor}{here}"));
+                    }
+                    writer.line(format!("{here} = 0"));
+                    i = end;
+                } else {
+                    writer.settle();
+                    writer.line("while cell[p] {".to_string());
+                    writer.depth += 1;
+                }
+            }
+            Token::LoopEnd(_) | Token::ProcEnd(_) => {
+                writer.settle();
+                writer.depth -= 1;
+                writer.line("}".to_string());
+            }
+            Token::ProcStart(_) => {
+                writer.settle();
+                writer.line("procedure cell[p] {".to_string());
+                writer.depth += 1;
+            }
+            Token::Call => {
+                writer.settle();
+                writer.line("call procedure cell[p]".to_string());
+            }
+            Token::Extern(id) => {
+                writer.settle();
+                writer.line(format!("{}(&cell[p])", externs::name(id)));
+            }
+            Token::SwitchTape => {
+                writer.settle();
+                writer.line("switch_tape()".to_string());
+            }
+            Token::ExchangeCell => writer.line(format!("exchange({here})")),
+            Token::Debug => {
+                writer.settle();
+                writer.line("debug_dump(p)".to_string());
+            }
+            Token::SetZero => writer.line(format!("{here} = 0")),
+            Token::MulAdd { offset, factor } => {
+                let target = cell(writer.offset + offset as isize);
+                let op = if factor < 0 { "-=" } else { "+=" };
+                let factor = match factor.abs() {
+                    1 => String::new(),
+                    n => format!("{n} * "),
+                };
+                writer.line(format!("{target} {op} {factor}{here}"));
+            }
+            Token::AddAt { offset, amount } => {
+                let target = cell(writer.offset + offset as isize);
+                let op = if amount < 0 { "-=" } else { "+=" };
+                writer.line(format!("{target} {op} {}", amount.abs()));
+            }
+            Token::ScanRight { stride, .. } => {
+                writer.settle();
+                writer.line(format!("while cell[p] {{ p += {stride} }}"));
+            }
+            Token::ScanLeft { stride, .. } => {
+                writer.settle();
+                writer.line(format!("while cell[p] {{ p -= {stride} }}"));
+            }
+        }
+        i += 1;
+    }
+    writer.settle();
+
+    writer.lines
+}
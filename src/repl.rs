@@ -0,0 +1,131 @@
+use crate::{
+    diagnostics::{Diagnostic, Emitter},
+    interpret::{Config, Machine},
+    lex::Tokens,
+};
+use std::io::{self, Write};
+
+/// Cells shown by `/tape` when no count is given
+const TAPE_WINDOW: usize = 16;
+
+const HELP: &str = "\
+/tape [START [COUNT]]  show the tape, around the pointer by default
+/set CELL VALUE        set a cell
+/reset                 clear the tape and move the pointer back to cell 0
+/opt                   turn optimising entered code on or off
+/help                  show this message
+/quit                  leave, as does the end of input";
+
+/// Prints `count` cells from `start`, marking the one the pointer is on.
+fn show_tape(machine: &Machine, start: Option<usize>, count: Option<usize>) {
+    let count = count.unwrap_or(TAPE_WINDOW).min(machine.len());
+    let start = start
+        .unwrap_or_else(|| machine.ptr().saturating_sub(count / 2))
+        .min(machine.len() - count);
+
+    let cells: Vec<String> = (start..start + count)
+        .map(|i| match machine.cell(i) {
+            value if i == machine.ptr() => format!("[{value}]"),
+            value => value.to_string(),
+        })
+        .collect();
+    println!("{start}: {}", cells.join(" "));
+    println!("pointer at cell {}", machine.ptr());
+}
+
+/// Parses the words after a meta-command as numbers.
+fn numbers<T: std::str::FromStr>(words: &[&str]) -> Result<Vec<T>, String> {
+    words
+        .iter()
+        .map(|word| {
+            word.parse()
+                .map_err(|_| format!("`{word}` is not a number"))
+        })
+        .collect()
+}
+
+/// Runs the meta-command `line`, returning whether to carry on.
+fn meta_command(
+    line: &str,
+    machine: &mut Machine,
+    config: &Config,
+    optimise: &mut bool,
+) -> Result<bool, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words[0] {
+        "/tape" => match numbers(&words[1..])?[..] {
+            [] => show_tape(machine, None, None),
+            [start] => show_tape(machine, Some(start), None),
+            [start, count] => show_tape(machine, Some(start), Some(count)),
+            _ => return Err("usage: /tape [START [COUNT]]".to_string()),
+        },
+        "/set" => match numbers::<u64>(&words[1..])?[..] {
+            [cell, value] if (cell as usize) < machine.len() => {
+                machine.set_cell(cell as usize, value)
+            }
+            [cell, _] => return Err(format!("cell {cell} is not on the tape")),
+            _ => return Err("usage: /set CELL VALUE".to_string()),
+        },
+        "/reset" => *machine = Machine::new(config),
+        "/opt" => {
+            *optimise = !*optimise;
+            println!("optimisation {}", if *optimise { "on" } else { "off" });
+        }
+        "/help" => println!("{HELP}"),
+        "/quit" => return Ok(false),
+        command => return Err(format!("unknown command `{command}`, try /help")),
+    }
+    Ok(true)
+}
+
+/// Reads brainfuck a line at a time and runs it on a tape that persists
+/// between lines. A line that leaves a loop open carries on to the next.
+/// `lex` turns the code entered into tokens, optimising them if asked.
+pub fn run(
+    config: &Config,
+    mut optimise: bool,
+    emitter: &Emitter,
+    lex: impl Fn(&str, bool) -> Result<Tokens, Diagnostic>,
+) -> io::Result<()> {
+    let mut machine = Machine::new(config);
+    let stdin = io::stdin();
+    let mut source = String::new();
+
+    println!("Type brainfuck to run it, or /help for commands.");
+    loop {
+        print!("{}", if source.is_empty() { "bf> " } else { "... " });
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+
+        if source.is_empty() && line.trim_start().starts_with('/') {
+            match meta_command(line.trim(), &mut machine, config, &mut optimise) {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(e) => eprintln!("error: {e}"),
+            }
+            continue;
+        }
+
+        source.push_str(&line);
+        let tokens = match lex(&source, optimise) {
+            Ok(tokens) => tokens,
+            // An unclosed loop may be closed on the next line
+            Err(diagnostic) if diagnostic.code == "E0002" => continue,
+            Err(diagnostic) => {
+                emitter.emit(&diagnostic);
+                source.clear();
+                continue;
+            }
+        };
+        source.clear();
+
+        if let Err(e) = machine.run(&tokens, &mut stdin.lock(), &mut io::stdout().lock()) {
+            eprintln!("error: {e}");
+        }
+    }
+}
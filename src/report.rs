@@ -0,0 +1,83 @@
+//! A record of how a program was built, for --build-report: what went in,
+//! what settings and tools it was built with, how long each phase took, and
+//! what came out, so that a build can be checked and done again.
+use crate::{digest, profile::Variables, timings::Timings};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildReport {
+    pub bfc_version: String,
+    /// The command line, as given
+    pub command: Vec<String>,
+    pub inputs: Vec<File>,
+    pub profile: Profile,
+    /// The template variables, after the profile's defaults and the command
+    /// line's overrides
+    pub variables: Variables,
+    /// The optimisation passes, in the order they ran
+    pub passes: Vec<&'static str>,
+    pub tools: Vec<Tool>,
+    /// Time spent in each phase, in the order they first ran
+    pub timings: Vec<Phase>,
+    pub outputs: Vec<File>,
+}
+
+/// A file read or written, and its contents' digest.
+#[derive(Debug, Clone, Serialize)]
+pub struct File {
+    pub path: String,
+    pub bytes: usize,
+    pub sha256: String,
+}
+
+impl File {
+    pub fn new(path: &str, contents: &[u8]) -> Self {
+        Self {
+            path: path.to_string(),
+            bytes: contents.len(),
+            sha256: digest::sha256_hex(contents),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Profile {
+    pub name: String,
+    /// Where it was read from
+    pub source: String,
+    /// The profile file's digest, for profiles read from one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// An assembler or linker that was run.
+#[derive(Debug, Clone, Serialize)]
+pub struct Tool {
+    /// What it was run as, `assembler` or `linker`
+    pub role: &'static str,
+    pub command: String,
+    pub args: Vec<String>,
+    /// The first line it printed when asked for its version, if it could be
+    /// asked
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Phase {
+    pub phase: &'static str,
+    pub ms: f64,
+}
+
+impl BuildReport {
+    /// Sets the timings to those so far.
+    pub fn set_timings(&mut self, timings: &Timings) {
+        self.timings = timings
+            .phases()
+            .iter()
+            .map(|&(phase, duration)| Phase {
+                phase,
+                ms: duration.as_secs_f64() * 1000.0,
+            })
+            .collect();
+    }
+}
@@ -0,0 +1,118 @@
+use crate::{
+    interpret::{self, Config},
+    lex::{Span, Token},
+};
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+    process::{Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often to check whether a program with a timeout has finished
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runs a compiled program to completion with `input` as its stdin, killing
+/// it with a `TimedOut` error if it runs for longer than `timeout`.
+pub fn run_binary(
+    binary: &Path,
+    input: &[u8],
+    timeout: Option<Duration>,
+) -> Result<Output, io::Error> {
+    let mut child = Command::new(binary)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(input)?;
+    let Some(timeout) = timeout else {
+        return child.wait_with_output();
+    };
+
+    // Read the output as it comes, so a program that writes a lot doesn't
+    // block on a full pipe while it's waited on
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = vec![];
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("timed out after {timeout:?}"),
+            ));
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    Ok(Output {
+        status,
+        stdout: reader.join().unwrap()?,
+        stderr: vec![],
+    })
+}
+
+/// Runs the compiled `binary` and the interpreter on the same input,
+/// printing whether their output and exit behaviour match. With `exit_cell`,
+/// the binary's exit status must be the cell the interpreter ends on.
+pub fn run(
+    binary: &Path,
+    tokens: &[(Token, Span)],
+    config: &Config,
+    input: &[u8],
+    exit_cell: bool,
+) -> Result<bool, io::Error> {
+    let compiled = run_binary(binary, input, config.timeout)?;
+
+    let mut interpreted = vec![];
+    let result = interpret::run(tokens, config, &mut &input[..], &mut interpreted);
+
+    let mut passed = true;
+    if let Some(i) = (0..compiled.stdout.len().max(interpreted.len()))
+        .find(|&i| compiled.stdout.get(i) != interpreted.get(i))
+    {
+        println!(
+            "output differs at byte {i}: compiled {:?}, interpreted {:?}",
+            compiled.stdout.get(i),
+            interpreted.get(i)
+        );
+        passed = false;
+    }
+
+    match (&result, compiled.status.success()) {
+        (Ok(cell), _) if exit_cell && compiled.status.code() != Some((*cell).into()) => {
+            println!(
+                "compiled program exited with {} but the interpreter ended on {cell}",
+                compiled.status
+            );
+            passed = false;
+        }
+        (Ok(_), _) if exit_cell => {}
+        (Ok(_), false) => {
+            println!(
+                "compiled program failed ({}) but the interpreter finished",
+                compiled.status
+            );
+            passed = false;
+        }
+        (Err(e), true) => {
+            println!("compiled program finished but the interpreter failed: {e}");
+            passed = false;
+        }
+        _ => {}
+    }
+
+    if passed {
+        println!("ok: {} bytes of matching output", interpreted.len());
+    }
+    Ok(passed)
+}
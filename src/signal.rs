@@ -0,0 +1,26 @@
+//! SIGUSR1, which `bfc vm --checkpoint` takes as a request to save the
+//! program's state without stopping it.
+use std::sync::atomic::AtomicBool;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn request(_: libc::c_int) {
+    REQUESTED.store(true, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Has SIGUSR1 set the flag returned, rather than end the process.
+#[cfg(unix)]
+pub fn on_sigusr1() -> &'static AtomicBool {
+    let handler = request as extern "C" fn(libc::c_int);
+    // SAFETY: the handler only stores to an atomic, which is async-signal
+    // safe
+    unsafe { libc::signal(libc::SIGUSR1, handler as libc::sighandler_t) };
+    &REQUESTED
+}
+
+#[cfg(not(unix))]
+pub fn on_sigusr1() -> &'static AtomicBool {
+    log::warn!("Checkpointing on a signal is only supported on Unix");
+    &REQUESTED
+}
@@ -0,0 +1,179 @@
+use crate::lex::{Span, Token};
+use serde::Serialize;
+
+/// Starts the comment codegen puts before each token's assembly when making a
+/// source map, followed by the token's index or `end`
+const MARKER: &str = "bfc-map ";
+
+/// The text of the comment marking where the assembly for the token at
+/// `index` starts, or where the assembly for the last token ends.
+pub fn marker(index: Option<usize>) -> String {
+    match index {
+        Some(index) => format!("{MARKER}{index}"),
+        None => format!("{MARKER}end"),
+    }
+}
+
+/// Relates each token of a program to the assembly generated for it, and to
+/// the machine code that assembled into when that's known.
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceMap {
+    pub version: u32,
+    /// The brainfuck file, which spans without a file of their own are in
+    pub source: String,
+    pub mappings: Vec<Mapping>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Mapping {
+    /// The file the token came from, if not the source
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    pub line: u32,
+    pub col: u32,
+    /// The token as brainfuck
+    pub token: String,
+    /// The first and last lines of assembly generated for the token,
+    /// counting from 1
+    pub asm_lines: [usize; 2],
+    /// Where the token's machine code starts in the text section of the
+    /// object file, if it was assembled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    /// Bytes of machine code the token assembled into
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+/// The index in the marker on `line`, `Some(None)` for the end marker, or
+/// `None` if it isn't a marker.
+fn parse_marker(line: &str) -> Option<Option<usize>> {
+    let (_, rest) = line.split_once(MARKER)?;
+    let word = rest.split_whitespace().next()?;
+    match word {
+        "end" => Some(None),
+        index => index.parse().ok().map(Some),
+    }
+}
+
+impl SourceMap {
+    /// Maps `tokens`, the tokens codegen was given, to the lines of `asm`
+    /// between the markers it left. Tokens that generated no code are left
+    /// out.
+    pub fn new(source: &str, tokens: &[(Token, Span)], asm: &str) -> Self {
+        let mut ranges: Vec<Option<[usize; 2]>> = vec![None; tokens.len()];
+        let mut current = None;
+        for (i, line) in asm.lines().enumerate() {
+            if let Some(index) = parse_marker(line) {
+                current = index.filter(|&index| index < tokens.len());
+                continue;
+            }
+            let line_number = i + 1;
+            if let Some(index) = current {
+                if !line.trim().is_empty() {
+                    let range = ranges[index].get_or_insert([line_number; 2]);
+                    range[1] = line_number;
+                }
+            }
+        }
+
+        let mappings = tokens
+            .iter()
+            .zip(ranges)
+            .filter_map(|(&(token, span), range)| {
+                Some(Mapping {
+                    file: span.file.map(|file| file.to_string()),
+                    line: span.line,
+                    col: span.col,
+                    token: token.to_bf(),
+                    asm_lines: range?,
+                    offset: None,
+                    size: None,
+                })
+            })
+            .collect();
+
+        SourceMap {
+            version: 1,
+            source: source.to_string(),
+            mappings,
+        }
+    }
+
+    /// Fills in the machine code of each mapping from a nasm listing of the
+    /// assembly.
+    pub fn add_listing(&mut self, listing: &str) {
+        let code = parse_listing(listing);
+        for mapping in &mut self.mappings {
+            let [first, last] = mapping.asm_lines;
+            let lines: Vec<Code> = (first..=last)
+                .filter_map(|line| code.get(line - 1).copied().flatten())
+                .collect();
+            if let Some(start) = lines.first() {
+                mapping.offset = Some(start.offset);
+                mapping.size = Some(lines.iter().map(|code| code.size).sum());
+            }
+        }
+    }
+}
+
+/// Machine code assembled from one line of assembly.
+#[derive(Debug, Clone, Copy)]
+struct Code {
+    offset: u64,
+    size: u64,
+}
+
+/// Reads a nasm listing into the code for each line of the assembly it
+/// lists, by the line's index. Lines of code that span several lines of
+/// listing, and the lines macros expand into, are added to the line they
+/// come from.
+fn parse_listing(listing: &str) -> Vec<Option<Code>> {
+    let mut code: Vec<Option<Code>> = vec![];
+    // Whether the last line's bytes carry on onto the next
+    let mut continued = false;
+
+    for line in listing.lines() {
+        let mut words = line.split_whitespace().peekable();
+        if words.next().and_then(|n| n.parse::<u64>().ok()).is_none() {
+            continue;
+        }
+
+        let mut assembled = None;
+        if let Some(offset) = words
+            .peek()
+            .filter(|word| word.len() == 8)
+            .and_then(|word| u64::from_str_radix(word, 16).ok())
+        {
+            words.next();
+            let bytes = words
+                .next_if(|word| {
+                    word.chars()
+                        .all(|c| c.is_ascii_hexdigit() || "[]()-".contains(c))
+                })
+                .unwrap_or("");
+            let digits = bytes.chars().filter(char::is_ascii_hexdigit).count();
+            assembled = Some((
+                Code {
+                    offset,
+                    size: digits as u64 / 2,
+                },
+                bytes.ends_with('-'),
+            ));
+        }
+        let expanded = words
+            .peek()
+            .is_some_and(|word| word.starts_with('<') && word.ends_with('>'));
+
+        let carries_on = continued || expanded;
+        continued = assembled.is_some_and(|(_, more)| more);
+        match (carries_on, assembled, code.last_mut()) {
+            (true, Some((more, _)), Some(Some(last))) => last.size += more.size,
+            (true, Some((more, _)), Some(last)) => *last = Some(more),
+            (true, _, _) => {}
+            (false, assembled, _) => code.push(assembled.map(|(code, _)| code)),
+        }
+    }
+
+    code
+}
@@ -0,0 +1,250 @@
+//! Specialising a program to input known when it's compiled. As much of the
+//! program as can be is run there and then, in the interpreter, and replaced
+//! by code that writes what it wrote and leaves the tape as it left it. A
+//! program that only needs the known input reduces to its output.
+use crate::{
+    interpret::{Config, Error, Machine, Program},
+    lex::{Span, Token, Tokens},
+    superinstructions,
+};
+use std::{fmt, io};
+
+/// How far a program got when it was run to specialise it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    /// Bytes of known input read
+    pub read: usize,
+    /// Bytes written
+    pub written: usize,
+    /// Why it stopped before the end, if it did
+    pub stopped: Option<Reason>,
+}
+
+/// Why running a program to specialise it stopped before the end.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    /// It would read past the known input here
+    Input(Span),
+    /// It ran out of steps here
+    Steps(Span),
+    /// It got to something only the compiled program can do, or that can't
+    /// be carried over from the interpreter, such as procedures
+    Unsupported(Span),
+    /// It fails here, as it will when compiled
+    Failed(Span),
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::Input(span) => write!(f, "it reads past the known input at {span}"),
+            Reason::Steps(span) => write!(f, "it ran out of steps at {span}"),
+            Reason::Unsupported(span) => write!(f, "it can't be run ahead of time from {span}"),
+            Reason::Failed(span) => write!(f, "it fails at {span}"),
+        }
+    }
+}
+
+/// What reading past known input fails with, when the input may go on.
+#[derive(Debug)]
+struct UnknownInput;
+
+impl fmt::Display for UnknownInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "input not known ahead of time")
+    }
+}
+
+impl std::error::Error for UnknownInput {}
+
+/// Known input, read a byte at a time.
+struct Known<'a> {
+    input: &'a [u8],
+    read: usize,
+    /// Whether the input ends where it runs out, rather than going on
+    ends: bool,
+}
+
+impl io::Read for Known<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.input.get(self.read) {
+            Some(&byte) if !buf.is_empty() => {
+                buf[0] = byte;
+                self.read += 1;
+                Ok(1)
+            }
+            Some(_) => Ok(0),
+            None if self.ends => Ok(0),
+            None => Err(io::Error::other(UnknownInput)),
+        }
+    }
+}
+
+/// Whether running `token` ahead of time would leave something behind that
+/// the program rebuilt from the tape can't have, such as a procedure or the
+/// other tape, or would do something only the compiled program should.
+fn unsupported(token: Token) -> bool {
+    matches!(
+        token,
+        Token::ProcStart(_)
+            | Token::Call
+            | Token::Extern(_)
+            | Token::Debug
+            | Token::SwitchTape
+            | Token::ExchangeCell
+    )
+}
+
+/// Runs `tokens` on `input` for at most `max_steps` steps. `,` reads `input`
+/// and then, if `ends` is set, the end of input; otherwise the run stops
+/// where it would read more. Returns a program that writes what the run
+/// wrote and sets up the tape as it left it, then carries on from where it
+/// stopped, reading whatever input follows `input`. If it stopped before
+/// reading all of `input`, other than by failing, the rest of `input` would
+/// be lost, so there's no such program and only how far it got is given.
+pub fn specialize(
+    tokens: &[(Token, Span)],
+    config: &Config,
+    input: &[u8],
+    ends: bool,
+    max_steps: u64,
+) -> Result<(Tokens, Summary), Summary> {
+    let mut machine = Machine::new(config);
+    let mut known = Known {
+        input,
+        read: 0,
+        ends,
+    };
+    let mut output = vec![];
+    let mut program = Program::new(tokens);
+    let mut steps = 0;
+    // Only a program with something that can't be run ahead of time needs
+    // to be run an instruction at a time, to stop just before it
+    let careful = tokens.iter().any(|&(token, _)| unsupported(token));
+
+    let stopped = loop {
+        let Some((token, span)) = program.next() else {
+            break None;
+        };
+        if careful && unsupported(token) {
+            break Some(Reason::Unsupported(span));
+        }
+        if steps == max_steps {
+            break Some(Reason::Steps(span));
+        }
+        let slice = if careful { 1 } else { max_steps - steps };
+        match machine.resume(&mut program, slice, &mut known, &mut output) {
+            Ok(left) => steps += slice - left,
+            Err(Error::Io(e)) if e.get_ref().is_some_and(|e| e.is::<UnknownInput>()) => {
+                break Some(Reason::Input(program.next().unwrap().1));
+            }
+            Err(_) => break Some(Reason::Failed(program.next().unwrap().1)),
+        }
+    };
+
+    let summary = Summary {
+        read: known.read,
+        written: output.len(),
+        stopped,
+    };
+    if known.read < input.len() && !matches!(stopped, None | Some(Reason::Failed(_))) {
+        return Err(summary);
+    }
+    if program.pc() == 0 {
+        return Ok((tokens.to_vec(), summary));
+    }
+
+    let span = tokens[0].1;
+    let mut specialized = vec![];
+    let bits = config.cell_bits;
+    // Output goes through the first cell, which is then set like the rest
+    let mut first = 0;
+    for &byte in &output {
+        change(&mut specialized, first, byte.into(), bits, span);
+        specialized.push((Token::PutChar, span));
+        first = byte.into();
+    }
+    change(&mut specialized, first, machine.cell(0), bits, span);
+    let mut ptr = 0;
+    for cell in 1..machine.len() {
+        let value = machine.cell(cell);
+        if value != 0 {
+            shift(&mut specialized, ptr, cell, span);
+            change(&mut specialized, 0, value, bits, span);
+            ptr = cell;
+        }
+    }
+    shift(&mut specialized, ptr, machine.ptr(), span);
+
+    specialized.extend(rest(tokens, program.pc()));
+    Ok((specialized, summary))
+}
+
+/// The tokens that carry on running `tokens` from `pc`. Inside loops, that's
+/// the rest of the body and then the whole loop again, for each loop out to
+/// the top level. Scans that are copied are numbered afresh, so that they
+/// stay unique.
+fn rest(tokens: &[(Token, Span)], pc: usize) -> Tokens {
+    let mut open = vec![];
+    for (i, &(token, _)) in tokens[..pc].iter().enumerate() {
+        match token {
+            Token::LoopStart(_) => open.push(i),
+            Token::LoopEnd(_) => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+
+    let mut copied = vec![];
+    let mut from = pc;
+    for &start in open.iter().rev() {
+        let Token::LoopStart(distance) = tokens[start].0 else {
+            unreachable!("only loops are open");
+        };
+        let end = start + distance as usize;
+        copied.extend_from_slice(&tokens[from..end]);
+        copied.extend_from_slice(&tokens[start..=end]);
+        from = end + 1;
+    }
+
+    let mut next_id = superinstructions::next_scan_id(tokens);
+    for (token, _) in &mut copied {
+        if let Token::ScanRight { id, .. } | Token::ScanLeft { id, .. } = token {
+            *id = next_id;
+            next_id += 1;
+        }
+    }
+    copied.extend_from_slice(&tokens[from..]);
+    copied
+}
+
+/// Pushes the instructions that change a cell holding `from` to hold `to`,
+/// taking whichever way round is shorter.
+fn change(tokens: &mut Tokens, from: u64, to: u64, bits: u32, span: Span) {
+    let mask = match bits {
+        64 => u64::MAX,
+        bits => (1 << bits) - 1,
+    };
+    let up = to.wrapping_sub(from) & mask;
+    let down = from.wrapping_sub(to) & mask;
+    let (mut amount, token): (u64, fn(u32) -> Token) = match up <= down {
+        true => (up, Token::Add),
+        false => (down, Token::Sub),
+    };
+    while amount > 0 {
+        let n = amount.min(u32::MAX.into());
+        tokens.push((token(n as u32), span));
+        amount -= n;
+    }
+}
+
+/// Pushes the instruction that moves the pointer from `from` to `to`.
+fn shift(tokens: &mut Tokens, from: usize, to: usize, span: Span) {
+    let token = match to.cmp(&from) {
+        std::cmp::Ordering::Equal => return,
+        std::cmp::Ordering::Greater => Token::PtrAdd((to - from) as u32),
+        std::cmp::Ordering::Less => Token::PtrSub((from - to) as u32),
+    };
+    tokens.push((token, span));
+}
@@ -0,0 +1,112 @@
+//! A summary of what compiling a program did, for --stats: how many of each
+//! kind of instruction there were before and after optimisation, how deeply
+//! loops nest, how much of the tape the program can reach, and how big the
+//! assembly and the binary came out.
+use crate::{
+    analysis::{self, Bounds},
+    lex::{Span, Token},
+};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Instructions of each kind as written, by [`Token::kind`]
+    pub before: BTreeMap<&'static str, usize>,
+    /// Instructions of each kind that code was generated for
+    pub after: BTreeMap<&'static str, usize>,
+    /// Deepest nesting of loops and procedures
+    pub depth: usize,
+    /// The cells the pointer stays within, as far as can be worked out, or
+    /// `None` before any program has been added
+    pub tape: Option<Bounds>,
+    pub asm_lines: Option<usize>,
+    /// Size of the binary or object in bytes
+    pub size: Option<u64>,
+}
+
+impl Stats {
+    /// Adds a program's instructions as lexed, before optimisation.
+    pub fn source(&mut self, tokens: &[(Token, Span)], tape_size: usize) {
+        let mut depth = 0;
+        for &(token, _) in tokens {
+            *self.before.entry(token.kind()).or_default() += 1;
+            match token {
+                Token::LoopStart(_) | Token::ProcStart(_) => {
+                    depth += 1;
+                    self.depth = self.depth.max(depth);
+                }
+                Token::LoopEnd(_) | Token::ProcEnd(_) => depth -= 1,
+                _ => {}
+            }
+        }
+
+        let bounds = analysis::tape_bounds(tokens, tape_size);
+        self.tape = Some(match (self.tape, bounds) {
+            (None, bounds) => bounds,
+            (Some(Bounds::Within { lo, hi }), Bounds::Within { lo: lo2, hi: hi2 }) => {
+                Bounds::Within {
+                    lo: lo.min(lo2),
+                    hi: hi.max(hi2),
+                }
+            }
+            (Some(escape @ Bounds::MayEscape(_)), _) | (_, escape) => escape,
+        });
+    }
+
+    /// Adds the instructions code was generated for.
+    pub fn compiled(&mut self, tokens: &[(Token, Span)]) {
+        for &(token, _) in tokens {
+            *self.after.entry(token.kind()).or_default() += 1;
+        }
+    }
+
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{:<16} {:>10} {:>10}",
+            "instructions", "before", "after"
+        )];
+        let kinds: Vec<&str> = self
+            .before
+            .keys()
+            .chain(
+                self.after
+                    .keys()
+                    .filter(|kind| !self.before.contains_key(*kind)),
+            )
+            .copied()
+            .collect();
+        let count = |counts: &BTreeMap<&str, usize>, kind| counts.get(kind).copied().unwrap_or(0);
+        for kind in kinds {
+            lines.push(format!(
+                "  {kind:<14} {:>10} {:>10}",
+                count(&self.before, kind),
+                count(&self.after, kind)
+            ));
+        }
+        lines.push(format!(
+            "  {:<14} {:>10} {:>10}",
+            "total",
+            self.before.values().sum::<usize>(),
+            self.after.values().sum::<usize>()
+        ));
+
+        lines.push(format!("{:<16} {:>10}", "loop depth", self.depth));
+        match self.tape {
+            Some(Bounds::Within { lo, hi }) => {
+                lines.push(format!("{:<16} cells {lo} to {hi}", "tape extent"));
+            }
+            Some(Bounds::MayEscape(span)) => lines.push(format!(
+                "{:<16} unknown, the pointer moves by a varying amount at {span}",
+                "tape extent"
+            )),
+            None => {}
+        }
+        if let Some(asm_lines) = self.asm_lines {
+            lines.push(format!("{:<16} {asm_lines:>10}", "asm lines"));
+        }
+        if let Some(size) = self.size {
+            lines.push(format!("{:<16} {size:>10} bytes", "size"));
+        }
+        lines
+    }
+}
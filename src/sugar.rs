@@ -0,0 +1,119 @@
+use crate::{diagnostics::Diagnostic, lex::Span};
+
+fn error(message: impl Into<String>, span: Span) -> Diagnostic {
+    Diagnostic::error("E0013", message).with_span(span)
+}
+
+/// The shortest brainfuck that adds `delta` to the current cell, either
+/// directly or with a multiplication loop that uses the cell to the right as
+/// its counter. That cell must be zero and is left zero.
+fn adjust(delta: i32) -> String {
+    let op = |n: i32| if n < 0 { "-" } else { "+" }.repeat(n.unsigned_abs() as usize);
+
+    let target = delta.abs();
+    let mut best = op(delta);
+    for a in 1..=target {
+        for b in 1..=target / a + 1 {
+            let rest = target - a * b;
+            if (a + b + rest.abs() + 7) as usize >= best.len() {
+                continue;
+            }
+            let sign = delta.signum();
+            best = format!(">{}[<{}>-]<{}", op(a), op(sign * b), op(sign * rest));
+        }
+    }
+    best
+}
+
+fn emit(output: &mut Vec<(char, Span)>, bf: &str, span: Span) {
+    output.extend(bf.chars().map(|c| (c, span)));
+}
+
+/// Reads the escape sequence after the `\` at `i`, returning the byte it
+/// stands for and the index after it.
+fn escape(source: &[(char, Span)], i: usize) -> Result<(u8, usize), Diagnostic> {
+    let Some(&(c, span)) = source.get(i + 1) else {
+        return Err(error("unterminated string literal", source[i].1));
+    };
+    let byte = match c {
+        'n' => b'\n',
+        't' => b'\t',
+        'r' => b'\r',
+        '0' => 0,
+        '\\' => b'\\',
+        '"' => b'"',
+        'x' => {
+            let digits: String = source[i + 2..].iter().take(2).map(|(c, _)| c).collect();
+            let byte = u8::from_str_radix(&digits, 16)
+                .ok()
+                .filter(|_| digits.len() == 2)
+                .ok_or_else(|| error(format!("invalid escape `\\x{digits}`"), span))?;
+            return Ok((byte, i + 4));
+        }
+        _ => return Err(error(format!("unknown escape `\\{c}`"), span)),
+    };
+    Ok((byte, i + 2))
+}
+
+/// Expands `"text"` into brainfuck that prints the text using the current
+/// cell, which must start and is left zero, and `{n}` into brainfuck that sets
+/// the current cell to `n`. Both use the cell to the right as scratch space,
+/// which must be zero. The expansion takes the position of the literal it came
+/// from.
+pub fn desugar(source: &[(char, Span)]) -> Result<Vec<(char, Span)>, Diagnostic> {
+    let mut output = vec![];
+
+    let mut i = 0;
+    while i < source.len() {
+        let (c, span) = source[i];
+        match c {
+            '"' => {
+                let mut bytes = vec![];
+                i += 1;
+                loop {
+                    match source.get(i) {
+                        None => return Err(error("unterminated string literal", span)),
+                        Some(('"', _)) => break,
+                        Some(('\\', _)) => {
+                            let (byte, next) = escape(source, i)?;
+                            bytes.push(byte);
+                            i = next;
+                        }
+                        Some((c, _)) => {
+                            bytes.extend(c.encode_utf8(&mut [0; 4]).bytes());
+                            i += 1;
+                        }
+                    }
+                }
+
+                let mut current = 0;
+                for byte in bytes {
+                    emit(&mut output, &adjust(byte as i32 - current), span);
+                    emit(&mut output, ".", span);
+                    current = byte as i32;
+                }
+                emit(&mut output, "[-]", span);
+            }
+            '{' => {
+                let Some(len) = source[i..].iter().position(|(c, _)| *c == '}') else {
+                    return Err(error("unterminated number literal", span));
+                };
+                let digits: String = source[i + 1..i + len].iter().map(|(c, _)| c).collect();
+                let n: u8 = digits.trim().parse().map_err(|_| {
+                    error(
+                        format!("`{digits}` is not a number between 0 and 255"),
+                        span,
+                    )
+                })?;
+
+                emit(&mut output, "[-]", span);
+                emit(&mut output, &adjust(n as i32), span);
+                i += len;
+            }
+            _ => output.push((c, span)),
+        }
+        i += 1;
+    }
+
+    Ok(output)
+}
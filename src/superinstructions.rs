@@ -0,0 +1,270 @@
+use crate::lex::{self, Span, Token, Tokens};
+use std::collections::BTreeMap;
+
+/// The pointer move covering `offset` cells, if any.
+fn shift(offset: i16) -> Option<Token> {
+    match offset {
+        0 => None,
+        n if n < 0 => Some(Token::PtrSub(n.unsigned_abs().into())),
+        n => Some(Token::PtrAdd(n as u32)),
+    }
+}
+
+/// The cell change adding `amount`, if any.
+fn change(amount: i16) -> Option<Token> {
+    match amount {
+        0 => None,
+        n if n < 0 => Some(Token::Sub(n.unsigned_abs().into())),
+        n => Some(Token::Add(n as u32)),
+    }
+}
+
+/// A shape of code that can be combined into superinstructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    /// Loops that count their cell down to zero, as `SetZero`
+    Clear,
+    /// Loops that move the pointer until it finds a zero cell
+    Scan,
+    /// Loops that add multiples of their cell to others, as `MulAdd`s
+    Mul,
+    /// Moves to a cell, a change to it and a move back, as `AddAt`
+    AddAt,
+}
+
+/// The lowest number that no scan in `tokens` has, and that the scans after
+/// it can count up from.
+pub fn next_scan_id(tokens: &[(Token, Span)]) -> u32 {
+    tokens
+        .iter()
+        .filter_map(|(token, _)| match *token {
+            Token::ScanRight { id, .. } | Token::ScanLeft { id, .. } => Some(id + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns the superinstructions that do the same as the loop with `body`,
+/// if there are any of `shapes`. A scan is numbered `id`.
+fn combine_loop(body: &[(Token, Span)], id: u32, shapes: &[Shape]) -> Option<Vec<Token>> {
+    match *body {
+        [(Token::Sub(1) | Token::Add(1), _)] if shapes.contains(&Shape::Clear) => {
+            return Some(vec![Token::SetZero])
+        }
+        [(Token::PtrAdd(stride), _)] if shapes.contains(&Shape::Scan) => {
+            let stride = stride.try_into().ok()?;
+            return Some(vec![Token::ScanRight { stride, id }]);
+        }
+        [(Token::PtrSub(stride), _)] if shapes.contains(&Shape::Scan) => {
+            let stride = stride.try_into().ok()?;
+            return Some(vec![Token::ScanLeft { stride, id }]);
+        }
+        _ if !shapes.contains(&Shape::Mul) => return None,
+        _ => {}
+    }
+
+    // A multiplication loop, which only moves and changes cells, ends up where
+    // it started and counts its own cell down by one
+    let mut offset = 0i64;
+    let mut factors = BTreeMap::new();
+    for (token, _) in body {
+        match *token {
+            Token::PtrAdd(n) => offset += i64::from(n),
+            Token::PtrSub(n) => offset -= i64::from(n),
+            Token::Add(n) => *factors.entry(offset).or_insert(0) += i64::from(n),
+            Token::Sub(n) => *factors.entry(offset).or_insert(0) -= i64::from(n),
+            _ => return None,
+        }
+    }
+    if offset != 0 || factors.remove(&0) != Some(-1) {
+        return None;
+    }
+
+    let mut tokens = factors
+        .into_iter()
+        .filter(|&(_, factor)| factor != 0)
+        .map(|(offset, factor)| {
+            Some(Token::MulAdd {
+                offset: offset.try_into().ok()?,
+                factor: factor.try_into().ok()?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    tokens.push(Token::SetZero);
+    Some(tokens)
+}
+
+/// Replaces code of `shapes` with superinstructions. Each one takes the
+/// position of the code it replaces. Nothing combines into more tokens than it
+/// replaces, so this works in place, writing the output over the tokens
+/// already read.
+pub fn combine(mut tokens: Tokens, shapes: &[Shape]) -> Tokens {
+    let mut len = 0;
+    let mut next_id = next_scan_id(&tokens);
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let (token, span) = tokens[i];
+        match (token, tokens.get(i + 1), tokens.get(i + 2)) {
+            (Token::LoopStart(distance), _, _) => {
+                let end = i + distance as usize;
+                if let Some(combined) = combine_loop(&tokens[i + 1..end], next_id, shapes) {
+                    next_id += 1;
+                    for token in combined {
+                        tokens[len] = (token, span);
+                        len += 1;
+                    }
+                    i = end + 1;
+                    continue;
+                }
+            }
+            (
+                Token::PtrAdd(there) | Token::PtrSub(there),
+                Some((Token::Add(n) | Token::Sub(n), _)),
+                Some((Token::PtrAdd(back) | Token::PtrSub(back), _)),
+            ) if shapes.contains(&Shape::AddAt)
+                && there == *back
+                && token.kind() != tokens[i + 2].0.kind()
+                && i16::try_from(there).is_ok()
+                && i16::try_from(*n).is_ok() =>
+            {
+                let offset = match token {
+                    Token::PtrAdd(_) => there as i16,
+                    _ => -(there as i16),
+                };
+                let amount = match tokens[i + 1].0 {
+                    Token::Add(_) => *n as i16,
+                    _ => -(*n as i16),
+                };
+                tokens[len] = (Token::AddAt { offset, amount }, span);
+                len += 1;
+                i += 3;
+                continue;
+            }
+            _ => {}
+        }
+        tokens[len] = (token, span);
+        len += 1;
+        i += 1;
+    }
+
+    tokens.truncate(len);
+    lex::link_loops(&mut tokens);
+    tokens
+}
+
+/// Rewrites every superinstruction that `supported` rejects into the base
+/// instructions it came from, so that profiles only need templates for the
+/// superinstructions they can do better.
+pub fn expand(tokens: Tokens, supported: impl Fn(Token) -> bool) -> Tokens {
+    if tokens
+        .iter()
+        .all(|&(token, _)| supported(token) || !token.is_superinstruction())
+    {
+        return tokens;
+    }
+
+    let mut output = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+    while i < tokens.len() {
+        let (token, span) = tokens[i];
+        if supported(token) || !token.is_superinstruction() {
+            output.push((token, span));
+            i += 1;
+            continue;
+        }
+
+        let base = match token {
+            Token::MulAdd { .. } => {
+                // Rebuild the whole loop the run came from, which cleared its
+                // cell. A run that isn't closed by a `SetZero` can't have come
+                // from one, and is left alone.
+                let zero = i + tokens[i..]
+                    .iter()
+                    .take_while(|(t, _)| matches!(t, Token::MulAdd { .. }))
+                    .count();
+                if tokens.get(zero).map(|&(t, _)| t) != Some(Token::SetZero) {
+                    output.extend_from_slice(&tokens[i..zero]);
+                    i = zero;
+                    continue;
+                }
+                let mut base = vec![Token::LoopStart(0), Token::Sub(1)];
+                for (token, _) in &tokens[i..zero] {
+                    let Token::MulAdd { offset, factor } = *token else {
+                        unreachable!()
+                    };
+                    base.extend(shift(offset));
+                    base.extend(change(factor));
+                    base.extend(shift(-offset));
+                }
+                base.push(Token::LoopEnd(0));
+                i = zero;
+                base
+            }
+            Token::SetZero => vec![Token::LoopStart(0), Token::Sub(1), Token::LoopEnd(0)],
+            Token::ScanRight { stride, .. } => {
+                vec![
+                    Token::LoopStart(0),
+                    Token::PtrAdd(stride.into()),
+                    Token::LoopEnd(0),
+                ]
+            }
+            Token::ScanLeft { stride, .. } => {
+                vec![
+                    Token::LoopStart(0),
+                    Token::PtrSub(stride.into()),
+                    Token::LoopEnd(0),
+                ]
+            }
+            Token::AddAt { offset, amount } => shift(offset)
+                .into_iter()
+                .chain(change(amount))
+                .chain(shift(-offset))
+                .collect(),
+            _ => unreachable!(),
+        };
+        output.extend(base.into_iter().map(|t| (t, span)));
+        i += 1;
+    }
+
+    lex::link_loops(&mut output);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spanned(tokens: &[Token]) -> Tokens {
+        let span = Span {
+            line: 1,
+            col: 1,
+            file: None,
+        };
+        tokens.iter().map(|&token| (token, span)).collect()
+    }
+
+    #[test]
+    fn expands_mul_runs_into_their_loop() {
+        let mul = Token::MulAdd {
+            offset: 1,
+            factor: 2,
+        };
+        let tokens = expand(spanned(&[mul, Token::SetZero]), |_| false);
+        let bf: String = tokens.iter().map(|(token, _)| token.to_bf()).collect();
+        assert_eq!(bf, "[->++<]");
+    }
+
+    #[test]
+    fn leaves_mul_runs_without_set_zero() {
+        let mul = Token::MulAdd {
+            offset: 1,
+            factor: 2,
+        };
+        let tokens = spanned(&[mul, Token::PutChar, Token::SetZero]);
+        let expanded = expand(tokens, |_| false);
+        assert_eq!(expanded[0].0, mul);
+        assert_eq!(expanded[1].0, Token::PutChar);
+    }
+}
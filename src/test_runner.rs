@@ -0,0 +1,91 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+pub struct Case {
+    pub name: String,
+    pub program: PathBuf,
+    pub input: Vec<u8>,
+    pub expected: Option<Vec<u8>>,
+}
+
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped(String),
+}
+
+/// Finds every `.bf`, `.ook`, `.pb` or `.bfir` program in `dir` along with its `.in` and `.expected`
+/// files, sorted by name.
+pub fn discover(dir: &Path) -> Result<Vec<Case>, io::Error> {
+    let mut cases = vec![];
+    for entry in fs::read_dir(dir)?.flatten() {
+        let program = entry.path();
+        if !matches!(
+            program.extension().and_then(|ext| ext.to_str()),
+            Some("bf" | "ook" | "pb" | "bfir")
+        ) {
+            continue;
+        }
+
+        cases.push(Case {
+            name: program.file_stem().unwrap().to_string_lossy().to_string(),
+            input: fs::read(program.with_extension("in")).unwrap_or_default(),
+            expected: fs::read(program.with_extension("expected")).ok(),
+            program,
+        });
+    }
+
+    cases.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(cases)
+}
+
+/// Compares output line by line, describing the first difference.
+pub fn compare(expected: &[u8], actual: &[u8]) -> Outcome {
+    if expected == actual {
+        return Outcome::Passed;
+    }
+
+    let expected = String::from_utf8_lossy(expected);
+    let actual = String::from_utf8_lossy(actual);
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    for line in 1.. {
+        match (expected_lines.next(), actual_lines.next()) {
+            (Some(e), Some(a)) if e == a => continue,
+            (None, None) => break,
+            (e, a) => {
+                return Outcome::Failed(format!(
+                    "line {line} differs\n    expected: {:?}\n    actual:   {:?}",
+                    e.unwrap_or("<end of output>"),
+                    a.unwrap_or("<end of output>")
+                ))
+            }
+        }
+    }
+    Outcome::Failed("output differs in line endings".to_string())
+}
+
+/// Prints the result of a single test.
+pub fn report(name: &str, outcome: &Outcome) {
+    match outcome {
+        Outcome::Passed => println!("test {name} ... ok"),
+        Outcome::Failed(reason) => println!("test {name} ... FAILED\n    {reason}"),
+        Outcome::Skipped(reason) => println!("test {name} ... skipped ({reason})"),
+    }
+}
+
+/// Prints the totals, returning whether every test that ran passed.
+pub fn summary(outcomes: &[Outcome]) -> bool {
+    let count = |f: fn(&Outcome) -> bool| outcomes.iter().filter(|o| f(o)).count();
+    let passed = count(|o| matches!(o, Outcome::Passed));
+    let failed = count(|o| matches!(o, Outcome::Failed(_)));
+    let skipped = count(|o| matches!(o, Outcome::Skipped(_)));
+
+    println!(
+        "\ntest result: {}. {passed} passed; {failed} failed; {skipped} skipped",
+        if failed == 0 { "ok" } else { "FAILED" }
+    );
+    failed == 0
+}
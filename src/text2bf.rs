@@ -0,0 +1,102 @@
+/// The most cells a generated program sets up before printing.
+const MAX_CELLS: usize = 8;
+
+/// Splits `bytes` into `k` groups of nearby values, returning the middle of
+/// each group.
+fn centers(bytes: &[u8], k: usize) -> Vec<u8> {
+    let mut sorted = bytes.to_vec();
+    sorted.sort_unstable();
+    let mut centers: Vec<f64> = (0..k)
+        .map(|i| sorted[(2 * i + 1) * sorted.len() / (2 * k)] as f64)
+        .collect();
+
+    for _ in 0..16 {
+        let mut sums = vec![(0.0, 0); k];
+        for &byte in &sorted {
+            let nearest = (0..k)
+                .min_by(|&a, &b| {
+                    let da = (centers[a] - byte as f64).abs();
+                    let db = (centers[b] - byte as f64).abs();
+                    da.total_cmp(&db)
+                })
+                .unwrap();
+            sums[nearest].0 += byte as f64;
+            sums[nearest].1 += 1;
+        }
+        for (center, (sum, count)) in centers.iter_mut().zip(sums) {
+            if count > 0 {
+                *center = sum / count as f64;
+            }
+        }
+    }
+
+    centers.into_iter().map(|c| c.round() as u8).collect()
+}
+
+/// Prints `bytes` by walking between `cells`, each time picking the cell
+/// that's cheapest to reach and adjust, and leaving it holding that byte.
+fn print(bytes: &[u8], cells: &mut [i32], mut pos: usize, program: &mut String) {
+    for &byte in bytes {
+        let target = byte as i32;
+        let cost = |i: usize| i.abs_diff(pos) + cells[i].abs_diff(target) as usize;
+        let next = (0..cells.len()).min_by_key(|&i| cost(i)).unwrap();
+
+        let (step, moves) = if next > pos {
+            ('>', next - pos)
+        } else {
+            ('<', pos - next)
+        };
+        program.extend(std::iter::repeat_n(step, moves));
+        let delta = target - cells[next];
+        let op = if delta > 0 { '+' } else { '-' };
+        program.extend(std::iter::repeat_n(op, delta.unsigned_abs() as usize));
+        program.push('.');
+
+        cells[next] = target;
+        pos = next;
+    }
+}
+
+/// Sets up cells near each of `centers` with a multiplication loop counting
+/// down from `counter` in cell 0, then prints `bytes` from them.
+fn with_cells(bytes: &[u8], centers: &[u8], counter: i32) -> String {
+    let factors: Vec<i32> = centers
+        .iter()
+        .map(|&c| (c as i32 + counter / 2) / counter)
+        .collect();
+
+    let mut program = "+".repeat(counter as usize);
+    program.push('[');
+    for &factor in &factors {
+        program.push('>');
+        program.push_str(&"+".repeat(factor as usize));
+    }
+    program.push_str(&"<".repeat(factors.len()));
+    program.push_str("-]>");
+
+    // Cell 0 is the spent counter, which is zero and still usable
+    let mut cells: Vec<i32> = std::iter::once(0)
+        .chain(factors.iter().map(|f| f * counter))
+        .collect();
+    print(bytes, &mut cells, 1, &mut program);
+    program
+}
+
+/// Generates a brainfuck program that prints `bytes`, trying a range of cell
+/// layouts and keeping the shortest result.
+pub fn generate(bytes: &[u8]) -> String {
+    let mut best = String::new();
+    print(bytes, &mut [0], 0, &mut best);
+
+    for k in 1..=MAX_CELLS.min(bytes.len()) {
+        let centers = centers(bytes, k);
+        for counter in 2..=16 {
+            let program = with_cells(bytes, &centers, counter);
+            if program.len() < best.len() {
+                best = program;
+            }
+        }
+    }
+
+    best
+}
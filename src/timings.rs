@@ -0,0 +1,45 @@
+use std::time::{Duration, Instant};
+
+/// Accumulated wall-clock time spent in each compilation phase, in the order
+/// the phases first ran.
+#[derive(Debug, Default)]
+pub struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    pub fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        // There's no clock on wasm32-unknown-unknown, so phases go untimed
+        if !cfg!(feature = "native") {
+            return f();
+        }
+        let start = Instant::now();
+        let result = f();
+        self.record(phase, start.elapsed());
+        result
+    }
+
+    pub fn record(&mut self, phase: &'static str, duration: Duration) {
+        let duration_ms = duration.as_secs_f64() * 1000.0;
+        log::info!(phase, duration_ms; "Finished {phase} in {duration_ms:.3}ms");
+        if let Some((_, total)) = self.phases.iter_mut().find(|(p, _)| *p == phase) {
+            *total += duration;
+        } else {
+            self.phases.push((phase, duration));
+        }
+    }
+
+    /// Each phase with the time spent in it, in the order they first ran.
+    pub fn phases(&self) -> &[(&'static str, Duration)] {
+        &self.phases
+    }
+
+    pub fn report(&self) -> Vec<String> {
+        let total: Duration = self.phases.iter().map(|(_, d)| *d).sum();
+        self.phases
+            .iter()
+            .chain([("total", total)].iter())
+            .map(|(phase, d)| format!("{phase:<12} {:>10.3}ms", d.as_secs_f64() * 1000.0))
+            .collect()
+    }
+}
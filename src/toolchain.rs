@@ -0,0 +1,344 @@
+use crate::{cache, diagnostics::Diagnostic, digest, timings::Timings};
+use once_cell::sync::Lazy;
+use platform_dirs::AppDirs;
+use serde_json::json;
+use std::{
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+    process::{self, Command, ExitStatus},
+};
+use tempfile::TempDir;
+
+static CACHE_PATH: Lazy<PathBuf> = Lazy::new(|| AppDirs::new(Some("bfc"), true).unwrap().cache_dir);
+
+#[derive(Debug)]
+pub enum ToolchainError {
+    Io(io::Error),
+    Spawn {
+        program: String,
+        source: io::Error,
+    },
+    Failed {
+        program: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+}
+
+impl fmt::Display for ToolchainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ToolchainError::Io(e) => write!(f, "{e}"),
+            ToolchainError::Spawn { program, source } => {
+                write!(f, "could not run {program}: {source}")
+            }
+            ToolchainError::Failed {
+                program,
+                status,
+                stderr,
+            } => {
+                write!(f, "{program} failed ({status})")?;
+                if !stderr.is_empty() {
+                    write!(f, ":\n{stderr}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl error::Error for ToolchainError {}
+
+impl From<ToolchainError> for Diagnostic {
+    fn from(e: ToolchainError) -> Self {
+        Diagnostic::error("E0004", e.to_string())
+    }
+}
+
+impl From<io::Error> for ToolchainError {
+    fn from(e: io::Error) -> Self {
+        ToolchainError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Toolchain {
+    pub nasm: String,
+    /// Arguments that make the assembler print its version
+    pub nasm_version_args: Vec<String>,
+    pub nasm_args: Vec<String>,
+    pub linker: String,
+    pub linker_args: Vec<String>,
+    pub reproducible_linker_args: Vec<String>,
+    pub strip_linker_args: Vec<String>,
+    pub small_linker_args: Vec<String>,
+    pub debug_nasm_args: Vec<String>,
+    /// Assembler arguments that make the code smaller, for -Os
+    pub size_nasm_args: Vec<String>,
+    /// Objects and libraries linked in with the program, such as the
+    /// functions its external calls go to
+    pub objects: Vec<String>,
+    pub save_temps: bool,
+    pub reproducible: bool,
+    pub debug: bool,
+    pub strip: bool,
+    pub small: bool,
+    /// Where the assembler writes a listing of the code each line of
+    /// assembly became, if anywhere
+    pub listing: Option<PathBuf>,
+    /// Whether to reuse objects and binaries made from the same input
+    /// before, rather than running the assembler and linker again
+    pub cache: bool,
+}
+
+impl Toolchain {
+    pub fn cache_path() -> &'static Path {
+        CACHE_PATH.as_path()
+    }
+
+    pub fn nasm_version(&self) -> Result<String, io::Error> {
+        Self::version(&self.nasm, &self.nasm_version_args)
+    }
+
+    pub fn linker_version(&self) -> Result<String, io::Error> {
+        Self::version(&self.linker, &["--version".to_string()])
+    }
+
+    fn version(program: &str, args: &[String]) -> Result<String, io::Error> {
+        let output = Command::new(program)
+            .args(args)
+            .output()
+            .map_err(|e| io::Error::new(e.kind(), format!("could not run {program}: {e}")))?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "{program} {} exited with {}",
+                args.join(" "),
+                output.status
+            )));
+        }
+
+        let text = if output.stdout.is_empty() {
+            output.stderr
+        } else {
+            output.stdout
+        };
+        Ok(String::from_utf8_lossy(&text)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    /// Assembles the program that `write_asm` writes into an object in
+    /// `temp_dir` named after `outfile`, giving the paths of the assembly and
+    /// the object, and the object's cache key if it's cached.
+    fn assemble(
+        &self,
+        write_asm: impl FnOnce(&Path, &mut Timings) -> Result<(), io::Error>,
+        outfile: &Path,
+        temp_dir: &TempDir,
+        timings: &mut Timings,
+    ) -> Result<(PathBuf, PathBuf, Option<String>), ToolchainError> {
+        let stem = if self.reproducible {
+            "bfc".as_ref()
+        } else {
+            outfile.file_stem().unwrap_or("out".as_ref())
+        };
+
+        let asm_path = temp_dir.path().join(stem).with_extension("s");
+        write_asm(&asm_path, timings)?;
+
+        let obj_path = temp_dir.path().join(stem).with_extension("o");
+
+        // The listing only comes from running the assembler, and an
+        // assembler that can't say its version may have changed since
+        let version = match self.cache && self.listing.is_none() {
+            true => self.nasm_version().ok(),
+            false => None,
+        };
+        let key = match version {
+            Some(version) => Some(cache::key(&json!({
+                "assembler": self.nasm,
+                "version": version,
+                "args": self.nasm_args,
+                "debug_args": if self.debug { &self.debug_nasm_args[..] } else { &[] },
+                "name": stem.to_string_lossy(),
+                "asm": digest::sha256_hex(&fs::read(&asm_path)?),
+            }))),
+            None => None,
+        };
+        if let Some(cached) = key.as_deref().and_then(cache::get) {
+            log::info!("Reusing cached object {}", cached.display());
+            fs::copy(cached, &obj_path)?;
+            return Ok((asm_path, obj_path, key));
+        }
+
+        // Assemble from inside the temp dir so that the file names recorded
+        // in the object don't depend on where the temp dir ended up.
+        let mut cmd = Command::new(&self.nasm);
+        cmd.current_dir(temp_dir.path()).args(&self.nasm_args);
+        if self.debug {
+            cmd.args(&self.debug_nasm_args);
+        }
+        if let Some(listing) = &self.listing {
+            cmd.arg("-l").arg(listing);
+        }
+        cmd.args(["-o", obj_path.file_name().unwrap().to_str().unwrap()])
+            .arg(asm_path.file_name().unwrap().to_str().unwrap());
+        timings.time("assemble", || run(cmd))?;
+        if let Some(key) = &key {
+            store(key, &obj_path);
+        }
+
+        Ok((asm_path, obj_path, key))
+    }
+
+    /// Assembles the program that `write_asm` writes to the path it's given
+    /// into an object file, without linking it.
+    pub fn generate_obj(
+        &self,
+        write_asm: impl FnOnce(&Path, &mut Timings) -> Result<(), io::Error>,
+        outfile: &Path,
+        timings: &mut Timings,
+    ) -> Result<(), ToolchainError> {
+        let temp_dir = temp_dir()?;
+        let (asm_path, obj_path, _) = self.assemble(write_asm, outfile, &temp_dir, timings)?;
+        fs::copy(&obj_path, outfile)?;
+
+        if self.save_temps {
+            fs::copy(&asm_path, outfile.with_extension("s"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Assembles and links the program that `write_asm` writes to the path
+    /// it's given.
+    pub fn generate_bin(
+        &self,
+        write_asm: impl FnOnce(&Path, &mut Timings) -> Result<(), io::Error>,
+        outfile: &Path,
+        timings: &mut Timings,
+    ) -> Result<(), ToolchainError> {
+        let temp_dir = temp_dir()?;
+        let (asm_path, obj_path, obj_key) =
+            self.assemble(write_asm, outfile, &temp_dir, timings)?;
+
+        let mut args = self.linker_args.clone();
+        if self.reproducible {
+            args.extend(self.reproducible_linker_args.iter().cloned());
+        }
+        if self.strip || self.small {
+            args.extend(self.strip_linker_args.iter().cloned());
+        }
+        if self.small {
+            args.extend(self.small_linker_args.iter().cloned());
+        }
+
+        // Objects linked in are keyed by their contents, and one that can't
+        // be read leaves the linker to report it. As with the assembler, a
+        // linker that can't say its version isn't trusted to be the same one.
+        let objects: Option<Vec<_>> = self
+            .objects
+            .iter()
+            .map(|object| {
+                let contents = fs::read(object).ok()?;
+                Some(json!({ "path": object, "sha256": digest::sha256_hex(&contents) }))
+            })
+            .collect();
+        let version = obj_key.as_ref().and_then(|_| self.linker_version().ok());
+        let key = obj_key
+            .zip(objects)
+            .zip(version)
+            .map(|((object, objects), version)| {
+                cache::key(&json!({
+                    "linker": self.linker,
+                    "version": version,
+                    "args": args,
+                    "object": object,
+                    "objects": objects,
+                }))
+            });
+        if let Some(cached) = key.as_deref().and_then(cache::get) {
+            log::info!("Reusing cached binary {}", cached.display());
+            fs::copy(cached, outfile)?;
+        } else {
+            let mut cmd = Command::new(&self.linker);
+            cmd.args(&args)
+                .args(["-o", outfile.to_str().unwrap()])
+                .arg(obj_path.to_str().unwrap())
+                .args(&self.objects);
+            timings.time("link", || run(cmd))?;
+            if let Some(key) = &key {
+                store(key, outfile);
+            }
+        }
+
+        if self.save_temps {
+            fs::copy(&asm_path, outfile.with_extension("s"))?;
+            fs::copy(&obj_path, outfile.with_extension("o"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Archives `objects` into a static library at `outfile` with `archiver`,
+    /// replacing whatever was there.
+    pub fn archive(
+        archiver: &str,
+        objects: &[PathBuf],
+        outfile: &Path,
+    ) -> Result<(), ToolchainError> {
+        match fs::remove_file(outfile) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        let mut cmd = Command::new(archiver);
+        cmd.arg("crs").arg(outfile).args(objects);
+        run(cmd)
+    }
+}
+
+/// A uniquely named scratch directory in the cache dir, removed on drop.
+pub fn temp_dir() -> Result<TempDir, io::Error> {
+    fs::create_dir_all(CACHE_PATH.as_path())?;
+    tempfile::Builder::new()
+        // The process id lets `bfc cache clean` tell the dirs of builds that
+        // were killed
+        .prefix(&format!("bfc-{}-", process::id()))
+        .tempdir_in(CACHE_PATH.as_path())
+}
+
+/// Caches `file` as the artifact with `key`. Failing to only costs the next
+/// build the time to make it again, so it's logged rather than failing this
+/// one.
+fn store(key: &str, file: &Path) {
+    if let Err(e) = cache::put(key, file) {
+        log::warn!("Could not cache {}: {e}", file.display());
+    }
+}
+
+/// Runs `cmd` to completion, turning a failure to start or a non-zero exit
+/// into an error carrying the program's stderr.
+fn run(mut cmd: Command) -> Result<(), ToolchainError> {
+    let program = cmd.get_program().to_string_lossy().to_string();
+    log::debug!("Running {:?}", cmd);
+
+    let output = cmd.output().map_err(|source| ToolchainError::Spawn {
+        program: program.clone(),
+        source,
+    })?;
+
+    if !output.status.success() {
+        return Err(ToolchainError::Failed {
+            program,
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr)
+                .trim_end()
+                .to_string(),
+        });
+    }
+
+    Ok(())
+}
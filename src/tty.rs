@@ -0,0 +1,65 @@
+//! The raw terminal mode --raw-tty asks for when interpreting, where `,` gets
+//! each key as it's pressed rather than once Enter is, and keys aren't
+//! echoed. The profiles' `raw_tty` routines do the same in compiled programs.
+use std::io::{self, Read, Write};
+
+/// Keeps stdin's terminal out of canonical mode and without echo until it's
+/// dropped, when the settings it had are put back. Does nothing if stdin
+/// isn't a terminal.
+pub struct RawTty {
+    #[cfg(unix)]
+    saved: Option<libc::termios>,
+}
+
+impl RawTty {
+    #[cfg(unix)]
+    pub fn enable() -> Self {
+        let fd = libc::STDIN_FILENO;
+        let mut termios = std::mem::MaybeUninit::uninit();
+        // SAFETY: tcgetattr fills in the termios when it succeeds, and it's
+        // only read if it did
+        if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+            log::info!("Input isn't a terminal, leaving it as it is");
+            return Self { saved: None };
+        }
+        let saved = unsafe { termios.assume_init() };
+
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: `raw` is a valid termios, copied from the terminal's own
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            log::warn!("Could not put the terminal in raw mode");
+            return Self { saved: None };
+        }
+        Self { saved: Some(saved) }
+    }
+
+    #[cfg(not(unix))]
+    pub fn enable() -> Self {
+        log::warn!("Raw terminal input is only supported on Unix");
+        Self {}
+    }
+}
+
+impl Drop for RawTty {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(saved) = &self.saved {
+            // SAFETY: `saved` is the termios the terminal had before
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved) };
+        }
+    }
+}
+
+/// Stdin, flushing stdout before each read so that a prompt written without
+/// a newline shows before the program waits for a key.
+pub struct PromptedStdin;
+
+impl Read for PromptedStdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdout().flush()?;
+        io::stdin().read(buf)
+    }
+}
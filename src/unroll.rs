@@ -0,0 +1,254 @@
+use crate::{
+    budget::Budget,
+    lex::{self, Span, Token, Tokens},
+    pgo::{self, Counts, Heat},
+};
+use std::collections::HashMap;
+
+/// What is known about cell values at some point, keyed by offset from the
+/// pointer where tracking began. Values are tracked without wrapping, and a
+/// cell that leaves 0..=255 becomes unknown, so that the result holds for
+/// every cell size.
+#[derive(Debug, Default)]
+struct Known {
+    cells: HashMap<isize, Option<i64>>,
+    rest_zero: bool,
+    offset: isize,
+}
+
+impl Known {
+    fn value_at(&self, offset: isize) -> Option<i64> {
+        match self.cells.get(&(self.offset + offset)) {
+            Some(value) => *value,
+            None if self.rest_zero => Some(0),
+            None => None,
+        }
+    }
+
+    fn value(&self) -> Option<i64> {
+        self.value_at(0)
+    }
+
+    fn add_at(&mut self, offset: isize, delta: Option<i64>) {
+        let value = self
+            .value_at(offset)
+            .zip(delta)
+            .map(|(v, delta)| v + delta)
+            .filter(|v| (0..=255).contains(v));
+        self.cells.insert(self.offset + offset, value);
+    }
+
+    fn add(&mut self, delta: i64) {
+        self.add_at(0, Some(delta));
+    }
+
+    fn forget(&mut self) {
+        *self = Known::default();
+    }
+}
+
+/// If `body` has no nested loops, leaves the pointer where it found it and
+/// changes its own cell by a constant negative amount, returns that amount.
+fn decrement(body: &[(Token, Span)]) -> Option<i64> {
+    let mut offset = 0isize;
+    let mut delta = 0i64;
+    for (token, _) in body {
+        match *token {
+            Token::PtrAdd(n) => offset += n as isize,
+            Token::PtrSub(n) => offset -= n as isize,
+            Token::Add(n) if offset == 0 => delta += n as i64,
+            Token::Sub(n) if offset == 0 => delta -= n as i64,
+            Token::Add(_) | Token::Sub(_) | Token::PutChar => {}
+            Token::GetChar if offset != 0 => {}
+            _ => return None,
+        }
+    }
+    (offset == 0 && delta < 0).then_some(-delta)
+}
+
+/// Replaces loops whose iteration count is known with that many copies of
+/// their body, as long as the copies come to at most `limit` tokens. The loop
+/// must count its cell down to exactly zero, so that the count doesn't
+/// depend on the cell size. Loops that never run are removed. Once `budget`
+/// runs out of time, the rest of the loops are left as they are.
+///
+/// With `counts` from a training run, hot loops may unroll further and loops
+/// that never ran in training aren't looked at beyond removing them.
+pub fn unroll_loops(
+    tokens: Tokens,
+    limit: usize,
+    budget: &Budget,
+    counts: Option<&Counts>,
+) -> Tokens {
+    let mut unroller = Unroller {
+        limit,
+        budget,
+        counts,
+        known: Known {
+            rest_zero: true,
+            ..Known::default()
+        },
+        output: Vec::with_capacity(tokens.len()),
+        expired: false,
+    };
+    unroller.copy(&tokens);
+    let mut output = unroller.output;
+    lex::link_loops(&mut output);
+    output
+}
+
+/// Copies a program into `output`, unrolling loops as it goes.
+struct Unroller<'a> {
+    limit: usize,
+    budget: &'a Budget,
+    counts: Option<&'a Counts>,
+    known: Known,
+    output: Tokens,
+    /// Whether the budget ran out, after which everything is copied as it is
+    expired: bool,
+}
+
+impl Unroller<'_> {
+    /// Copies `tokens` to the output, unrolling the loops it can. The copies
+    /// of an unrolled body are gone through in turn, since what's known
+    /// changes from one to the next.
+    fn copy(&mut self, tokens: &[(Token, Span)]) {
+        let mut i = 0;
+        while i < tokens.len() {
+            if self.expired {
+                self.output.extend_from_slice(&tokens[i..]);
+                return;
+            }
+            let (token, span) = tokens[i];
+            match token {
+                Token::PtrAdd(n) => self.known.offset += n as isize,
+                Token::PtrSub(n) => self.known.offset -= n as isize,
+                Token::Add(n) => self.known.add(n as i64),
+                Token::Sub(n) => self.known.add(-(n as i64)),
+                Token::GetChar | Token::ExchangeCell => {
+                    self.known.cells.insert(self.known.offset, None);
+                }
+                Token::PutChar | Token::Debug | Token::LoopEnd(_) | Token::ProcEnd(_) => {}
+                Token::LoopStart(_) if self.budget.expired() => {
+                    log::info!("Ran out of time unrolling loops, leaving the rest rolled");
+                    self.expired = true;
+                    continue;
+                }
+                Token::LoopStart(distance) => {
+                    let end = i + distance as usize;
+                    let body = &tokens[i + 1..end];
+                    let heat = self
+                        .counts
+                        .map_or(Heat::Unknown, |counts| counts.heat(span));
+                    let limit = match heat {
+                        Heat::Hot => self.limit * pgo::HOT_UNROLL_FACTOR,
+                        _ => self.limit,
+                    };
+                    let iterations = match self.known.value() {
+                        Some(0) => Some(0),
+                        _ if heat == Heat::Cold => None,
+                        Some(value) => decrement(body)
+                            .filter(|step| value % step == 0)
+                            .map(|step| (value / step) as usize),
+                        None => None,
+                    };
+
+                    if let Some(iterations) = iterations.filter(|n| n * body.len() <= limit) {
+                        // Carry on through the copies, which may make later
+                        // loops unrollable too
+                        for _ in 0..iterations {
+                            self.copy(body);
+                        }
+                    } else {
+                        self.output.extend_from_slice(&tokens[i..=end]);
+                        // All that's known after a loop is that its cell is
+                        // zero
+                        self.known.forget();
+                        self.known.cells.insert(0, Some(0));
+                    }
+                    i = end + 1;
+                    continue;
+                }
+                Token::ProcStart(n) => {
+                    let end = i + tokens[i..]
+                        .iter()
+                        .position(|(t, _)| *t == Token::ProcEnd(n))
+                        .unwrap();
+                    self.output.extend_from_slice(&tokens[i..=end]);
+                    i = end + 1;
+                    continue;
+                }
+                Token::Call | Token::Extern(_) | Token::SwitchTape => self.known.forget(),
+                Token::SetZero => {
+                    self.known.cells.insert(self.known.offset, Some(0));
+                }
+                Token::MulAdd { offset, factor } => self
+                    .known
+                    .add_at(offset.into(), self.known.value().map(|v| v * factor as i64)),
+                Token::AddAt { offset, amount } => {
+                    self.known.add_at(offset.into(), Some(amount.into()))
+                }
+                Token::ScanRight { .. } | Token::ScanLeft { .. } => {
+                    self.known.forget();
+                    self.known.cells.insert(0, Some(0));
+                }
+            }
+            self.output.push((token, span));
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `source` with loops unrolled into at most `limit` tokens, as
+    /// brainfuck, after checking the loops are still linked.
+    fn unrolled(source: &str, limit: usize) -> String {
+        let tokens = lex::lex(lex::spanned_chars(source, None)).tokens().unwrap();
+        let tokens = unroll_loops(tokens, limit, &Budget::new(None, None), None);
+        for (i, &(token, _)) in tokens.iter().enumerate() {
+            if let Token::LoopStart(distance) = token {
+                let end = i + distance as usize;
+                assert_eq!(tokens[end].0, Token::LoopEnd(distance), "loop at {i}");
+            }
+        }
+        tokens.iter().map(|(token, _)| token.to_bf()).collect()
+    }
+
+    #[test]
+    fn unrolls_known_counts() {
+        assert_eq!(unrolled("+++[>+<-]", 100), "+++>+<->+<->+<-");
+        assert_eq!(unrolled("++++[>+<--]", 100), "++++>+<-->+<--");
+    }
+
+    #[test]
+    fn removes_loops_that_never_run() {
+        assert_eq!(unrolled("[>+<-]+.", 100), "+.");
+    }
+
+    #[test]
+    fn keeps_loops_it_cannot_count() {
+        // Unknown input, a body that doesn't count down, a count the step
+        // doesn't divide, a nested loop and a body over the limit
+        for source in [",[>+<-]", "++[>+<]", "+++[>+<--]", "++[>[-]<-]"] {
+            assert_eq!(unrolled(source, 100), source);
+        }
+        assert_eq!(unrolled("+++[>+<-]", 11), "+++[>+<-]");
+    }
+
+    #[test]
+    fn carries_on_through_the_copies() {
+        // Each copy adds 3 to the next cell, which then counts down from 6
+        assert_eq!(unrolled("++[>+++<-]>[-]", 100), "++>+++<->+++<->------");
+    }
+
+    #[test]
+    fn links_the_loops_left() {
+        assert_eq!(
+            unrolled("++[>+<-],[>+<-[>+<-]]", 100),
+            "++>+<->+<-,[>+<-[>+<-]]"
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use crate::{
+    interpret::{Config, Machine, Program},
+    lex::{Span, Token},
+};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::{Line, Span as Styled, Text},
+    widgets::{Block, Paragraph},
+    DefaultTerminal, Frame,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{self, Read},
+    time::{Duration, Instant},
+};
+
+/// How often the view is redrawn
+const TICK: Duration = Duration::from_millis(50);
+
+/// Slowest and fastest speeds, in steps a second
+const MIN_SPEED: u64 = 1;
+const MAX_SPEED: u64 = 10_000_000;
+
+const KEYS: &str = "space pause  s step  +/- speed  q quit";
+const INPUT_KEYS: &str = "type input  enter newline  ctrl-d end input  esc quit";
+
+/// The program's input, keeping what it has read so far to show.
+struct Input {
+    pending: VecDeque<u8>,
+    read: Vec<u8>,
+    /// Whether more can be typed once `pending` runs out, rather than the
+    /// program reading the end of input
+    interactive: bool,
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.pending.read(buf)?;
+        self.read.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+struct View<'a> {
+    program: Program<'a>,
+    machine: Machine,
+    input: Input,
+    output: Vec<u8>,
+    paused: bool,
+    speed: u64,
+    /// Set once the program finishes or fails
+    outcome: Option<Result<(), String>>,
+    /// Lines of each source file, read when first shown
+    sources: HashMap<String, Vec<String>>,
+    infile: &'a str,
+}
+
+impl View<'_> {
+    /// Whether the program is stopped at a `,` until some input is typed.
+    fn is_waiting(&self) -> bool {
+        self.input.interactive
+            && self.input.pending.is_empty()
+            && matches!(self.program.next(), Some((Token::GetChar, _)))
+    }
+
+    fn is_running(&self) -> bool {
+        !self.paused && self.outcome.is_none() && !self.is_waiting()
+    }
+
+    /// Runs up to `steps` ops, stopping at a `,` that's waiting for input.
+    fn step(&mut self, mut steps: u64) {
+        while steps > 0 && self.outcome.is_none() && !self.is_waiting() {
+            // Only a `,` can wait, so with input to spare it all runs at once
+            let batch = if self.input.interactive { 1 } else { steps };
+            let result =
+                self.machine
+                    .resume(&mut self.program, batch, &mut self.input, &mut self.output);
+            steps -= batch;
+
+            if let Err(e) = result {
+                self.outcome = Some(Err(e.to_string()));
+            } else if self.program.is_finished() {
+                self.outcome = Some(Ok(()));
+            }
+        }
+    }
+
+    /// Handles a key press, returning whether to carry on.
+    fn key(&mut self, code: KeyCode, ctrl: bool) -> bool {
+        if self.is_waiting() {
+            match code {
+                KeyCode::Esc => return false,
+                KeyCode::Char('d') if ctrl => self.input.interactive = false,
+                KeyCode::Char(c) => {
+                    let mut buf = [0; 4];
+                    self.input.pending.extend(c.encode_utf8(&mut buf).bytes());
+                }
+                KeyCode::Enter => self.input.pending.push_back(b'\n'),
+                _ => {}
+            }
+            return true;
+        }
+
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char(' ') => self.paused = !self.paused,
+            KeyCode::Char('s') | KeyCode::Right => {
+                self.paused = true;
+                self.step(1);
+            }
+            KeyCode::Char('+') | KeyCode::Char('=') => {
+                self.speed = (self.speed * 10).min(MAX_SPEED);
+            }
+            KeyCode::Char('-') => self.speed = (self.speed / 10).max(MIN_SPEED),
+            _ => {}
+        }
+        true
+    }
+
+    /// The line `span` is on, read from its file.
+    fn source_line(&mut self, span: Span) -> Option<&str> {
+        let file = span.file.map_or(self.infile, |file| file.as_str());
+        let lines = self.sources.entry(file.to_string()).or_insert_with(|| {
+            fs::read_to_string(file)
+                .map(|contents| contents.lines().map(str::to_string).collect())
+                .unwrap_or_default()
+        });
+        lines.get(span.line as usize - 1).map(String::as_str)
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [tape, source, io, status] = Layout::vertical([
+            Constraint::Length(5),
+            Constraint::Length(4),
+            Constraint::Min(4),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [input, output] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)]).areas(io);
+
+        self.draw_tape(frame, tape);
+        self.draw_source(frame, source);
+        draw_bytes(frame, input, "Input read", &self.input.read);
+        draw_bytes(frame, output, "Output", &self.output);
+        self.draw_status(frame, status);
+    }
+
+    /// Draws the cells around the pointer, with their indices above them.
+    fn draw_tape(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(format!(" Tape, pointer at {} ", self.machine.ptr()));
+        let inner = block.inner(area);
+
+        let width = (0..self.machine.len())
+            .map(|i| self.machine.cell(i))
+            .max()
+            .unwrap_or(0)
+            .max(u8::MAX.into())
+            .to_string()
+            .len()
+            .max(self.machine.len().to_string().len())
+            + 1;
+        let count = (inner.width as usize / width).clamp(1, self.machine.len());
+        let start = self
+            .machine
+            .ptr()
+            .saturating_sub(count / 2)
+            .min(self.machine.len() - count);
+
+        let mut indices = vec![];
+        let mut cells = vec![];
+        let mut pointer = vec![];
+        for i in start..start + count {
+            let style = if i == self.machine.ptr() {
+                Style::new().reversed()
+            } else {
+                Style::new()
+            };
+            indices.push(Styled::raw(format!("{i:>width$}")).dim());
+            cells.push(Styled::styled(
+                format!("{:>width$}", self.machine.cell(i)),
+                style,
+            ));
+            let marker = if i == self.machine.ptr() { "^" } else { "" };
+            pointer.push(Styled::raw(format!("{marker:>width$}")));
+        }
+
+        let text = Text::from(vec![
+            Line::from(indices),
+            Line::from(cells),
+            Line::from(pointer),
+        ]);
+        frame.render_widget(Paragraph::new(text).block(block), area);
+    }
+
+    /// Draws the next instruction and the line of source it comes from.
+    fn draw_source(&mut self, frame: &mut Frame, area: Rect) {
+        let Some((token, span)) = self.program.next() else {
+            let block = Block::bordered().title(" Next instruction ");
+            frame.render_widget(
+                Paragraph::new("none, the program has ended").block(block),
+                area,
+            );
+            return;
+        };
+
+        let title = format!(" Next instruction: {} at {span} ", token.kind());
+        let width = area.width.saturating_sub(2) as usize;
+        let line = match self.source_line(span) {
+            Some(line) => {
+                let chars: Vec<char> = line.chars().collect();
+                let col = (span.col as usize - 1).min(chars.len());
+                // Scroll long lines to keep the instruction in view
+                let first = col.saturating_sub(width / 2);
+                let before: String = chars[first..col].iter().collect();
+                let at: String = chars.get(col).map(char::to_string).unwrap_or_default();
+                let after: String = chars.iter().skip(col + 1).collect();
+                Line::from(vec![
+                    Styled::raw(before),
+                    Styled::raw(at).reversed(),
+                    Styled::raw(after),
+                ])
+            }
+            None => Line::from(token.to_bf()),
+        };
+
+        let block = Block::bordered().title(title);
+        frame.render_widget(Paragraph::new(line).block(block), area);
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let (state, keys) = match &self.outcome {
+            Some(Ok(())) => ("finished".to_string(), KEYS),
+            Some(Err(e)) => (format!("failed: {e}"), KEYS),
+            None if self.is_waiting() => ("waiting for input".to_string(), INPUT_KEYS),
+            None if self.paused => ("paused".to_string(), KEYS),
+            None => ("running".to_string(), KEYS),
+        };
+        let line = Line::from(vec![
+            Styled::raw(format!(" {state} ")).reversed(),
+            Styled::raw(format!(" {} steps/s  {keys}", self.speed)),
+        ]);
+        frame.render_widget(Paragraph::new(line), area);
+    }
+}
+
+/// Draws `bytes` as text, scrolled to show the end.
+fn draw_bytes(frame: &mut Frame, area: Rect, title: &str, bytes: &[u8]) {
+    let text = String::from_utf8_lossy(bytes);
+    let lines = text.lines().count() + usize::from(text.ends_with('\n'));
+    let height = area.height.saturating_sub(2) as usize;
+    let scroll = lines.saturating_sub(height) as u16;
+
+    let block = Block::bordered().title(format!(" {title} "));
+    frame.render_widget(Paragraph::new(text).block(block).scroll((scroll, 0)), area);
+}
+
+fn event_loop(terminal: &mut DefaultTerminal, view: &mut View) -> io::Result<()> {
+    let mut last = Instant::now();
+    // Steps owed from earlier ticks, for speeds under one step a tick
+    let mut due = 0.0;
+
+    loop {
+        terminal.draw(|frame| view.draw(frame))?;
+
+        if event::poll(TICK)? {
+            if let Event::Key(key) = event::read()? {
+                let ctrl = key.modifiers.contains(event::KeyModifiers::CONTROL);
+                if key.kind == KeyEventKind::Press && !view.key(key.code, ctrl) {
+                    return Ok(());
+                }
+            }
+        }
+
+        let now = Instant::now();
+        if view.is_running() {
+            due += view.speed as f64 * (now - last).as_secs_f64();
+            let steps = due as u64;
+            due -= steps as f64;
+            view.step(steps);
+        } else {
+            due = 0.0;
+        }
+        last = now;
+    }
+}
+
+/// Runs `tokens` from `infile` in a terminal view of the tape, the next
+/// instruction and the input and output so far, until the user quits.
+/// `input` is fed to the program first, after which it waits for what's
+/// typed unless `interactive` is false. Returns everything the program wrote.
+pub fn run(
+    tokens: &[(Token, Span)],
+    config: &Config,
+    infile: &str,
+    input: Vec<u8>,
+    interactive: bool,
+) -> io::Result<Vec<u8>> {
+    let mut view = View {
+        program: Program::new(tokens),
+        machine: Machine::new(config),
+        input: Input {
+            pending: input.into(),
+            read: vec![],
+            interactive,
+        },
+        output: vec![],
+        paused: true,
+        speed: 10,
+        outcome: None,
+        sources: HashMap::new(),
+        infile,
+    };
+    if view.program.is_finished() {
+        view.outcome = Some(Ok(()));
+    }
+
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, &mut view);
+    ratatui::restore();
+
+    result.map(|()| view.output)
+}